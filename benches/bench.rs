@@ -0,0 +1,47 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The scale-out harness of the benchmarks (see `mkbench.rs`), along with the
+//! synthetic data structure of the `synthetic` benchmark.
+//!
+//! `ScaleBenchBuilder` runs a data structure replicated with node-replication
+//! for every combination of replica strategy, log strategy, thread mapping,
+//! number of threads and batch size it is configured with, and reports the
+//! throughput of every run.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use node_replication::Replica;
+//! use nr_bench::bench::{
+//!     generate_operations, AbstractDataStructure, LogStrategy, Operation, ScaleBenchBuilder,
+//!     TestHarness,
+//! };
+//!
+//! let ops = generate_operations(10_000, 0, false, false, true);
+//! let mut harness = TestHarness::default();
+//!
+//! ScaleBenchBuilder::<Replica<AbstractDataStructure>>::new(ops)
+//!     .machine_defaults()
+//!     .log_strategy(LogStrategy::One)
+//!     .configure(
+//!         &mut harness,
+//!         "synthetic-scaleout",
+//!         |cid, rid, _log, replica, op, _batch_size| match op {
+//!             Operation::ReadOperation(mut o) => {
+//!                 o.set_tid(cid as usize);
+//!                 replica.execute(o, rid).unwrap().unwrap();
+//!             }
+//!             Operation::WriteOperation(mut o) => {
+//!                 o.set_tid(cid as usize);
+//!                 replica.execute_mut(o, rid).unwrap().unwrap();
+//!             }
+//!         },
+//!     );
+//! ```
+
+pub use crate::abstract_ds::{generate_operations, AbstractDataStructure, OpRd, OpWr};
+pub use crate::mkbench::{BenchFn, LogStrategy, ReplicaStrategy, ReplicaTrait, ScaleBenchBuilder};
+pub use crate::utils::benchmark::TestHarness;
+pub use crate::utils::topology::ThreadMapping;
+pub use crate::utils::Operation;
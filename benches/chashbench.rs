@@ -231,6 +231,7 @@ impl Default for NrHashMap {
 impl Dispatch for NrHashMap {
     type ReadOperation = OpRd;
     type WriteOperation = OpWr;
+    type ScanOperation = OpRd;
     type Response = Result<u64, ()>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -248,6 +249,10 @@ impl Dispatch for NrHashMap {
             }
         }
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 struct ReplicaAndToken<'a> {
@@ -448,14 +448,15 @@ impl<'a> ReplicaAndToken<'a> {
 impl<'a> Backend for ReplicaAndToken<'a> {
     fn b_get(&mut self, key: u64) -> u64 {
         match self.replica.execute(OpRd::Get(key), self.token) {
-            Ok(res) => return res,
-            Err(_) => unreachable!(),
+            Ok(Ok(res)) => return res,
+            _ => unreachable!(),
         }
     }
 
     fn b_put(&mut self, key: u64, value: u64) {
         self.replica
             .execute_mut(OpWr::Put(key, value), self.token)
+            .unwrap()
             .unwrap();
     }
 }
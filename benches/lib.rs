@@ -0,0 +1,17 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Parts of the benchmarks that other crates can reuse to evaluate their own
+//! data structures with node-replication. Enable the `bench` feature to get
+//! the [bench] module.
+#![cfg_attr(feature = "bench", feature(bench_black_box))]
+
+#[cfg(feature = "bench")]
+mod abstract_ds;
+#[cfg(feature = "bench")]
+mod mkbench;
+#[cfg(feature = "bench")]
+mod utils;
+
+#[cfg(feature = "bench")]
+pub mod bench;
@@ -87,6 +87,7 @@ impl Default for SegQueueWrapper {
 impl Dispatch for SegQueueWrapper {
     type ReadOperation = QueueConcurrent;
     type WriteOperation = ();
+    type ScanOperation = QueueConcurrent;
     type Response = Result<Option<u64>, ()>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -104,6 +105,10 @@ impl Dispatch for SegQueueWrapper {
     fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
         unreachable!("dispatch_mut should not be called here")
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -124,6 +129,7 @@ impl Default for SkipListWrapper {
 impl Dispatch for SkipListWrapper {
     type ReadOperation = SkipListConcurrent;
     type WriteOperation = ();
+    type ScanOperation = SkipListConcurrent;
     type Response = Result<Option<u64>, ()>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -140,4 +146,8 @@ impl Dispatch for SkipListWrapper {
     fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
         unreachable!("dispatch_mut should not be called here")
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
@@ -106,6 +106,7 @@ impl Default for SkipListWrapper {
 impl Dispatch for SkipListWrapper {
     type ReadOperation = SkipListConcurrent;
     type WriteOperation = OpWr;
+    type ScanOperation = SkipListConcurrent;
     type Response = Result<Option<u64>, ()>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -123,4 +124,8 @@ impl Dispatch for SkipListWrapper {
             }
         }
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
@@ -15,6 +15,7 @@ use std::sync::Arc;
 use node_replication::Dispatch;
 use node_replication::Log;
 use node_replication::Replica;
+use node_replication::ReplicaId;
 use rand::distributions::Distribution;
 use rand::{Rng, RngCore};
 
@@ -74,7 +75,7 @@ fn log_scale_bench(c: &mut TestHarness) {
             "log-append",
             |_cid, rid, log, replica, op, batch_size| match op {
                 Operation::WriteOperation(o) => {
-                    let _r = log.append(&vec![*o], rid.id(), |_o, _i| {});
+                    let _r = log.append(&vec![*o], ReplicaId::new(rid.id().get()), |_o, _i| {});
                 }
                 _ => unreachable!(),
             },
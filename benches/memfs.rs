@@ -348,10 +348,10 @@ fn memfs_scale_out(c: &mut TestHarness) {
             "memfs-scaleout",
             |_cid, rid, _log, replica, op, _batch_size| match op {
                 Operation::ReadOperation(o) => {
-                    replica.execute(*o, rid).unwrap();
+                    replica.execute(*o, rid).unwrap().unwrap();
                 }
                 Operation::WriteOperation(o) => {
-                    replica.execute_mut(*o, rid).unwrap();
+                    replica.execute_mut(*o, rid).unwrap().unwrap();
                 }
             },
         );
@@ -1,7 +1,9 @@
 // Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-//! Helper functions to instantiate and configure benchmarks.
+//! Helper functions to instantiate and configure benchmarks. With the `bench`
+//! feature, the library of this crate exports `ScaleBenchBuilder` (see
+//! `bench.rs`).
 //!
 //! The file exports two items:
 //!  - baseline_comparison: A generic function to compare a data-structure
@@ -28,10 +30,10 @@ use cnr::{Dispatch, Log, Replica, ReplicaToken};
 use csv::WriterBuilder;
 use log::*;
 #[cfg(feature = "nr")]
-use node_replication::{Dispatch, Log, Replica, ReplicaToken};
+use node_replication::{Dispatch, Log, Replica, ReplicaToken, SubReplica, MAX_THREADS_PER_REPLICA};
 use rand::seq::SliceRandom;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 
 use crate::utils;
 use crate::utils::benchmark::*;
@@ -49,7 +51,7 @@ use arr_macro::arr;
 pub const WARN_THRESHOLD: usize = 1 << 28;
 
 #[cfg(feature = "nr")]
-type BenchFn<R> = fn(
+pub type BenchFn<R> = fn(
     crate::utils::ThreadId,
     ReplicaToken,
     &Arc<Log<'static, <<R as ReplicaTrait>::D as Dispatch>::WriteOperation>>,
@@ -62,7 +64,7 @@ type BenchFn<R> = fn(
 );
 
 #[cfg(feature = "c_nr")]
-type BenchFn<R> = fn(
+pub type BenchFn<R> = fn(
     crate::utils::ThreadId,
     ReplicaToken,
     &Vec<Arc<Log<'static, <<R as ReplicaTrait>::D as Dispatch>::WriteOperation>>>,
@@ -79,6 +81,22 @@ pub trait ReplicaTrait {
 
     fn new_arc(log: Vec<Arc<Log<'static, <Self::D as Dispatch>::WriteOperation>>>) -> Arc<Self>;
 
+    /// Creates a replica for a group of `cores` cores with
+    /// `ReplicaStrategy::Hierarchical`. `sibling` is the replica of another group
+    /// on the same socket, if one was created already.
+    ///
+    /// By default, all groups of a socket share one replica.
+    fn new_arc_grouped(
+        log: Vec<Arc<Log<'static, <Self::D as Dispatch>::WriteOperation>>>,
+        sibling: Option<&Arc<Self>>,
+        cores: usize,
+    ) -> Arc<Self> {
+        match sibling {
+            Some(sibling) => sibling.clone(),
+            None => Self::new_arc(log),
+        }
+    }
+
     fn register_me(&self) -> Option<ReplicaToken>;
 
     fn sync_me(&self, idx: ReplicaToken);
@@ -115,6 +133,9 @@ impl<'a, T: Dispatch + Sync + Default> ReplicaTrait for Replica<'a, T> {
     }
 
     fn sync_me(&self, idx: ReplicaToken) {
+        #[cfg(feature = "nr")]
+        self.sync(idx).expect("Replica failed.");
+        #[cfg(feature = "c_nr")]
         self.sync(idx);
     }
 
@@ -132,7 +153,10 @@ impl<'a, T: Dispatch + Sync + Default> ReplicaTrait for Replica<'a, T> {
         op: <Self::D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <Self::D as Dispatch>::Response {
-        self.execute_mut(op, idx)
+        #[cfg(feature = "nr")]
+        return self.execute_mut(op, idx).expect("Replica failed.");
+        #[cfg(feature = "c_nr")]
+        return self.execute_mut(op, idx);
     }
 
     fn exec_scan(
@@ -141,7 +165,7 @@ impl<'a, T: Dispatch + Sync + Default> ReplicaTrait for Replica<'a, T> {
         idx: ReplicaToken,
     ) -> <Self::D as Dispatch>::Response {
         #[cfg(feature = "nr")]
-        return self.execute_mut(op, idx);
+        return self.execute_mut(op, idx).expect("Replica failed.");
         #[cfg(feature = "c_nr")]
         return self.execute_mut_scan(op, idx);
     }
@@ -151,7 +175,69 @@ impl<'a, T: Dispatch + Sync + Default> ReplicaTrait for Replica<'a, T> {
         op: <Self::D as Dispatch>::ReadOperation,
         idx: ReplicaToken,
     ) -> <Self::D as Dispatch>::Response {
-        self.execute(op, idx)
+        #[cfg(feature = "nr")]
+        return self.execute(op, idx).expect("Replica failed.");
+        #[cfg(feature = "c_nr")]
+        return self.execute(op, idx);
+    }
+}
+
+/// Groups of cores that share a replica (see `ReplicaStrategy::Hierarchical`).
+#[cfg(feature = "nr")]
+impl<T: Dispatch + Sync + Default> ReplicaTrait for SubReplica<'static, T> {
+    type D = T;
+
+    fn new_arc(log: Vec<Arc<Log<'static, <Self::D as Dispatch>::WriteOperation>>>) -> Arc<Self> {
+        // Every thread of the group takes a second identifier on the replica for
+        // reads, and the group one more.
+        let members = (MAX_THREADS_PER_REPLICA - 1) / 2;
+        SubReplica::new(&Replica::new(&log[0]), members).expect("Can't create group.")
+    }
+
+    fn new_arc_grouped(
+        log: Vec<Arc<Log<'static, <Self::D as Dispatch>::WriteOperation>>>,
+        sibling: Option<&Arc<Self>>,
+        cores: usize,
+    ) -> Arc<Self> {
+        let replica = match sibling {
+            Some(sibling) => sibling.replica().clone(),
+            None => Replica::new(&log[0]),
+        };
+        SubReplica::new(&replica, cores).expect("Too many groups for a socket.")
+    }
+
+    fn sync_me(&self, idx: ReplicaToken) {
+        self.sync(idx).expect("Replica failed.");
+    }
+
+    fn log_sync(&self, _idx: ReplicaToken, _logid: usize) {}
+
+    fn register_me(&self) -> Option<ReplicaToken> {
+        self.register()
+    }
+
+    fn exec(
+        &self,
+        op: <Self::D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> <Self::D as Dispatch>::Response {
+        self.execute_mut(op, idx).expect("Replica failed.")
+    }
+
+    fn exec_scan(
+        &self,
+        op: <Self::D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> <Self::D as Dispatch>::Response {
+        self.execute_mut(op, idx).expect("Replica failed.")
+    }
+
+    fn exec_ro(
+        &self,
+        op: <Self::D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> <Self::D as Dispatch>::Response {
+        self.execute(op, idx).expect("Replica failed.")
     }
 }
 
@@ -336,7 +422,7 @@ pub(crate) fn baseline_comparison<R: ReplicaTrait>(
 }
 
 /// How replicas are mapped to cores/threads.
-#[derive(Serialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub enum ReplicaStrategy {
     /// One replica per system.
     One,
@@ -350,6 +436,30 @@ pub enum ReplicaStrategy {
     Socket,
     /// One for every hardware thread.
     PerThread,
+    /// One replica per socket, shared by groups of (at most) the given number
+    /// of cores. The cores of a group combine among themselves first, so only
+    /// one core per group contends for the combiner lock of the replica.
+    /// Benchmarks need to use `SubReplica` as the replica type for this; other
+    /// replica types get one replica per socket.
+    Hierarchical(usize),
+}
+
+impl Serialize for ReplicaStrategy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (index, variant) = match *self {
+            ReplicaStrategy::One => (0, "One"),
+            ReplicaStrategy::L1 => (1, "L1"),
+            ReplicaStrategy::L2 => (2, "L2"),
+            ReplicaStrategy::L3 => (3, "L3"),
+            ReplicaStrategy::Socket => (4, "Socket"),
+            ReplicaStrategy::PerThread => (5, "PerThread"),
+            // CSV files can't hold variants with data, so encode it in the name.
+            ReplicaStrategy::Hierarchical(cores) => {
+                return serializer.collect_str(&format_args!("Hierarchical{}", cores));
+            }
+        };
+        serializer.serialize_unit_variant("ReplicaStrategy", index, variant)
+    }
 }
 
 impl fmt::Display for ReplicaStrategy {
@@ -361,6 +471,7 @@ impl fmt::Display for ReplicaStrategy {
             ReplicaStrategy::L3 => write!(f, "L3"),
             ReplicaStrategy::Socket => write!(f, "Socket"),
             ReplicaStrategy::PerThread => write!(f, "PerThread"),
+            ReplicaStrategy::Hierarchical(cores) => write!(f, "Hierarchical{}", cores),
         }
     }
 }
@@ -374,6 +485,7 @@ impl fmt::Debug for ReplicaStrategy {
             ReplicaStrategy::L3 => write!(f, "RS=L3"),
             ReplicaStrategy::Socket => write!(f, "RS=Socket"),
             ReplicaStrategy::PerThread => write!(f, "RS=PerThread"),
+            ReplicaStrategy::Hierarchical(cores) => write!(f, "RS=Hierarchical{}", cores),
         }
     }
 }
@@ -626,6 +738,10 @@ where
     }
 
     fn alloc_replicas(&mut self, replicas: &mut Vec<Arc<R>>) {
+        if let ReplicaStrategy::Hierarchical(_) = self.rs {
+            return self.alloc_groups(replicas);
+        }
+
         let mut handles = Vec::with_capacity(self.rm.len());
         for (rid, cores) in self.rm.clone().into_iter() {
             let log = self.log.clone();
@@ -654,34 +770,60 @@ where
         }
     }
 
+    /// Like `alloc_replicas`, but for `ReplicaStrategy::Hierarchical`: the groups
+    /// of a socket are created one after the other, so that all but the first
+    /// one can share the replica of the first one.
+    fn alloc_groups(&mut self, replicas: &mut Vec<Arc<R>>) {
+        let topology = MachineTopology::new();
+        let mut groups: Vec<(usize, Vec<Cpu>)> = self.rm.clone().into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut first_on_socket: HashMap<Socket, Arc<R>> = HashMap::new();
+        for (_rid, cores) in groups {
+            let core0 = cores[0];
+            let socket = topology
+                .sockets()
+                .into_iter()
+                .find(|s| topology.cpus_on_socket(*s).iter().any(|c| c.cpu == core0))
+                .expect("Core not found in topology.");
+
+            let log = self.log.clone();
+            let sibling = first_on_socket.get(&socket).cloned();
+            let replica = thread::spawn(move || {
+                // Pinning the thread to the replica' cores forces the memory
+                // allocation to be local to the where a replica will be used later
+                utils::pin_thread(core0);
+                R::new_arc_grouped(log, sibling.as_ref(), cores.len())
+            })
+            .join()
+            .unwrap();
+
+            first_on_socket
+                .entry(socket)
+                .or_insert_with(|| replica.clone());
+            replicas.push(replica);
+        }
+    }
+
     fn startup(&mut self) {
         let stuck = Arc::new(arr![AtomicUsize::new(0); 192]);
         let nlogs = self.log.len();
 
         #[cfg(feature = "c_nr")]
         {
-            use core::sync::atomic::AtomicBool;
             for i in 0..nlogs {
                 let stuck = stuck.clone();
-                let func = move |rid: &[AtomicBool; 192], idx: usize| {
-                    for replia in 0..192 {
-                        if rid[replia].compare_exchange_weak(
-                            true,
-                            false,
-                            Ordering::Relaxed,
-                            Ordering::Relaxed,
-                        ) == Ok(true)
-                        {
-                            stuck[replia].compare_exchange_weak(
-                                0,
-                                idx,
-                                Ordering::Release,
-                                Ordering::Relaxed,
-                            );
-                        }
-                    }
+                let listener = move |log_id: usize, rid: usize| {
+                    let _r = stuck[rid - 1].compare_exchange(
+                        0,
+                        log_id,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    );
+                };
+                unsafe {
+                    Arc::get_mut_unchecked(&mut self.log[i]).set_gc_listener(Arc::new(listener))
                 };
-                unsafe { Arc::get_mut_unchecked(&mut self.log[i]).update_closure(func) };
             }
         }
 
@@ -972,6 +1114,26 @@ where
                     rm.insert(idx, vec![core]);
                 }
             }
+            ReplicaStrategy::Hierarchical(cores_per_group) => {
+                assert!(cores_per_group > 0, "Groups need at least one core.");
+                let mut sockets: Vec<Socket> = cpus.iter().map(|t| t.socket).collect();
+                sockets.sort();
+                sockets.dedup();
+
+                // Groups of the same socket get consecutive identifiers.
+                let mut rid = 0;
+                for s in sockets {
+                    let on_socket: Vec<Cpu> = cpus
+                        .iter()
+                        .filter(|c| c.socket == s)
+                        .map(|c| c.cpu)
+                        .collect();
+                    for group in on_socket.chunks(cores_per_group) {
+                        rm.insert(rid, group.to_vec());
+                        rid += 1;
+                    }
+                }
+            }
         };
 
         rm
@@ -1136,7 +1298,7 @@ where
     ///
     /// TestHarness will be configured to create a run for every
     /// possible triplet: (replica strategy, thread mapping, #threads).
-    pub(crate) fn configure(&self, c: &mut TestHarness, name: &str, f: BenchFn<R>)
+    pub fn configure(&self, c: &mut TestHarness, name: &str, f: BenchFn<R>)
     where
         R: ReplicaTrait + Sync + Send,
         <R::D as Dispatch>::WriteOperation: Send + Sync + Copy,
@@ -86,6 +86,7 @@ unsafe impl Sync for NrFilesystem {}
 impl Dispatch for NrFilesystem {
     type ReadOperation = OpRd;
     type WriteOperation = OpWr;
+    type ScanOperation = OpRd;
     type Response = Result<usize, ()>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -116,6 +117,10 @@ impl Dispatch for NrFilesystem {
             }
         }
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 fn generate_nrfs_ops(write_ratio: usize) -> Vec<Operation<OpRd, OpWr>> {
@@ -12,6 +12,9 @@ use rand::RngCore;
 
 use node_replication::rwlock::RwLock;
 
+/// Number of reader threads supported by the lock.
+const READERS: usize = 256;
+
 fn main() {
     let args = std::env::args().filter(|e| e != "--bench");
     let matches = App::new("RwLock Benchmarker")
@@ -81,7 +84,7 @@ fn main() {
     }
 
     if versions.contains(&"rwlock") {
-        let map = Arc::new(RwLock::<usize>::default());
+        let map = Arc::new(RwLock::<usize, READERS>::default());
         let start = time::Instant::now();
         let end = start + dur;
         join.extend((0..readers).into_iter().map(|tid| {
@@ -119,7 +122,7 @@ fn run_std(lock: Arc<StdLock<usize>>, end: time::Instant, write: bool) -> (bool,
 }
 
 fn run_rwlock(
-    lock: Arc<RwLock<usize>>,
+    lock: Arc<RwLock<usize, READERS>>,
     end: time::Instant,
     write: bool,
     tid: usize,
@@ -130,7 +130,9 @@ impl BenchmarkGroup {
     pub fn finish(self) {}
 }
 
-pub(crate) struct TestHarness {
+/// Runs benchmark groups, every benchmark for a fixed `duration` (five seconds
+/// by default).
+pub struct TestHarness {
     duration: Duration,
 }
 
@@ -144,7 +146,8 @@ impl TestHarness {
 }
 
 impl TestHarness {
-    pub(crate) fn new(d: Duration) -> Self {
+    /// Creates a harness that runs every benchmark for `d`.
+    pub fn new(d: Duration) -> Self {
         TestHarness { duration: d }
     }
 }
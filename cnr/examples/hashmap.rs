@@ -45,6 +45,7 @@ impl LogMapper for Access {
 impl Dispatch for NrHashMap {
     type ReadOperation = Access;
     type WriteOperation = Modify;
+    type ScanOperation = Access;
     type Response = Option<u64>;
 
     /// The `dispatch` function applies the immutable operations.
@@ -60,6 +61,11 @@ impl Dispatch for NrHashMap {
             Modify::Put(key, value) => self.storage.insert(key, value),
         }
     }
+
+    /// The `dispatch_scan` function applies the scan operations.
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 /// We initialize a log, and two replicas for a hashmap, register with the replica
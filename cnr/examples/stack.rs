@@ -66,6 +66,7 @@ impl Default for Stack {
 impl Dispatch for Stack {
     type ReadOperation = Access;
     type WriteOperation = Modify;
+    type ScanOperation = Access;
     type Response = Result<u32, ()>;
 
     /// The `dispatch` function applies the immutable operations.
@@ -94,6 +95,11 @@ impl Dispatch for Stack {
             },
         }
     }
+
+    /// The `dispatch_scan` function applies the scan operations.
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 /// We initialize a log, and two replicas for a stack, register with the replica
@@ -0,0 +1,350 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Ready-made replicated versions of common collections, so that they don't
+//! need to be wrapped by hand to be used with a [Replica](../struct.Replica.html).
+//!
+//! Map operations go to a log based on the hash of their key; all operations on
+//! a vector go to the first log. The collections aren't concurrent, so every
+//! adapter protects its collection with a lock; operations on different logs
+//! still serialize on it.
+//!
+//! # Example
+//!
+//! ```
+//! use cnr::adapters::{MapRead, MapWrite, NrBTreeMap};
+//! use cnr::{Log, Replica};
+//! use std::sync::Arc;
+//!
+//! let log = Arc::new(Log::<MapWrite<u64, u64>>::new(2 * 1024 * 1024, 1));
+//! let replica = Replica::<NrBTreeMap<u64, u64>>::new(vec![log]);
+//! let idx = replica.register().unwrap();
+//!
+//! replica.execute_mut(MapWrite::Insert(1, 2), idx);
+//! assert_eq!(replica.execute(MapRead::Get(1), idx), Some(2));
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use core::cell::UnsafeCell;
+use core::fmt::Debug;
+use core::hash::{Hash, Hasher};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::{Dispatch, LogMapper};
+
+/// A collection along with a spinlock that serializes all accesses to it.
+pub(crate) struct Locked<T> {
+    lock: AtomicBool,
+    inner: UnsafeCell<T>,
+}
+
+/// Access to `inner` is serialized by `lock`.
+unsafe impl<T: Send> Sync for Locked<T> {}
+
+impl<T> Locked<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Locked {
+            lock: AtomicBool::new(false),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// Runs `f` with exclusive access to the collection.
+    pub(crate) fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let r = f(unsafe { &mut *self.inner.get() });
+        self.lock.store(false, Ordering::Release);
+        r
+    }
+}
+
+/// FNV-1a; hashes keys the same way on every replica (and without `std`).
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Maps an operation on `key` to a log based on the hash of `key`.
+fn key_log<K: Hash>(key: &K, nlogs: usize, logs: &mut Vec<usize>) {
+    let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+    key.hash(&mut hasher);
+    logs.clear();
+    logs.push(hasher.finish() as usize % nlogs);
+}
+
+/// Write operations on [NrHashMap](struct.NrHashMap.html) and
+/// [NrBTreeMap](struct.NrBTreeMap.html). Both return the previous value of the
+/// key, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapWrite<K, V> {
+    /// Inserts a value for a key.
+    Insert(K, V),
+    /// Removes a key.
+    Remove(K),
+}
+
+/// Read operations on [NrHashMap](struct.NrHashMap.html) and
+/// [NrBTreeMap](struct.NrBTreeMap.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapRead<K> {
+    /// Returns the value of a key, if any.
+    Get(K),
+}
+
+/// A replicated `HashMap`.
+#[cfg(feature = "std")]
+pub struct NrHashMap<K, V> {
+    storage: Locked<HashMap<K, V>>,
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Default for NrHashMap<K, V> {
+    fn default() -> Self {
+        NrHashMap {
+            storage: Locked::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash, V> LogMapper for MapWrite<K, V> {
+    fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+        match self {
+            MapWrite::Insert(key, _) | MapWrite::Remove(key) => key_log(key, nlogs, logs),
+        }
+    }
+}
+
+impl<K: Hash> LogMapper for MapRead<K> {
+    fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+        match self {
+            MapRead::Get(key) => key_log(key, nlogs, logs),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Dispatch for NrHashMap<K, V>
+where
+    K: Hash + Eq + Clone + Debug + Send,
+    V: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = MapRead<K>;
+    type WriteOperation = MapWrite<K, V>;
+    type ScanOperation = MapRead<K>;
+    type Response = Option<V>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            MapRead::Get(key) => self.storage.with(|map| map.get(&key).cloned()),
+        }
+    }
+
+    fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        self.storage.with(|map| match op {
+            MapWrite::Insert(key, value) => map.insert(key, value),
+            MapWrite::Remove(key) => map.remove(&key),
+        })
+    }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
+}
+
+/// A replicated `BTreeMap`.
+pub struct NrBTreeMap<K, V> {
+    storage: Locked<BTreeMap<K, V>>,
+}
+
+impl<K: Ord, V> Default for NrBTreeMap<K, V> {
+    fn default() -> Self {
+        NrBTreeMap {
+            storage: Locked::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<K, V> Dispatch for NrBTreeMap<K, V>
+where
+    K: Ord + Hash + Clone + Debug + Send,
+    V: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = MapRead<K>;
+    type WriteOperation = MapWrite<K, V>;
+    type ScanOperation = MapRead<K>;
+    type Response = Option<V>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            MapRead::Get(key) => self.storage.with(|map| map.get(&key).cloned()),
+        }
+    }
+
+    fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        self.storage.with(|map| match op {
+            MapWrite::Insert(key, value) => map.insert(key, value),
+            MapWrite::Remove(key) => map.remove(&key),
+        })
+    }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
+}
+
+/// Write operations on [NrVec](struct.NrVec.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum VecWrite<T> {
+    /// Appends an element; returns `None`.
+    Push(T),
+    /// Removes the last element and returns it.
+    Pop,
+    /// Replaces the element at an index and returns the previous one. Does
+    /// nothing and returns `None` if the index is out of bounds.
+    Set(usize, T),
+}
+
+/// Read operations on [NrVec](struct.NrVec.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum VecRead {
+    /// Returns the element at an index, if any.
+    Get(usize),
+}
+
+impl<T> LogMapper for VecWrite<T> {
+    fn hash(&self, _nlogs: usize, logs: &mut Vec<usize>) {
+        logs.clear();
+        logs.push(0);
+    }
+}
+
+impl LogMapper for VecRead {
+    fn hash(&self, _nlogs: usize, logs: &mut Vec<usize>) {
+        logs.clear();
+        logs.push(0);
+    }
+}
+
+/// A replicated `Vec`.
+pub struct NrVec<T> {
+    storage: Locked<Vec<T>>,
+}
+
+impl<T> Default for NrVec<T> {
+    fn default() -> Self {
+        NrVec {
+            storage: Locked::new(Vec::new()),
+        }
+    }
+}
+
+impl<T> Dispatch for NrVec<T>
+where
+    T: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = VecRead;
+    type WriteOperation = VecWrite<T>;
+    type ScanOperation = VecRead;
+    type Response = Option<T>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            VecRead::Get(index) => self.storage.with(|vec| vec.get(index).cloned()),
+        }
+    }
+
+    fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        self.storage.with(|vec| match op {
+            VecWrite::Push(value) => {
+                vec.push(value);
+                None
+            }
+            VecWrite::Pop => vec.pop(),
+            VecWrite::Set(index, value) => vec
+                .get_mut(index)
+                .map(|slot| core::mem::replace(slot, value)),
+        })
+    }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Log, Replica};
+    use alloc::sync::Arc;
+
+    // Tests that writes through a replicated BTreeMap are visible on all replicas.
+    #[test]
+    fn test_btreemap() {
+        let log = Arc::new(Log::<MapWrite<u64, u64>>::new(1024 * 1024, 1));
+        let r1 = Replica::<NrBTreeMap<u64, u64>>::new(vec![log.clone()]);
+        let r2 = Replica::<NrBTreeMap<u64, u64>>::new(vec![log]);
+        let (i1, i2) = (r1.register().unwrap(), r2.register().unwrap());
+
+        assert_eq!(r1.execute_mut(MapWrite::Insert(1, 10), i1), None);
+        assert_eq!(r2.execute_mut(MapWrite::Insert(1, 11), i2), Some(10));
+        assert_eq!(r1.execute(MapRead::Get(1), i1), Some(11));
+        assert_eq!(r1.execute_mut(MapWrite::Remove(1), i1), Some(11));
+        assert_eq!(r2.execute(MapRead::Get(1), i2), None);
+    }
+
+    // Tests that a replicated HashMap returns the latest value of every key when
+    // its keys are spread over several logs.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_hashmap() {
+        let logs: Vec<_> = (0..4)
+            .map(|i| Arc::new(Log::<MapWrite<u64, u64>>::new(1024 * 1024, i + 1)))
+            .collect();
+        let replica = Replica::<NrHashMap<u64, u64>>::new(logs);
+        let idx = replica.register().unwrap();
+
+        for key in 0..100 {
+            assert_eq!(replica.execute_mut(MapWrite::Insert(key, key), idx), None);
+        }
+        for key in 0..100 {
+            assert_eq!(replica.execute(MapRead::Get(key), idx), Some(key));
+        }
+    }
+
+    // Tests the operations of a replicated Vec.
+    #[test]
+    fn test_vec() {
+        let log = Arc::new(Log::<VecWrite<u64>>::new(1024 * 1024, 1));
+        let replica = Replica::<NrVec<u64>>::new(vec![log]);
+        let idx = replica.register().unwrap();
+
+        assert_eq!(replica.execute_mut(VecWrite::Push(1), idx), None);
+        assert_eq!(replica.execute_mut(VecWrite::Push(2), idx), None);
+        assert_eq!(replica.execute_mut(VecWrite::Set(0, 3), idx), Some(1));
+        assert_eq!(replica.execute_mut(VecWrite::Set(5, 3), idx), None);
+        assert_eq!(replica.execute(VecRead::Get(0), idx), Some(3));
+        assert_eq!(replica.execute_mut(VecWrite::Pop, idx), Some(2));
+        assert_eq!(replica.execute(VecRead::Get(1), idx), None);
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Runs sequential data structures written for the single-log
+//! `node-replication` crate on top of this crate, so that they can move over
+//! one at a time.
+//!
+//! A data structure implements the [Dispatch](trait.Dispatch.html) trait of
+//! this module, which (unlike [crate::Dispatch]) takes `&mut self` for write
+//! operations and doesn't need [LogMapper](../trait.LogMapper.html) for its
+//! operations. Its operations all go to a single log, and a lock serializes
+//! every access to it, like in [adapters](../adapters/index.html).
+//!
+//! # Example
+//!
+//! ```
+//! use cnr::compat::{Dispatch, Log, Replica};
+//! use std::sync::Arc;
+//!
+//! #[derive(Default)]
+//! struct Counter(u64);
+//!
+//! impl Dispatch for Counter {
+//!     type ReadOperation = ();
+//!     type WriteOperation = u64;
+//!     type Response = u64;
+//!
+//!     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+//!         self.0
+//!     }
+//!
+//!     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+//!         self.0 += op;
+//!         self.0
+//!     }
+//! }
+//!
+//! let log = Arc::new(Log::<<Counter as Dispatch>::WriteOperation>::default());
+//! let replica = Replica::<Counter>::new(&log);
+//! let idx = replica.register().unwrap();
+//!
+//! assert_eq!(replica.execute_mut(5, idx), 5);
+//! assert_eq!(replica.execute((), idx), 5);
+//! ```
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::fmt::Debug;
+
+use crate::adapters::Locked;
+use crate::{LogMapper, ReplicaToken};
+
+/// The `Dispatch` trait of the single-log crate. See [crate::Dispatch] for
+/// what the methods do.
+pub trait Dispatch {
+    /// A read-only operation.
+    type ReadOperation: Sized + Clone + PartialEq + Debug;
+
+    /// A write operation.
+    type WriteOperation: Sized + Clone + PartialEq + Debug + Send;
+
+    /// The value returned by the data structure for an operation.
+    type Response: Sized + Clone;
+
+    /// Executes a read-only operation against the data structure.
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response;
+
+    /// Executes a write operation against the data structure.
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response;
+}
+
+/// An operation of a sequential data structure. Always goes to the first log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Single<T>(pub T);
+
+impl<T> LogMapper for Single<T> {
+    fn hash(&self, _nlogs: usize, logs: &mut Vec<usize>) {
+        logs.clear();
+        logs.push(0);
+    }
+}
+
+/// A log of operations of a sequential data structure.
+pub type Log<'a, T> = crate::Log<'a, Single<T>>;
+
+/// A sequential data structure along with a lock that serializes all accesses
+/// to it. Implements [crate::Dispatch], so it can be replicated with
+/// [crate::Replica]; scans are executed as read operations.
+pub struct Sequential<D> {
+    inner: Locked<D>,
+}
+
+impl<D: Default> Default for Sequential<D> {
+    fn default() -> Self {
+        Sequential {
+            inner: Locked::new(D::default()),
+        }
+    }
+}
+
+impl<D: Dispatch> crate::Dispatch for Sequential<D> {
+    type ReadOperation = Single<D::ReadOperation>;
+    type WriteOperation = Single<D::WriteOperation>;
+    type Response = D::Response;
+    type ScanOperation = Single<D::ReadOperation>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        self.inner.with(|d| d.dispatch(op.0))
+    }
+
+    fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        self.inner.with(|d| d.dispatch_mut(op.0))
+    }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.inner.with(|d| d.dispatch(op.0))
+    }
+}
+
+/// A replica of a sequential data structure, with the constructor and methods
+/// of the replica of the single-log crate. A thin wrapper around a
+/// [crate::Replica] of one log, which `inner` returns for everything else.
+pub struct Replica<'a, D>
+where
+    D: Sized + Dispatch + Send,
+    D::WriteOperation: 'a,
+{
+    inner: Arc<crate::Replica<'a, Sequential<D>>>,
+}
+
+impl<'a, D> Replica<'a, D>
+where
+    D: Sized + Default + Dispatch + Send,
+    D::WriteOperation: 'a,
+{
+    /// Creates a replica of the data structure that executes the operations
+    /// on `log`.
+    pub fn new<'b>(log: &Arc<Log<'b, D::WriteOperation>>) -> Arc<Replica<'b, D>> {
+        Arc::new(Replica {
+            inner: crate::Replica::new(vec![log.clone()]),
+        })
+    }
+}
+
+impl<'a, D> Replica<'a, D>
+where
+    D: Sized + Dispatch + Send,
+    D::WriteOperation: 'a,
+{
+    /// Registers a thread with the replica. See `crate::Replica::register`.
+    pub fn register(&self) -> Option<ReplicaToken> {
+        self.inner.register()
+    }
+
+    /// Executes a write operation. See `crate::Replica::execute_mut`.
+    pub fn execute_mut(&self, op: D::WriteOperation, idx: ReplicaToken) -> D::Response {
+        self.inner.execute_mut(Single(op), idx)
+    }
+
+    /// Executes a read-only operation. See `crate::Replica::execute`.
+    pub fn execute(&self, op: D::ReadOperation, idx: ReplicaToken) -> D::Response {
+        self.inner.execute(Single(op), idx)
+    }
+
+    /// Executes outstanding operations on the log against the replica. See
+    /// `crate::Replica::sync`.
+    pub fn sync(&self, idx: ReplicaToken) {
+        self.inner.sync(idx)
+    }
+
+    /// Runs `v` against the data structure of the replica. See
+    /// `crate::Replica::verify`.
+    #[doc(hidden)]
+    pub fn verify<F: FnMut(&D)>(&self, mut v: F) {
+        self.inner
+            .verify(|s: &Sequential<D>| s.inner.with(|d| v(d)))
+    }
+
+    /// Returns the replica this one wraps.
+    pub fn inner(&self) -> &Arc<crate::Replica<'a, Sequential<D>>> {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl Dispatch for Counter {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.0
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.0 += op;
+            self.0
+        }
+    }
+
+    // Tests that two replicas of a sequential data structure sharing a log
+    // see each other's writes.
+    #[test]
+    fn test_compat_replicas() {
+        let log = Arc::new(Log::<u64>::default());
+        let one = Replica::<Counter>::new(&log);
+        let two = Replica::<Counter>::new(&log);
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
+
+        assert_eq!(one.execute_mut(3, i1), 3);
+        assert_eq!(two.execute_mut(4, i2), 7);
+        assert_eq!(one.execute((), i1), 7);
+
+        two.sync(i2);
+        two.verify(|c: &Counter| assert_eq!(c.0, 7));
+    }
+}
@@ -232,6 +232,13 @@ where
         unsafe { (*self.batch[self.index(s)].as_ptr()).2.clone() }
     }
 
+    /// Returns true if every operation enqueued on this context was executed,
+    /// i.e., the combiner handed out its response.
+    #[inline(always)]
+    pub(crate) fn is_drained(&self) -> bool {
+        self.comb.load(Ordering::Relaxed) == self.tail.load(Ordering::Relaxed)
+    }
+
     /// Returns the maximum number of operations that will go pending on this context.
     #[inline(always)]
     pub(crate) fn batch_size() -> usize {
@@ -67,12 +67,30 @@
 //!    }
 //! }
 //!
+//! /// We support a scan operation that returns the number of entries in the
+//! /// hashmap. It has to observe the keys of all logs.
+//! #[derive(Debug, PartialEq, Clone)]
+//! pub enum Scan {
+//!    Len,
+//! }
+//!
+//! /// A scan spans all the logs.
+//! impl LogMapper for Scan {
+//!    fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+//!       logs.clear();
+//!       match self {
+//!          Scan::Len => logs.extend(0..nlogs),
+//!       }
+//!    }
+//! }
+//!
 //! /// The Dispatch traits executes `ReadOperation` (our Access enum)
 //! /// and `WriteOperation` (our Modify enum) against the replicated
 //! /// data-structure.
 //! impl Dispatch for CNRHashMap {
 //!    type ReadOperation = Access;
 //!    type WriteOperation = Modify;
+//!    type ScanOperation = Scan;
 //!    type Response = Option<usize>;
 //!
 //!    /// The `dispatch` function applies the immutable operations.
@@ -91,15 +109,28 @@
 //!            Modify::Put(key, value) => self.storage.insert(key, value),
 //!        }
 //!    }
+//!
+//!    /// The `dispatch_scan` function applies the scan operations.
+//!    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+//!        match op {
+//!            Scan::Len => Some(self.storage.len()),
+//!        }
+//!    }
 //! }
 //! ```
 #![no_std]
 #![cfg_attr(
     feature = "unstable",
-    feature(new_uninit, get_mut_unchecked, negative_impls, core_intrinsics)
+    feature(
+        new_uninit,
+        get_mut_unchecked,
+        negative_impls,
+        core_intrinsics,
+        allocator_api
+    )
 )]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 #[macro_use]
 extern crate alloc;
@@ -113,12 +144,19 @@ extern crate log as logging;
 #[macro_use]
 extern crate static_assertions;
 
+pub mod adapters;
+pub mod compat;
 mod context;
 mod log;
 mod replica;
+mod router;
 
-pub use crate::log::{Log, MAX_REPLICAS_PER_LOG};
-pub use replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
+pub use crate::log::{GcListener, Log, LogError, MAX_REPLICAS_PER_LOG};
+pub use replica::{
+    BufferStats, Clock, CombinerPhase, CombinerStatus, Replica, ReplicaPoisoned, ReplicaToken,
+    MAX_THREADS_PER_REPLICA,
+};
+pub use router::{HashRouter, LogRouter, RangeRouter};
 
 use alloc::vec::Vec;
 use core::fmt::Debug;
@@ -131,9 +169,10 @@ use core::fmt::Debug;
 /// All the conflicting operations must map to a single log and the commutative
 /// operations can map to same or different logs based on the operation argument.
 ///
-/// [Replica](struct.Replica.html) internally performs a modulo operation on `hash`
-/// return value with the total number of logs. The data structure can implement
-/// trait to return a value between 0 and (#logs-1) to avoid the modulo operation.
+/// [Replica](struct.Replica.html) passes every value returned by `hash` to its
+/// [LogRouter](trait.LogRouter.html) to pick the log. The default router,
+/// [HashRouter](struct.HashRouter.html), performs a modulo operation with the
+/// total number of logs, so values between 0 and (#logs-1) are used as they are.
 ///
 /// When the replica calls `hash`, the implementor can assume that the capacity
 /// of `logs` >= `nlogs` and that `logs` is empty.
@@ -164,6 +203,12 @@ pub trait Dispatch {
     /// `WriteOperation` successfully executes against it.
     type Response: Sized + Clone;
 
+    /// A read-only operation that may need to observe the state of the data
+    /// structure across several logs at once (e.g., iterating over all keys).
+    /// Its [LogMapper](trait.LogMapper.html) implementation returns every log the
+    /// operation spans; the replica syncs all of them before executing it.
+    type ScanOperation: Sized + Clone + PartialEq + Debug + LogMapper;
+
     /// Method on the data structure that allows a read-only operation to be
     /// executed against it.
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response;
@@ -171,6 +216,11 @@ pub trait Dispatch {
     /// Method on the data structure that allows a write operation to be
     /// executed against it.
     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response;
+
+    /// Method on the data structure that allows a scan operation to be
+    /// executed against it. No write operation on any of the logs the scan
+    /// maps to is executed concurrently on the same replica.
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response;
 }
 
 #[cfg(doctest)]
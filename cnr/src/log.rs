@@ -2,16 +2,22 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::alloc::{alloc, dealloc, Layout};
-use alloc::prelude::v1::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use core::cell::{Cell, UnsafeCell};
+#[cfg(feature = "unstable")]
+use alloc::alloc::Global;
+#[cfg(feature = "unstable")]
+use core::alloc::{AllocError, Allocator};
+use core::cell::Cell;
 use core::default::Default;
 use core::fmt;
-use core::hint::spin_loop;
+#[cfg(not(feature = "unstable"))]
+use core::marker::PhantomData;
 use core::mem::{align_of, size_of};
 use core::ops::{Drop, FnMut};
+#[cfg(feature = "unstable")]
+use core::ptr::{null_mut, NonNull};
 use core::slice::from_raw_parts_mut;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
@@ -46,9 +52,118 @@ const_assert!(GC_FROM_HEAD >= 1 && (GC_FROM_HEAD & (GC_FROM_HEAD - 1) == 0));
 /// Should be a power of two to avoid divisions.
 const WARN_THRESHOLD: usize = 1 << 28;
 
-/// Callback function which indicates which replicas need to be advanced for GC
-/// to make progress.
-type CallbackFn = dyn FnMut(&[AtomicBool; MAX_REPLICAS_PER_LOG], usize);
+/// Errors that can occur when creating a [Log](struct.Log.html) or when
+/// registering a replica against one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogError {
+    /// The allocator failed to provide memory for the log.
+    OutOfMemory,
+
+    /// The requested size of the log can't be represented as a valid
+    /// memory layout.
+    InvalidSize,
+
+    /// The maximum number of replicas (`MAX_REPLICAS_PER_LOG`) are already
+    /// registered with the log.
+    TooManyReplicas,
+
+    /// The logs of a replica can't be reconfigured because operations are
+    /// still in flight, or because a log that should be new was used already.
+    NotQuiescent,
+}
+
+/// Allocator that provides the memory for a [Log](struct.Log.html) and the
+/// per-thread state of a [Replica](struct.Replica.html).
+///
+/// Forwards to the allocator passed to `Log::new_in` or `Replica::new_in`, or
+/// to the global allocator if the log (or replica) was created without one.
+/// Custom allocators require the `unstable` feature.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct LogAllocator<'a> {
+    #[cfg(feature = "unstable")]
+    inner: Option<&'a (dyn Allocator + Sync)>,
+
+    #[cfg(not(feature = "unstable"))]
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> LogAllocator<'a> {
+    /// Creates an allocator that forwards to `alloc`.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn new(alloc: &'a (dyn Allocator + Sync)) -> LogAllocator<'a> {
+        LogAllocator { inner: Some(alloc) }
+    }
+
+    /// Allocates memory for `layout`. Returns a null pointer on failure.
+    ///
+    /// # Safety
+    /// Same as `alloc::alloc::alloc`, `layout` must have a non-zero size.
+    pub(crate) unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "unstable")]
+        if let Some(a) = self.inner {
+            return a
+                .allocate(layout)
+                .map_or(null_mut(), |p| p.cast::<u8>().as_ptr());
+        }
+
+        alloc(layout)
+    }
+
+    /// Frees memory that was previously returned by `alloc_raw`.
+    ///
+    /// # Safety
+    /// Same as `alloc::alloc::dealloc`, `ptr` must have been allocated by this
+    /// allocator with the same `layout`.
+    pub(crate) unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "unstable")]
+        if let Some(a) = self.inner {
+            return a.deallocate(NonNull::new_unchecked(ptr), layout);
+        }
+
+        dealloc(ptr, layout)
+    }
+}
+
+#[cfg(feature = "unstable")]
+unsafe impl<'a> Allocator for LogAllocator<'a> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.inner {
+            Some(a) => a.allocate(layout),
+            None => Global.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.inner {
+            Some(a) => a.deallocate(ptr, layout),
+            None => Global.deallocate(ptr, layout),
+        }
+    }
+}
+
+/// Notified by a [Log](struct.Log.html) when replicas that don't execute it
+/// hold up its garbage collection. Installed with `Log::set_gc_listener`.
+///
+/// Closures `Fn(log_id, dormant_replica)` are listeners too.
+pub trait GcListener: Send + Sync {
+    /// Called when replica `dormant_replica` fell so far behind on log `log_id`
+    /// that appends will soon wait for it. The listener can then wake up a
+    /// thread of the replica (e.g., to call `Replica::sync`) or evict it.
+    ///
+    /// Called from within `append`, by the thread that noticed the stall; it
+    /// must not append to the log itself. Called at most once per replica and
+    /// stall, until the head of the log advances again.
+    fn on_stall(&self, log_id: usize, dormant_replica: usize);
+}
+
+impl<F> GcListener for F
+where
+    F: Fn(usize, usize) + Send + Sync,
+{
+    fn on_stall(&self, log_id: usize, dormant_replica: usize) {
+        self(log_id, dormant_replica)
+    }
+}
 
 /// An entry that sits on the log. Each entry consists of three fields: The operation to
 /// be performed when a thread reaches this entry on the log, the replica that appended
@@ -77,8 +192,10 @@ where
     /// Identifies if the operation is immutable scan or not.
     is_read_op: bool,
 
-    /// If operation is of scan type, then `depends_on` stores
-    /// the offsets in other logs this operation depends on.
+    /// If operation is of scan type, then `depends_on` stores the offsets
+    /// in other logs this operation depends on: the offsets on all its
+    /// logs for the entry on the root log, the offset on the root log for
+    /// the entries on the other logs.
     depends_on: Option<Arc<Vec<usize>>>,
 
     /// Indicates whether this entry represents a valid operation when on the log.
@@ -120,6 +237,9 @@ where
     /// Size of the underlying log in bytes. Required for dealloc.
     rawb: usize,
 
+    /// Allocator that provided `rawp`. Required for dealloc.
+    allocator: LogAllocator<'a>,
+
     /// The maximum number of entries that can be held inside the log.
     size: usize,
 
@@ -156,13 +276,9 @@ where
     /// track log wrap-arounds for each of them separately.
     lmasks: [CachePadded<Cell<bool>>; MAX_REPLICAS_PER_LOG],
 
-    /// The application can provide a callback function to the log. The function is invoked
-    /// when one or more replicas lag and stop the log to garbage collected the entries.
-    /// One example of a callback function is to notify the application with a lagging
-    /// replica number for this log. The application can then take action to start the log
-    /// consumption on that replica. If the application is proactively taking measures to
-    /// consume the log on all the replicas, then the variable can be initialized to default.
-    gc: UnsafeCell<Box<CallbackFn>>,
+    /// Notified when one or more replicas lag and keep the log from garbage
+    /// collecting entries, if installed with `set_gc_listener`.
+    listener: Option<Arc<dyn GcListener>>,
 
     /// Use to append scan op atomically to all the logs.
     scanlock: CachePadded<AtomicUsize>,
@@ -170,10 +286,6 @@ where
     /// Check if the log can issue a GC callback; reset
     /// after the GC is done in `advance_head` function.
     notify_replicas: CachePadded<AtomicBool>,
-
-    /// Use this array in GC callback function to notify other replicas to make progress.
-    /// Assumes that the callback handler clears the replica-ids which need to do GC.
-    dormant_replicas: [AtomicBool; MAX_REPLICAS_PER_LOG],
 }
 
 impl<'a, T> fmt::Debug for Log<'a, T>
@@ -222,7 +334,101 @@ where
     ///
     /// This method also allocates memory for the log upfront. No further allocations
     /// will be performed once this method returns.
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_new` to handle
+    /// allocation failures gracefully.
     pub fn new<'b>(bytes: usize, idx: usize) -> Log<'b, T> {
+        match Log::try_new(bytes, idx) {
+            Ok(log) => log,
+            Err(LogError::InvalidSize) => {
+                panic!("Alignment error while allocating the shared log!")
+            }
+            Err(_) => panic!("Failed to allocate memory for the shared log!"),
+        }
+    }
+
+    /// Constructs and returns a log of size `bytes` bytes. Similar to `new`,
+    /// but returns an error instead of panicking if the memory for the log
+    /// can't be allocated.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cnr::{Log, LogError};
+    ///
+    /// // Operation type that will go onto the log.
+    /// #[derive(Clone)]
+    /// enum Operation {
+    ///     Read,
+    ///     Write(u64),
+    /// }
+    ///
+    /// // Creates a 1 Mega Byte sized log.
+    /// let l = Log::<Operation>::try_new(1 * 1024 * 1024, 1);
+    /// assert!(l.is_ok());
+    ///
+    /// // A log this large can never be allocated.
+    /// let l = Log::<Operation>::try_new(usize::MAX, 1);
+    /// assert_eq!(l.err(), Some(LogError::InvalidSize));
+    /// ```
+    pub fn try_new<'b>(bytes: usize, idx: usize) -> Result<Log<'b, T>, LogError> {
+        Log::try_new_with(bytes, idx, LogAllocator::default())
+    }
+
+    /// Constructs and returns a log of size `bytes` bytes whose memory is
+    /// provided by `alloc` instead of the global allocator. This is useful to
+    /// place the log on a specific NUMA node or in persistent memory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #![feature(allocator_api)]
+    /// use cnr::Log;
+    /// use std::alloc::System;
+    ///
+    /// // Operation type that will go onto the log.
+    /// #[derive(Clone)]
+    /// enum Operation {
+    ///     Read,
+    ///     Write(u64),
+    /// }
+    ///
+    /// // Creates a 1 Mega Byte sized log backed by the system allocator.
+    /// let l = Log::<Operation>::new_in(1 * 1024 * 1024, 1, &System);
+    /// ```
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_new_in` to handle
+    /// allocation failures gracefully.
+    #[cfg(feature = "unstable")]
+    pub fn new_in(bytes: usize, idx: usize, alloc: &(dyn Allocator + Sync)) -> Log<'_, T> {
+        match Log::try_new_in(bytes, idx, alloc) {
+            Ok(log) => log,
+            Err(LogError::InvalidSize) => {
+                panic!("Alignment error while allocating the shared log!")
+            }
+            Err(_) => panic!("Failed to allocate memory for the shared log!"),
+        }
+    }
+
+    /// Similar to `new_in`, but returns an error instead of panicking if the
+    /// memory for the log can't be allocated.
+    #[cfg(feature = "unstable")]
+    pub fn try_new_in(
+        bytes: usize,
+        idx: usize,
+        alloc: &(dyn Allocator + Sync),
+    ) -> Result<Log<'_, T>, LogError> {
+        Log::try_new_with(bytes, idx, LogAllocator::new(alloc))
+    }
+
+    /// Constructs a log of size `bytes` bytes with memory from `allocator`.
+    fn try_new_with(
+        bytes: usize,
+        idx: usize,
+        allocator: LogAllocator<'_>,
+    ) -> Result<Log<'_, T>, LogError> {
         // Calculate the number of entries that will go into the log, and retrieve a
         // slice to it from the allocated region of memory.
         let mut num = bytes / Log::<T>::entry_size();
@@ -240,15 +446,14 @@ where
         };
 
         // Now that we have the actual number of entries, allocate the log.
-        let b = num * Log::<T>::entry_size();
-        let mem = unsafe {
-            alloc(
-                Layout::from_size_align(b, align_of::<Cell<Entry<T>>>())
-                    .expect("Alignment error while allocating the shared log!"),
-            )
-        };
+        let b = num
+            .checked_mul(Log::<T>::entry_size())
+            .ok_or(LogError::InvalidSize)?;
+        let layout = Layout::from_size_align(b, align_of::<Cell<Entry<T>>>())
+            .map_err(|_| LogError::InvalidSize)?;
+        let mem = unsafe { allocator.alloc_raw(layout) };
         if mem.is_null() {
-            panic!("Failed to allocate memory for the shared log!");
+            return Err(LogError::OutOfMemory);
         }
         let raw = unsafe { from_raw_parts_mut(mem as *mut Cell<Entry<T>>, num) };
 
@@ -275,11 +480,10 @@ where
         const LTAIL_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::new(0));
         #[allow(clippy::declare_interior_mutable_const)]
         const LMASK_DEFAULT: CachePadded<Cell<bool>> = CachePadded::new(Cell::new(true));
-        #[allow(clippy::declare_interior_mutable_const)]
-        const DORMANT_DEFAULT: AtomicBool = AtomicBool::new(false);
-        Log {
+        Ok(Log {
             rawp: mem,
             rawb: b,
+            allocator,
             size: num,
             idx,
             slog: raw,
@@ -289,13 +493,10 @@ where
             ltails: [LTAIL_DEFAULT; MAX_REPLICAS_PER_LOG],
             next: CachePadded::new(AtomicUsize::new(1usize)),
             lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
-            gc: UnsafeCell::new(Box::new(
-                |_rid: &[AtomicBool; MAX_REPLICAS_PER_LOG], _lid: usize| {},
-            )),
+            listener: None,
             scanlock: CachePadded::new(AtomicUsize::new(0)),
             notify_replicas: CachePadded::new(AtomicBool::new(true)),
-            dormant_replicas: [DORMANT_DEFAULT; MAX_REPLICAS_PER_LOG],
-        }
+        })
     }
 
     /// Returns the size of an entry in bytes.
@@ -303,15 +504,16 @@ where
         size_of::<Cell<Entry<T>>>()
     }
 
-    /// The application calls this function to update the callback function.
-    /// The application does not need to call this function if it knows that all
-    /// the replicas are active for this log and no replica will lag behind.
+    /// Installs `listener` to be notified about replicas that hold up garbage
+    /// collection of the log (see `GcListener`). The application does not need
+    /// to install one if it knows that all the replicas are active for this log
+    /// and no replica will lag behind.
     ///
     /// # Example
     ///
     /// ```
     /// use cnr::Log;
-    /// use core::sync::atomic::AtomicBool;
+    /// use std::sync::Arc;
     ///
     /// // Operation type that will go onto the log.
     /// #[derive(Clone)]
@@ -324,17 +526,13 @@ where
     /// // Creates a 1 Mega Byte sized log.
     /// let mut l = Log::<Operation>::new(1 * 1024 * 1024, 1);
     ///
-    /// // Update the callback function for the log.
-    /// let callback_func = |rid: &[AtomicBool; 192], idx: usize| {
-    ///     // Take action on log `idx` and replicas in `rid`.
-    /// };
-    /// l.update_closure(callback_func)
+    /// // Get notified about replicas that hold up garbage collection.
+    /// l.set_gc_listener(Arc::new(|log_id: usize, dormant_replica: usize| {
+    ///     // Wake up a thread of `dormant_replica`, or evict it.
+    /// }));
     /// ```
-    pub fn update_closure(
-        &mut self,
-        gc: impl FnMut(&[AtomicBool; MAX_REPLICAS_PER_LOG], usize) + 'static,
-    ) {
-        unsafe { *self.gc.get() = Box::new(gc) };
+    pub fn set_gc_listener(&mut self, listener: Arc<dyn GcListener>) {
+        self.listener = Some(listener);
     }
 
     /// Registers a replica with the log. Returns an identifier that the replica
@@ -402,12 +600,15 @@ where
                 let mut is_stuck = false;
                 let cur_local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
 
-                // Find the smallest local tail across all replicas.
-                for idx in 1..r {
-                    let local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
-                    if cur_local_tail > local_tail && cur_local_tail - local_tail > self.size / 3 {
-                        self.dormant_replicas[idx - 1].store(true, Ordering::Relaxed);
+                // Find the replicas that are far behind this one.
+                let lagging = |rid: usize| {
+                    let local_tail = self.ltails[rid - 1].load(Ordering::Relaxed);
+                    cur_local_tail > local_tail && cur_local_tail - local_tail > self.size / 3
+                };
+                for rid in 1..r {
+                    if lagging(rid) {
                         is_stuck = true;
+                        break;
                     }
                 }
 
@@ -419,7 +620,11 @@ where
                         Ordering::Relaxed,
                     ) == Ok(true)
                 {
-                    unsafe { (*self.gc.get())(&self.dormant_replicas, self.idx) };
+                    if let Some(listener) = self.listener.as_ref() {
+                        for rid in (1..r).filter(|rid| lagging(*rid)) {
+                            listener.on_stall(self.idx, rid);
+                        }
+                    }
                 }
             }
 
@@ -474,7 +679,10 @@ where
         }
     }
 
-    /// Adds a scan operation to the shared log.
+    /// Adds a scan operation to the shared log. `offset` holds the offsets of
+    /// the operation on the logs it was appended to so far; it is empty if this
+    /// is the root log of the operation, whose entry is filled in later with
+    /// `fix_scan_entry`.
     #[inline(always)]
     #[doc(hidden)]
     pub(crate) fn try_append_scan<
@@ -520,10 +728,8 @@ where
 
         // Successfully reserved entries on the shared log. Add the operations in.
         log_offset = tail;
-        if self.idx != 1 {
-            unsafe {
-                self.update_entry(log_offset, op, idx, true, Some(Arc::new(vec![offset[0]])))
-            };
+        if let Some(&root) = offset.first() {
+            unsafe { self.update_entry(log_offset, op, idx, true, Some(Arc::new(vec![root]))) };
         }
 
         // If needed, advance the head of the log forward to make room on the log.
@@ -578,7 +784,7 @@ where
     }
 
     /// Try to acquire the scan lock.
-    fn try_scan_lock(&self, tid: usize) -> bool {
+    pub(crate) fn try_scan_lock(&self, tid: usize) -> bool {
         for _i in 0..4 {
             if unsafe {
                 core::ptr::read_volatile(
@@ -606,13 +812,6 @@ where
         true
     }
 
-    /// Acquire the scan lock.
-    pub(crate) fn acquire_scan_lock(&self, tid: usize) {
-        while !self.try_scan_lock(tid) {
-            spin_loop();
-        }
-    }
-
     /// Release the scan lock.
     pub(crate) fn release_scan_lock(&self) {
         self.scanlock.store(0, Ordering::Release);
@@ -815,6 +1014,34 @@ where
     pub(crate) fn get_ctail(&self) -> usize {
         self.ctail.load(Ordering::Relaxed)
     }
+
+    /// This method returns the local tail of the replica with identifier `idx`,
+    /// i.e., the logical offset up to which it has applied the log.
+    #[inline(always)]
+    pub(crate) fn get_ltail(&self, idx: usize) -> usize {
+        self.ltails[idx - 1].load(Ordering::Relaxed)
+    }
+
+    /// Returns the global unique id of the log, passed to `new`.
+    #[inline(always)]
+    pub(crate) fn id(&self) -> usize {
+        self.idx
+    }
+
+    /// Returns true if no operation was ever appended to the log.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Relaxed) == 0
+    }
+
+    /// Returns true if every replica registered with the log executed all the
+    /// operations appended to it.
+    pub(crate) fn is_drained(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let r = self.next.load(Ordering::Relaxed);
+        self.ltails[..r - 1]
+            .iter()
+            .all(|ltail| ltail.load(Ordering::Relaxed) == tail)
+    }
 }
 
 impl<'a, T> Default for Log<'a, T>
@@ -834,7 +1061,7 @@ where
     /// Destructor for the shared log.
     fn drop(&mut self) {
         unsafe {
-            dealloc(
+            self.allocator.dealloc_raw(
                 self.rawp,
                 Layout::from_size_align(self.rawb, align_of::<Cell<Entry<T>>>())
                     .expect("Alignment error while deallocating the shared log!"),
@@ -924,6 +1151,45 @@ mod tests {
         assert_eq!(l.slog.len(), n.unwrap());
     }
 
+    // Tests that try_new() reports an error for sizes that can't be allocated.
+    #[test]
+    fn test_log_try_new_invalid_size() {
+        assert_eq!(
+            Log::<Operation>::try_new(usize::MAX, 1).err(),
+            Some(LogError::InvalidSize)
+        );
+        assert!(Log::<Operation>::try_new(isize::MAX as usize + 1, 1).is_err());
+        assert!(Log::<Operation>::try_new(1024, 1).is_ok());
+    }
+
+    // Tests that a log created with `new_in` gets its memory from the provided
+    // allocator and returns it on drop.
+    #[cfg(feature = "unstable")]
+    #[test]
+    fn test_log_create_in() {
+        use std::alloc::System;
+
+        struct CountingAlloc(AtomicUsize);
+
+        unsafe impl Allocator for CountingAlloc {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                self.0.fetch_add(layout.size(), Ordering::Relaxed);
+                System.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.0.fetch_sub(layout.size(), Ordering::Relaxed);
+                System.deallocate(ptr, layout)
+            }
+        }
+
+        let a = CountingAlloc(AtomicUsize::new(0));
+        let l = Log::<Operation>::new_in(1024, 1, &a);
+        assert_eq!(a.0.load(Ordering::Relaxed), l.rawb);
+        drop(l);
+        assert_eq!(a.0.load(Ordering::Relaxed), 0);
+    }
+
     // Tests if the log can be successfully default constructed.
     #[test]
     fn test_log_create_default() {
@@ -1043,6 +1309,30 @@ mod tests {
         assert_eq!(l.tail.load(Ordering::Relaxed), l.size - GC_FROM_HEAD + 3);
     }
 
+    // Tests that the GC listener is told about a replica that lags far behind,
+    // once until the head of the log advances.
+    #[test]
+    fn test_log_gc_listener() {
+        let mut l = Log::<Operation>::default();
+        let stalls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let s = stalls.clone();
+        l.set_gc_listener(Arc::new(move |log_id: usize, rid: usize| {
+            s.lock().unwrap().push((log_id, rid))
+        }));
+
+        l.next.store(3, Ordering::Relaxed);
+        l.tail.store(l.size / 2, Ordering::Relaxed);
+        l.ltails[0].store(l.size / 2, Ordering::Relaxed);
+        let o = [(Operation::Read, 1, false)];
+        for _i in 0..2 {
+            l.append(&o, 1, |_o: Operation, _i: usize, _, _, _, _| -> bool {
+                true
+            });
+        }
+
+        assert_eq!(*stalls.lock().unwrap(), vec![(l.idx, 2)]);
+    }
+
     // Tests that on log wrap around, the local mask stays
     // the same because entries have not been executed yet.
     #[test]
@@ -5,15 +5,23 @@ use core::cell::RefCell;
 use core::hint::spin_loop;
 #[cfg(feature = "unstable")]
 use core::intrinsics::unlikely;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+#[cfg(feature = "unstable")]
+use core::alloc::Allocator;
 
 use crossbeam_utils::CachePadded;
 
 use super::context::Context;
-use super::log::Log;
+#[cfg(feature = "unstable")]
+use super::log::LogAllocator;
+use super::log::{Log, LogError};
+use super::router::{HashRouter, LogRouter};
 use super::Dispatch;
 use super::LogMapper;
 
@@ -65,6 +73,58 @@ const_assert!(
     MAX_THREADS_PER_REPLICA >= 1 && (MAX_THREADS_PER_REPLICA & (MAX_THREADS_PER_REPLICA - 1) == 0)
 );
 
+/// Allocates an empty vector with space for `capacity` elements. Unlike
+/// `Vec::with_capacity`, this reports allocation failures to the caller
+/// instead of aborting.
+fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, LogError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| LogError::InvalidSize)?;
+    if layout.size() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(LogError::OutOfMemory);
+    }
+
+    // The memory was allocated by the global allocator with the layout of
+    // `[T; capacity]` which is what `Vec` expects for its buffer.
+    Ok(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
+
+/// Same as `try_vec_with_capacity`, but the memory for the vector is provided
+/// by `allocator`.
+#[cfg(feature = "unstable")]
+fn try_vec_with_capacity_in<T>(
+    capacity: usize,
+    allocator: LogAllocator,
+) -> Result<Vec<T, LogAllocator>, LogError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| LogError::InvalidSize)?;
+    if layout.size() == 0 {
+        return Ok(Vec::new_in(allocator));
+    }
+
+    let ptr = unsafe { allocator.alloc_raw(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(LogError::OutOfMemory);
+    }
+
+    // The memory was allocated by `allocator` with the layout of
+    // `[T; capacity]` which is what `Vec` expects for its buffer.
+    Ok(unsafe { Vec::from_raw_parts_in(ptr, 0, capacity, allocator) })
+}
+
+/// List of per-thread contexts of a replica. With the `unstable` feature, the
+/// contexts can live in memory provided by a custom allocator.
+#[cfg(not(feature = "unstable"))]
+type Contexts<'a, D> =
+    Vec<CachePadded<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>>;
+#[cfg(feature = "unstable")]
+type Contexts<'a, D> = Vec<
+    CachePadded<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>,
+    LogAllocator<'a>,
+>;
+
 /// Type that has meta-data about either scan or write op while it's in the log.
 type OperationState<D> = (<D as Dispatch>::WriteOperation, usize, bool);
 
@@ -91,6 +151,15 @@ where
     /// Number of pending operations for each thread per log.
     pending: [CachePadded<AtomicBool>; MAX_THREADS_PER_REPLICA],
 
+    /// Upper bound on the number of set `pending` flags. Incremented before a
+    /// thread sets its flag and decremented after the combiner clears it, so
+    /// the combiner can stop scanning `pending` once it found that many.
+    npending: CachePadded<AtomicUsize>,
+
+    /// Number of `pending` flags the combiner checked that weren't set. Only
+    /// updated by the combiner.
+    wasted_checks: AtomicU64,
+
     /// A buffer of operations for flat combining. The combiner stages operations in
     /// here and then batch appends them into the shared log. This helps amortize
     /// the cost of the compare_and_swap() on the tail of the log. Each entry in buffer
@@ -100,38 +169,180 @@ where
     /// A buffer of scan type operations for flat combining. Each entry in buffer
     /// contains the write operation, hash, and is_read(we store scan read ops).
     scan_buffer: CachePadded<RefCell<Vec<OperationState<D>>>>,
+
+    /// Combined capacity of `buffer` and `scan_buffer`, in operations. Only
+    /// updated by the combiner.
+    capacity: AtomicUsize,
+
+    /// Most operations collected in one round since the replica was created.
+    /// Only updated by the combiner.
+    peak: AtomicUsize,
+
+    /// Most operations collected in one round since the buffers were last
+    /// shrunk (or checked for shrinking). Only updated by the combiner.
+    window_peak: AtomicUsize,
+
+    /// Rounds of flat combining since the buffers were last checked for
+    /// shrinking. Only updated by the combiner.
+    rounds: AtomicUsize,
+
+    /// Number of times the combiner shrunk the buffers. Only updated by the
+    /// combiner.
+    shrinks: AtomicU64,
+
+    /// When the thread holding the combiner lock acquired it, according to
+    /// the clock of the replica. Only updated by the thread holding the lock.
+    acquired_at: AtomicU64,
+
+    /// The `CombinerPhase` of the thread holding the combiner lock, as a
+    /// `usize`. Only updated by the thread holding the lock.
+    phase: AtomicUsize,
+}
+
+/// Returns the current time in units of the caller's choice (e.g., nanoseconds
+/// or cycles). Timestamps in a `CombinerStatus` come from this clock.
+pub type Clock = fn() -> u64;
+
+/// What the thread holding the combiner lock of a log is doing, as reported by
+/// `Replica::combiner_status`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(usize)]
+pub enum CombinerPhase {
+    /// Nobody holds the combiner lock.
+    #[default]
+    Idle,
+    /// Collecting operations from the threads registered with the replica.
+    Collecting,
+    /// Appending the collected operations to the log. This includes executing
+    /// entries of the log while waiting for other replicas to free up space.
+    Appending,
+    /// Executing operations of the log against the replica (or a scan), and
+    /// handing out the responses of the operations of this replica.
+    Executing,
+}
+
+impl CombinerPhase {
+    fn from_usize(phase: usize) -> CombinerPhase {
+        match phase {
+            1 => CombinerPhase::Collecting,
+            2 => CombinerPhase::Appending,
+            3 => CombinerPhase::Executing,
+            _ => CombinerPhase::Idle,
+        }
+    }
+}
+
+/// Who holds the combiner lock of one log of a replica and what it is doing,
+/// returned by `Replica::combiner_status`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CombinerStatus {
+    /// Identifier of the thread holding the lock (as in its `ReplicaToken`),
+    /// or `None` if nobody holds it. Identifiers larger than
+    /// `MAX_THREADS_PER_REPLICA` mean that the replica holds the lock on
+    /// behalf of no particular thread (e.g., in `verify`).
+    pub owner: Option<usize>,
+
+    /// When `owner` acquired the lock, according to the clock installed with
+    /// `Replica::set_clock`. Zero if nobody holds the lock or there is no clock.
+    pub acquired_at: u64,
+
+    /// What `owner` is doing.
+    pub phase: CombinerPhase,
+}
+
+/// Memory used by the combiners of one log of a replica to stage operations,
+/// returned by `Replica::buffer_stats`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BufferStats {
+    /// Number of operations the staging buffers currently have room for.
+    pub capacity: usize,
+
+    /// Most operations the combiners collected in one round so far.
+    pub high_watermark: usize,
+
+    /// Number of times the buffers were shrunk to fit the recent load (see
+    /// `Replica::set_buffer_shrink_interval`).
+    pub shrinks: u64,
+}
+
+/// Error surfaced by a replica once a thread panicked while holding one of its
+/// combiner locks (e.g., because `Dispatch::dispatch_mut` panicked). The
+/// replica may have executed only part of the operations it collected, so its
+/// data structure can't be trusted anymore.
+///
+/// Operations issued against a poisoned replica panic with this error (as the
+/// payload of the panic with the `std` feature) rather than waiting forever
+/// for a combiner that is gone.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ReplicaPoisoned;
+
+impl core::fmt::Display for ReplicaPoisoned {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "a thread panicked while combining on this replica")
+    }
+}
+
+/// Releases the combiner locks of `logs` and marks `replica` as poisoned when
+/// dropped. Taken by the holder of the locks while it runs operations of the
+/// data structure, and forgotten once it is done; it is only dropped if the
+/// holder unwinds.
+struct PoisonOnUnwind<'r, 'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    replica: &'r Replica<'a, D>,
+    logs: &'r [usize],
+}
+
+impl<'r, 'a, D> Drop for PoisonOnUnwind<'r, 'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        self.replica.poisoned.store(true, Ordering::Release);
+        for &logidx in self.logs.iter() {
+            self.replica.unlock(logidx);
+        }
+    }
 }
 
 impl<'a, D> LogState<'a, D>
 where
     D: Sized + Dispatch + Sync,
 {
-    fn new(log: Arc<Log<'a, <D as Dispatch>::WriteOperation>>) -> LogState<'a, D> {
+    fn try_new(
+        log: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    ) -> Result<LogState<'a, D>, LogError> {
         #[allow(clippy::declare_interior_mutable_const)]
         const PENDING_DEFAULT: CachePadded<AtomicBool> = CachePadded::new(AtomicBool::new(false));
 
-        let idx = log.register().unwrap();
-        LogState {
+        let buffer = try_vec_with_capacity(
+            MAX_THREADS_PER_REPLICA
+                * Context::<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>::batch_size(
+                ),
+        )?;
+        let scan_buffer = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
+        let capacity = buffer.capacity() + scan_buffer.capacity();
+
+        // Register last, so we don't use up a slot on the log if an allocation fails.
+        let idx = log.register().ok_or(LogError::TooManyReplicas)?;
+        Ok(LogState {
             slog: log.clone(),
             idx,
             combiner: CachePadded::new(AtomicUsize::new(0)),
             pending: [PENDING_DEFAULT; MAX_THREADS_PER_REPLICA],
-            buffer:
-                CachePadded::new(
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                ),
-            scan_buffer: CachePadded::new(RefCell::new(Vec::with_capacity(
-                MAX_THREADS_PER_REPLICA,
-            ))),
-        }
+            npending: CachePadded::new(AtomicUsize::new(0)),
+            wasted_checks: AtomicU64::new(0),
+            buffer: CachePadded::new(RefCell::new(buffer)),
+            scan_buffer: CachePadded::new(RefCell::new(scan_buffer)),
+            capacity: AtomicUsize::new(capacity),
+            peak: AtomicUsize::new(0),
+            window_peak: AtomicUsize::new(0),
+            rounds: AtomicUsize::new(0),
+            shrinks: AtomicU64::new(0),
+            acquired_at: AtomicU64::new(0),
+            phase: AtomicUsize::new(CombinerPhase::Idle as usize),
+        })
     }
 }
 
@@ -161,7 +372,7 @@ where
     /// cannot perform flat combining (because another thread might be doing so).
     ///
     /// The vector is initialized with `MAX_THREADS_PER_REPLICA` elements.
-    contexts: Vec<CachePadded<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>>,
+    contexts: Contexts<'a, D>,
 
     /// It is used to store the log offsets in various logs for scan operations.
     offsets: Vec<RefCell<Vec<usize>>>,
@@ -171,6 +382,21 @@ where
 
     /// An instance of per log state maintained by each replica.
     logstate: Vec<CachePadded<LogState<'a, D>>>,
+
+    /// Rounds of flat combining on a log after which its combiner shrinks the
+    /// staging buffers to the recent load. Zero if they are never shrunk.
+    shrink_interval: AtomicUsize,
+
+    /// The `Clock` installed with `set_clock`, as a `usize`. Zero if there is
+    /// none.
+    clock: AtomicUsize,
+
+    /// Set once a thread panicked while holding a combiner lock of this
+    /// replica, see `ReplicaPoisoned`.
+    poisoned: AtomicBool,
+
+    /// Picks the logs for the keys `LogMapper::hash` returns, see `set_router`.
+    router: Box<dyn LogRouter>,
 }
 
 /// The Replica is Sync. Member variables are protected by a CAS on `combiner`.
@@ -240,6 +466,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     // A read returns the underlying u64.
@@ -258,6 +485,14 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         None
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// // Create one or more logs.
@@ -269,6 +504,51 @@ where
     pub fn new(logs: Vec<Arc<Log<'_, <D as Dispatch>::WriteOperation>>>) -> Arc<Replica<'_, D>> {
         Replica::with_data(logs, Default::default())
     }
+
+    /// Similar to [`Replica<D>::new`], but returns an error instead of
+    /// panicking if the replica can't register with one of the logs or if
+    /// the memory for the per-thread state of the replica can't be allocated.
+    ///
+    /// # Note
+    /// If registration fails for one of the logs, the replica stays
+    /// registered with the logs that precede it in `logs`.
+    pub fn try_new(
+        logs: Vec<Arc<Log<'_, <D as Dispatch>::WriteOperation>>>,
+    ) -> Result<Arc<Replica<'_, D>>, LogError> {
+        Replica::try_with_data(logs, Default::default())
+    }
+
+    /// Similar to [`Replica<D>::new`], but the per-thread contexts of the
+    /// replica (which make up most of its memory) are allocated with `alloc`
+    /// instead of the global allocator. This is useful to place the replica
+    /// state on a specific NUMA node or in persistent memory.
+    ///
+    /// # Panics
+    /// If the replica can't register with one of the logs or the memory for
+    /// its per-thread state can't be allocated. Use `try_new_in` to handle
+    /// these failures gracefully.
+    #[cfg(feature = "unstable")]
+    pub fn new_in<'b>(
+        logs: Vec<Arc<Log<'b, <D as Dispatch>::WriteOperation>>>,
+        alloc: &'b (dyn Allocator + Sync),
+    ) -> Arc<Replica<'b, D>> {
+        match Replica::try_new_in(logs, alloc) {
+            Ok(replica) => replica,
+            Err(LogError::TooManyReplicas) => panic!("Failed to register replica with the log!"),
+            Err(_) => panic!("Failed to allocate memory for the replica!"),
+        }
+    }
+
+    /// Similar to [`Replica<D>::new_in`], but returns an error instead of
+    /// panicking if the replica can't register with one of the logs or if
+    /// the memory for the per-thread state of the replica can't be allocated.
+    #[cfg(feature = "unstable")]
+    pub fn try_new_in<'b>(
+        logs: Vec<Arc<Log<'b, <D as Dispatch>::WriteOperation>>>,
+        alloc: &'b (dyn Allocator + Sync),
+    ) -> Result<Arc<Replica<'b, D>>, LogError> {
+        Replica::try_with_data_in(logs, Default::default(), LogAllocator::new(alloc))
+    }
 }
 
 impl<'a, D> Replica<'a, D>
@@ -284,45 +564,73 @@ where
     /// If `with_data` is used, care must be taken that the same state is passed
     /// to every Replica object. If not the resulting operations executed
     /// against replicas may not give deterministic results.
-    #[cfg(not(feature = "unstable"))]
     pub fn with_data(
         logs: Vec<Arc<Log<'_, <D as Dispatch>::WriteOperation>>>,
         d: D,
     ) -> Arc<Replica<'_, D>> {
-        let mut contexts = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
-        let mut offsets = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
-        let mut hash = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
+        match Replica::try_with_data(logs, d) {
+            Ok(replica) => replica,
+            Err(LogError::TooManyReplicas) => panic!("Failed to register replica with the log!"),
+            Err(_) => panic!("Failed to allocate memory for the replica!"),
+        }
+    }
+
+    /// Similar to [`Replica<D>::with_data`], but returns an error instead of
+    /// panicking if the replica can't register with one of the logs or if
+    /// the memory for the per-thread state of the replica can't be allocated.
+    #[cfg(not(feature = "unstable"))]
+    pub fn try_with_data(
+        logs: Vec<Arc<Log<'_, <D as Dispatch>::WriteOperation>>>,
+        d: D,
+    ) -> Result<Arc<Replica<'_, D>>, LogError> {
+        let mut contexts = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
+        let mut offsets = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
+        let mut hash = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
 
         for idx in 0..MAX_THREADS_PER_REPLICA {
             contexts.push(CachePadded::new(Context::new(idx + 1)));
-            offsets.push(RefCell::new(Vec::with_capacity(logs.len())));
-            hash.push(CachePadded::new(RefCell::new(Vec::with_capacity(
+            offsets.push(RefCell::new(try_vec_with_capacity(logs.len())?));
+            hash.push(CachePadded::new(RefCell::new(try_vec_with_capacity(
                 logs.len(),
-            ))));
+            )?)));
         }
 
         // Add per-log state
-        let mut logstate = Vec::with_capacity(logs.len());
+        let mut logstate = try_vec_with_capacity(logs.len())?;
         for log in logs.iter() {
-            logstate.push(CachePadded::new(LogState::new(log.clone())));
+            logstate.push(CachePadded::new(LogState::try_new(log.clone())?));
         }
 
-        Arc::new(Replica {
+        Ok(Arc::new(Replica {
             next: CachePadded::new(AtomicUsize::new(1)),
             data: CachePadded::new(d),
             logstate,
             contexts,
             offsets,
             hash,
-        })
+            shrink_interval: AtomicUsize::new(0),
+            clock: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            router: Box::new(HashRouter),
+        }))
     }
 
-    /// See `with_data` documentation without unstable feature.
+    /// See `try_with_data` documentation without unstable feature.
     #[cfg(feature = "unstable")]
-    pub fn with_data(
+    pub fn try_with_data(
         logs: Vec<Arc<Log<'_, <D as Dispatch>::WriteOperation>>>,
         d: D,
-    ) -> Arc<Replica<'_, D>> {
+    ) -> Result<Arc<Replica<'_, D>>, LogError> {
+        Replica::try_with_data_in(logs, d, LogAllocator::default())
+    }
+
+    /// Creates a replica whose per-thread contexts are allocated by `allocator`.
+    #[cfg(feature = "unstable")]
+    fn try_with_data_in<'b>(
+        logs: Vec<Arc<Log<'b, <D as Dispatch>::WriteOperation>>>,
+        d: D,
+        allocator: LogAllocator<'b>,
+    ) -> Result<Arc<Replica<'b, D>>, LogError> {
         use core::mem::MaybeUninit;
 
         let mut uninit_replica: Arc<MaybeUninit<Replica<D>>> = Arc::new_zeroed();
@@ -335,10 +643,14 @@ where
             uninit_ptr.write(Replica {
                 next: CachePadded::new(AtomicUsize::new(1)),
                 data: CachePadded::new(d),
-                logstate: Vec::with_capacity(logs.len()),
-                contexts: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
-                offsets: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
-                hash: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
+                logstate: try_vec_with_capacity(logs.len())?,
+                contexts: try_vec_with_capacity_in(MAX_THREADS_PER_REPLICA, allocator)?,
+                offsets: try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?,
+                hash: try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?,
+                shrink_interval: AtomicUsize::new(0),
+                clock: AtomicUsize::new(0),
+                poisoned: AtomicBool::new(false),
+                router: Box::new(HashRouter),
             });
 
             let mut replica = uninit_replica.assume_init();
@@ -350,12 +662,12 @@ where
                     .push(CachePadded::new(Context::new(idx + 1)));
                 replica_mut
                     .offsets
-                    .push(RefCell::new(Vec::with_capacity(logs.len())));
+                    .push(RefCell::new(try_vec_with_capacity(logs.len())?));
                 replica_mut
                     .hash
-                    .push(CachePadded::new(RefCell::new(Vec::with_capacity(
+                    .push(CachePadded::new(RefCell::new(try_vec_with_capacity(
                         logs.len(),
-                    ))));
+                    )?)));
             }
 
             // Add per-log state
@@ -363,10 +675,10 @@ where
                 Arc::get_mut(&mut replica)
                     .unwrap()
                     .logstate
-                    .push(CachePadded::new(LogState::new(log.clone())));
+                    .push(CachePadded::new(LogState::try_new(log.clone())?));
             }
 
-            replica
+            Ok(replica)
         }
     }
 
@@ -416,6 +728,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     fn dispatch(
@@ -432,6 +745,14 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         None
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
@@ -508,6 +829,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     fn dispatch(
@@ -524,6 +846,14 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         None
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
@@ -538,10 +868,10 @@ where
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        self.check_poisoned();
         let mut hash_vec = self.hash[idx.0 - 1].borrow_mut();
-        hash_vec.clear();
         // Calculate the hash of the operation to map the operation to a log.
-        op.hash(self.logstate.len(), &mut hash_vec);
+        self.logs_of(&op, &mut hash_vec);
         assert_eq!(hash_vec.len(), 1);
         let hash = hash_vec[0];
 
@@ -558,6 +888,13 @@ where
     /// This method executes an mutable operation against this replica that depends
     /// on multiple logs and returns a response.
     ///
+    /// The operation is appended to every log `LogMapper::hash` maps it to, which
+    /// can be all the logs or only some of them (e.g., a rename between two
+    /// directories that are partitioned to different logs). It is executed
+    /// atomically with respect to the operations on each of these logs: every
+    /// replica executes it after the operations appended to these logs before it,
+    /// and before the ones appended after it.
+    ///
     /// `idx` is an identifier for the thread performing the execute operation.
     ///
     /// # Example
@@ -603,6 +940,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     fn dispatch(
@@ -619,6 +957,14 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         Some(op.0)
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
@@ -634,23 +980,69 @@ where
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
-        let nlogs = self.logstate.len();
+        self.check_poisoned();
+        let (root, nlogs) = {
+            let mut logs = self.hash[idx.0 - 1].borrow_mut();
+            self.logs_of(&op, &mut logs);
+            (logs[0], logs.len())
+        };
 
-        // If there is only one log in the system, then execute
-        // scan operation as a mutable operations.
-        if nlogs == 1 {
-            return self.execute_mut(op, idx);
-        }
+        // If the operation maps to a single log, then execute
+        // it as a regular mutable operation.
+        let is_scan = nlogs > 1;
 
-        let hash = 0; /* Fake hash; scan op is appended to each log.*/
-        // Enqueue the operation onto the thread local batch and then try to flat combine.
-        self.make_pending(op, idx.0, hash, true, false);
+        // The operation is enqueued for its root log, whose combiner appends
+        // it to all the logs it maps to.
+        self.make_pending(op, idx.0, root, is_scan, false);
 
         // A thread becomes combiner for operations with hash same as its own operation.
-        self.try_combine(idx.0, hash);
+        self.try_combine(idx.0, root);
+
+        let resp = self.get_response(idx.0, root);
+
+        // The other logs stop at the entries of the operation until the root
+        // log executed it; let them catch up. `hash` still holds the logs of
+        // the operation, the combiner computed the same ones.
+        for i in 1..nlogs {
+            let logidx = self.hash[idx.0 - 1].borrow()[i];
+            self.try_combine(idx.0, logidx);
+        }
 
         // Return the response to the caller function.
-        self.get_response(idx.0, hash)
+        resp
+    }
+
+    /// Computes the logs operation `op` goes to: the logs the router of the
+    /// replica picks for the keys returned by `LogMapper::hash`, in increasing
+    /// order of log ids. For mutable scan operations, the first one is the root
+    /// log of the operation.
+    fn logs_of<O: LogMapper>(&self, op: &O, logs: &mut Vec<usize>) {
+        let nlogs = self.logstate.len();
+        logs.clear();
+        op.hash(nlogs, logs);
+        for logidx in logs.iter_mut() {
+            *logidx = self.router.route(*logidx, nlogs);
+            assert!(
+                *logidx < nlogs,
+                "LogRouter picked a log that doesn't exist."
+            );
+        }
+        logs.sort_unstable();
+        logs.dedup();
+    }
+
+    /// Tries to acquire the scan lock of all `logs` for thread `tid`. Returns
+    /// false (with none of the locks held) if one of the locks is taken.
+    fn try_scan_lock_logs(&self, tid: usize, logs: &[usize]) -> bool {
+        for (i, &logidx) in logs.iter().enumerate() {
+            if !self.logstate[logidx].slog.try_scan_lock(tid) {
+                for &locked in logs[..i].iter() {
+                    self.logstate[locked].slog.release_scan_lock();
+                }
+                return false;
+            }
+        }
+        true
     }
 
     fn append_scan(&self, op: (<D as Dispatch>::WriteOperation, usize, bool), thread_id: usize) {
@@ -658,12 +1050,16 @@ where
         let mut entries = self.offsets[thread_id - 1].borrow_mut();
         entries.clear();
 
-        let nlogs = self.logstate.len();
-        hash_vec.clear();
-        op.0.hash(nlogs, &mut hash_vec);
+        self.logs_of(&op.0, &mut hash_vec);
         let root_log = hash_vec[0];
 
-        self.logstate[root_log].slog.acquire_scan_lock(thread_id);
+        // Hold the scan lock of every log the operation is appended to, so
+        // that operations sharing some logs are appended to all of them in the
+        // same order. Otherwise, their entries could wait on each other.
+        while !self.try_scan_lock_logs(thread_id, &hash_vec) {
+            self.check_poisoned();
+            spin_loop();
+        }
         for logidx in hash_vec.iter() {
             let entry = loop {
                 let f = |o: <D as Dispatch>::WriteOperation,
@@ -697,7 +1093,9 @@ where
             };
             entries.push(entry);
         }
-        self.logstate[root_log].slog.release_scan_lock();
+        for logidx in hash_vec.iter() {
+            self.logstate[*logidx].slog.release_scan_lock();
+        }
 
         let mut offset = Vec::new();
         offset.reserve_exact(entries.len());
@@ -757,6 +1155,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     fn dispatch(
@@ -773,6 +1172,14 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         None
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
@@ -788,11 +1195,20 @@ where
         op: <D as Dispatch>::ReadOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        self.check_poisoned();
         self.read_only(op, idx.0)
     }
 
-    /// This method executes an mutable operation against this replica that depends
-    /// on multiple logs and returns a response.
+    /// Executes a scan operation against this replica and returns a response.
+    /// A scan is a read-only operation that may depend on multiple logs; it maps
+    /// to the logs it depends on through its [LogMapper](trait.LogMapper.html).
+    ///
+    /// The replica is synced up against all these logs and the scan executes
+    /// while no write operation from any of them is applied on this replica, so
+    /// it observes a consistent state across the logs. Scans are not appended to
+    /// the logs, hence other replicas are not involved. Instead, the replica
+    /// syncs up against completed tails that all logs had at the same time,
+    /// which takes several attempts while operations complete on the logs.
     ///
     /// `idx` is an identifier for the thread performing the execute operation.
     ///
@@ -839,6 +1255,7 @@ where
     /// impl Dispatch for Data {
     ///     type ReadOperation = OpRd;
     ///     type WriteOperation = OpWr;
+    ///     type ScanOperation = OpRd;
     ///     type Response = Option<usize>;
     ///
     ///     fn dispatch(
@@ -855,50 +1272,116 @@ where
     ///         self.junk.store(op.0, Ordering::Relaxed);
     ///         Some(op.0)
     ///     }
+    ///
+    ///     // A scan returns the underlying u64 as well.
+    ///     fn dispatch_scan(
+    ///         &self,
+    ///         _op: Self::ScanOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk.load(Ordering::Relaxed))
+    ///     }
     /// }
     ///
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
     /// let replica = Replica::<Data>::new(vec![log]);
     /// let idx = replica.register().expect("Failed to register with replica.");
     ///
-    /// // execute_mut_scan() can be used to write to the replicated data structure
+    /// let _wr = replica.execute_mut(OpWr(100), idx);
+    ///
+    /// // execute_scan() can be used to read from the replicated data structure
     /// // through all the logs.
-    /// let res = replica.execute_mut_scan(OpWr(100), idx);
+    /// let res = replica.execute_scan(OpRd(()), idx);
     /// assert_eq!(Some(100), res);
     pub fn execute_scan(
         &self,
-        op: <D as Dispatch>::WriteOperation,
+        op: <D as Dispatch>::ScanOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
-        let nlogs = self.logstate.len();
+        self.check_poisoned();
+        let mut logs = self.hash[idx.0 - 1].borrow_mut();
+
+        // Combiner locks are acquired in increasing order of log ids.
+        self.logs_of(&op, &mut logs);
+
+        // Become the combiner of all the logs the scan depends on. This ensures
+        // that no other thread applies operations from these logs while we sync
+        // up against them and execute the scan. If a lock is taken, release all
+        // locks acquired so far and try again; combiners never block on each
+        // other's locks, hence we can't deadlock.
+        while !self.try_lock_logs(idx.0, &logs) {
+            self.check_poisoned();
+            spin_loop();
+        }
+        for &logidx in logs.iter() {
+            self.on_lock(logidx, CombinerPhase::Executing);
+        }
+        let guard = self.poison_on_unwind(&logs);
+
+        // The scan takes effect at a point in time at which all logs had the
+        // completed tails it syncs up against. Reading each tail once isn't
+        // enough: an operation could complete on a log whose tail was read
+        // already, and an operation issued after it on a log whose tail
+        // wasn't, so the scan would see the latter but not the former. Tails
+        // only grow, so if reading all of them again yields the same tails,
+        // they all held at once in between.
+        let mut ctails = self.offsets[idx.0 - 1].borrow_mut();
+        ctails.clear();
+        ctails.extend(logs.iter().map(|&l| self.logstate[l].slog.get_ctail()));
+        loop {
+            let mut stable = true;
+            for (ctail, &logidx) in ctails.iter_mut().zip(logs.iter()) {
+                let now = self.logstate[logidx].slog.get_ctail();
+                stable &= now == *ctail;
+                *ctail = now;
+            }
+            if stable {
+                break;
+            }
+            spin_loop();
+        }
 
-        // If there is only one log in the system, then execute
-        // scan operation as a mutable operations.
-        if nlogs == 1 {
-            // We can perform the read scan if our replica is synced up against
-            // the shared log. If it isn't, then try to combine until it is synced up.
-            let hash_idx = nlogs - 1;
-            let ctail = self.logstate[hash_idx].slog.get_ctail();
-            while !self.logstate[hash_idx]
+        // Sync up against the completed tail of every log. A mutable scan can
+        // make `exec` stop early on one log until another one makes progress,
+        // so keep iterating over all logs until all of them are synced up.
+        for (&ctail, &logidx) in ctails.iter().zip(logs.iter()) {
+            while !self.logstate[logidx]
                 .slog
-                .is_replica_synced_for_reads(self.logstate[hash_idx].idx, ctail)
+                .is_replica_synced_for_reads(self.logstate[logidx].idx, ctail)
             {
-                self.try_combine(idx.0, hash_idx);
+                for &other in logs.iter() {
+                    self.exec(idx.0, other);
+                }
                 spin_loop();
             }
-
-            return self.data.dispatch_mut(op);
         }
 
-        let hash = 0; /* Fake hash; scan op is appended to each log.*/
-        // Enqueue the operation onto the thread local batch and then try to flat combine.
-        self.make_pending(op, idx.0, hash, true, true);
+        let resp = self.data.dispatch_scan(op);
 
-        // A thread becomes combiner for operations with hash same as its own operation.
-        self.try_combine(idx.0, hash);
+        mem::forget(guard);
+        for &logidx in logs.iter() {
+            self.unlock(logidx);
+        }
+        resp
+    }
 
-        // Return the response to the caller function.
-        self.get_response(idx.0, hash)
+    /// Tries to acquire the combiner lock of all `logs` for thread `tid`. Returns
+    /// false (with none of the locks held) if one of the locks is taken.
+    fn try_lock_logs(&self, tid: usize, logs: &[usize]) -> bool {
+        for (i, &logidx) in logs.iter().enumerate() {
+            if self.logstate[logidx].combiner.compare_exchange_weak(
+                0,
+                tid,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) != Ok(0)
+            {
+                for &locked in logs[..i].iter() {
+                    self.unlock(locked);
+                }
+                return false;
+            }
+        }
+        true
     }
 
     /// Busy waits until a response is available within the thread's context.
@@ -914,6 +1397,7 @@ where
             if let Some(resp) = r {
                 return resp;
             }
+            self.check_poisoned();
 
             iter += 1;
 
@@ -924,6 +1408,99 @@ where
         }
     }
 
+    /// Returns the logical offset up to which this replica has applied the
+    /// operations of the log at position `log_id` in the list of logs passed to
+    /// the constructor (the same numbering [LogMapper](trait.LogMapper.html) uses).
+    ///
+    /// The offset only ever grows, so it identifies the version of the data
+    /// structure at this replica with respect to that log. Two replicas that
+    /// report the same offset for every log have applied the same operations.
+    ///
+    /// # Panics
+    /// If `log_id` is not smaller than the number of logs of this replica.
+    pub fn applied_offset(&self, log_id: usize) -> u64 {
+        let logstate = &self.logstate[log_id];
+        logstate.slog.get_ltail(logstate.idx) as u64
+    }
+
+    /// Returns how often the combiners of the log at position `log_id` checked
+    /// a registered thread for operations on that log without finding any. A
+    /// high number compared to the operations on the log means that most
+    /// registered threads are idle, or issue their operations to other logs.
+    ///
+    /// # Panics
+    /// If `log_id` is not smaller than the number of logs of this replica.
+    pub fn wasted_pending_checks(&self, log_id: usize) -> u64 {
+        self.logstate[log_id].wasted_checks.load(Ordering::Relaxed)
+    }
+
+    /// Returns how much memory the combiners of the log at position `log_id`
+    /// use to stage operations, and how many operations they needed it for.
+    ///
+    /// # Panics
+    /// If `log_id` is not smaller than the number of logs of this replica.
+    pub fn buffer_stats(&self, log_id: usize) -> BufferStats {
+        let logstate = &self.logstate[log_id];
+        BufferStats {
+            capacity: logstate.capacity.load(Ordering::Relaxed),
+            high_watermark: logstate.peak.load(Ordering::Relaxed),
+            shrinks: logstate.shrinks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns `Err(ReplicaPoisoned)` if a thread panicked while holding one of
+    /// the combiner locks of this replica. Operations issued against the
+    /// replica panic from then on, instead of waiting for the combiner.
+    pub fn poisoned(&self) -> Result<(), ReplicaPoisoned> {
+        if self.is_poisoned() {
+            Err(ReplicaPoisoned)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns which thread holds the combiner lock of the log at position
+    /// `log_id`, since when, and what it is doing. Useful to find out why a
+    /// replica stopped making progress, e.g., from a watchdog thread.
+    ///
+    /// The fields are read one after the other while the lock can change hands,
+    /// so they can be inconsistent with each other for a lock that is acquired
+    /// and released frequently; a stuck combiner shows up consistently.
+    ///
+    /// # Panics
+    /// If `log_id` is not smaller than the number of logs of this replica.
+    pub fn combiner_status(&self, log_id: usize) -> CombinerStatus {
+        let logstate = &self.logstate[log_id];
+        match logstate.combiner.load(Ordering::Acquire) {
+            0 => CombinerStatus::default(),
+            owner => CombinerStatus {
+                owner: Some(owner),
+                acquired_at: logstate.acquired_at.load(Ordering::Relaxed),
+                phase: CombinerPhase::from_usize(logstate.phase.load(Ordering::Relaxed)),
+            },
+        }
+    }
+
+    /// Installs the clock that timestamps acquisitions of the combiner locks
+    /// of this replica (see `combiner_status`). There is none by default, since
+    /// the replica can't tell the time without `std`.
+    pub fn set_clock(&self, clock: Clock) {
+        self.clock.store(clock as usize, Ordering::Relaxed);
+    }
+
+    /// Makes the combiner of each log check every `rounds` rounds of flat
+    /// combining whether the buffers it stages operations in are much larger
+    /// than needed for the most operations it collected in a round since the
+    /// last check. If so, it shrinks them to that size. The buffers grow again
+    /// if the load picks up.
+    ///
+    /// The buffers are allocated for the worst case up front, which pins memory
+    /// for each log even if only few threads issue operations. Zero (the
+    /// default) never shrinks them.
+    pub fn set_buffer_shrink_interval(&self, rounds: usize) {
+        self.shrink_interval.store(rounds, Ordering::Relaxed);
+    }
+
     /// Executes a passed in closure against the replica's underlying data
     /// structure. Useful for unit testing; can be used to verify certain
     /// properties of the data structure after issuing a bunch of operations
@@ -943,8 +1520,11 @@ where
             Ordering::Acquire,
         ) != Ok(0)
         {
+            self.check_poisoned();
             spin_loop();
         }
+        self.on_lock(0, CombinerPhase::Executing);
+        let guard = self.poison_on_unwind(&[0]);
 
         let mut f = |o: <D as Dispatch>::WriteOperation,
                      _i: usize,
@@ -961,7 +1541,8 @@ where
 
         v(&self.data);
 
-        self.logstate[0].combiner.store(0, Ordering::Release);
+        mem::forget(guard);
+        self.unlock(0);
     }
 
     /// This method is useful when a replica stops making progress and some threads
@@ -997,6 +1578,106 @@ where
         }
     }
 
+    /// Adds `log` as the last log of this replica, e.g., to split the operations
+    /// of a log that became a hotspot over two logs. `LogMapper::hash` is then
+    /// called with one more log, and decides which operations move to `log`.
+    ///
+    /// `log` must be new and its id (the `idx` passed to `Log::new`) must
+    /// follow the id of the last log of this replica.
+    ///
+    /// # Note
+    /// The logs can only be changed while the replicated data structure is
+    /// idle. Taking `&mut self` (e.g., with `Arc::get_mut`) makes sure no thread
+    /// uses this replica; the caller has to make sure the same holds for the
+    /// other replicas of the logs, and add `log` to all of them before issuing
+    /// further operations. Otherwise, replicas could map conflicting operations
+    /// to different logs.
+    ///
+    /// Fails with `LogError::NotQuiescent` if a replica has yet to execute
+    /// operations on one of the logs of this replica, if a thread of this
+    /// replica has operations in flight, or if `log` was used already.
+    ///
+    /// # Panics
+    /// If the id of `log` doesn't follow the id of the last log.
+    pub fn add_log(
+        &mut self,
+        log: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    ) -> Result<(), LogError> {
+        assert_eq!(
+            log.id(),
+            self.logstate.len() + 1,
+            "Logs must be added in the order of their ids."
+        );
+        self.check_quiescent()?;
+        if !log.is_empty() {
+            return Err(LogError::NotQuiescent);
+        }
+
+        // `LogMapper::hash` may assume that it can push one entry per log.
+        let nlogs = self.logstate.len() + 1;
+        for (offsets, hash) in self.offsets.iter_mut().zip(self.hash.iter_mut()) {
+            offsets.get_mut().reserve(nlogs);
+            hash.get_mut().reserve(nlogs);
+        }
+
+        self.logstate
+            .push(CachePadded::new(LogState::try_new(log)?));
+        Ok(())
+    }
+
+    /// Removes the last log of this replica and returns it, e.g., to merge its
+    /// operations back into the remaining logs. `LogMapper::hash` is then
+    /// called with one log less.
+    ///
+    /// The same restrictions as for `add_log` apply: remove the log from every
+    /// replica of the logs while the replicated data structure is idle.
+    ///
+    /// Fails with `LogError::NotQuiescent` if a replica has yet to execute
+    /// operations on one of the logs of this replica, or if a thread of this
+    /// replica has operations in flight.
+    ///
+    /// # Panics
+    /// If this replica has only one log.
+    pub fn remove_log(
+        &mut self,
+    ) -> Result<Arc<Log<'a, <D as Dispatch>::WriteOperation>>, LogError> {
+        assert!(self.logstate.len() > 1, "A replica needs at least one log.");
+        self.check_quiescent()?;
+
+        let logstate = self.logstate.pop().unwrap();
+        Ok(logstate.slog.clone())
+    }
+
+    /// Replaces the `LogRouter` that picks the log for each key returned by
+    /// `LogMapper::hash`. The default is `HashRouter`.
+    ///
+    /// The same restrictions as for `add_log` apply: install the same router on
+    /// every replica of the logs while the replicated data structure is idle.
+    ///
+    /// Fails with `LogError::NotQuiescent` if a replica has yet to execute
+    /// operations on one of the logs of this replica, or if a thread of this
+    /// replica has operations in flight.
+    pub fn set_router<R: LogRouter + 'static>(&mut self, router: R) -> Result<(), LogError> {
+        self.check_quiescent()?;
+        self.router = Box::new(router);
+        Ok(())
+    }
+
+    /// Returns an error unless every replica executed all operations on the
+    /// logs of this replica and no thread of this replica has operations in
+    /// flight.
+    fn check_quiescent(&self) -> Result<(), LogError> {
+        let next = self.next.load(Ordering::Relaxed);
+        let idle = self.contexts[..next - 1].iter().all(|c| c.is_drained())
+            && self.logstate.iter().all(|l| l.slog.is_drained());
+
+        if idle {
+            Ok(())
+        } else {
+            Err(LogError::NotQuiescent)
+        }
+    }
+
     /// Issues a read-only operation against the replica and returns a response.
     /// Makes sure the replica is synced up against the log before doing so.
     fn read_only(
@@ -1005,9 +1686,8 @@ where
         tid: usize,
     ) -> <D as Dispatch>::Response {
         let mut hash_vec = self.hash[tid - 1].borrow_mut();
-        hash_vec.clear();
         // Calculate the hash of the operation to map the operation to a log.
-        op.hash(self.logstate.len(), &mut hash_vec);
+        self.logs_of(&op, &mut hash_vec);
         assert_eq!(hash_vec.len(), 1);
         let hash_idx = hash_vec[0];
 
@@ -1038,7 +1718,12 @@ where
     ) -> bool {
         loop {
             if self.contexts[tid - 1].enqueue(op.clone(), hash, is_scan, is_read_op) {
-                self.logstate[hash].pending[tid - 1].store(true, Ordering::Release);
+                let logstate = &self.logstate[hash];
+                logstate.npending.fetch_add(1, Ordering::Relaxed);
+                if logstate.pending[tid - 1].swap(true, Ordering::Release) {
+                    // Already set; the combiner collects both operations at once.
+                    logstate.npending.fetch_sub(1, Ordering::Relaxed);
+                }
                 break;
             }
         }
@@ -1048,6 +1733,8 @@ where
     /// Appends an operation to the log and attempts to perform flat combining.
     /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
     fn try_combine(&self, tid: usize, hashidx: usize) {
+        self.check_poisoned();
+
         // First, check if there already is a flat combiner. If there is no active flat combiner
         // then try to acquire the combiner lock. If there is, then just return.
         for _i in 0..4 {
@@ -1075,12 +1762,15 @@ where
         }
 
         // Successfully became the combiner; perform one round of flat combining.
+        self.on_lock(hashidx, CombinerPhase::Collecting);
+        let guard = self.poison_on_unwind(core::slice::from_ref(&hashidx));
         self.combine(tid, hashidx);
+        mem::forget(guard);
 
         // Allow other threads to perform flat combining once we have finished all our work.
         // At this point, we've dropped all mutable references to thread contexts and to
         // the staging buffer as well.
-        self.logstate[hashidx].combiner.store(0, Ordering::Release);
+        self.unlock(hashidx);
     }
 
     /// Performs one round of flat combining. Collects, appends and executes operations.
@@ -1090,14 +1780,23 @@ where
         let mut buffer = self.logstate[hashidx].buffer.borrow_mut();
         let mut scan_buffer = self.logstate[hashidx].scan_buffer.borrow_mut();
         let pending = &self.logstate[hashidx].pending;
+        let npending = &self.logstate[hashidx].npending;
 
         buffer.clear();
         scan_buffer.clear();
 
         let next = self.next.load(Ordering::Relaxed);
 
-        // Collect operations from each thread registered with this replica.
+        // Collect operations from each thread registered with this replica. Stop
+        // once as many flags were found as were set when we started; flags set
+        // in the meantime are picked up by the next round.
+        let mut remaining = npending.load(Ordering::Acquire);
+        let mut wasted = 0;
         for tid in 1..next {
+            if remaining == 0 {
+                break;
+            }
+
             if pending[tid - 1].compare_exchange_weak(
                 true,
                 false,
@@ -1105,13 +1804,26 @@ where
                 Ordering::Relaxed,
             ) == Ok(true)
             {
+                npending.fetch_sub(1, Ordering::Relaxed);
+                remaining -= 1;
+
                 // pass hash of current op to contexts, only get ops from context that have the same hash/log id
                 self.contexts[tid - 1].ops(&mut buffer, &mut scan_buffer, hashidx);
+            } else {
+                wasted += 1;
             }
         }
+        if wasted > 0 {
+            let wasted_checks = &self.logstate[hashidx].wasted_checks;
+            wasted_checks.store(
+                wasted_checks.load(Ordering::Relaxed) + wasted,
+                Ordering::Relaxed,
+            );
+        }
 
         // Append all collected operations into the shared log. We pass a closure
         // in here because operations on the log might need to be consumed for GC.
+        self.set_phase(hashidx, CombinerPhase::Appending);
         {
             let f = |o: <D as Dispatch>::WriteOperation,
                      rid: usize,
@@ -1142,31 +1854,150 @@ where
                 self.append_scan(scan_buffer[i].clone(), thread_id);
             }
         }
+        self.recycle_buffers(hashidx, &mut buffer, &mut scan_buffer);
 
         // Execute any operations on the shared log against this replica.
-        {
-            let mut f = |o: <D as Dispatch>::WriteOperation,
-                         rid: usize,
-                         tid: usize,
-                         is_scan,
-                         is_read_op,
-                         depends_on: Option<Arc<Vec<usize>>>|
-             -> bool {
-                if unlikely(is_scan) {
-                    let depends_on = depends_on.as_ref().unwrap();
-                    self.handle_scan_op(o, thread_id, hashidx, rid, tid, is_read_op, depends_on)
-                } else {
-                    let resp = self.data.dispatch_mut(o);
-                    if rid == self.logstate[hashidx].idx {
-                        self.contexts[tid - 1].enqueue_resp(resp);
-                    };
-                    true
+        self.set_phase(hashidx, CombinerPhase::Executing);
+        self.exec(thread_id, hashidx);
+    }
+
+    /// Records when the combiner lock of log `hashidx` was acquired and what
+    /// its holder does first. Must be called right after acquiring the lock.
+    fn on_lock(&self, hashidx: usize, phase: CombinerPhase) {
+        let logstate = &self.logstate[hashidx];
+        logstate.acquired_at.store(self.now(), Ordering::Relaxed);
+        logstate.phase.store(phase as usize, Ordering::Relaxed);
+    }
+
+    /// Records what the holder of the combiner lock of log `hashidx` does now.
+    fn set_phase(&self, hashidx: usize, phase: CombinerPhase) {
+        self.logstate[hashidx]
+            .phase
+            .store(phase as usize, Ordering::Relaxed);
+    }
+
+    /// Returns a guard that releases the combiner locks of `logs` and poisons
+    /// the replica if the caller, which holds these locks, unwinds before it
+    /// forgets the guard.
+    fn poison_on_unwind<'r>(&'r self, logs: &'r [usize]) -> PoisonOnUnwind<'r, 'a, D> {
+        PoisonOnUnwind {
+            replica: self,
+            logs,
+        }
+    }
+
+    /// Returns true if the replica is poisoned, see `ReplicaPoisoned`.
+    fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Panics with `ReplicaPoisoned` if the replica is poisoned.
+    fn check_poisoned(&self) {
+        if unlikely(self.is_poisoned()) {
+            #[cfg(feature = "std")]
+            std::panic::panic_any(ReplicaPoisoned);
+            #[cfg(not(feature = "std"))]
+            panic!("{}", ReplicaPoisoned);
+        }
+    }
+
+    /// Releases the combiner lock of log `hashidx`.
+    fn unlock(&self, hashidx: usize) {
+        let logstate = &self.logstate[hashidx];
+        logstate
+            .phase
+            .store(CombinerPhase::Idle as usize, Ordering::Relaxed);
+        logstate.combiner.store(0, Ordering::Release);
+    }
+
+    /// Returns the time according to the installed clock, or zero without one.
+    fn now(&self) -> u64 {
+        match self.clock.load(Ordering::Relaxed) {
+            0 => 0,
+            // Only ever set from a `Clock` in `set_clock`.
+            clock => unsafe { core::mem::transmute::<usize, Clock>(clock)() },
+        }
+    }
+
+    /// Records how many operations the combiner of log `hashidx` collected in
+    /// this round. Every `shrink_interval` rounds, shrinks the staging buffers
+    /// to the most operations collected in a round since the last check if
+    /// they have room for more than twice that. Must be called by the combiner
+    /// once the operations were appended.
+    fn recycle_buffers(
+        &self,
+        hashidx: usize,
+        buffer: &mut Vec<OperationState<D>>,
+        scan_buffer: &mut Vec<OperationState<D>>,
+    ) {
+        let logstate = &self.logstate[hashidx];
+        let n = buffer.len() + scan_buffer.len();
+        if n > logstate.peak.load(Ordering::Relaxed) {
+            logstate.peak.store(n, Ordering::Relaxed);
+        }
+
+        let window_peak = core::cmp::max(logstate.window_peak.load(Ordering::Relaxed), n);
+        let rounds = logstate.rounds.load(Ordering::Relaxed) + 1;
+        let interval = self.shrink_interval.load(Ordering::Relaxed);
+        if interval == 0 || rounds < interval {
+            logstate.window_peak.store(window_peak, Ordering::Relaxed);
+            logstate.rounds.store(rounds, Ordering::Relaxed);
+            logstate.capacity.store(
+                buffer.capacity() + scan_buffer.capacity(),
+                Ordering::Relaxed,
+            );
+            return;
+        }
+        logstate.window_peak.store(0, Ordering::Relaxed);
+        logstate.rounds.store(0, Ordering::Relaxed);
+
+        // Keep the old buffers if a smaller one can't be allocated.
+        let mut shrunk = false;
+        for b in [&mut *buffer, &mut *scan_buffer] {
+            if b.capacity() / 2 > window_peak {
+                if let Ok(smaller) = try_vec_with_capacity(window_peak) {
+                    *b = smaller;
+                    shrunk = true;
                 }
-            };
-            self.logstate[hashidx]
-                .slog
-                .exec(self.logstate[hashidx].idx, &mut f);
+            }
+        }
+        if shrunk {
+            logstate.shrinks.store(
+                logstate.shrinks.load(Ordering::Relaxed) + 1,
+                Ordering::Relaxed,
+            );
         }
+        logstate.capacity.store(
+            buffer.capacity() + scan_buffer.capacity(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Executes operations from the log `hashidx` against this replica. Must
+    /// be called by thread `thread_id` while it holds the combiner lock.
+    #[inline(always)]
+    fn exec(&self, thread_id: usize, hashidx: usize) {
+        let mut f = |o: <D as Dispatch>::WriteOperation,
+                     rid: usize,
+                     tid: usize,
+                     is_scan,
+                     is_read_op,
+                     depends_on: Option<Arc<Vec<usize>>>|
+         -> bool {
+            if unlikely(is_scan) {
+                let depends_on = depends_on.as_ref().unwrap();
+                self.handle_scan_op(o, thread_id, hashidx, rid, tid, is_read_op, depends_on)
+            } else {
+                let resp = self.data.dispatch_mut(o);
+                if rid == self.logstate[hashidx].idx {
+                    self.contexts[tid - 1].enqueue_resp(resp);
+                };
+                true
+            }
+        };
+        self.logstate[hashidx]
+            .slog
+            .exec(self.logstate[hashidx].idx, &mut f);
     }
 
     /// This method handles the scan operations; this method is called
@@ -1189,24 +2020,23 @@ where
             return true;
         }
 
-        let is_root = depends_on.len() == self.logstate.len();
-        if is_root {
-            // Root log for scan operation.
-            for (logidx, depends_on) in depends_on
-                .iter()
-                .enumerate()
-                .take(self.logstate.len())
-                .skip(1)
-            {
+        let mut logs = Vec::with_capacity(self.logstate.len());
+        self.logs_of(&op, &mut logs);
+
+        let root = logs[0];
+        if hashidx == root {
+            // Root log for scan operation; `depends_on` holds the offsets of
+            // the operation on each of its logs.
+            for (&logidx, &depends_on) in logs.iter().zip(depends_on.iter()).skip(1) {
                 if !self.logstate[logidx]
                     .slog
-                    .is_replica_synced_for_reads(self.logstate[logidx].idx, *depends_on)
+                    .is_replica_synced_for_reads(self.logstate[logidx].idx, depends_on)
                 {
                     self.try_combine(thread_id, logidx);
                 }
             }
 
-            if self.is_replica_sync_for_logs(1, self.logstate.len(), depends_on) {
+            if self.is_replica_sync_for_logs(&logs[1..], &depends_on[1..]) {
                 let resp = self.data.dispatch_mut(op);
                 if issuer_rid == self.logstate[hashidx].idx {
                     self.contexts[issuer_tid - 1].enqueue_resp(resp);
@@ -1216,16 +2046,15 @@ where
                 false
             }
         } else {
-            // Leaf log(s) for scan operation.
-            let logidx = 0;
-            match self.logstate[logidx]
-                .slog
-                .is_replica_synced_for_reads(self.logstate[logidx].idx, depends_on[logidx])
-            {
+            // Leaf log(s) for scan operation; `depends_on` holds the offset
+            // of the operation on the root log. Wait until the root log
+            // executed it, so that the operation is atomic on every log.
+            let executed = [depends_on[0] + 1];
+            match self.is_replica_sync_for_logs(&[root], &executed) {
                 true => true,
                 false => {
-                    self.try_combine(thread_id, logidx);
-                    self.is_replica_sync_for_logs(logidx, logidx + 1, depends_on)
+                    self.try_combine(thread_id, root);
+                    self.is_replica_sync_for_logs(&[root], &executed)
                 }
             }
         }
@@ -1234,15 +2063,14 @@ where
     /// This method checks if the current replica has applied each log upto ltails respectively.
     ///
     /// # Arguments
-    /// * `start`: The starting log number.
-    /// * `end`: The ending log number.
-    /// * `ltails`: Local tail for each log from `start` to `end`.
+    /// * `logs`: The log numbers.
+    /// * `ltails`: Local tail for each log in `logs`.
     ///
     /// # Return
     /// Return true if the replica has applied the each log upto respective ltails.
-    fn is_replica_sync_for_logs(&self, start: usize, end: usize, tails: &[usize]) -> bool {
+    fn is_replica_sync_for_logs(&self, logs: &[usize], tails: &[usize]) -> bool {
         let mut is_synced = true;
-        for (logidx, tail) in tails.iter().enumerate().take(end).skip(start) {
+        for (&logidx, tail) in logs.iter().zip(tails.iter()) {
             if !self.logstate[logidx]
                 .slog
                 .is_replica_synced_for_reads(self.logstate[logidx].idx, *tail)
@@ -1291,6 +2119,7 @@ mod test {
     impl Dispatch for Data {
         type ReadOperation = OpRd;
         type WriteOperation = OpWr;
+        type ScanOperation = OpRd;
         type Response = Result<usize, ()>;
 
         fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
@@ -1301,6 +2130,10 @@ mod test {
             self.junk.fetch_add(1, Ordering::Relaxed);
             return Ok(107);
         }
+
+        fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+            self.dispatch(op)
+        }
     }
 
     // Tests whether we can construct a Replica given a log.
@@ -1319,6 +2152,77 @@ mod test {
         assert_eq!(repl.data.junk.load(Ordering::Relaxed), 0);
     }
 
+    // Tests that try_new() fails once a log has no free replica slots left.
+    #[test]
+    fn test_replica_try_new_too_many() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024, 1));
+        while slog.register().is_some() {}
+
+        assert_eq!(
+            Replica::<Data>::try_new(vec![slog]).err(),
+            Some(LogError::TooManyReplicas)
+        );
+    }
+
+    // Tests that applied_offset() follows the operations a replica applied from the log.
+    #[test]
+    fn test_replica_applied_offset() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(vec![slog.clone()]);
+        let two = Replica::<Data>::new(vec![slog]);
+        let idx = one.register().unwrap();
+
+        assert_eq!(one.applied_offset(0), 0);
+        assert_eq!(one.execute_mut(OpWr(121), idx), Ok(107));
+        assert_eq!(one.execute_mut(OpWr(122), idx), Ok(107));
+        assert_eq!(one.applied_offset(0), 2);
+        assert_eq!(two.applied_offset(0), 0);
+
+        let idx = two.register().unwrap();
+        assert_eq!(two.execute(OpRd(11), idx), Ok(2));
+        assert_eq!(two.applied_offset(0), 2);
+    }
+
+    // Tests that logs can be added to and removed from idle replicas, but not
+    // while one of the replicas lags behind on the logs.
+    #[test]
+    fn test_replica_add_remove_log() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let mut one = Replica::<Data>::new(vec![slog.clone()]);
+        let mut two = Replica::<Data>::new(vec![slog]);
+        let idx = one.register().unwrap();
+        assert_eq!(one.execute_mut(OpWr(121), idx), Ok(107));
+
+        let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(
+            1024 * 1024,
+            2,
+        ));
+        assert_eq!(
+            Arc::get_mut(&mut one).unwrap().add_log(log.clone()),
+            Err(LogError::NotQuiescent)
+        );
+
+        let t2 = two.register().unwrap();
+        two.sync(t2);
+        Arc::get_mut(&mut one)
+            .unwrap()
+            .add_log(log.clone())
+            .unwrap();
+        Arc::get_mut(&mut two)
+            .unwrap()
+            .add_log(log.clone())
+            .unwrap();
+        assert_eq!(one.logstate[1].idx, 1);
+        assert_eq!(two.logstate[1].idx, 2);
+
+        assert_eq!(one.execute_mut(OpWr(122), idx), Ok(107));
+        assert_eq!(two.execute(OpRd(11), t2), Ok(2));
+
+        let removed = Arc::get_mut(&mut one).unwrap().remove_log().unwrap();
+        assert!(Arc::ptr_eq(&removed, &log));
+        assert_eq!(one.logstate.len(), 1);
+    }
+
     // Tests whether we can register with this replica and receive an idx.
     #[test]
     fn test_replica_register() {
@@ -1387,6 +2291,116 @@ mod test {
         assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
     }
 
+    // Tests that the combiner only scans the pending flags of registered threads
+    // until it found all the operations, and counts the flags it checked in vain.
+    #[test]
+    fn test_replica_try_combine_wasted_checks() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(vec![slog]);
+
+        repl.next.store(9, Ordering::SeqCst);
+        repl.try_combine(1, 0);
+        assert_eq!(repl.wasted_pending_checks(0), 0);
+
+        repl.make_pending(OpWr(121), 1, 0, false, false);
+        repl.try_combine(1, 0);
+        assert_eq!(repl.wasted_pending_checks(0), 0);
+
+        repl.make_pending(OpWr(121), 8, 0, false, false);
+        repl.try_combine(1, 0);
+        assert_eq!(repl.wasted_pending_checks(0), 7);
+        assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
+        assert_eq!(repl.logstate[0].npending.load(Ordering::Relaxed), 0);
+    }
+
+    // Tests that the combiner shrinks its staging buffers to the recent load once
+    // per shrink interval, and that they grow again under more load.
+    #[test]
+    fn test_replica_buffer_shrink() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(vec![slog]);
+        let initial = repl.buffer_stats(0);
+        assert_eq!(
+            initial.capacity,
+            MAX_THREADS_PER_REPLICA * (Context::<u64, Result<u64, ()>>::batch_size() + 1)
+        );
+
+        repl.set_buffer_shrink_interval(2);
+        repl.next.store(9, Ordering::SeqCst);
+        repl.make_pending(OpWr(121), 1, 0, false, false);
+        repl.try_combine(1, 0);
+        assert_eq!(
+            repl.buffer_stats(0),
+            BufferStats {
+                high_watermark: 1,
+                ..initial
+            }
+        );
+
+        repl.make_pending(OpWr(121), 1, 0, false, false);
+        repl.make_pending(OpWr(121), 2, 0, false, false);
+        repl.try_combine(1, 0);
+        assert_eq!(
+            repl.buffer_stats(0),
+            BufferStats {
+                capacity: 4,
+                high_watermark: 2,
+                shrinks: 1,
+            }
+        );
+
+        for tid in 1..9 {
+            repl.make_pending(OpWr(121), tid, 0, false, false);
+        }
+        repl.try_combine(1, 0);
+        let stats = repl.buffer_stats(0);
+        assert!(stats.capacity >= 10);
+        assert_eq!(stats.high_watermark, 8);
+        assert_eq!(repl.data.junk.load(Ordering::Relaxed), 11);
+    }
+
+    // Tests that the status of a combiner lock reports its holder, when it was
+    // acquired according to the installed clock, and what the holder is doing.
+    #[test]
+    fn test_replica_combiner_status() {
+        fn clock() -> u64 {
+            42
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(vec![slog]);
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.combiner_status(0), CombinerStatus::default());
+
+        repl.logstate[0].combiner.store(idx.0, Ordering::SeqCst);
+        repl.on_lock(0, CombinerPhase::Collecting);
+        assert_eq!(
+            repl.combiner_status(0),
+            CombinerStatus {
+                owner: Some(idx.0),
+                acquired_at: 0,
+                phase: CombinerPhase::Collecting,
+            }
+        );
+
+        repl.set_clock(clock);
+        repl.on_lock(0, CombinerPhase::Collecting);
+        repl.set_phase(0, CombinerPhase::Appending);
+        assert_eq!(
+            repl.combiner_status(0),
+            CombinerStatus {
+                owner: Some(idx.0),
+                acquired_at: 42,
+                phase: CombinerPhase::Appending,
+            }
+        );
+
+        repl.unlock(0);
+        assert_eq!(repl.combiner_status(0), CombinerStatus::default());
+        assert_eq!(repl.execute_mut(OpWr(121), idx), Ok(107));
+        assert_eq!(repl.combiner_status(0), CombinerStatus::default());
+    }
+
     // Tests whether try_combine() fails if someone else is currently flat combining.
     #[test]
     fn test_replica_try_combine_fail() {
@@ -1514,6 +2528,7 @@ mod test {
         impl Dispatch for Block {
             type ReadOperation = OpRd;
             type WriteOperation = OpWr;
+            type ScanOperation = OpRd;
             type Response = Result<usize, ()>;
 
             fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
@@ -1526,6 +2541,10 @@ mod test {
                 self.junk.fetch_add(1, Ordering::Relaxed);
                 return Ok(107);
             }
+
+            fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+                self.dispatch(op)
+            }
         }
 
         let slog1 = Arc::new(Log::<<Block as Dispatch>::WriteOperation>::default());
@@ -1578,6 +2597,7 @@ mod test {
     pub enum WriteOp {
         Set(usize),
         SetScan(usize),
+        SetLogs(usize, usize),
     }
 
     impl LogMapper for WriteOp {
@@ -1590,6 +2610,10 @@ mod test {
                         logs.push(i);
                     }
                 }
+                WriteOp::SetLogs(a, b) => {
+                    logs.push(*b);
+                    logs.push(*a);
+                }
             }
         }
     }
@@ -1604,9 +2628,20 @@ mod test {
         }
     }
 
+    #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+    pub struct ScanOp;
+
+    impl LogMapper for ScanOp {
+        fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+            logs.clear();
+            logs.extend(0..nlogs);
+        }
+    }
+
     impl Dispatch for ScanDS {
         type ReadOperation = ReadOp;
         type WriteOperation = WriteOp;
+        type ScanOperation = ScanOp;
         type Response = Result<usize, ()>;
 
         fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
@@ -1616,6 +2651,10 @@ mod test {
         fn dispatch_mut(&self, _op: Self::WriteOperation) -> Self::Response {
             return Ok(self.junk.fetch_add(1, Ordering::Relaxed));
         }
+
+        fn dispatch_scan(&self, _op: Self::ScanOperation) -> Self::Response {
+            Ok(self.junk.load(Ordering::Relaxed))
+        }
     }
 
     #[test]
@@ -1713,16 +2752,16 @@ mod test {
         }
 
         let ltails = vec![0, 0, 0, 0];
-        assert_eq!(true, repl.is_replica_sync_for_logs(0, 1, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(1, 2, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(2, 3, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(3, 4, &ltails));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[0], &ltails[0..1]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[1], &ltails[1..2]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[2], &ltails[2..3]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[3], &ltails[3..4]));
 
         let ltails = vec![1, 1, 1, 1];
-        assert_eq!(false, repl.is_replica_sync_for_logs(0, 1, &ltails));
-        assert_eq!(false, repl.is_replica_sync_for_logs(1, 2, &ltails));
-        assert_eq!(false, repl.is_replica_sync_for_logs(2, 3, &ltails));
-        assert_eq!(false, repl.is_replica_sync_for_logs(3, 4, &ltails));
+        assert_eq!(false, repl.is_replica_sync_for_logs(&[0], &ltails[0..1]));
+        assert_eq!(false, repl.is_replica_sync_for_logs(&[1], &ltails[1..2]));
+        assert_eq!(false, repl.is_replica_sync_for_logs(&[2], &ltails[2..3]));
+        assert_eq!(false, repl.is_replica_sync_for_logs(&[3], &ltails[3..4]));
     }
 
     #[test]
@@ -1746,10 +2785,203 @@ mod test {
         }
 
         let ltails = vec![nlogs, nlogs, nlogs, nlogs];
-        assert_eq!(true, repl.is_replica_sync_for_logs(0, 1, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(1, 2, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(2, 3, &ltails));
-        assert_eq!(true, repl.is_replica_sync_for_logs(3, 4, &ltails));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[0], &ltails[0..1]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[1], &ltails[1..2]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[2], &ltails[2..3]));
+        assert_eq!(true, repl.is_replica_sync_for_logs(&[3], &ltails[3..4]));
+    }
+
+    // Tests that execute_scan() syncs the replica up against all the logs it
+    // depends on, including operations appended by other replicas.
+    #[test]
+    fn test_execute_scan() {
+        let mut logs = vec![];
+        let nlogs = 4;
+
+        for i in 0..nlogs {
+            logs.push(Arc::new(Log::<<ScanDS as Dispatch>::WriteOperation>::new(
+                4 * 1024 * 1024,
+                i + 1,
+            )));
+        }
+
+        let repl1 = Replica::<ScanDS>::new(logs.clone());
+        let repl2 = Replica::<ScanDS>::new(logs.clone());
+        let idx1 = repl1.register().unwrap();
+        let idx2 = repl2.register().unwrap();
+
+        for i in 0..2 * nlogs {
+            assert_eq!(repl2.execute_mut(WriteOp::Set(i), idx2), Ok(i));
+        }
+        assert_eq!(repl2.execute_mut_scan(WriteOp::SetScan(0), idx2), Ok(8));
+
+        assert_eq!(repl1.execute_scan(ScanOp, idx1), Ok(2 * nlogs + 1));
+        for i in 0..nlogs {
+            assert_eq!(repl1.logstate[i].combiner.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    // Tests that scans complete while another replica keeps appending to the
+    // logs, and never observe fewer operations than a scan before them.
+    #[test]
+    fn test_execute_scan_concurrent_writes() {
+        let mut logs = vec![];
+        let nlogs = 2;
+
+        for i in 0..nlogs {
+            logs.push(Arc::new(Log::<<ScanDS as Dispatch>::WriteOperation>::new(
+                4 * 1024 * 1024,
+                i + 1,
+            )));
+        }
+
+        let repl1 = Replica::<ScanDS>::new(logs.clone());
+        let repl2 = Replica::<ScanDS>::new(logs.clone());
+        let nops = 10_000;
+
+        let writer = {
+            let repl1 = repl1.clone();
+            thread::spawn(move || {
+                let idx = repl1.register().unwrap();
+                for i in 0..nops {
+                    repl1.execute_mut(WriteOp::Set(i), idx).unwrap();
+                }
+            })
+        };
+
+        let idx = repl2.register().unwrap();
+        let mut last = 0;
+        while last < nops {
+            let seen = repl2.execute_scan(ScanOp, idx).unwrap();
+            assert!(seen >= last);
+            last = seen;
+        }
+        writer.join().unwrap();
+    }
+
+    // Tests that an operation on a subset of the logs is executed after the
+    // operations before it on each of these logs, and only touches them.
+    #[test]
+    fn test_execute_mut_scan_subset() {
+        let mut logs = vec![];
+        let nlogs = 4;
+
+        for i in 0..nlogs {
+            logs.push(Arc::new(Log::<<ScanDS as Dispatch>::WriteOperation>::new(
+                4 * 1024 * 1024,
+                i + 1,
+            )));
+        }
+
+        let repl1 = Replica::<ScanDS>::new(logs.clone());
+        let repl2 = Replica::<ScanDS>::new(logs.clone());
+        let idx1 = repl1.register().unwrap();
+        let idx2 = repl2.register().unwrap();
+
+        assert_eq!(repl2.execute_mut(WriteOp::Set(1), idx2), Ok(0));
+        assert_eq!(repl2.execute_mut(WriteOp::Set(3), idx2), Ok(1));
+        assert_eq!(repl2.execute_mut_scan(WriteOp::SetLogs(3, 1), idx2), Ok(2));
+        assert_eq!(repl2.execute_mut(WriteOp::Set(3), idx2), Ok(3));
+
+        // Log 1 is the root of the operation, log 3 only holds a leaf entry.
+        assert_eq!(repl1.execute_mut(WriteOp::Set(1), idx1), Ok(3));
+        repl1.sync(idx1);
+        for (log_id, applied) in [0, 3, 0, 3].iter().enumerate() {
+            assert_eq!(repl1.applied_offset(log_id), *applied);
+        }
+        repl1.verify(|d| assert_eq!(d.junk.load(Ordering::Relaxed), 5));
+    }
+
+    // Tests that write and scan operations go to the logs the router picks,
+    // and that the router can't be changed while a replica lags behind.
+    #[test]
+    fn test_replica_set_router() {
+        let mut logs = vec![];
+        let nlogs = 4;
+
+        for i in 0..nlogs {
+            logs.push(Arc::new(Log::<<ScanDS as Dispatch>::WriteOperation>::new(
+                4 * 1024 * 1024,
+                i + 1,
+            )));
+        }
+
+        let reverse = |key: usize, nlogs: usize| nlogs - 1 - key;
+        let mut repl1 = Replica::<ScanDS>::new(logs.clone());
+        let mut repl2 = Replica::<ScanDS>::new(logs.clone());
+        Arc::get_mut(&mut repl1)
+            .unwrap()
+            .set_router(reverse)
+            .unwrap();
+        Arc::get_mut(&mut repl2)
+            .unwrap()
+            .set_router(reverse)
+            .unwrap();
+        let idx1 = repl1.register().unwrap();
+        let idx2 = repl2.register().unwrap();
+
+        assert_eq!(repl1.execute_mut(WriteOp::Set(0), idx1), Ok(0));
+        assert_eq!(repl1.execute_mut_scan(WriteOp::SetLogs(0, 1), idx1), Ok(1));
+        assert_eq!(repl1.execute(ReadOp(0), idx1), Ok(2));
+        for (log_id, applied) in [0, 0, 1, 2].iter().enumerate() {
+            assert_eq!(repl1.applied_offset(log_id), *applied);
+        }
+
+        assert_eq!(
+            Arc::get_mut(&mut repl1).unwrap().set_router(HashRouter),
+            Err(LogError::NotQuiescent)
+        );
+        repl2.sync(idx2);
+        repl2.verify(|d| assert_eq!(d.junk.load(Ordering::Relaxed), 2));
+        assert_eq!(repl2.execute_scan(ScanOp, idx2), Ok(2));
+    }
+
+    // Tests that concurrent operations on overlapping subsets of the logs
+    // (with different root logs) don't deadlock.
+    #[test]
+    fn test_execute_mut_scan_overlapping() {
+        let mut logs = vec![];
+        let nlogs = 3;
+        let nops = 500;
+
+        for i in 0..nlogs {
+            logs.push(Arc::new(Log::<<ScanDS as Dispatch>::WriteOperation>::new(
+                4 * 1024 * 1024,
+                i + 1,
+            )));
+        }
+
+        let repl1 = Replica::<ScanDS>::new(logs.clone());
+        let repl2 = Replica::<ScanDS>::new(logs.clone());
+
+        let mut threads = Vec::new();
+        for (i, op) in [
+            WriteOp::SetLogs(0, 2),
+            WriteOp::SetLogs(1, 2),
+            WriteOp::SetScan(0),
+        ]
+        .iter()
+        .enumerate()
+        {
+            for r in [repl1.clone(), repl2.clone()].iter() {
+                let (r, op) = (r.clone(), *op);
+                threads.push(thread::spawn(move || {
+                    let idx = r.register().unwrap();
+                    for j in 0..nops {
+                        r.execute_mut_scan(op, idx).unwrap();
+                        r.execute_mut(WriteOp::Set(i + j), idx).unwrap();
+                    }
+                }));
+            }
+        }
+
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+
+        let idx = repl1.register().unwrap();
+        repl1.sync(idx);
+        repl1.verify(|d| assert_eq!(d.junk.load(Ordering::Relaxed), 12 * nops));
     }
 
     #[test]
@@ -1783,4 +3015,61 @@ mod test {
         );
         assert_eq!(Ok(0), repl.get_response(idx.id(), hash));
     }
+
+    // A data structure whose write operations panic on `OpWr(13)`.
+    #[derive(Default)]
+    struct Fragile {
+        junk: AtomicUsize,
+    }
+
+    impl Dispatch for Fragile {
+        type ReadOperation = OpRd;
+        type WriteOperation = OpWr;
+        type ScanOperation = OpRd;
+        type Response = Result<usize, ()>;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            Ok(self.junk.load(Ordering::Relaxed))
+        }
+
+        fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+            assert_ne!(op.0, 13, "unlucky operation");
+            Ok(self.junk.fetch_add(1, Ordering::Relaxed))
+        }
+
+        fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+            self.dispatch(op)
+        }
+    }
+
+    // Tests that a panic while combining releases the combiner lock and poisons
+    // the replica, so that threads waiting for a response and later operations
+    // panic instead of hanging.
+    #[test]
+    fn test_replica_poisoned() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let slog = Arc::new(Log::<<Fragile as Dispatch>::WriteOperation>::new(1024, 1));
+        let repl = Replica::<Fragile>::new(vec![slog]);
+        let idx1 = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(OpWr(1), idx1), Ok(0));
+        assert_eq!(repl.poisoned(), Ok(()));
+
+        // `idx2` waits for the combiner, which collects the operation of `idx1`
+        // first and panics on it.
+        assert!(repl.make_pending(OpWr(1), idx2.0, 0, false, false));
+        let r = catch_unwind(AssertUnwindSafe(|| repl.execute_mut(OpWr(13), idx1)));
+        assert!(r.is_err());
+        assert_eq!(repl.logstate[0].combiner.load(Ordering::Relaxed), 0);
+        assert_eq!(repl.poisoned(), Err(ReplicaPoisoned));
+
+        let r = catch_unwind(AssertUnwindSafe(|| repl.get_response(idx2.0, 0)));
+        assert!(r.is_err());
+        let r = catch_unwind(AssertUnwindSafe(|| repl.execute(OpRd(0), idx2)));
+        #[cfg(feature = "std")]
+        assert!(r.unwrap_err().is::<ReplicaPoisoned>());
+        #[cfg(not(feature = "std"))]
+        assert!(r.is_err());
+    }
 }
@@ -0,0 +1,90 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Routing of operations to logs. [LogMapper](../trait.LogMapper.html) turns an
+//! operation into one or more keys, and the `LogRouter` of a
+//! [Replica](../struct.Replica.html) decides which log each key goes to.
+
+/// Maps the keys that [LogMapper](../trait.LogMapper.html) returns for an
+/// operation to logs. Installed with `Replica::set_router`; every replica of
+/// the same logs has to use the same router, otherwise conflicting operations
+/// could end up on different logs.
+///
+/// Closures `Fn(key, nlogs) -> log` are routers too.
+pub trait LogRouter: Send + Sync {
+    /// Returns the log that `key` goes to when there are `nlogs` logs. Must be
+    /// smaller than `nlogs`, and must always be the same for the same key.
+    fn route(&self, key: usize, nlogs: usize) -> usize;
+}
+
+impl<F> LogRouter for F
+where
+    F: Fn(usize, usize) -> usize + Send + Sync,
+{
+    fn route(&self, key: usize, nlogs: usize) -> usize {
+        self(key, nlogs)
+    }
+}
+
+/// Routes a key to the log `key % nlogs`. The default router of a replica.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HashRouter;
+
+impl LogRouter for HashRouter {
+    fn route(&self, key: usize, nlogs: usize) -> usize {
+        key % nlogs
+    }
+}
+
+/// Splits the keys `0..keys` into one contiguous range per log, so that
+/// neighboring keys share a log (e.g., to keep a range scan on few logs).
+/// Keys beyond the last range go to the last log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RangeRouter {
+    keys: usize,
+}
+
+impl RangeRouter {
+    /// Creates a router for the keys `0..keys`.
+    ///
+    /// # Panics
+    /// If `keys` is zero.
+    pub fn new(keys: usize) -> RangeRouter {
+        assert!(keys > 0, "A RangeRouter needs at least one key.");
+        RangeRouter { keys }
+    }
+}
+
+impl LogRouter for RangeRouter {
+    fn route(&self, key: usize, nlogs: usize) -> usize {
+        if key >= self.keys {
+            return nlogs - 1;
+        }
+        (key as u128 * nlogs as u128 / self.keys as u128) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that the range router hands out contiguous, equally sized ranges.
+    #[test]
+    fn test_router_range() {
+        let router = RangeRouter::new(100);
+        assert_eq!(router.route(0, 4), 0);
+        assert_eq!(router.route(24, 4), 0);
+        assert_eq!(router.route(25, 4), 1);
+        assert_eq!(router.route(99, 4), 3);
+        assert_eq!(router.route(usize::MAX, 4), 3);
+        assert_eq!(router.route(50, 1), 0);
+    }
+
+    // Tests that the hash router and closures route as expected.
+    #[test]
+    fn test_router_hash_and_closure() {
+        assert_eq!(HashRouter.route(7, 4), 3);
+        let reverse = |key: usize, nlogs: usize| nlogs - 1 - key % nlogs;
+        assert_eq!(reverse.route(0, 4), 3);
+    }
+}
@@ -62,6 +62,7 @@ impl Default for CNRHashmap {
 impl Dispatch for CNRHashmap {
     type ReadOperation = OpRd;
     type WriteOperation = OpWr;
+    type ScanOperation = OpRd;
     type Response = Option<usize>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -76,6 +77,10 @@ impl Dispatch for CNRHashmap {
             OpWr::PutScan(key, val) => self.hashmap.insert(key, val),
         }
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 fn setup(nlogs: usize, nreplicas: usize, nops: usize, nthreads: usize) {
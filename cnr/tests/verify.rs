@@ -113,6 +113,7 @@ impl Default for CNRHashmap {
 impl Dispatch for CNRHashmap {
     type ReadOperation = OpRd;
     type WriteOperation = OpWr;
+    type ScanOperation = OpRd;
     type Response = Option<usize>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
@@ -127,6 +128,10 @@ impl Dispatch for CNRHashmap {
             OpWr::PutScan(key, val) => self.insert_scan(key, val),
         }
     }
+
+    fn dispatch_scan(&self, op: Self::ScanOperation) -> Self::Response {
+        self.dispatch(op)
+    }
 }
 
 #[test]
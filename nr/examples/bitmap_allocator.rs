@@ -0,0 +1,211 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A replicated free-block bitmap, as used by file systems and kernels to hand
+//! out disk blocks or physical frames. Threads allocate and free blocks in
+//! bursts, which makes for an allocation-heavy workload that relies on
+//! batching; the example doubles as a benchmark.
+//!
+//! Run with `--features stats` to also print the sizing advice of the log.
+use std::sync::Arc;
+use std::time::Instant;
+
+use node_replication::Dispatch;
+use node_replication::Log;
+use node_replication::Replica;
+
+/// Number of blocks managed by the bitmap.
+const BLOCKS: u64 = 1 << 16;
+
+/// Number of threads per replica.
+const THREADS: usize = 4;
+
+/// Number of blocks a thread allocates (and then frees again) in one burst.
+const BURST: usize = 64;
+
+/// Number of bursts issued by every thread.
+const ROUNDS: usize = 1000;
+
+/// A bitmap with one bit per block; a set bit marks an allocated block.
+struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Bitmap {
+            words: vec![0; (BLOCKS / 64) as usize],
+        }
+    }
+}
+
+impl Bitmap {
+    fn is_free(&self, block: u64) -> bool {
+        self.words[(block / 64) as usize] & (1 << (block % 64)) == 0
+    }
+
+    fn set(&mut self, block: u64, allocated: bool) {
+        let word = &mut self.words[(block / 64) as usize];
+        if allocated {
+            *word |= 1 << (block % 64);
+        } else {
+            *word &= !(1 << (block % 64));
+        }
+    }
+}
+
+/// Operations that change the bitmap.
+#[derive(Clone, Debug, PartialEq)]
+enum Modify {
+    /// Allocates the free block with the lowest number.
+    Alloc,
+    /// Allocates the given block if it is free.
+    AllocAt(u64),
+    /// Frees the given block.
+    Free(u64),
+}
+
+/// Operations that only read the bitmap.
+#[derive(Clone, Debug, PartialEq)]
+enum Access {
+    /// Returns the block if it is free.
+    IsFree(u64),
+    /// Returns the number of free blocks.
+    FreeBlocks,
+}
+
+impl Dispatch for Bitmap {
+    type ReadOperation = Access;
+    type WriteOperation = Modify;
+    type Response = Option<u64>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            Access::IsFree(block) => Some(block).filter(|b| self.is_free(*b)),
+            Access::FreeBlocks => Some(
+                self.words
+                    .iter()
+                    .map(|w| w.count_zeros() as u64)
+                    .sum::<u64>(),
+            ),
+        }
+    }
+
+    /// Every replica executes the operations in the same order, so picking the
+    /// lowest free block hands out the same block on all of them.
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            Modify::Alloc => {
+                let (i, word) = self
+                    .words
+                    .iter()
+                    .enumerate()
+                    .find(|(_i, w)| **w != u64::MAX)?;
+                let block = i as u64 * 64 + word.trailing_ones() as u64;
+                self.set(block, true);
+                Some(block)
+            }
+            Modify::AllocAt(block) => {
+                if !self.is_free(block) {
+                    return None;
+                }
+                self.set(block, true);
+                Some(block)
+            }
+            Modify::Free(block) => {
+                if self.is_free(block) {
+                    return None;
+                }
+                self.set(block, false);
+                Some(block)
+            }
+        }
+    }
+}
+
+/// Allocates and frees blocks in bursts on one thread of `replica`.
+fn worker(replica: Arc<Replica<Bitmap>>, tid: usize) {
+    let idx = replica.register().expect("Unable to register with replica");
+    let allocs = vec![Modify::Alloc; BURST];
+
+    for round in 0..ROUNDS {
+        let blocks: Vec<u64> = replica
+            .execute_mut_batch(&allocs, idx)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.expect("Ran out of blocks"))
+            .collect();
+
+        // Claims a block of its own if nobody holds it, without appending an
+        // operation to the log if it is taken.
+        let wanted = ((tid * ROUNDS + round) as u64 * 7919) % BLOCKS;
+        let claimed = replica
+            .execute_rmw(
+                Access::IsFree(wanted),
+                |free| free.map(Modify::AllocAt),
+                idx,
+            )
+            .unwrap()
+            .flatten();
+
+        let frees: Vec<Modify> = blocks
+            .iter()
+            .chain(claimed.iter())
+            .map(|b| Modify::Free(*b))
+            .collect();
+        for freed in replica.execute_mut_batch(&frees, idx).unwrap() {
+            assert!(freed.is_some(), "Freed a block that wasn't allocated");
+        }
+    }
+}
+
+/// Runs the workload on two replicas of the bitmap and prints throughput and
+/// combining statistics.
+fn main() {
+    let log = Arc::new(Log::<<Bitmap as Dispatch>::WriteOperation>::new(
+        2 * 1024 * 1024,
+    ));
+    let replicas = [Replica::<Bitmap>::new(&log), Replica::<Bitmap>::new(&log)];
+
+    let start = Instant::now();
+    let mut threads = Vec::with_capacity(replicas.len() * THREADS);
+    for (r, replica) in replicas.iter().enumerate() {
+        for t in 0..THREADS {
+            let replica = replica.clone();
+            threads.push(std::thread::spawn(move || worker(replica, r * THREADS + t)));
+        }
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    // Every block was freed again, and both replicas agree on that.
+    for replica in replicas.iter() {
+        let idx = replica.register().expect("Unable to register with replica");
+        assert_eq!(replica.execute(Access::FreeBlocks, idx), Ok(Some(BLOCKS)));
+    }
+
+    // Allocations, frees and a read-modify-write per burst (the block it
+    // claims may have been taken, so this is an upper bound).
+    let ops = replicas.len() * THREADS * ROUNDS * (2 * BURST + 2);
+    println!(
+        "up to {} operations in {:?} ({:.0} ops/s)",
+        ops,
+        elapsed,
+        ops as f64 / elapsed.as_secs_f64()
+    );
+    for (r, replica) in replicas.iter().enumerate() {
+        let metrics = replica.metrics();
+        println!(
+            "replica {}: {} combines, {:.1} operations per combine, {} gc stalls",
+            r,
+            metrics.combines,
+            metrics.combined_ops as f64 / metrics.combines.max(1) as f64,
+            metrics.gc_stalls
+        );
+    }
+
+    #[cfg(feature = "stats")]
+    println!("{:?}", log.advise());
+}
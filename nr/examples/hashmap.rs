@@ -67,9 +67,9 @@ fn main() {
     let thread_loop = |replica: &Arc<Replica<NrHashMap>>, ridx| {
         for i in 0..2048 {
             let _r = match i % 2 {
-                0 => replica.execute_mut(Modify::Put(i, i + 1), ridx),
+                0 => replica.execute_mut(Modify::Put(i, i + 1), ridx).unwrap(),
                 1 => {
-                    let response = replica.execute(Access::Get(i - 1), ridx);
+                    let response = replica.execute(Access::Get(i - 1), ridx).unwrap();
                     assert_eq!(response, Some(i));
                     response
                 }
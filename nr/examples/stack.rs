@@ -91,9 +91,9 @@ fn main() {
     let thread_loop = |replica: &Arc<Replica<Stack>>, ridx| {
         for i in 0..2048 {
             let _r = match i % 3 {
-                0 => replica.execute_mut(Modify::Push(i as u32), ridx),
-                1 => replica.execute_mut(Modify::Pop, ridx),
-                2 => replica.execute(Access::Peek, ridx),
+                0 => replica.execute_mut(Modify::Push(i as u32), ridx).unwrap(),
+                1 => replica.execute_mut(Modify::Pop, ridx).unwrap(),
+                2 => replica.execute(Access::Peek, ridx).unwrap(),
                 _ => unreachable!(),
             };
         }
@@ -0,0 +1,277 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Ready-made replicated versions of common collections, so that they don't
+//! need to be wrapped by hand to be used with a [Replica](../struct.Replica.html).
+//!
+//! # Example
+//!
+//! ```
+//! use node_replication::adapters::{MapRead, MapWrite, NrBTreeMap};
+//! use node_replication::{Log, Replica};
+//! use std::sync::Arc;
+//!
+//! let log = Arc::new(Log::<MapWrite<u64, u64>>::default());
+//! let replica = Replica::<NrBTreeMap<u64, u64>>::new(&log);
+//! let idx = replica.register().unwrap();
+//!
+//! replica.execute_mut(MapWrite::Insert(1, 2), idx).unwrap();
+//! assert_eq!(replica.execute(MapRead::Get(1), idx), Ok(Some(2)));
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use core::fmt::Debug;
+use core::ops::Deref;
+
+#[cfg(feature = "std")]
+use core::hash::Hash;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::Dispatch;
+
+/// Write operations on [NrHashMap](struct.NrHashMap.html) and
+/// [NrBTreeMap](struct.NrBTreeMap.html). Both return the previous value of the
+/// key, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapWrite<K, V> {
+    /// Inserts a value for a key.
+    Insert(K, V),
+    /// Removes a key.
+    Remove(K),
+}
+
+/// Read operations on [NrHashMap](struct.NrHashMap.html) and
+/// [NrBTreeMap](struct.NrBTreeMap.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapRead<K> {
+    /// Returns the value of a key, if any.
+    Get(K),
+}
+
+/// A replicated `HashMap`. Dereferences to the map, e.g., for
+/// [`Replica::verify`](../struct.Replica.html#method.verify).
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct NrHashMap<K, V> {
+    storage: HashMap<K, V>,
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Default for NrHashMap<K, V> {
+    fn default() -> Self {
+        NrHashMap {
+            storage: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Deref for NrHashMap<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Dispatch for NrHashMap<K, V>
+where
+    K: Hash + Eq + Clone + Debug + Send,
+    V: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = MapRead<K>;
+    type WriteOperation = MapWrite<K, V>;
+    type Response = Option<V>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            MapRead::Get(key) => self.storage.get(&key).cloned(),
+        }
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            MapWrite::Insert(key, value) => self.storage.insert(key, value),
+            MapWrite::Remove(key) => self.storage.remove(&key),
+        }
+    }
+}
+
+/// A replicated `BTreeMap`. Dereferences to the map.
+#[derive(Clone, Debug)]
+pub struct NrBTreeMap<K, V> {
+    storage: BTreeMap<K, V>,
+}
+
+impl<K: Ord, V> Default for NrBTreeMap<K, V> {
+    fn default() -> Self {
+        NrBTreeMap {
+            storage: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K, V> Deref for NrBTreeMap<K, V> {
+    type Target = BTreeMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+impl<K, V> Dispatch for NrBTreeMap<K, V>
+where
+    K: Ord + Clone + Debug + Send,
+    V: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = MapRead<K>;
+    type WriteOperation = MapWrite<K, V>;
+    type Response = Option<V>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            MapRead::Get(key) => self.storage.get(&key).cloned(),
+        }
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            MapWrite::Insert(key, value) => self.storage.insert(key, value),
+            MapWrite::Remove(key) => self.storage.remove(&key),
+        }
+    }
+}
+
+/// Write operations on [NrVec](struct.NrVec.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum VecWrite<T> {
+    /// Appends an element; returns `None`.
+    Push(T),
+    /// Removes the last element and returns it.
+    Pop,
+    /// Replaces the element at an index and returns the previous one. Does
+    /// nothing and returns `None` if the index is out of bounds.
+    Set(usize, T),
+}
+
+/// Read operations on [NrVec](struct.NrVec.html).
+#[derive(Clone, Debug, PartialEq)]
+pub enum VecRead {
+    /// Returns the element at an index, if any.
+    Get(usize),
+}
+
+/// A replicated `Vec`. Dereferences to the vector.
+#[derive(Clone, Debug)]
+pub struct NrVec<T> {
+    storage: Vec<T>,
+}
+
+impl<T> Default for NrVec<T> {
+    fn default() -> Self {
+        NrVec {
+            storage: Vec::new(),
+        }
+    }
+}
+
+impl<T> Deref for NrVec<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+impl<T> Dispatch for NrVec<T>
+where
+    T: Clone + Debug + PartialEq + Send,
+{
+    type ReadOperation = VecRead;
+    type WriteOperation = VecWrite<T>;
+    type Response = Option<T>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            VecRead::Get(index) => self.storage.get(index).cloned(),
+        }
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            VecWrite::Push(value) => {
+                self.storage.push(value);
+                None
+            }
+            VecWrite::Pop => self.storage.pop(),
+            VecWrite::Set(index, value) => self
+                .storage
+                .get_mut(index)
+                .map(|slot| core::mem::replace(slot, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Log, Replica};
+    use alloc::sync::Arc;
+
+    // Tests that writes through a replicated BTreeMap are visible on all replicas.
+    #[test]
+    fn test_btreemap() {
+        let log = Arc::new(Log::<MapWrite<u64, u64>>::default());
+        let r1 = Replica::<NrBTreeMap<u64, u64>>::new(&log);
+        let r2 = Replica::<NrBTreeMap<u64, u64>>::new(&log);
+        let (i1, i2) = (r1.register().unwrap(), r2.register().unwrap());
+
+        assert_eq!(r1.execute_mut(MapWrite::Insert(1, 10), i1), Ok(None));
+        assert_eq!(r2.execute_mut(MapWrite::Insert(1, 11), i2), Ok(Some(10)));
+        assert_eq!(r1.execute(MapRead::Get(1), i1), Ok(Some(11)));
+        assert_eq!(r1.execute_mut(MapWrite::Remove(1), i1), Ok(Some(11)));
+        assert_eq!(r2.execute(MapRead::Get(1), i2), Ok(None));
+        r1.verify(|map| assert!(map.is_empty()));
+    }
+
+    // Tests that a replicated HashMap returns the latest value of every key.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_hashmap() {
+        let log = Arc::new(Log::<MapWrite<u64, u64>>::default());
+        let replica = Replica::<NrHashMap<u64, u64>>::new(&log);
+        let idx = replica.register().unwrap();
+
+        for key in 0..100 {
+            assert_eq!(
+                replica.execute_mut(MapWrite::Insert(key, key), idx),
+                Ok(None)
+            );
+        }
+        for key in 0..100 {
+            assert_eq!(replica.execute(MapRead::Get(key), idx), Ok(Some(key)));
+        }
+        replica.verify(|map| assert_eq!(map.len(), 100));
+    }
+
+    // Tests the operations of a replicated Vec.
+    #[test]
+    fn test_vec() {
+        let log = Arc::new(Log::<VecWrite<u64>>::default());
+        let replica = Replica::<NrVec<u64>>::new(&log);
+        let idx = replica.register().unwrap();
+
+        assert_eq!(replica.execute_mut(VecWrite::Push(1), idx), Ok(None));
+        assert_eq!(replica.execute_mut(VecWrite::Push(2), idx), Ok(None));
+        assert_eq!(replica.execute_mut(VecWrite::Set(0, 3), idx), Ok(Some(1)));
+        assert_eq!(replica.execute_mut(VecWrite::Set(5, 3), idx), Ok(None));
+        assert_eq!(replica.execute(VecRead::Get(0), idx), Ok(Some(3)));
+        assert_eq!(replica.execute_mut(VecWrite::Pop, idx), Ok(Some(2)));
+        assert_eq!(replica.execute(VecRead::Get(1), idx), Ok(None));
+        replica.verify(|vec| assert_eq!(**vec, [3]));
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recommends a size for the log based on how it is used during the first
+//! minutes of operation. Requires the `stats` feature.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use std::time::Instant;
+
+use crate::ids::ReplicaId;
+
+/// How long the log observes appends after it was created (or grew) before
+/// its advice is final.
+pub(crate) const WARMUP: Duration = Duration::from_secs(120);
+
+/// Fraction of appends that may wait for garbage collection before the log
+/// counts as too small.
+const STALL_RATIO: f64 = 0.01;
+
+/// Fraction of stalls that have to wait for the same replica before that
+/// replica, rather than the size of the log, counts as the bottleneck.
+const SLOW_REPLICA_RATIO: f64 = 0.9;
+
+/// Fraction of appends that may lose the race for the tail of the log before
+/// the log counts as contended.
+const RETRY_RATIO: f64 = 0.1;
+
+/// What holds up appends to the log, according to `Log::advise`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Bottleneck {
+    /// Appends rarely wait; the log is fine as it is.
+    None,
+
+    /// Appends often wait for garbage collection, with different replicas
+    /// being the furthest behind. A larger log absorbs the bursts.
+    LogSize,
+
+    /// Appends often wait for garbage collection, almost always for the same
+    /// replica. Growing the log only delays the stalls; the replica needs more
+    /// threads executing operations (or `Replica::sync` calls) instead.
+    SlowReplica,
+
+    /// Replicas often race each other to append to the log. More logs (e.g.,
+    /// with CNR) spread the appends.
+    Contention,
+}
+
+/// A recommendation for the size of a log, returned by `Log::advise`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SizingAdvice {
+    /// The size of the log in bytes to use, e.g., with `NodeReplicated::grow_log`.
+    /// The current size unless the bottleneck is `Bottleneck::LogSize`.
+    pub suggested_bytes: usize,
+
+    /// The number of logs to spread operations over. One unless the
+    /// bottleneck is `Bottleneck::Contention`.
+    pub suggested_logs: usize,
+
+    /// What holds up appends the most.
+    pub bottleneck: Bottleneck,
+
+    /// Operations appended per second during the warm-up window.
+    pub append_rate: f64,
+
+    /// Times per second the head of the log moved during the warm-up window.
+    pub gc_rate: f64,
+
+    /// The most entries that were on the log at once, i.e., how far the
+    /// slowest replica fell behind the tail.
+    pub max_lag: usize,
+
+    /// Whether the warm-up window is over. Until it is, the advice is based on
+    /// fewer observations and may still change.
+    pub complete: bool,
+}
+
+/// Counters a log maintains during the warm-up window.
+pub(crate) struct SizingStats {
+    /// When the log was created.
+    created: Instant,
+
+    /// When the window started, in nanoseconds since `created`.
+    started: AtomicU64,
+
+    /// Set once the window is over; nothing is counted afterwards.
+    done: AtomicBool,
+
+    /// Number of successful appends.
+    appends: AtomicU64,
+
+    /// Number of operations appended.
+    entries: AtomicU64,
+
+    /// Number of appends that lost the race for the tail and retried.
+    retries: AtomicU64,
+
+    /// Number of appends that waited for garbage collection.
+    stalls: AtomicU64,
+
+    /// Number of stalls that waited for the same replica as the one before.
+    repeats: AtomicU64,
+
+    /// The replica the last stall waited for.
+    slowest: AtomicUsize,
+
+    /// Number of times the head moved.
+    gcs: AtomicU64,
+
+    /// The most entries that were on the log at once.
+    max_lag: AtomicUsize,
+}
+
+impl SizingStats {
+    pub(crate) fn new() -> SizingStats {
+        SizingStats {
+            created: Instant::now(),
+            started: AtomicU64::new(0),
+            done: AtomicBool::new(false),
+            appends: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            repeats: AtomicU64::new(0),
+            slowest: AtomicUsize::new(0),
+            gcs: AtomicU64::new(0),
+            max_lag: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the time that passed since the window started, capped at the
+    /// length of the window.
+    fn elapsed(&self) -> Duration {
+        let started = Duration::from_nanos(self.started.load(Ordering::Relaxed));
+        self.created
+            .elapsed()
+            .checked_sub(started)
+            .unwrap_or_default()
+            .min(WARMUP)
+    }
+
+    /// Returns true while the window is open.
+    #[inline(always)]
+    fn observing(&self) -> bool {
+        if self.done.load(Ordering::Relaxed) {
+            return false;
+        }
+        if self.elapsed() >= WARMUP {
+            self.done.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Forgets everything observed and opens a new window, e.g., because the
+    /// log grew.
+    pub(crate) fn restart(&self) {
+        let now = self.created.elapsed().as_nanos() as u64;
+        self.started.store(now, Ordering::Relaxed);
+        for counter in [
+            &self.appends,
+            &self.entries,
+            &self.retries,
+            &self.stalls,
+            &self.repeats,
+            &self.gcs,
+        ]
+        .iter()
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.slowest.store(0, Ordering::Relaxed);
+        self.max_lag.store(0, Ordering::Relaxed);
+        self.done.store(false, Ordering::Relaxed);
+    }
+
+    /// `nops` operations were appended, leaving `lag` entries on the log.
+    #[inline(always)]
+    pub(crate) fn on_append(&self, nops: usize, lag: usize) {
+        if self.observing() {
+            self.appends.fetch_add(1, Ordering::Relaxed);
+            self.entries.fetch_add(nops as u64, Ordering::Relaxed);
+            self.max_lag.fetch_max(lag, Ordering::Relaxed);
+        }
+    }
+
+    /// An append lost the race for the tail.
+    #[inline(always)]
+    pub(crate) fn on_retry(&self) {
+        if self.observing() {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// An append started waiting for `slowest` to free up entries.
+    #[inline(always)]
+    pub(crate) fn on_stall(&self, slowest: ReplicaId) {
+        if self.observing() {
+            self.stalls.fetch_add(1, Ordering::Relaxed);
+            if self.slowest.swap(slowest.get(), Ordering::Relaxed) == slowest.get() {
+                self.repeats.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The head of the log moved.
+    #[inline(always)]
+    pub(crate) fn on_gc(&self) {
+        if self.observing() {
+            self.gcs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Advises on the size of a log of `bytes` bytes, with entries of
+    /// `entry_size` bytes, that `replicas` replicas use.
+    pub(crate) fn advise(&self, bytes: usize, entry_size: usize, replicas: usize) -> SizingAdvice {
+        let complete = !self.observing();
+        let secs = self.elapsed().as_secs_f64().max(f64::EPSILON);
+
+        let appends = self.appends.load(Ordering::Relaxed);
+        let stalls = self.stalls.load(Ordering::Relaxed);
+        let repeats = self.repeats.load(Ordering::Relaxed);
+        let retries = self.retries.load(Ordering::Relaxed);
+        let max_lag = self.max_lag.load(Ordering::Relaxed);
+
+        let mut advice = SizingAdvice {
+            suggested_bytes: bytes,
+            suggested_logs: 1,
+            bottleneck: Bottleneck::None,
+            append_rate: self.entries.load(Ordering::Relaxed) as f64 / secs,
+            gc_rate: self.gcs.load(Ordering::Relaxed) as f64 / secs,
+            max_lag,
+            complete,
+        };
+        if appends == 0 {
+            return advice;
+        }
+
+        // The first stall has no replica before it to compare with.
+        if stalls as f64 > STALL_RATIO * appends as f64 {
+            if replicas > 1 && repeats as f64 >= SLOW_REPLICA_RATIO * (stalls - 1) as f64 {
+                advice.bottleneck = Bottleneck::SlowReplica;
+            } else {
+                // Leave room for twice the lag that filled the log, so the
+                // next burst of that size doesn't stall.
+                let needed = max_lag.saturating_mul(2).saturating_mul(entry_size);
+                advice.bottleneck = Bottleneck::LogSize;
+                advice.suggested_bytes = needed
+                    .max(bytes.saturating_mul(2))
+                    .checked_next_power_of_two()
+                    .unwrap_or(usize::MAX);
+            }
+        } else if retries as f64 > RETRY_RATIO * appends as f64 {
+            advice.bottleneck = Bottleneck::Contention;
+            advice.suggested_logs = replicas.max(2);
+        }
+
+        advice
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that a log without stalls or retries is left as it is.
+    #[test]
+    fn test_advisor_none() {
+        let stats = SizingStats::new();
+        assert_eq!(stats.advise(1024, 64, 2).bottleneck, Bottleneck::None);
+
+        stats.on_append(4, 4);
+        let advice = stats.advise(1024, 64, 2);
+        assert_eq!(advice.bottleneck, Bottleneck::None);
+        assert_eq!(advice.suggested_bytes, 1024);
+        assert_eq!(advice.suggested_logs, 1);
+        assert_eq!(advice.max_lag, 4);
+        assert!(!advice.complete);
+    }
+
+    // Tests that stalls waiting for different replicas grow the log, and
+    // stalls that always wait for the same replica blame that replica.
+    #[test]
+    fn test_advisor_stalls() {
+        let stats = SizingStats::new();
+        for i in 0..10 {
+            stats.on_append(1, 32);
+            stats.on_stall(ReplicaId::new(1 + i % 2));
+        }
+        let advice = stats.advise(1024, 64, 2);
+        assert_eq!(advice.bottleneck, Bottleneck::LogSize);
+        assert_eq!(advice.suggested_bytes, 4096);
+
+        stats.restart();
+        for _i in 0..10 {
+            stats.on_append(1, 32);
+            stats.on_stall(ReplicaId::new(2));
+        }
+        let advice = stats.advise(1024, 64, 2);
+        assert_eq!(advice.bottleneck, Bottleneck::SlowReplica);
+        assert_eq!(advice.suggested_bytes, 1024);
+    }
+
+    // Tests that frequent retries suggest more logs.
+    #[test]
+    fn test_advisor_contention() {
+        let stats = SizingStats::new();
+        for _i in 0..10 {
+            stats.on_retry();
+            stats.on_append(1, 1);
+        }
+        let advice = stats.advise(1024, 64, 4);
+        assert_eq!(advice.bottleneck, Bottleneck::Contention);
+        assert_eq!(advice.suggested_logs, 4);
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Debugging aid to catch threads that combine on a replica of another NUMA
+//! node, e.g., because cores were mapped to the wrong replica during bring-up.
+
+use core::mem::transmute;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The hook installed with `set_current_node`, as an address. Zero if none is
+/// installed.
+static CURRENT_NODE: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs `f` as the function that returns the NUMA node of the core the
+/// calling thread runs on. In debug builds, replicas that were assigned a node
+/// with `Replica::set_node` then assert that they only ever combine (and hence
+/// execute the log) on that node.
+///
+/// The hook is global and can be replaced at any time.
+pub fn set_current_node(f: fn() -> usize) {
+    CURRENT_NODE.store(f as usize, Ordering::Release);
+}
+
+/// Returns the node of the current core, if a hook is installed.
+pub(crate) fn current_node() -> Option<usize> {
+    match CURRENT_NODE.load(Ordering::Acquire) {
+        0 => None,
+        // The address was stored by `set_current_node` from a `fn() -> usize`.
+        f => Some(unsafe { transmute::<usize, fn() -> usize>(f) }()),
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A common interface for replicas, so that tooling works with any of them.
+
+use crate::log::DeltaCodec;
+use crate::replica::{Replica, ReplicaError, ReplicaToken};
+use crate::Dispatch;
+
+/// The operations that threads run against a replica of `D`. Implemented by
+/// `Replica` and `NodeReplicated`, and by replicas that users write themselves
+/// (e.g., one that partitions `D` over several replicas), so that code which
+/// drives replicas, like a benchmark harness, works with any of them.
+pub trait ReplicaApi<D>
+where
+    D: Sized + Dispatch,
+{
+    /// Identifies a thread registered with the replica.
+    type Token;
+
+    /// Registers the calling thread. Returns `None` if the replica has no room
+    /// for another thread.
+    fn register(&self) -> Option<Self::Token>;
+
+    /// Executes the write operation `op` and returns its response.
+    fn exec(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        token: &Self::Token,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError>;
+
+    /// Executes the read-only operation `op` and returns its response.
+    fn exec_ro(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        token: &Self::Token,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError>;
+
+    /// Executes the operations that other replicas appended to the log, e.g.,
+    /// because the thread won't execute operations for a while.
+    fn sync(&self, token: &Self::Token) -> Result<(), ReplicaError>;
+}
+
+impl<'a, D, C> ReplicaApi<D> for Replica<'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    type Token = ReplicaToken;
+
+    fn register(&self) -> Option<ReplicaToken> {
+        Replica::register(self)
+    }
+
+    fn exec(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        token: &ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.execute_mut(op, *token)
+    }
+
+    fn exec_ro(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        token: &ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.execute(op, *token)
+    }
+
+    fn sync(&self, token: &ReplicaToken) -> Result<(), ReplicaError> {
+        Replica::sync(self, *token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use crate::log::Log;
+    use alloc::sync::Arc;
+
+    /// Runs a few operations against any replica.
+    fn count<R: ReplicaApi<Counter>>(replica: &R) -> u64 {
+        let token = replica.register().unwrap();
+        replica.exec(2, &token).unwrap();
+        replica.exec(3, &token).unwrap();
+        replica.sync(&token).unwrap();
+        replica.exec_ro((), &token).unwrap()
+    }
+
+    // Tests that code generic over `ReplicaApi` drives a `Replica`.
+    #[test]
+    fn test_api_replica() {
+        let log = Arc::new(Log::<u64>::default());
+        let replica = Replica::<Counter>::new(&log);
+        assert_eq!(count(&*replica), 5);
+    }
+}
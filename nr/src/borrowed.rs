@@ -0,0 +1,147 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Read-only operations that return data borrowed from the replicated data
+//! structure instead of an owned copy.
+
+use core::fmt;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::rwlock::ReadGuard;
+use crate::{Dispatch, MAX_THREADS_PER_REPLICA};
+
+/// Implemented by data structures whose read-only operations can return a
+/// reference into the data structure, to be executed with
+/// [`Replica::execute_ref`](struct.Replica.html#method.execute_ref). Avoids
+/// cloning large values (e.g., looking up a buffer in a map), which `dispatch`
+/// has to do since its `Response` is owned.
+pub trait DispatchRef: Dispatch {
+    /// The type of data a read-only operation borrows, e.g., `[u8]` for a map
+    /// with byte buffers as values.
+    type Borrowed: ?Sized;
+
+    /// Method on the data structure that allows a read-only operation to be
+    /// executed against it, returning a reference into the data structure or
+    /// `None` if there is nothing to return (e.g., the key doesn't exist).
+    fn dispatch_ref(&self, op: Self::ReadOperation) -> Option<&Self::Borrowed>;
+}
+
+/// Data borrowed from the data structure of a replica, returned by
+/// `Replica::execute_ref`. Holds the read lock of the replica until dropped.
+///
+/// Combiners can't execute operations against the replica while this is
+/// around, so write operations issued to the replica stall. Drop it quickly,
+/// and don't issue operations against the replica from the thread holding it:
+/// they wait for the replica to catch up, which requires the lock.
+pub struct ReadRef<'a, D>
+where
+    D: Sized + DispatchRef + Sync,
+{
+    /// Keeps combiners from mutating the data structure while `data` is used.
+    _guard: ReadGuard<'a, D, MAX_THREADS_PER_REPLICA>,
+
+    /// Points into the data structure guarded by `_guard`.
+    data: NonNull<<D as DispatchRef>::Borrowed>,
+}
+
+impl<'a, D> ReadRef<'a, D>
+where
+    D: Sized + DispatchRef + Sync,
+{
+    /// Executes `op` against the data structure behind `guard`. Returns `None`
+    /// (releasing the lock) if the operation doesn't return anything.
+    pub(crate) fn new(
+        guard: ReadGuard<'a, D, MAX_THREADS_PER_REPLICA>,
+        op: <D as Dispatch>::ReadOperation,
+    ) -> Option<ReadRef<'a, D>> {
+        let data = NonNull::from(guard.dispatch_ref(op)?);
+        Some(ReadRef {
+            _guard: guard,
+            data,
+        })
+    }
+}
+
+impl<D> Deref for ReadRef<'_, D>
+where
+    D: Sized + DispatchRef + Sync,
+{
+    type Target = <D as DispatchRef>::Borrowed;
+
+    fn deref(&self) -> &Self::Target {
+        // Safe: `data` points into the data structure, which can't be mutated
+        // (or dropped) while `_guard` holds the read lock.
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<D> fmt::Debug for ReadRef<'_, D>
+where
+    D: Sized + DispatchRef + Sync,
+    <D as DispatchRef>::Borrowed: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Log, Replica};
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::collections::HashMap;
+    use std::vec;
+
+    // Map from keys to byte buffers.
+    #[derive(Default)]
+    struct Blobs(HashMap<u64, Vec<u8>>);
+
+    impl Dispatch for Blobs {
+        type ReadOperation = u64;
+        type WriteOperation = (u64, Vec<u8>);
+        type Response = Option<Vec<u8>>;
+
+        fn dispatch(&self, key: Self::ReadOperation) -> Self::Response {
+            self.0.get(&key).cloned()
+        }
+
+        fn dispatch_mut(&mut self, (key, blob): Self::WriteOperation) -> Self::Response {
+            self.0.insert(key, blob)
+        }
+    }
+
+    impl DispatchRef for Blobs {
+        type Borrowed = [u8];
+
+        fn dispatch_ref(&self, key: Self::ReadOperation) -> Option<&Self::Borrowed> {
+            self.0.get(&key).map(|blob| blob.as_slice())
+        }
+    }
+
+    // Tests that borrowed data reflects the operations executed before the read,
+    // and that the read lock is released once the data is dropped (or if there
+    // is nothing to borrow), so that writes make progress again.
+    #[test]
+    fn test_replica_execute_ref() {
+        let log = Arc::new(Log::<(u64, Vec<u8>)>::default());
+        let replica = Replica::<Blobs>::new(&log);
+        let other = Replica::<Blobs>::new(&log);
+        let idx = replica.register().unwrap();
+        let oidx = other.register().unwrap();
+
+        other.execute_mut((1, vec![1, 2, 3]), oidx).unwrap();
+        {
+            let blob = replica.execute_ref(1, idx).unwrap().unwrap();
+            assert_eq!(&*blob, &[1, 2, 3]);
+        }
+        assert!(replica.execute_ref(2, idx).unwrap().is_none());
+
+        replica.execute_mut((1, vec![4]), idx).unwrap();
+        let blob = replica.execute_ref(1, idx).unwrap().unwrap();
+        assert_eq!(&*blob, &[4]);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tokens that are tied to their replica at compile time.
+
+use core::marker::PhantomData;
+
+use crate::log::{DeltaCodec, IdentityCodec};
+use crate::replica::{Replica, ReplicaError, ReplicaToken};
+use crate::Dispatch;
+
+/// An invariant lifetime: a brand can't be coerced into another one.
+type Brand<'brand> = PhantomData<fn(&'brand ()) -> &'brand ()>;
+
+/// A replica whose tokens are branded with the lifetime `'brand`, handed out by
+/// [`Replica::with_brand`](struct.Replica.html#method.with_brand). Every call
+/// of `with_brand` makes up a new brand, so the compiler rejects a token used
+/// with another replica than the one that handed it out, instead of the
+/// replica corrupting the state of one of its threads at runtime.
+///
+/// # Example
+///
+/// ```compile_fail
+/// use node_replication::{Dispatch, Log, Replica};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::default());
+/// let (one, two) = (Replica::<Data>::new(&log), Replica::<Data>::new(&log));
+///
+/// one.with_brand(|one| {
+///     two.with_brand(|two| {
+///         let idx = one.register().unwrap();
+///         // Doesn't compile: `idx` carries the brand of `one`.
+///         two.execute_mut(1, idx)
+///     })
+/// });
+/// ```
+pub struct BrandedReplica<'r, 'a, 'brand, D, C = IdentityCodec>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// The replica that hands out the tokens.
+    replica: &'r Replica<'a, D, C>,
+
+    _brand: Brand<'brand>,
+}
+
+/// A token handed out by a [BrandedReplica](struct.BrandedReplica.html),
+/// which can only be used with that replica.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BrandedToken<'brand> {
+    /// The token of the thread on the replica.
+    token: ReplicaToken,
+
+    _brand: Brand<'brand>,
+}
+
+impl<'brand> BrandedToken<'brand> {
+    /// Returns the unbranded token, e.g., for methods of `Replica` that
+    /// `BrandedReplica` doesn't offer. Nothing stops it from being used with
+    /// another replica.
+    pub fn token(&self) -> ReplicaToken {
+        self.token
+    }
+}
+
+impl<'r, 'a, 'brand, D, C> BrandedReplica<'r, 'a, 'brand, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Brands `replica`. Only `Replica::with_brand` may call this, with a brand
+    /// no other replica has.
+    pub(crate) fn new(replica: &'r Replica<'a, D, C>) -> Self {
+        BrandedReplica {
+            replica,
+            _brand: PhantomData,
+        }
+    }
+
+    /// Returns the replica, e.g., for methods that `BrandedReplica` doesn't
+    /// offer.
+    pub fn replica(&self) -> &'r Replica<'a, D, C> {
+        self.replica
+    }
+
+    /// Same as `Replica::register`, but returns a branded token.
+    pub fn register(&self) -> Option<BrandedToken<'brand>> {
+        self.replica.register().map(|token| BrandedToken {
+            token,
+            _brand: PhantomData,
+        })
+    }
+
+    /// Same as `Replica::deregister`.
+    pub fn deregister(&self, idx: BrandedToken<'brand>) {
+        self.replica.deregister(idx.token)
+    }
+
+    /// Same as `Replica::execute_mut`.
+    pub fn execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: BrandedToken<'brand>,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.replica.execute_mut(op, idx.token)
+    }
+
+    /// Same as `Replica::execute`.
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: BrandedToken<'brand>,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.replica.execute(op, idx.token)
+    }
+
+    /// Same as `Replica::sync`.
+    pub fn sync(&self, idx: BrandedToken<'brand>) -> Result<(), ReplicaError> {
+        self.replica.sync(idx.token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use crate::log::Log;
+    use alloc::sync::Arc;
+
+    // Tests that branded tokens execute operations against their replica, and
+    // that replicas hand out the identifiers of deregistered branded tokens again.
+    #[test]
+    fn test_brand_tokens() {
+        let log = Arc::new(Log::<u64>::default());
+        let (one, two) = (Replica::<Counter>::new(&log), Replica::<Counter>::new(&log));
+
+        let id = one.with_brand(|one| {
+            let idx = one.register().unwrap();
+            assert_eq!(one.execute_mut(5, idx), Ok(5));
+            one.deregister(idx);
+            idx.token().id()
+        });
+        assert_eq!(one.register().unwrap().id(), id);
+
+        two.with_brand(|two| {
+            let idx = two.register().unwrap();
+            assert_eq!(two.sync(idx), Ok(()));
+            assert_eq!(two.execute((), idx), Ok(5));
+            assert_eq!(two.replica().execute((), idx.token()), Ok(5));
+        });
+    }
+}
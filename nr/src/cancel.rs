@@ -0,0 +1,104 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Cooperative cancellation of threads that wait on a replica or the log.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::gc::{GcContext, GcHelpPolicy, HelpAction};
+use crate::replica::ReplicaError;
+
+/// Lets one thread tell others to stop waiting on a replica, e.g., to shut
+/// down worker tasks that wait on a replica which stopped making progress.
+/// Passed to `Replica::execute_mut_cancellable`, `Replica::execute_cancellable`,
+/// `Replica::sync_cancellable` and `Log::append_cancellable`, which poll it
+/// while they wait and return `Cancelled` once it is cancelled.
+///
+/// Clones share their state: cancelling one cancels all of them. A token
+/// can't be reset.
+///
+/// The token is also a [GcHelpPolicy](trait.GcHelpPolicy.html). Installed with
+/// `Replica::set_gc_policy`, it makes combiners stop waiting for space on a
+/// full log once it is cancelled, like
+/// [ErrorOnFull](struct.ErrorOnFull.html) does right away.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that isn't cancelled.
+    pub fn new() -> CancellationToken {
+        Default::default()
+    }
+
+    /// Cancels the token, and every clone of it.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Returns true if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl GcHelpPolicy for CancellationToken {
+    fn on_log_full(&self, _ctx: GcContext) -> HelpAction {
+        if self.is_cancelled() {
+            HelpAction::Error
+        } else {
+            HelpAction::Exec
+        }
+    }
+}
+
+/// Returned by the `*_cancellable` methods of `Replica` and `Log` if their
+/// [CancellationToken](struct.CancellationToken.html) was cancelled before
+/// they completed, or if the replica failed while they waited.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Cancelled {
+    /// The token was cancelled before the call completed.
+    Requested {
+        /// Whether the operation was handed to the replica before the call
+        /// gave up. If so, it still gets executed at some point, but its
+        /// response is dropped.
+        enqueued: bool,
+    },
+
+    /// The replica can no longer execute operations.
+    Failed(ReplicaError),
+}
+
+impl From<ReplicaError> for Cancelled {
+    fn from(e: ReplicaError) -> Cancelled {
+        Cancelled::Failed(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ids::{LogOffset, ReplicaId};
+
+    // Tests that clones share the cancellation, and that the token only makes
+    // a waiting combiner give up once it is cancelled.
+    #[test]
+    fn test_cancellation_token() {
+        let ctx = GcContext {
+            replica: ReplicaId::new(1),
+            iteration: 1,
+            head: LogOffset::new(0),
+            tail: LogOffset::new(1024),
+            slowest: ReplicaId::new(2),
+        };
+
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        assert_eq!(clone.on_log_full(ctx), HelpAction::Exec);
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+        assert_eq!(clone.on_log_full(ctx), HelpAction::Error);
+    }
+}
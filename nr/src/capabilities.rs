@@ -0,0 +1,131 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reports the limits and features this crate was built with, so that code
+//! built on top of it can adapt at runtime instead of hard-coding them.
+
+use crate::context::MAX_PENDING_OPS;
+use crate::log::{Log, DEFAULT_LOG_BYTES, GC_FROM_HEAD, MAX_REPLICAS_PER_LOG};
+use crate::replica::MAX_THREADS_PER_REPLICA;
+
+/// The Cargo features this crate was built with.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Features {
+    /// `std`: functionality that needs an operating system, e.g.,
+    /// `NodeReplicated`, timeouts and the `testing` module.
+    pub std: bool,
+
+    /// `delta`: custom `DeltaCodec`s for operations on the log.
+    pub delta: bool,
+
+    /// `erased`: replicas of different data structures sharing a log.
+    pub erased: bool,
+
+    /// `export`: `Log::export` of the operations on the log.
+    pub export: bool,
+
+    /// `multi-ring`: the `MultiRingLog`.
+    pub multi_ring: bool,
+
+    /// `pmem`: logs in persistent memory.
+    pub pmem: bool,
+
+    /// `stats`: `Log::advise` recommending a size for the log.
+    pub stats: bool,
+
+    /// `strict-tokens`: tokens that panic when used on another thread.
+    pub strict_tokens: bool,
+
+    /// `no-alloc-runtime`: replicas that panic when they allocate after
+    /// they were created.
+    pub no_alloc_runtime: bool,
+}
+
+/// Limits and features of this build of the crate, returned by `capabilities`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// The most replicas that can register with one log.
+    pub max_replicas_per_log: usize,
+
+    /// The most threads that can register with one replica at the same time.
+    pub max_threads_per_replica: usize,
+
+    /// The most write operations a thread hands to the combiner of its replica
+    /// at once. A combiner appends at most `batch_size` operations per thread
+    /// of its replica to the log in one go.
+    pub batch_size: usize,
+
+    /// Size in bytes of an entry on the log for the operations that
+    /// `capabilities` was asked about.
+    pub entry_size: usize,
+
+    /// The fewest entries a log can have; smaller logs are rounded up.
+    pub min_log_entries: usize,
+
+    /// The size in bytes of a log created with `Log::default`.
+    pub default_log_bytes: usize,
+
+    /// The features this crate was built with.
+    pub features: Features,
+}
+
+/// Returns the limits and features of this build of the crate, with entries of
+/// the log holding write operations of type `T`.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{capabilities, MAX_THREADS_PER_REPLICA};
+///
+/// let caps = capabilities::<u64>();
+/// assert_eq!(caps.max_threads_per_replica, MAX_THREADS_PER_REPLICA);
+///
+/// // Size a log for 10000 operations of type `u64`.
+/// let bytes = (10_000usize.max(caps.min_log_entries) * caps.entry_size).next_power_of_two();
+/// assert!(bytes >= caps.min_log_entries * caps.entry_size);
+/// ```
+pub fn capabilities<T: Sized + Clone>() -> Capabilities {
+    Capabilities {
+        // Identifiers of replicas start at one.
+        max_replicas_per_log: MAX_REPLICAS_PER_LOG - 1,
+        max_threads_per_replica: MAX_THREADS_PER_REPLICA,
+        batch_size: MAX_PENDING_OPS,
+        entry_size: Log::<T>::entry_size(),
+        min_log_entries: 2 * GC_FROM_HEAD,
+        default_log_bytes: DEFAULT_LOG_BYTES,
+        features: Features {
+            std: cfg!(feature = "std"),
+            delta: cfg!(feature = "delta"),
+            erased: cfg!(feature = "erased"),
+            export: cfg!(feature = "export"),
+            multi_ring: cfg!(feature = "multi-ring"),
+            pmem: cfg!(feature = "pmem"),
+            stats: cfg!(feature = "stats"),
+            strict_tokens: cfg!(feature = "strict-tokens"),
+            no_alloc_runtime: cfg!(feature = "no-alloc-runtime"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+
+    // Tests that the reported limits are the ones replicas and logs enforce.
+    #[test]
+    fn test_capabilities_limits() {
+        let caps = capabilities::<u64>();
+        assert_eq!(caps.features.std, cfg!(feature = "std"));
+
+        let log = Arc::new(Log::<u64>::new(1));
+        assert_eq!(log.capacity(), caps.min_log_entries);
+        for _i in 0..caps.max_replicas_per_log {
+            assert!(log.register().is_some());
+        }
+        assert!(log.register().is_none());
+
+        let log = Log::<u64>::default();
+        assert_eq!(log.capacity() * caps.entry_size, caps.default_log_bytes);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A small table of reads that are about to be dispatched against a replica,
+//! so that threads issuing the same read at the same time can share a single
+//! dispatch (see `Replica::execute_coalesced`).
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::hash::{Hash, Hasher};
+
+use std::collections::hash_map::DefaultHasher;
+use std::sync::{Condvar, Mutex};
+
+use crate::replica::ReplicaError;
+use crate::Dispatch;
+
+/// Number of buckets in the table. Reads that hash to different buckets don't
+/// contend on the same lock.
+const BUCKETS: usize = 16;
+
+/// What a read returned; shared with every thread that waited for it.
+type Outcome<D> = Result<<D as Dispatch>::Response, ReplicaError>;
+
+/// A read that one thread (the leader) dispatches on behalf of the threads
+/// that issued the same read while it was in the table.
+pub(crate) struct Pending<D: Dispatch> {
+    op: <D as Dispatch>::ReadOperation,
+
+    /// `None` until the leader is done. `Some(None)` if the leader gave up
+    /// without a response, in which case the waiters dispatch the read
+    /// themselves.
+    outcome: Mutex<Option<Option<Outcome<D>>>>,
+
+    /// Signalled once `outcome` is set.
+    done: Condvar,
+}
+
+impl<D: Dispatch> Pending<D> {
+    /// Blocks until the leader is done with the read. Returns `None` if it gave
+    /// up without a response.
+    pub(crate) fn wait(&self) -> Option<Outcome<D>> {
+        let mut outcome = self.outcome.lock().unwrap();
+        while outcome.is_none() {
+            outcome = self.done.wait(outcome).unwrap();
+        }
+        outcome.as_ref().unwrap().clone()
+    }
+
+    /// Hands `outcome` to the waiters and wakes them up.
+    fn complete(&self, outcome: Option<Outcome<D>>) {
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.done.notify_all();
+    }
+}
+
+/// Whether the thread dispatches the read or waits for another thread to.
+pub(crate) enum Turn<'t, D: Dispatch> {
+    Lead(Leader<'t, D>),
+    Wait(Arc<Pending<D>>),
+}
+
+/// Held by the thread that dispatches a read. Dropping it without calling
+/// `finish()` (e.g., because the dispatch panicked) lets the waiters dispatch
+/// the read themselves.
+pub(crate) struct Leader<'t, D: Dispatch> {
+    table: &'t Coalescer<D>,
+    bucket: usize,
+    pending: Arc<Pending<D>>,
+    open: bool,
+    finished: bool,
+}
+
+impl<'t, D: Dispatch> Leader<'t, D> {
+    /// Removes the read from the table; threads issuing it from now on won't
+    /// wait for this leader anymore.
+    pub(crate) fn close(&mut self) {
+        if self.open {
+            let mut bucket = self.table.buckets[self.bucket].lock().unwrap();
+            bucket.retain(|p| !Arc::ptr_eq(p, &self.pending));
+            self.open = false;
+        }
+    }
+
+    /// Closes the read and hands `outcome` to the threads that waited for it.
+    pub(crate) fn finish(mut self, outcome: &Outcome<D>) {
+        self.close();
+        // The table no longer hands out the read, so nobody else can start
+        // waiting for it.
+        if Arc::strong_count(&self.pending) > 1 {
+            self.pending.complete(Some(outcome.clone()));
+        }
+        self.finished = true;
+    }
+}
+
+impl<'t, D: Dispatch> Drop for Leader<'t, D> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.close();
+            self.pending.complete(None);
+        }
+    }
+}
+
+/// Reads in flight on a replica, bucketed by their hash.
+pub(crate) struct Coalescer<D: Dispatch> {
+    buckets: [Mutex<Vec<Arc<Pending<D>>>>; BUCKETS],
+}
+
+/// Entries are only added by `join()`, which requires operations and responses
+/// that can be shared with other threads; the table stays empty otherwise.
+unsafe impl<D: Dispatch> Send for Coalescer<D> {}
+unsafe impl<D: Dispatch> Sync for Coalescer<D> {}
+
+impl<D: Dispatch> Default for Coalescer<D> {
+    fn default() -> Self {
+        Coalescer {
+            buckets: Default::default(),
+        }
+    }
+}
+
+impl<D: Dispatch> Coalescer<D>
+where
+    <D as Dispatch>::ReadOperation: Hash + Eq + Send + Sync,
+    <D as Dispatch>::Response: Send,
+{
+    /// Waits for an identical read that is in the table already, or adds `op`
+    /// to the table for the calling thread to dispatch.
+    pub(crate) fn join(&self, op: &<D as Dispatch>::ReadOperation) -> Turn<'_, D> {
+        let mut hasher = DefaultHasher::new();
+        op.hash(&mut hasher);
+        let b = hasher.finish() as usize % BUCKETS;
+
+        let mut bucket = self.buckets[b].lock().unwrap();
+        if let Some(pending) = bucket.iter().find(|p| p.op == *op) {
+            return Turn::Wait(pending.clone());
+        }
+
+        let pending = Arc::new(Pending {
+            op: op.clone(),
+            outcome: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        bucket.push(pending.clone());
+        Turn::Lead(Leader {
+            table: self,
+            bucket: b,
+            pending,
+            open: true,
+            finished: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Data;
+
+    impl Dispatch for Data {
+        type ReadOperation = u64;
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+            op
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            op
+        }
+    }
+
+    fn lead(turn: Turn<Data>) -> Leader<Data> {
+        match turn {
+            Turn::Lead(leader) => leader,
+            Turn::Wait(_) => panic!("Expected to lead the read."),
+        }
+    }
+
+    fn wait(turn: Turn<Data>) -> Arc<Pending<Data>> {
+        match turn {
+            Turn::Lead(_) => panic!("Expected to wait for the read."),
+            Turn::Wait(pending) => pending,
+        }
+    }
+
+    // Tests that identical reads wait for the leader until it closes the read and
+    // receive its response, while other reads get their own leader.
+    #[test]
+    fn test_coalescer_join() {
+        let c = Coalescer::<Data>::default();
+        let mut leader = lead(c.join(&1));
+        let waiter = wait(c.join(&1));
+        let other = lead(c.join(&2));
+
+        leader.close();
+        let late = lead(c.join(&1));
+
+        leader.finish(&Ok(7));
+        assert_eq!(waiter.wait(), Some(Ok(7)));
+        drop((other, late));
+    }
+
+    // Tests that waiters dispatch the read themselves if the leader gives up.
+    #[test]
+    fn test_coalescer_abandoned() {
+        let c = Coalescer::<Data>::default();
+        let leader = lead(c.join(&1));
+        let waiter = wait(c.join(&1));
+
+        drop(leader);
+        assert_eq!(waiter.wait(), None);
+        let _leader = lead(c.join(&1));
+    }
+}
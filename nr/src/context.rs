@@ -4,14 +4,38 @@
 use alloc::vec::Vec;
 use core::cell::Cell;
 use core::default::Default;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use core::task::Waker;
 
 use crossbeam_utils::CachePadded;
 
+use crate::ids::LogOffset;
+
+#[cfg(feature = "std")]
+use crate::ratelimit::TokenBucket;
+
 /// The maximum number of operations that can be batched inside this context.
 /// NOTE: This constant must be a power of two for index() to work.
 pub(crate) const MAX_PENDING_OPS: usize = 32;
 const_assert!(MAX_PENDING_OPS >= 1 && (MAX_PENDING_OPS & (MAX_PENDING_OPS - 1) == 0));
 
+/// Pushes `item` onto `buffer`, which was allocated with enough capacity for
+/// every item pushed onto it when its replica was created. With the
+/// `no-alloc-runtime` feature, panics instead of growing (and hence
+/// allocating) if that isn't the case.
+#[inline(always)]
+pub(crate) fn push_within<T>(buffer: &mut Vec<T>, item: T) {
+    #[cfg(feature = "no-alloc-runtime")]
+    assert!(
+        buffer.len() < buffer.capacity(),
+        "A buffer of the replica would allocate after construction."
+    );
+    buffer.push(item);
+}
+
 /// A pending operation is a combination of the its op-code (T),
 /// and the corresponding result (R).
 type PendingOperation<T, R> = Cell<(Option<T>, Option<R>)>;
@@ -29,6 +53,15 @@ type PendingOperation<T, R> = Cell<(Option<T>, Option<R>)>;
 ///
 /// `R` is a type parameter required by the struct. It is the type on the result obtained
 /// when an operation is executed against the replica.
+///
+/// `tail` and `comb` are atomics that are each written by one thread and read by
+/// another. Every write to them is a release store and every read that is followed
+/// by an access to the batch is an acquire load, so that the batch entries they
+/// cover are visible to the other thread.
+///
+/// An operation can come with a pointer to memory of the thread that issued it (see
+/// `enqueue_into()`). The combiner then writes the response there instead of into
+/// the batch and sets the completion flag of the entry, which the thread waits on.
 #[repr(align(64))]
 pub(crate) struct Context<T, R>
 where
@@ -39,10 +72,28 @@ where
     /// well as the results obtained on executing them against a replica.
     batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING_OPS],
 
+    /// For each entry in the batch, where the combiner writes the response to (if
+    /// not null). Written by the thread that owns this context before it publishes
+    /// the operation, and read by the combiner.
+    out: [Cell<*mut R>; MAX_PENDING_OPS],
+
+    /// For each entry in the batch, set by the combiner once it wrote the response
+    /// to `out`. Cleared by the thread that owns this context.
+    done: [AtomicBool; MAX_PENDING_OPS],
+
+    /// For each entry in the batch, the logical offset at which the combiner
+    /// appended the operation to the shared log. Written by the combiner before
+    /// it hands out the response.
+    offsets: [Cell<usize>; MAX_PENDING_OPS],
+
+    /// Maximum number of operations pending on this context at any time. At
+    /// most `MAX_PENDING_OPS`; set when the context is created.
+    capacity: usize,
+
     /// Logical array index at which new operations will be enqueued into the batch.
     /// This variable is updated by the thread that owns this context, and is read by the
-    /// combiner. We can avoid making it an atomic by assuming we're on x86.
-    pub tail: CachePadded<Cell<usize>>,
+    /// combiner.
+    pub tail: CachePadded<AtomicUsize>,
 
     /// Logical array index from which any attempt to dequeue responses will be made.
     /// This variable is only accessed by the thread that owns this context.
@@ -50,8 +101,37 @@ where
 
     /// Logical array index from which the operations will be dequeued for flat combining.
     /// This variable is updated by the combiner, and is read by the thread that owns this context.
-    /// We can avoid making it an atomic by assuming we're on x86.
-    pub comb: CachePadded<Cell<usize>>,
+    pub comb: CachePadded<AtomicUsize>,
+
+    /// Number of operations whose caller gave up waiting for the response. Their
+    /// responses are dropped instead of being returned by `res()`.
+    /// This variable is only accessed by the thread that owns this context.
+    pub abandoned: Cell<usize>,
+
+    /// Rate limit for write operations issued by the thread that owns this context.
+    /// This variable is only accessed by the thread that owns this context.
+    #[cfg(feature = "std")]
+    pub limit: Cell<Option<TokenBucket>>,
+
+    /// The thread that owns this context while it is parked waiting for
+    /// responses (see `park()`). Set by that thread, read by the combiner.
+    #[cfg(feature = "std")]
+    waiter: std::sync::Mutex<Option<std::thread::Thread>>,
+
+    /// Whether the thread that owns this context is (about to be) parked.
+    #[cfg(feature = "std")]
+    parked: AtomicBool,
+
+    /// The waker of the task that owns this context while it waits for a
+    /// combiner (see `register_waker()`). Set by that task, taken by the combiner
+    /// while holding `waker_lock`.
+    waker: Cell<Option<Waker>>,
+
+    /// Whether a thread accesses `waker`.
+    waker_lock: AtomicBool,
+
+    /// Whether the task that owns this context is (about to be) waiting.
+    waiting: AtomicBool,
 }
 
 impl<T, R> Default for Context<T, R>
@@ -61,47 +141,120 @@ where
 {
     /// Default constructor for the context.
     fn default() -> Context<T, R> {
-        let mut batch: [CachePadded<PendingOperation<T, R>>; MAX_PENDING_OPS] =
-            unsafe { ::core::mem::MaybeUninit::zeroed().assume_init() };
-        for elem in &mut batch[..] {
-            *elem = CachePadded::new(Cell::new((None, None)));
+        // Write the entries in place; all-zero bytes aren't necessarily a valid
+        // (droppable) `Option<T>` if `T` owns memory.
+        let mut batch: MaybeUninit<[CachePadded<PendingOperation<T, R>>; MAX_PENDING_OPS]> =
+            MaybeUninit::uninit();
+        let elems = batch.as_mut_ptr() as *mut CachePadded<PendingOperation<T, R>>;
+        for i in 0..MAX_PENDING_OPS {
+            unsafe {
+                elems
+                    .add(i)
+                    .write(CachePadded::new(Cell::new((None, None))))
+            };
         }
+        let batch = unsafe { batch.assume_init() };
+
+        // Null pointers, false flags and zero offsets are all zeroes.
+        let out = unsafe { MaybeUninit::zeroed().assume_init() };
+        let done = unsafe { MaybeUninit::zeroed().assume_init() };
+        let offsets = unsafe { MaybeUninit::zeroed().assume_init() };
 
         Context {
             batch,
-            tail: CachePadded::new(Cell::new(Default::default())),
+            out,
+            done,
+            offsets,
+            capacity: MAX_PENDING_OPS,
+            tail: CachePadded::new(AtomicUsize::new(0)),
             head: CachePadded::new(Cell::new(Default::default())),
-            comb: CachePadded::new(Cell::new(Default::default())),
+            comb: CachePadded::new(AtomicUsize::new(0)),
+            abandoned: Cell::new(0),
+            #[cfg(feature = "std")]
+            limit: Cell::new(None),
+            #[cfg(feature = "std")]
+            waiter: std::sync::Mutex::new(None),
+            #[cfg(feature = "std")]
+            parked: AtomicBool::new(false),
+            waker: Cell::new(None),
+            waker_lock: AtomicBool::new(false),
+            waiting: AtomicBool::new(false),
         }
     }
 }
 
+/// The context is Send. The pointers in `out` are only dereferenced by the combiner
+/// while the thread that handed them over waits for the response.
+unsafe impl<T, R> Send for Context<T, R>
+where
+    T: Sized + Clone + Send,
+    R: Sized + Clone + Send,
+{
+}
+
 impl<T, R> Context<T, R>
 where
     T: Sized + Clone,
     R: Sized + Clone,
 {
+    /// Creates a context that holds at most `capacity` pending operations.
+    ///
+    /// # Panics
+    /// If `capacity` is zero or larger than `MAX_PENDING_OPS`.
+    pub(crate) fn with_capacity(capacity: usize) -> Context<T, R> {
+        assert!(
+            capacity > 0 && capacity <= MAX_PENDING_OPS,
+            "Capacity of a context must be within 1..={}.",
+            MAX_PENDING_OPS
+        );
+        Context {
+            capacity,
+            ..Default::default()
+        }
+    }
+
     /// Enqueues an operation onto this context's batch of pending operations.
     ///
     /// Returns true if the operation was successfully enqueued. False otherwise.
     #[inline(always)]
     pub(crate) fn enqueue(&self, op: T) -> bool {
-        let t = self.tail.get();
+        self.enqueue_into(op, ptr::null_mut())
+    }
+
+    /// Like `enqueue()`, but the combiner writes the response to `out` (unless it's
+    /// null) instead of into the batch. Collect it with `res_into()`; `out` must stay
+    /// valid until then.
+    #[inline(always)]
+    pub(crate) fn enqueue_into(&self, op: T, out: *mut R) -> bool {
+        self.drop_abandoned();
+
+        let t = self.tail.load(Ordering::Relaxed);
         let h = self.head.get();
+        let c = self.comb.load(Ordering::Relaxed);
+        invariant!(
+            batch_order,
+            h <= c && c <= t && t - h <= self.capacity,
+            "head {}, comb {}, tail {}",
+            h,
+            c,
+            t
+        );
 
         // Check if we have space in the batch to hold this operation. If we don't, then
         // return false to the caller thread.
-        if t - h == MAX_PENDING_OPS {
+        if t - h == self.capacity {
             return false;
         };
 
         // Add in the operation to the batch. Once added, update the tail so that the
-        // combiner sees this operation. Relying on TSO here to make sure that the tail
-        // is updated only after the operation has been written in.
+        // combiner sees this operation. The release store makes sure that the tail is
+        // updated only after the operation has been written in.
         let e = self.batch[self.index(t)].as_ptr();
         unsafe { (*e).0 = Some(op) };
+        self.out[self.index(t)].set(out);
+        self.done[self.index(t)].store(false, Ordering::Relaxed);
 
-        self.tail.set(t + 1);
+        self.tail.store(t + 1, Ordering::Release);
         true
     }
 
@@ -110,7 +263,7 @@ where
     /// replica this thread is registered against.
     #[inline(always)]
     pub(crate) fn enqueue_resps(&self, responses: &[R]) {
-        let h = self.comb.get();
+        let h = self.comb.load(Ordering::Relaxed);
         let n = responses.len();
 
         // Empty slice passed in; no work to do, so simply return.
@@ -120,22 +273,57 @@ where
 
         // Starting from `comb`, write all responses into the batch. Assume here that
         // the slice above doesn't cause us to cross the tail of the batch.
+        invariant!(
+            batch_order,
+            h + n <= self.tail.load(Ordering::Relaxed),
+            "{} responses from comb {} cross tail {}",
+            n,
+            h,
+            self.tail.load(Ordering::Relaxed)
+        );
         for (i, response) in responses.iter().enumerate().take(n) {
+            let out = self.out[self.index(h + i)].get();
+            if !out.is_null() {
+                // The thread waits for the flag, so the write is visible once it's set.
+                unsafe { ptr::write(out, response.clone()) };
+                self.done[self.index(h + i)].store(true, Ordering::Release);
+                continue;
+            }
+
             let e = self.batch[self.index(h + i)].as_ptr();
             unsafe {
                 (*e).1 = Some(response.clone());
             }
         }
 
-        self.comb.set(h + n);
+        // Publish the responses to the thread that owns this context.
+        self.comb.store(h + n, Ordering::Release);
     }
 
-    /// Adds any pending operations on this context to a passed in buffer. Returns the
-    /// the number of such operations that were added in.
+    /// Records that the next `n` operations whose responses will be enqueued were
+    /// appended to the shared log starting at offset `first`. Must be invoked by
+    /// the combiner before `enqueue_resps()`, which publishes the offsets.
     #[inline(always)]
-    pub(crate) fn ops(&self, buffer: &mut Vec<T>) -> usize {
-        let mut h = self.comb.get();
-        let t = self.tail.get();
+    pub(crate) fn set_offsets(&self, first: LogOffset, n: usize) {
+        let h = self.comb.load(Ordering::Relaxed);
+        for i in 0..n {
+            self.offsets[self.index(h + i)].set(first.get() + i);
+        }
+    }
+
+    /// Returns the log offset of the operation whose response was dequeued last.
+    #[inline(always)]
+    pub(crate) fn last_offset(&self) -> LogOffset {
+        debug_assert!(self.head.get() > 0);
+        LogOffset::new(self.offsets[self.index(self.head.get() - 1)].get())
+    }
+
+    /// Adds up to `max` pending operations on this context to a passed in buffer,
+    /// oldest first. Returns the the number of such operations that were added in.
+    #[inline(always)]
+    pub(crate) fn ops(&self, buffer: &mut Vec<T>, max: usize) -> usize {
+        let mut h = self.comb.load(Ordering::Relaxed);
+        let t = self.tail.load(Ordering::Acquire);
 
         // No operations on this thread; return to the caller indicating so.
         if h == t {
@@ -150,7 +338,7 @@ where
         // passed in buffer. Return the number of operations that were added.
         let mut n = 0;
         loop {
-            if h == t {
+            if h == t || n == max {
                 break;
             };
 
@@ -158,7 +346,8 @@ where
             // valid operation ready for flat combining. Hence, calling unwrap() here
             // on the operation is safe.
             unsafe {
-                buffer.push(
+                push_within(
+                    buffer,
                     (*self.batch[self.index(h)].as_ptr())
                         .0
                         .as_ref()
@@ -174,11 +363,26 @@ where
         n
     }
 
+    /// Returns true if the combiner picked up every operation enqueued so far.
+    #[inline(always)]
+    pub(crate) fn is_drained(&self) -> bool {
+        self.comb.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
     /// Returns a single response if available. Otherwise, returns None.
     #[inline(always)]
     pub(crate) fn res(&self) -> Option<R> {
+        self.res_indexed().map(|(_i, r)| r)
+    }
+
+    /// Like `res()`, but also returns the logical array index at which the
+    /// operation was enqueued (the tail of the batch at the time).
+    #[inline(always)]
+    pub(crate) fn res_indexed(&self) -> Option<(usize, R)> {
+        self.drop_abandoned();
+
         let s = self.head.get();
-        let f = self.comb.get();
+        let f = self.comb.load(Ordering::Acquire);
 
         // No responses ready yet; return to the caller.
         if s == f {
@@ -189,16 +393,168 @@ where
             panic!("Head of thread-local batch has advanced beyond combiner offset!");
         }
 
+        debug_assert!(self.out[self.index(s)].get().is_null());
         self.head.set(s + 1);
-        unsafe { (*self.batch[self.index(s)].as_ptr()).1.clone() }
+        unsafe { (*self.batch[self.index(s)].as_ptr()).1.clone() }.map(|r| (s, r))
     }
 
-    /// Returns the maximum number of operations that will go pending on this context.
+    /// Returns true once the combiner wrote the response of the oldest operation,
+    /// which must have been enqueued with `enqueue_into()`, to its `out` pointer.
+    #[inline(always)]
+    pub(crate) fn res_into(&self) -> bool {
+        self.drop_abandoned();
+
+        // The combiner moves `comb` past the operation right after setting the flag;
+        // the thread only moves its head past it once both happened. The acquire
+        // load of the flag makes the response visible.
+        let s = self.head.get();
+        if self.abandoned.get() > 0 {
+            return false;
+        }
+
+        debug_assert!(
+            s < self.tail.load(Ordering::Relaxed) && !self.out[self.index(s)].get().is_null()
+        );
+        if self.comb.load(Ordering::Relaxed) <= s
+            || !self.done[self.index(s)].load(Ordering::Acquire)
+        {
+            return false;
+        }
+
+        self.out[self.index(s)].set(ptr::null_mut());
+        self.head.set(s + 1);
+        true
+    }
+
+    /// Blocks the thread that owns this context until a combiner releases the
+    /// combiner lock (see `unpark()`), or for at most `timeout`. Returns right
+    /// away if a response is already there or if `combining` returns false, as
+    /// nobody would wake the thread up then.
+    #[cfg(feature = "std")]
+    pub(crate) fn park<F: FnOnce() -> bool>(&self, timeout: core::time::Duration, combining: F) {
+        *self.waiter.lock().unwrap() = Some(std::thread::current());
+
+        // Pairs with the fence in `unpark()`: either the combiner sees the flag
+        // and wakes us up, or we see the responses it enqueued (and the lock it
+        // released) and don't sleep.
+        self.parked.store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if self.comb.load(Ordering::Relaxed) == self.head.get() && combining() {
+            std::thread::park_timeout(timeout);
+        }
+        self.parked.store(false, Ordering::Relaxed);
+    }
+
+    /// Wakes up the thread that owns this context if it is parked. Invoked by
+    /// the combiner after it enqueued responses and released the lock.
+    #[cfg(feature = "std")]
+    pub(crate) fn unpark(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked.swap(false, Ordering::Relaxed) {
+            if let Some(waiter) = self.waiter.lock().unwrap().as_ref() {
+                waiter.unpark();
+            }
+        }
+    }
+
+    /// Registers `waker` to be woken by `wake()` once a combiner released the
+    /// combiner lock. Returns false without waiting if a response is already
+    /// there or if `combining` returns false, as nobody would wake the task up
+    /// then.
+    pub(crate) fn register_waker<F: FnOnce() -> bool>(&self, waker: &Waker, combining: F) -> bool {
+        self.with_waker(|w| match w {
+            Some(w) if w.will_wake(waker) => {}
+            _ => *w = Some(waker.clone()),
+        });
+
+        // Pairs with the fence in `Replica::wake_tasks()`: either the combiner
+        // sees the flag and wakes us up, or we see the responses it enqueued
+        // (and the lock it released) and don't wait.
+        self.waiting.store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if self.comb.load(Ordering::Relaxed) == self.head.get() && combining() {
+            return true;
+        }
+        self.waiting.store(false, Ordering::Relaxed);
+        false
+    }
+
+    /// Wakes the task that owns this context if it is waiting. Invoked by the
+    /// combiner after it enqueued responses and released the lock.
+    pub(crate) fn wake(&self) {
+        if self.waiting.swap(false, Ordering::Relaxed) {
+            if let Some(waker) = self.with_waker(Option::take) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Runs `f` on `waker` while holding `waker_lock`.
+    fn with_waker<F, U>(&self, f: F) -> U
+    where
+        F: FnOnce(&mut Option<Waker>) -> U,
+    {
+        while self
+            .waker_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+
+        let mut waker = self.waker.take();
+        let r = f(&mut waker);
+        self.waker.set(waker);
+
+        self.waker_lock.store(false, Ordering::Release);
+        r
+    }
+
+    /// Returns true if another operation can be enqueued onto this context.
+    #[inline(always)]
+    pub(crate) fn has_room(&self) -> bool {
+        self.drop_abandoned();
+        self.tail.load(Ordering::Relaxed) - self.head.get() < self.capacity
+    }
+
+    /// Marks the oldest operation without a response as abandoned; its response
+    /// will be dropped once the combiner returns it.
+    #[inline(always)]
+    pub(crate) fn abandon(&self) {
+        self.abandoned.set(self.abandoned.get() + 1);
+    }
+
+    /// Drops the responses of abandoned operations that are available, freeing
+    /// up their slots in the batch.
+    #[inline(always)]
+    fn drop_abandoned(&self) {
+        let n = self.abandoned.get();
+        if n == 0 {
+            return;
+        }
+
+        let s = self.head.get();
+        let ready = core::cmp::min(n, self.comb.load(Ordering::Acquire) - s);
+        for i in s..s + ready {
+            unsafe { (*self.batch[self.index(i)].as_ptr()).1 = None };
+        }
+
+        self.head.set(s + ready);
+        self.abandoned.set(n - ready);
+    }
+
+    /// Returns the maximum number of operations that will go pending on any context.
     #[inline(always)]
     pub(crate) fn batch_size() -> usize {
         MAX_PENDING_OPS
     }
 
+    /// Returns the maximum number of operations that will go pending on this context.
+    #[inline(always)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     /// Given a logical address, returns an index into the batch at which it falls.
     #[inline(always)]
     fn index(&self, logical: usize) -> usize {
@@ -216,9 +572,9 @@ mod test {
     fn test_context_create_default() {
         let c = Context::<u64, Result<u64, ()>>::default();
         assert_eq!(c.batch.len(), MAX_PENDING_OPS);
-        assert_eq!(c.tail.get(), 0);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 0);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 0);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 0);
     }
 
     // Tests whether we can successfully enqueue an operation onto the context.
@@ -227,21 +583,35 @@ mod test {
         let c = Context::<u64, Result<u64, ()>>::default();
         assert!(c.enqueue(121));
         unsafe { assert_eq!((*c.batch[0].as_ptr()).0, Some(121)) };
-        assert_eq!(c.tail.take(), 1);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 1);
         assert_eq!(c.head.take(), 0);
-        assert_eq!(c.comb.take(), 0);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 0);
     }
 
     // Tests that enqueues on the context fail when it's batch of operations is full.
     #[test]
     fn test_context_enqueue_full() {
         let c = Context::<u64, Result<u64, ()>>::default();
-        c.tail.set(MAX_PENDING_OPS);
+        c.tail.store(MAX_PENDING_OPS, Ordering::Relaxed);
 
         assert!(!c.enqueue(100));
-        assert_eq!(c.tail.get(), MAX_PENDING_OPS);
+        assert_eq!(c.tail.load(Ordering::Relaxed), MAX_PENDING_OPS);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 0);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 0);
+    }
+
+    // Tests that enqueues on a context created with a smaller capacity fail once
+    // that many operations are pending.
+    #[test]
+    fn test_context_enqueue_capacity() {
+        let c = Context::<u64, Result<u64, ()>>::with_capacity(4);
+        assert_eq!(c.capacity(), 4);
+
+        for i in 0..4 {
+            assert!(c.enqueue(i));
+        }
+        assert!(!c.enqueue(100));
+        assert_eq!(c.tail.load(Ordering::Relaxed), 4);
     }
 
     // Tests that we can successfully enqueue responses onto the context.
@@ -250,13 +620,13 @@ mod test {
         let c = Context::<u64, Result<u64, ()>>::default();
         let r = [Ok(11), Ok(12), Ok(13), Ok(14)];
 
-        c.tail.set(16);
-        c.comb.set(12);
+        c.tail.store(16, Ordering::Relaxed);
+        c.comb.store(12, Ordering::Relaxed);
         c.enqueue_resps(&r);
 
-        assert_eq!(c.tail.get(), 16);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 16);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 16);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 16);
 
         assert_eq!(c.batch[12].get().1, Some(r[0]));
         assert_eq!(c.batch[13].get().1, Some(r[1]));
@@ -264,6 +634,22 @@ mod test {
         assert_eq!(c.batch[15].get().1, Some(r[3]));
     }
 
+    // Tests that the log offsets set before enqueuing responses are returned for
+    // the responses in the order they're dequeued.
+    #[test]
+    fn test_context_offsets() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        assert!(c.enqueue(1) && c.enqueue(2));
+
+        c.set_offsets(LogOffset::new(40), 2);
+        c.enqueue_resps(&[Ok(11), Ok(12)]);
+
+        assert_eq!(c.res(), Some(Ok(11)));
+        assert_eq!(c.last_offset(), LogOffset::new(40));
+        assert_eq!(c.res(), Some(Ok(12)));
+        assert_eq!(c.last_offset(), LogOffset::new(41));
+    }
+
     // Tests that attempting to enqueue an empty batch of responses on the context
     // does nothing.
     #[test]
@@ -271,13 +657,13 @@ mod test {
         let c = Context::<u64, Result<u64, ()>>::default();
         let r = [];
 
-        c.tail.set(16);
-        c.comb.set(12);
+        c.tail.store(16, Ordering::Relaxed);
+        c.comb.store(12, Ordering::Relaxed);
         c.enqueue_resps(&r);
 
-        assert_eq!(c.tail.get(), 16);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 16);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 12);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 12);
 
         assert_eq!(c.batch[12].get().1, None);
     }
@@ -286,17 +672,17 @@ mod test {
     #[test]
     fn test_context_ops() {
         let c = Context::<usize, usize>::default();
-        let mut o = vec![];
+        let mut o = Vec::with_capacity(MAX_PENDING_OPS);
 
         for idx in 0..MAX_PENDING_OPS / 2 {
             assert!(c.enqueue(idx * idx))
         }
 
-        assert_eq!(c.ops(&mut o), MAX_PENDING_OPS / 2);
+        assert_eq!(c.ops(&mut o, usize::MAX), MAX_PENDING_OPS / 2);
         assert_eq!(o.len(), MAX_PENDING_OPS / 2);
-        assert_eq!(c.tail.get(), MAX_PENDING_OPS / 2);
+        assert_eq!(c.tail.load(Ordering::Relaxed), MAX_PENDING_OPS / 2);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 0);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 0);
 
         for idx in 0..MAX_PENDING_OPS / 2 {
             assert_eq!(o[idx], idx * idx)
@@ -309,14 +695,14 @@ mod test {
         let c = Context::<usize, usize>::default();
         let mut o = vec![];
 
-        c.tail.set(8);
-        c.comb.set(8);
+        c.tail.store(8, Ordering::Relaxed);
+        c.comb.store(8, Ordering::Relaxed);
 
-        assert_eq!(c.ops(&mut o), 0);
+        assert_eq!(c.ops(&mut o, usize::MAX), 0);
         assert_eq!(o.len(), 0);
-        assert_eq!(c.tail.get(), 8);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 8);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 8);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 8);
     }
 
     // Tests whether ops() panics if the combiner head advances beyond the tail.
@@ -326,10 +712,10 @@ mod test {
         let c = Context::<usize, usize>::default();
         let mut o = vec![];
 
-        c.tail.set(6);
-        c.comb.set(9);
+        c.tail.store(6, Ordering::Relaxed);
+        c.comb.store(9, Ordering::Relaxed);
 
-        assert_eq!(c.ops(&mut o), 0);
+        assert_eq!(c.ops(&mut o, usize::MAX), 0);
     }
 
     // Tests whether we can retrieve responses enqueued on this context.
@@ -338,11 +724,11 @@ mod test {
         let c = Context::<u64, Result<u64, ()>>::default();
         let r = [Ok(11), Ok(12), Ok(13), Ok(14)];
 
-        c.tail.set(16);
+        c.tail.store(16, Ordering::Relaxed);
         c.enqueue_resps(&r);
 
-        assert_eq!(c.tail.get(), 16);
-        assert_eq!(c.comb.get(), 4);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 16);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 4);
 
         assert_eq!(c.res(), Some(r[0]));
         assert_eq!(c.head.get(), 1);
@@ -362,28 +748,119 @@ mod test {
     fn test_context_res_empty() {
         let c = Context::<usize, usize>::default();
 
-        c.tail.set(8);
+        c.tail.store(8, Ordering::Relaxed);
 
-        assert_eq!(c.tail.get(), 8);
+        assert_eq!(c.tail.load(Ordering::Relaxed), 8);
         assert_eq!(c.head.get(), 0);
-        assert_eq!(c.comb.get(), 0);
+        assert_eq!(c.comb.load(Ordering::Relaxed), 0);
 
         assert_eq!(c.res(), None);
     }
 
+    // Tests that res skips the responses of abandoned operations, even if they
+    // only come back after the operation was abandoned.
+    #[test]
+    fn test_context_res_abandoned() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        assert!(c.enqueue(1));
+        assert!(c.enqueue(2));
+
+        c.abandon();
+        assert_eq!(c.res(), None);
+        assert_eq!(c.abandoned.get(), 1);
+
+        c.comb.store(0, Ordering::Relaxed);
+        c.enqueue_resps(&[Ok(11), Ok(12)]);
+
+        assert_eq!(c.res(), Some(Ok(12)));
+        assert_eq!(c.abandoned.get(), 0);
+        assert_eq!(c.head.get(), 2);
+    }
+
+    // Tests that responses of operations enqueued with enqueue_into() are written
+    // to their pointer instead of the batch, and collected with res_into().
+    #[test]
+    fn test_context_res_into() {
+        let c = Context::<u64, Result<u64, ()>>::default();
+        let mut out = Err(());
+        assert!(c.enqueue_into(1, &mut out));
+        assert!(!c.res_into());
+
+        c.enqueue_resps(&[Ok(11)]);
+        assert!(c.res_into());
+        assert_eq!(out, Ok(11));
+        assert_eq!(c.batch[0].get().1, None);
+        assert_eq!(c.head.get(), 1);
+    }
+
     // Tests that res panics if the head moves beyond the combiner offset.
     #[test]
     #[should_panic]
     fn test_context_res_panic() {
         let c = Context::<usize, usize>::default();
 
-        c.tail.set(8);
-        c.comb.set(4);
+        c.tail.store(8, Ordering::Relaxed);
+        c.comb.store(4, Ordering::Relaxed);
         c.head.set(6);
 
         assert_eq!(c.res(), None);
     }
 
+    // Tests that operations and responses handed between the thread that owns a
+    // context and a combiner on another thread arrive intact and in order. Mostly
+    // interesting on weakly ordered CPUs.
+    #[test]
+    fn test_context_cross_thread() {
+        struct Shared(Context<u64, u64>);
+        unsafe impl Sync for Shared {}
+
+        const OPS: u64 = 10_000;
+        let c = std::sync::Arc::new(Shared(Context::default()));
+
+        let combiner = {
+            let c = c.clone();
+            std::thread::spawn(move || {
+                let mut buffer = Vec::with_capacity(MAX_PENDING_OPS);
+                let mut seen = 0;
+                while seen < OPS {
+                    buffer.clear();
+                    c.0.ops(&mut buffer, usize::MAX);
+                    for op in buffer.iter() {
+                        assert_eq!(*op, seen);
+                        seen += 1;
+                    }
+                    let resps: Vec<u64> = buffer.iter().map(|op| op * 2).collect();
+                    c.0.enqueue_resps(&resps);
+                    std::thread::yield_now();
+                }
+            })
+        };
+
+        let mut next = 0;
+        for op in 0..OPS {
+            while !c.0.enqueue(op) {
+                match c.0.res() {
+                    Some(resp) => {
+                        assert_eq!(resp, next * 2);
+                        next += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+        }
+        while next < OPS {
+            match c.0.res() {
+                Some(resp) => {
+                    assert_eq!(resp, next * 2);
+                    next += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+
+        combiner.join().unwrap();
+    }
+
     // Tests that batch_size() works correctly.
     #[test]
     fn test_context_batch_size() {
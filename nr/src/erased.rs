@@ -0,0 +1,334 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Type-erased operations, so that replicas of different data structures (e.g.,
+//! the old and the new version of a data structure during a rollout) can
+//! consume the same shared log. Requires the `erased` feature.
+
+use alloc::vec::Vec;
+
+use core::marker::PhantomData;
+use core::mem::align_of;
+
+use crate::ids::OpOrigin;
+use crate::Dispatch;
+
+/// A write operation in erased form, as stored on a shared log of type
+/// `Log<ErasedOp>`: a tag identifying how the operation is encoded, along with
+/// the encoded operation.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ErasedOp {
+    /// Identifies the encoding of `payload`, e.g., the kind of operation and
+    /// the version of the data structure that issued it.
+    pub tag: u32,
+
+    /// The encoded operation.
+    pub payload: Vec<u8>,
+}
+
+/// Converts the write operations of a data structure to and from
+/// [ErasedOp](struct.ErasedOp.html). Implemented by the user for every data
+/// structure wrapped in an [Erased](struct.Erased.html).
+///
+/// All data structures sharing a log must agree on the meaning of the tags
+/// they understand: decoding an operation has to yield an operation with the
+/// same effect on every replica, or the replicas diverge.
+pub trait OpCodec<T> {
+    /// Encodes `op`.
+    fn encode(op: &T) -> ErasedOp;
+
+    /// Decodes `op`, or returns `None` if the tag isn't understood by this
+    /// data structure. Such operations are skipped.
+    fn decode(op: &ErasedOp) -> Option<T>;
+}
+
+/// Wraps a data structure so that its write operations are stored on the shared
+/// log as [ErasedOp](struct.ErasedOp.html)s, encoded and decoded with the codec
+/// `X`. Replicas of `Erased` data structures with the same log can wrap
+/// different data structures (and codecs) and still consume the same ordered
+/// history of operations.
+///
+/// Write operations are issued with `Erased::op`. Operations whose tag the
+/// codec doesn't understand are skipped and yield `None`; other operations
+/// yield the response of the wrapped data structure. Read operations aren't
+/// stored on the log and are passed to the wrapped data structure as is.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{Dispatch, Erased, ErasedOp, Log, OpCodec, Replica};
+///
+/// use std::convert::TryInto;
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// /// Stores writes as tag 1, followed by the value.
+/// struct DataCodec;
+///
+/// impl OpCodec<u64> for DataCodec {
+///     fn encode(op: &u64) -> ErasedOp {
+///         ErasedOp { tag: 1, payload: op.to_le_bytes().to_vec() }
+///     }
+///
+///     fn decode(op: &ErasedOp) -> Option<u64> {
+///         match op.tag {
+///             1 => Some(u64::from_le_bytes(op.payload.as_slice().try_into().ok()?)),
+///             _ => None,
+///         }
+///     }
+/// }
+///
+/// type ErasedData = Erased<Data, DataCodec>;
+///
+/// let log = Arc::new(Log::<ErasedOp>::default());
+/// let replica = Replica::<ErasedData>::new(&log);
+/// let idx = replica.register().unwrap();
+///
+/// assert_eq!(replica.execute_mut(ErasedData::op(&5), idx), Ok(Some(None)));
+/// assert_eq!(replica.execute((), idx), Ok(Some(Some(5))));
+/// ```
+pub struct Erased<D, X> {
+    data: D,
+
+    /// The codec is only used through its associated functions.
+    codec: PhantomData<fn() -> X>,
+}
+
+impl<D, X> Erased<D, X>
+where
+    D: Dispatch,
+    X: OpCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Wraps `data`.
+    pub fn new(data: D) -> Erased<D, X> {
+        Erased {
+            data,
+            codec: PhantomData,
+        }
+    }
+
+    /// Encodes the write operation `op` of the wrapped data structure, to be
+    /// passed to `Replica::execute_mut`.
+    pub fn op(op: &<D as Dispatch>::WriteOperation) -> ErasedOp {
+        X::encode(op)
+    }
+
+    /// Returns the wrapped data structure.
+    pub fn get_ref(&self) -> &D {
+        &self.data
+    }
+}
+
+impl<D, X> Default for Erased<D, X>
+where
+    D: Dispatch + Default,
+    X: OpCodec<<D as Dispatch>::WriteOperation>,
+{
+    fn default() -> Self {
+        Erased::new(D::default())
+    }
+}
+
+impl<D, X> Dispatch for Erased<D, X>
+where
+    D: Dispatch,
+    X: OpCodec<<D as Dispatch>::WriteOperation>,
+{
+    type ReadOperation = <D as Dispatch>::ReadOperation;
+    type WriteOperation = ErasedOp;
+    type Response = Option<<D as Dispatch>::Response>;
+
+    // `Option` adds at most one alignment unit for its discriminant.
+    const MAX_RESPONSE_SIZE: usize =
+        <D as Dispatch>::MAX_RESPONSE_SIZE + align_of::<<D as Dispatch>::Response>();
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        Some(self.data.dispatch(op))
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        let op = X::decode(&op)?;
+        Some(self.data.dispatch_mut(op))
+    }
+
+    fn dispatch_mut_ctx(&mut self, op: Self::WriteOperation, origin: OpOrigin) -> Self::Response {
+        let op = X::decode(&op)?;
+        Some(self.data.dispatch_mut_ctx(op, origin))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Log, Replica};
+
+    use alloc::sync::Arc;
+    use core::convert::TryInto;
+    use std::vec;
+
+    // The old version of a data structure: a counter that can be incremented.
+    #[derive(Default)]
+    struct CounterV1(u64);
+
+    impl Dispatch for CounterV1 {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.0
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.0 += op;
+            self.0
+        }
+    }
+
+    // The new version of the data structure, which can also reset the counter.
+    #[derive(Clone, Debug, PartialEq)]
+    enum OpV2 {
+        Add(u64),
+        Reset,
+    }
+
+    #[derive(Default)]
+    struct CounterV2(u64);
+
+    impl Dispatch for CounterV2 {
+        type ReadOperation = ();
+        type WriteOperation = OpV2;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.0
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            match op {
+                OpV2::Add(n) => self.0 += n,
+                OpV2::Reset => self.0 = 0,
+            }
+            self.0
+        }
+    }
+
+    const TAG_ADD: u32 = 1;
+    const TAG_RESET: u32 = 2;
+
+    fn decode_add(op: &ErasedOp) -> Option<u64> {
+        Some(u64::from_le_bytes(op.payload.as_slice().try_into().ok()?))
+    }
+
+    struct CodecV1;
+
+    impl OpCodec<u64> for CodecV1 {
+        fn encode(op: &u64) -> ErasedOp {
+            ErasedOp {
+                tag: TAG_ADD,
+                payload: op.to_le_bytes().to_vec(),
+            }
+        }
+
+        fn decode(op: &ErasedOp) -> Option<u64> {
+            match op.tag {
+                TAG_ADD => decode_add(op),
+                _ => None,
+            }
+        }
+    }
+
+    struct CodecV2;
+
+    impl OpCodec<OpV2> for CodecV2 {
+        fn encode(op: &OpV2) -> ErasedOp {
+            match op {
+                OpV2::Add(n) => CodecV1::encode(n),
+                OpV2::Reset => ErasedOp {
+                    tag: TAG_RESET,
+                    payload: Vec::new(),
+                },
+            }
+        }
+
+        fn decode(op: &ErasedOp) -> Option<OpV2> {
+            match op.tag {
+                TAG_ADD => decode_add(op).map(OpV2::Add),
+                TAG_RESET => Some(OpV2::Reset),
+                _ => None,
+            }
+        }
+    }
+
+    type V1 = Erased<CounterV1, CodecV1>;
+    type V2 = Erased<CounterV2, CodecV2>;
+
+    // Tests that operations survive encoding and decoding, and that unknown
+    // tags or malformed payloads are rejected.
+    #[test]
+    fn test_erased_codec() {
+        assert_eq!(CodecV2::decode(&V2::op(&OpV2::Add(7))), Some(OpV2::Add(7)));
+        assert_eq!(CodecV2::decode(&V2::op(&OpV2::Reset)), Some(OpV2::Reset));
+        assert_eq!(CodecV1::decode(&V1::op(&7)), Some(7));
+        assert_eq!(CodecV1::decode(&V2::op(&OpV2::Reset)), None);
+
+        let truncated = ErasedOp {
+            tag: TAG_ADD,
+            payload: vec![1, 2, 3],
+        };
+        assert_eq!(CodecV1::decode(&truncated), None);
+    }
+
+    // Tests that replicas of two different data structures consume the same
+    // history from one log, each skipping operations it doesn't understand.
+    #[test]
+    fn test_erased_shared_log() {
+        let log = Arc::new(Log::<ErasedOp>::default());
+        let old = Replica::<V1>::new(&log);
+        let new = Replica::<V2>::new(&log);
+        let oidx = old.register().unwrap();
+        let nidx = new.register().unwrap();
+
+        assert_eq!(old.execute_mut(V1::op(&2), oidx), Ok(Some(2)));
+        assert_eq!(new.execute_mut(V2::op(&OpV2::Add(3)), nidx), Ok(Some(None)));
+        assert_eq!(old.execute((), oidx), Ok(Some(None)));
+
+        assert_eq!(new.execute_mut(V2::op(&OpV2::Reset), nidx), Ok(Some(0)));
+        assert_eq!(new.execute_mut(V2::op(&OpV2::Add(1)), nidx), Ok(Some(1)));
+
+        // The old version doesn't know about resets and skips them.
+        let unknown = ErasedOp {
+            tag: 99,
+            payload: Vec::new(),
+        };
+        assert_eq!(old.execute_mut(unknown, oidx), Ok(None));
+        assert_eq!(old.execute((), oidx), Ok(Some(6)));
+        assert_eq!(new.execute((), nidx), Ok(Some(1)));
+        old.verify(|d| assert_eq!(d.get_ref().0, 6));
+    }
+}
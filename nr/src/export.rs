@@ -0,0 +1,287 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Export of the operations on a shared log for offline analysis, replay
+//! benchmarking and bug reports. Requires the `export` feature.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ids::{LogOffset, ReplicaId};
+use crate::log::{DeltaCodec, Log};
+
+/// Identifies an export of a log.
+const MAGIC: &[u8; 8] = b"NRLOGEXP";
+
+/// Version of the export format written by `Log::export`.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Size of the header of an export in bytes.
+const HEADER_SIZE: usize = 16;
+
+/// Size of the metadata of a record in bytes.
+const RECORD_SIZE: usize = 20;
+
+/// Returns the `bincode` configuration that operations are encoded with:
+/// fixed-size, little-endian integers.
+fn codec() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+/// Errors that can occur when reading an export with `Log::import`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExportError {
+    /// The data doesn't start with the magic of an export.
+    InvalidMagic,
+
+    /// The export was written in a version of the format this library can't
+    /// read.
+    UnsupportedVersion(u32),
+
+    /// The data ends in the middle of the header or of a record.
+    Truncated,
+
+    /// An operation couldn't be decoded, e.g., because it was exported from a
+    /// log with a different operation type.
+    InvalidOperation,
+}
+
+/// An operation read from an export, along with the metadata of its entry on
+/// the exported log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExportedEntry<T> {
+    /// Logical offset of the entry on the log.
+    pub offset: LogOffset,
+
+    /// Identifier of the replica that appended the operation.
+    pub replica: ReplicaId,
+
+    /// The operation.
+    pub op: T,
+}
+
+impl<'a, T, C> Log<'a, T, C>
+where
+    T: Sized + Clone + Serialize + DeserializeOwned,
+    C: DeltaCodec<T>,
+{
+    /// Writes the committed operations on the log (those that at least one
+    /// replica executed) that weren't garbage collected yet to `writer`. The
+    /// export is handed to `writer` in pieces, in order.
+    ///
+    /// Can be called while replicas use the log. Operations that every replica
+    /// executed can be garbage collected at any point, including during the
+    /// export, and are left out then.
+    ///
+    /// # Format
+    /// An export is a header followed by one record per operation, in log order.
+    /// All integers are little-endian.
+    ///
+    /// The header is 16 bytes long:
+    ///
+    /// | Bytes  | Content                                     |
+    /// |--------|---------------------------------------------|
+    /// | 0..8   | Magic, `b"NRLOGEXP"`                        |
+    /// | 8..12  | Format version (`u32`), currently 1         |
+    /// | 12..16 | Reserved (`u32`), zero                      |
+    ///
+    /// Every record is 20 bytes of metadata followed by the operation:
+    ///
+    /// | Bytes  | Content                                     |
+    /// |--------|---------------------------------------------|
+    /// | 0..8   | Logical offset of the entry on the log (`u64`) |
+    /// | 8..16  | Replica that appended the operation (`u64`) |
+    /// | 16..20 | Length `n` of the operation (`u32`)         |
+    /// | 20..   | `n` bytes, the operation encoded with `bincode`, using fixed-size integers |
+    ///
+    /// Records follow until the end of the export; there is no trailer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().unwrap();
+    ///
+    /// // Operations stay on the log at least until this replica executes them.
+    /// let _lagging = Replica::<Data>::new(&log);
+    ///
+    /// replica.execute_mut(10, idx).unwrap();
+    /// replica.execute_mut(20, idx).unwrap();
+    ///
+    /// let mut bytes = Vec::new();
+    /// log.export(|b: &[u8]| bytes.extend_from_slice(b));
+    ///
+    /// let entries = Log::<u64>::import(&bytes).unwrap();
+    /// assert_eq!(entries.len(), 2);
+    /// assert_eq!(entries[1].op, 20);
+    /// ```
+    pub fn export<W: FnMut(&[u8])>(&self, mut writer: W) {
+        let mut header = [0; HEADER_SIZE];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&EXPORT_VERSION.to_le_bytes());
+        writer(&header);
+
+        let ctail = self.get_ctail();
+        for (op, replica, offset) in self.iter_from(LogOffset::new(self.get_head())) {
+            if offset.get() >= ctail {
+                break;
+            }
+
+            let op = codec().serialize(&op).expect("Failed to encode operation.");
+            let mut record = [0; RECORD_SIZE];
+            record[0..8].copy_from_slice(&(offset.get() as u64).to_le_bytes());
+            record[8..16].copy_from_slice(&(replica.get() as u64).to_le_bytes());
+            record[16..20].copy_from_slice(&(op.len() as u32).to_le_bytes());
+            writer(&record);
+            writer(&op);
+        }
+    }
+
+    /// Reads the operations from an export written by `export`, in log order.
+    /// Meant for tests and tools that replay an operation history; the
+    /// operations aren't added to any log.
+    pub fn import(data: &[u8]) -> Result<Vec<ExportedEntry<T>>, ExportError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ExportError::Truncated);
+        }
+        if &data[0..8] != MAGIC {
+            return Err(ExportError::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version != EXPORT_VERSION {
+            return Err(ExportError::UnsupportedVersion(version));
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = &data[HEADER_SIZE..];
+        while !rest.is_empty() {
+            if rest.len() < RECORD_SIZE {
+                return Err(ExportError::Truncated);
+            }
+            let offset = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+            let replica = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[16..20].try_into().unwrap()) as usize;
+
+            rest = &rest[RECORD_SIZE..];
+            if rest.len() < len {
+                return Err(ExportError::Truncated);
+            }
+            let op = codec()
+                .deserialize(&rest[..len])
+                .map_err(|_e| ExportError::InvalidOperation)?;
+            rest = &rest[len..];
+
+            entries.push(ExportedEntry {
+                offset: LogOffset::new(offset as usize),
+                replica: ReplicaId::new(replica as usize),
+                op,
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec;
+
+    // Tests that an export can be read back with all committed operations and
+    // their metadata, leaving out operations no replica executed yet.
+    #[test]
+    fn test_export_import() {
+        let l = Log::<u64>::default();
+        let idx = l.register().unwrap();
+        let _lagging = l.register().unwrap();
+        l.append(&[10, 20, 30], idx, |_o: u64, _i: ReplicaId| {});
+        l.exec(idx, &mut |_o: u64, _i: ReplicaId| {});
+        l.append(&[40], idx, |_o: u64, _i: ReplicaId| {});
+
+        let mut bytes = Vec::new();
+        l.export(|b: &[u8]| bytes.extend_from_slice(b));
+        assert_eq!(&bytes[0..8], MAGIC);
+
+        let entries = Log::<u64>::import(&bytes).unwrap();
+        let ops: Vec<(u64, usize, usize)> = entries
+            .into_iter()
+            .map(|e| (e.op, e.replica.get(), e.offset.get()))
+            .collect();
+        assert_eq!(ops, vec![(10, 1, 0), (20, 1, 1), (30, 1, 2)]);
+    }
+
+    // Tests that import rejects data that isn't a complete export it can read.
+    #[test]
+    fn test_export_import_invalid() {
+        let l = Log::<u64>::default();
+        let idx = l.register().unwrap();
+        let _lagging = l.register().unwrap();
+        l.append(&[10], idx, |_o: u64, _i: ReplicaId| {});
+        l.exec(idx, &mut |_o: u64, _i: ReplicaId| {});
+
+        let mut bytes = Vec::new();
+        l.export(|b: &[u8]| bytes.extend_from_slice(b));
+
+        assert_eq!(
+            Log::<u64>::import(&bytes[..bytes.len() - 1]),
+            Err(ExportError::Truncated)
+        );
+        assert_eq!(
+            Log::<u64>::import(&bytes[..HEADER_SIZE - 1]),
+            Err(ExportError::Truncated)
+        );
+
+        let mut other = bytes.clone();
+        other[0] = b'X';
+        assert_eq!(Log::<u64>::import(&other), Err(ExportError::InvalidMagic));
+
+        let mut other = bytes.clone();
+        other[8] = 2;
+        assert_eq!(
+            Log::<u64>::import(&other),
+            Err(ExportError::UnsupportedVersion(2))
+        );
+
+        assert_eq!(
+            Log::<u32>::import(&bytes),
+            Err(ExportError::InvalidOperation)
+        );
+    }
+}
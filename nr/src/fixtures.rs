@@ -0,0 +1,25 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Data structures shared by the unit tests of several modules.
+
+use crate::Dispatch;
+
+/// A counter that writes add to. Reads and writes return its value.
+#[derive(Clone, Default)]
+pub(crate) struct Counter(pub(crate) u64);
+
+impl Dispatch for Counter {
+    type ReadOperation = ();
+    type WriteOperation = u64;
+    type Response = u64;
+
+    fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+        self.0
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        self.0 += op;
+        self.0
+    }
+}
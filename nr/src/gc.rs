@@ -0,0 +1,283 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Policies that decide what a replica does while it waits for garbage
+//! collection to free up space on a full log.
+
+use crate::ids::{LogOffset, ReplicaId};
+
+/// State of the shared log passed to a [GcHelpPolicy](trait.GcHelpPolicy.html)
+/// when a replica has to wait for space on the log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GcContext {
+    /// Identifier of the waiting replica.
+    pub replica: ReplicaId,
+
+    /// Number of times the replica checked for space so far during this wait,
+    /// starting at one.
+    pub iteration: usize,
+
+    /// Logical offset of the head of the log.
+    pub head: LogOffset,
+
+    /// Logical offset of the tail of the log.
+    pub tail: LogOffset,
+
+    /// Identifier of the replica that is furthest behind on the log, i.e., the
+    /// one that holds up garbage collection.
+    pub slowest: ReplicaId,
+}
+
+/// What a replica does next while it waits for space on the log; returned by
+/// [GcHelpPolicy::on_log_full](trait.GcHelpPolicy.html#tymethod.on_log_full).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HelpAction {
+    /// Execute outstanding operations on the log against the waiting replica
+    /// (so that it isn't the one holding up garbage collection) and check
+    /// again.
+    Exec,
+
+    /// Like `Exec`, but spin for the given number of iterations before checking
+    /// again. Reduces contention on the log while another replica catches up.
+    Backoff(usize),
+
+    /// Stop waiting. An append gives up without adding its operations to the
+    /// log; the operations stay with their threads and are appended by a later
+    /// round of flat combining. A replica advancing the head stops trying to
+    /// free up more space.
+    Error,
+}
+
+/// Decides how a replica helps garbage collection when the shared log is full,
+/// i.e., when it waits for other replicas to execute entries so that the head
+/// of the log can be moved forward. Install one with
+/// [`Replica::set_gc_policy`](struct.Replica.html#method.set_gc_policy).
+///
+/// The policy is consulted by the combiner of the replica once per round of
+/// waiting, both in `append` and while advancing the head of the log.
+pub trait GcHelpPolicy {
+    /// The log is full; returns what the replica should do next.
+    fn on_log_full(&self, ctx: GcContext) -> HelpAction;
+}
+
+/// Keeps executing the log against the waiting replica until there is space.
+/// This is the default policy; it never gives up.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecSelf;
+
+impl GcHelpPolicy for ExecSelf {
+    fn on_log_full(&self, _ctx: GcContext) -> HelpAction {
+        HelpAction::Exec
+    }
+}
+
+/// Executes the log against the waiting replica and then spins for an
+/// exponentially growing number of iterations, up to `max_spins`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Backoff {
+    /// Upper bound for the number of iterations to spin for in one round.
+    pub max_spins: usize,
+}
+
+impl GcHelpPolicy for Backoff {
+    fn on_log_full(&self, ctx: GcContext) -> HelpAction {
+        let spins = 1usize
+            .checked_shl(ctx.iteration.min(63) as u32)
+            .unwrap_or(usize::MAX);
+        HelpAction::Backoff(spins.min(self.max_spins))
+    }
+}
+
+/// Gives up as soon as the log is full, so that the threads of the replica
+/// see the backpressure instead of the combiner waiting on a stalled replica.
+/// Combine with `Replica::execute_mut_until` to bound how long a thread waits
+/// for its operation.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorOnFull;
+
+impl GcHelpPolicy for ErrorOnFull {
+    fn on_log_full(&self, _ctx: GcContext) -> HelpAction {
+        HelpAction::Error
+    }
+}
+
+/// Passed to the handler of a [StallHandler](struct.StallHandler.html) once a
+/// replica waited long enough for space on the log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AppendStall {
+    /// Identifier of the waiting replica.
+    pub replica: ReplicaId,
+
+    /// Identifier of the replica that is furthest behind on the log, i.e., the
+    /// one to help (e.g., with `Replica::help_sync`).
+    pub slowest: ReplicaId,
+
+    /// Number of times the replica checked for space so far during this wait.
+    pub iterations: usize,
+
+    /// Number of entries between the head and the tail of the log.
+    pub occupancy: usize,
+}
+
+impl From<GcContext> for AppendStall {
+    fn from(ctx: GcContext) -> AppendStall {
+        AppendStall {
+            replica: ctx.replica,
+            slowest: ctx.slowest,
+            iterations: ctx.iteration,
+            occupancy: ctx.tail.get() - ctx.head.get(),
+        }
+    }
+}
+
+/// Backs off exponentially (like [Backoff](struct.Backoff.html)) while the log
+/// is full, and once a replica waited `threshold` rounds, lets `handler`
+/// decide what to do in every further round. The handler can help the replica
+/// holding up garbage collection catch up instead of waiting for its threads
+/// to do so, which may take long if they are idle or descheduled.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{Dispatch, HelpAction, Log, Replica, StallHandler};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::new(1));
+/// let (one, two) = (Replica::<Data>::new(&log), Replica::<Data>::new(&log));
+///
+/// // No thread ever uses `two`; `one` executes the log against it instead of
+/// // waiting for it forever.
+/// let other = two.clone();
+/// one.set_gc_policy(Arc::new(StallHandler::new(4, 1 << 10, move |stall| {
+///     assert_eq!(stall.slowest, other.id());
+///     other.help_sync().unwrap();
+///     HelpAction::Exec
+/// })));
+///
+/// let idx = one.register().unwrap();
+/// for _i in 0..4 * log.capacity() {
+///     one.execute_mut(1, idx).unwrap();
+/// }
+/// ```
+pub struct StallHandler<F> {
+    /// Number of rounds to back off for before calling `handler`.
+    threshold: usize,
+
+    /// Upper bound for the number of iterations to spin for in one round.
+    max_spins: usize,
+
+    /// Decides what to do once the replica waited `threshold` rounds.
+    handler: F,
+}
+
+impl<F> StallHandler<F>
+where
+    F: Fn(AppendStall) -> HelpAction,
+{
+    /// Creates a policy that backs off (spinning for at most `max_spins`
+    /// iterations per round) for `threshold - 1` rounds and then calls
+    /// `handler` in every round.
+    ///
+    /// # Panics
+    /// If `threshold` is zero.
+    pub fn new(threshold: usize, max_spins: usize, handler: F) -> StallHandler<F> {
+        assert!(threshold > 0, "The threshold must be at least one round.");
+        StallHandler {
+            threshold,
+            max_spins,
+            handler,
+        }
+    }
+}
+
+impl<F> GcHelpPolicy for StallHandler<F>
+where
+    F: Fn(AppendStall) -> HelpAction,
+{
+    fn on_log_full(&self, ctx: GcContext) -> HelpAction {
+        if ctx.iteration >= self.threshold {
+            return (self.handler)(AppendStall::from(ctx));
+        }
+
+        Backoff {
+            max_spins: self.max_spins,
+        }
+        .on_log_full(ctx)
+    }
+}
+
+impl<F> core::fmt::Debug for StallHandler<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "StallHandler {{ threshold: {}, max_spins: {} }}",
+            self.threshold, self.max_spins
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ctx(iteration: usize) -> GcContext {
+        GcContext {
+            replica: ReplicaId::new(1),
+            iteration,
+            head: LogOffset::new(0),
+            tail: LogOffset::new(1024),
+            slowest: ReplicaId::new(2),
+        }
+    }
+
+    // Tests that the backoff grows exponentially and is capped at `max_spins`.
+    #[test]
+    fn test_gc_backoff() {
+        let p = Backoff { max_spins: 100 };
+        assert_eq!(p.on_log_full(ctx(1)), HelpAction::Backoff(2));
+        assert_eq!(p.on_log_full(ctx(4)), HelpAction::Backoff(16));
+        assert_eq!(p.on_log_full(ctx(7)), HelpAction::Backoff(100));
+        assert_eq!(p.on_log_full(ctx(1000)), HelpAction::Backoff(100));
+    }
+
+    // Tests that the handler only gets to decide once the threshold is reached,
+    // and that the policy backs off before.
+    #[test]
+    fn test_gc_stall_handler() {
+        let p = StallHandler::new(3, 100, |stall: AppendStall| {
+            assert_eq!(stall.slowest, ReplicaId::new(2));
+            assert_eq!(stall.occupancy, 1024);
+            HelpAction::Error
+        });
+        assert_eq!(p.on_log_full(ctx(1)), HelpAction::Backoff(2));
+        assert_eq!(p.on_log_full(ctx(2)), HelpAction::Backoff(4));
+        assert_eq!(p.on_log_full(ctx(3)), HelpAction::Error);
+        assert_eq!(p.on_log_full(ctx(1000)), HelpAction::Error);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Distinct types for the identifiers and offsets passed around by the log and
+//! the replicas, so that mixing them up doesn't compile.
+
+use core::fmt;
+
+/// Implements the conversions shared by all identifiers.
+macro_rules! identifier {
+    ($name:ident) => {
+        impl $name {
+            /// Creates the identifier from its raw value.
+            pub const fn new(raw: usize) -> $name {
+                $name(raw)
+            }
+
+            /// Returns the raw value of the identifier.
+            pub const fn get(self) -> usize {
+                self.0
+            }
+        }
+
+        impl From<$name> for usize {
+            fn from(id: $name) -> usize {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+/// Identifies a replica registered with a [Log](struct.Log.html). The log hands
+/// these out when replicas register, starting at one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ReplicaId(usize);
+
+identifier!(ReplicaId);
+
+impl ReplicaId {
+    /// Position of the replica in the per-replica arrays of the log.
+    #[inline(always)]
+    pub(crate) fn index(self) -> usize {
+        self.0 - 1
+    }
+}
+
+/// Identifies a thread registered with a [Replica](struct.Replica.html). The
+/// replica hands these out (wrapped in a
+/// [ReplicaToken](struct.ReplicaToken.html)) when threads register, starting
+/// at one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ThreadId(usize);
+
+identifier!(ThreadId);
+
+impl ThreadId {
+    /// Position of the thread in the per-thread arrays of the replica.
+    #[inline(always)]
+    pub(crate) fn index(self) -> usize {
+        self.0 - 1
+    }
+}
+
+/// A logical offset on a [Log](struct.Log.html), i.e., the number of entries
+/// appended to the log before the entry at this offset. Logical offsets keep
+/// growing as the log wraps around.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LogOffset(usize);
+
+identifier!(LogOffset);
+
+/// Identifies a write operation a thread submitted with
+/// [`Replica::submit`](struct.Replica.html#method.submit), to match it with its
+/// response from [`Replica::poll`](struct.Replica.html#method.poll). Increases
+/// with every operation the thread issues on the replica.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OpId(usize);
+
+identifier!(OpId);
+
+/// Where a write operation on the log came from, passed to
+/// [`Dispatch::dispatch_mut_ctx`](trait.Dispatch.html#method.dispatch_mut_ctx).
+/// Every replica executing the operation sees the same origin.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OpOrigin {
+    /// The replica whose combiner appended the operation to the log.
+    pub replica: ReplicaId,
+
+    /// The logical offset of the operation on the log. Unique for every
+    /// operation, and increasing in the order replicas execute them.
+    pub offset: LogOffset,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that identifiers starting at one map to the first slot of the
+    // arrays they index.
+    #[test]
+    fn test_ids_index() {
+        assert_eq!(ReplicaId::new(1).index(), 0);
+        assert_eq!(ThreadId::new(256).index(), 255);
+        assert_eq!(usize::from(LogOffset::new(7)), 7);
+    }
+}
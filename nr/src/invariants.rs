@@ -0,0 +1,42 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Checks for the invariants the log and the replica rely on. They are
+//! `debug_assert!`s that are only compiled in with the `invariant-checks`
+//! feature, so that debug builds of downstream crates don't pay for them by
+//! default.
+//!
+//! Every check has a name, which is part of the panic message:
+//!
+//! - `ltail_in_log`: a replica's local tail is within `[head, tail]` of the
+//!   log whenever the replica publishes it.
+//! - `ctail_in_log`: the completed tail never passes the tail of the log.
+//! - `head_behind_tail`: the head of the log never passes its tail.
+//! - `entry_dead_before_reuse`: an entry is only overwritten once every
+//!   replica executed the operation that was on it before (i.e., once the head
+//!   moved past it).
+//! - `lmask_matches_ltail`: a replica's alive mask is the parity of the pass
+//!   over the log of its local tail; it flips exactly when the replica wraps
+//!   around.
+//! - `combiner_exclusive`: only the owner of the combiner lock of a replica
+//!   touches its data structure and the thread contexts on its behalf.
+//! - `batch_order`: the pointers into a thread context are ordered
+//!   (`head <= comb <= tail`) and at most a batch apart.
+
+/// Panics with the name of invariant `$name` if `$cond` doesn't hold, in debug
+/// builds with the `invariant-checks` feature.
+macro_rules! invariant {
+    ($name:ident, $cond:expr) => {
+        invariant!($name, $cond, "")
+    };
+    ($name:ident, $cond:expr, $($arg:tt)+) => {
+        if cfg!(feature = "invariant-checks") {
+            debug_assert!(
+                $cond,
+                "invariant `{}` violated: {}",
+                stringify!($name),
+                format_args!($($arg)+)
+            );
+        }
+    };
+}
@@ -69,8 +69,11 @@
     feature = "unstable",
     feature(new_uninit, get_mut_unchecked, negative_impls)
 )]
+#![cfg_attr(feature = "deadlock-detection", feature(backtrace))]
+#![cfg_attr(feature = "size-checks", feature(const_panic))]
+#![cfg_attr(feature = "pmem", feature(asm))]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 extern crate alloc;
@@ -84,13 +87,101 @@ extern crate log as logging;
 #[macro_use]
 extern crate static_assertions;
 
+// Defines `invariant!`, so it has to come before the modules that use it.
+#[macro_use]
+mod invariants;
+
+pub mod adapters;
+#[cfg(feature = "stats")]
+mod advisor;
+mod affinity;
+mod api;
+mod borrowed;
+mod brand;
+mod cancel;
+mod capabilities;
+#[cfg(feature = "std")]
+mod coalesce;
 mod context;
+#[cfg(feature = "erased")]
+mod erased;
+#[cfg(feature = "export")]
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod gc;
+mod ids;
+#[cfg(feature = "deadlock-detection")]
+mod lockdep;
 mod log;
+mod metrics;
+mod nested;
+#[cfg(feature = "pmem")]
+mod pmem;
+mod published;
+#[cfg(feature = "std")]
+mod ratelimit;
+mod readlog;
+#[cfg(feature = "std")]
+pub mod recovery;
+#[cfg(feature = "std")]
+pub mod replay;
 mod replica;
+#[cfg(feature = "multi-ring")]
+mod rings;
 pub mod rwlock;
-
-pub use crate::log::{Log, MAX_REPLICAS_PER_LOG};
-pub use replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
+mod snapshot;
+mod subreplica;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+mod topology;
+mod wire;
+
+pub use crate::log::{
+    Compactable, Log, LogConfig, LogCorruption, LogError, LogIterator, LogLayout,
+    MAX_REPLICAS_PER_LOG,
+};
+#[cfg(feature = "delta")]
+pub use crate::log::{DeltaCodec, IdentityCodec};
+#[cfg(feature = "std")]
+pub use crate::log::{LogOptions, HUGE_PAGE_SIZE};
+#[cfg(feature = "stats")]
+pub use advisor::{Bottleneck, SizingAdvice};
+pub use affinity::set_current_node;
+pub use api::ReplicaApi;
+pub use borrowed::{DispatchRef, ReadRef};
+pub use brand::{BrandedReplica, BrandedToken};
+pub use cancel::{CancellationToken, Cancelled};
+pub use capabilities::{capabilities, Capabilities, Features};
+#[cfg(feature = "erased")]
+pub use erased::{Erased, ErasedOp, OpCodec};
+#[cfg(feature = "export")]
+pub use export::{ExportError, ExportedEntry, EXPORT_VERSION};
+pub use gc::{
+    AppendStall, Backoff, ErrorOnFull, ExecSelf, GcContext, GcHelpPolicy, HelpAction, StallHandler,
+};
+pub use ids::{LogOffset, OpId, OpOrigin, ReplicaId, ThreadId};
+pub use metrics::{ReplicaMetrics, ReplicaObserver};
+pub use nested::Nested;
+#[cfg(feature = "std")]
+pub use ratelimit::{RateLimit, Throttled};
+pub use readlog::{DispatchReadMut, ReadCtx};
+pub use replica::{
+    CombinerConfig, OverflowPolicy, ParkStrategy, QuiesceReport, Replica, ReplicaError,
+    ReplicaToken, RunReport, SlotTaken, Timeout, VersionToken, Work, WouldBlock,
+    MAX_THREADS_PER_REPLICA,
+};
+#[cfg(feature = "multi-ring")]
+pub use rings::{MultiRingLog, OpSize};
+pub use snapshot::{Checkpoint, Snapshot};
+pub use subreplica::SubReplica;
+#[cfg(feature = "std")]
+pub use topology::{
+    current_numa_node, numa_nodes, AffinityGuard, AffinityManager, Combiners, NodeReplicated,
+    NodeToken, SysfsAffinity, TopologyError,
+};
+pub use wire::{ImportGap, WireEntry};
 
 use core::fmt::Debug;
 
@@ -110,12 +201,21 @@ pub trait Dispatch {
     /// A write operation. When executed against the data structure, an operation of
     /// this type is allowed to mutate state. The library ensures that this is done so
     /// in a thread-safe manner.
+    ///
+    /// Operations can own heap memory (e.g., `String` keys). The shared log keeps a
+    /// clone of every operation until its entry is overwritten or the log is dropped.
     type WriteOperation: Sized + Clone + PartialEq + Debug + Send;
 
     /// The type on the value returned by the data structure when a `ReadOperation` or a
     /// `WriteOperation` successfully executes against it.
     type Response: Sized + Clone;
 
+    /// Upper bound on the size of `Response` in bytes. Responses are copied
+    /// around between the combiner and the threads of a replica, so large ones
+    /// slow down every operation. Only checked (at compile time) with the
+    /// `size-checks` feature.
+    const MAX_RESPONSE_SIZE: usize = 64;
+
     /// Method on the data structure that allows a read-only operation to be
     /// executed against it.
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response;
@@ -123,6 +223,21 @@ pub trait Dispatch {
     /// Method on the data structure that allows a write operation to be
     /// executed against it.
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response;
+
+    /// Same as `dispatch_mut`, but also gets the origin of the operation: the
+    /// replica that appended it and its offset on the log. Replicas execute
+    /// operations through this method; by default, it ignores `origin` and
+    /// calls `dispatch_mut` without it.
+    ///
+    /// Every replica executes the operations on the log in the same order and
+    /// passes them the same origin, so data structures that resolve conflicts
+    /// between writers (e.g., last-writer-wins registers) can use it to break
+    /// ties and still end up in the same state on every replica. The origin
+    /// depends on the interleaving of threads, though: running the same
+    /// operations again can put them at other offsets.
+    fn dispatch_mut_ctx(&mut self, op: Self::WriteOperation, _origin: OpOrigin) -> Self::Response {
+        self.dispatch_mut(op)
+    }
 }
 
 #[cfg(doctest)]
@@ -0,0 +1,227 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Detects combiner locks of different replicas being acquired in inconsistent
+//! order, which can deadlock (e.g., if `dispatch_mut` of one replicated data
+//! structure issues operations against another one and vice versa).
+//!
+//! Every thread tracks the combiner locks it holds. Whenever a thread tries to
+//! acquire a combiner lock while holding others, the order is recorded in a
+//! global graph. If the graph already says that the lock being acquired was
+//! held while (possibly indirectly) acquiring one of the locks the thread holds,
+//! we panic. Enabled with the `deadlock-detection` feature.
+
+use core::cell::{RefCell, UnsafeCell};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use std::backtrace::Backtrace;
+use std::format;
+use std::thread_local;
+use std::vec;
+use std::vec::Vec;
+
+/// Identifier handed to the next replica. Identifiers are never reused (unlike
+/// addresses), so dropping a replica doesn't confuse the graph.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    /// Combiner locks held by the current thread, in acquisition order.
+    static HELD: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+/// `from` was held while `to` was acquired.
+struct Edge {
+    from: usize,
+    to: usize,
+    backtrace: Backtrace,
+}
+
+/// All edges ever observed, protected by a spinlock. Only touched on nested
+/// acquisitions, so contention isn't a concern.
+struct Graph {
+    lock: AtomicBool,
+    edges: UnsafeCell<Vec<Edge>>,
+}
+
+unsafe impl Sync for Graph {}
+
+static GRAPH: Graph = Graph {
+    lock: AtomicBool::new(false),
+    edges: UnsafeCell::new(Vec::new()),
+};
+
+impl Graph {
+    fn with<R, F: FnOnce(&mut Vec<Edge>) -> R>(&self, f: F) -> R {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+
+        let r = f(unsafe { &mut *self.edges.get() });
+        self.lock.store(false, Ordering::Release);
+        r
+    }
+}
+
+/// Returns the first edge of a path from `from` to `to`, if there is one.
+fn path(edges: &[Edge], from: usize, to: usize) -> Option<&Edge> {
+    let mut visited = Vec::new();
+    let mut stack: Vec<(usize, Option<usize>)> = vec![(from, None)];
+
+    while let Some((node, first)) = stack.pop() {
+        if visited.contains(&node) {
+            continue;
+        }
+        visited.push(node);
+
+        for (i, e) in edges.iter().enumerate().filter(|(_i, e)| e.from == node) {
+            let first = first.unwrap_or(i);
+            if e.to == to {
+                return Some(&edges[first]);
+            }
+            stack.push((e.to, Some(first)));
+        }
+    }
+
+    None
+}
+
+/// Returns a new identifier for the combiner lock of a replica.
+pub(crate) fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Must be called before the current thread tries to acquire combiner lock `id`.
+/// Panics if doing so can deadlock.
+pub(crate) fn before_acquire(id: usize) {
+    HELD.with(|held| {
+        let held = held.borrow();
+        if held.is_empty() {
+            return;
+        }
+
+        if held.contains(&id) {
+            panic!(
+                "Thread tries to acquire the combiner lock of replica {} which it already holds.\n{}",
+                id,
+                Backtrace::force_capture()
+            );
+        }
+
+        // Don't panic while holding the lock of the graph.
+        let conflict = GRAPH.with(|edges| {
+            for &h in held.iter() {
+                if let Some(e) = path(edges, id, h) {
+                    return Some(format!(
+                        "Inconsistent combiner lock order: acquiring replica {} while holding \
+                         replica {}, but replica {} was held while acquiring replica {} before.\n\n\
+                         Current acquisition:\n{}\n\nPrevious acquisition:\n{}",
+                        id,
+                        h,
+                        e.from,
+                        e.to,
+                        Backtrace::force_capture(),
+                        e.backtrace
+                    ));
+                }
+            }
+
+            for &h in held.iter() {
+                if !edges.iter().any(|e| e.from == h && e.to == id) {
+                    edges.push(Edge {
+                        from: h,
+                        to: id,
+                        backtrace: Backtrace::force_capture(),
+                    });
+                }
+            }
+
+            None
+        });
+
+        if let Some(msg) = conflict {
+            panic!("{}", msg);
+        }
+    });
+}
+
+/// Must be called after the current thread acquired combiner lock `id`.
+pub(crate) fn acquired(id: usize) {
+    HELD.with(|held| held.borrow_mut().push(id));
+}
+
+/// Must be called after the current thread released combiner lock `id`.
+pub(crate) fn released(id: usize) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|&h| h == id) {
+            held.remove(pos);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lock(id: usize) {
+        before_acquire(id);
+        acquired(id);
+    }
+
+    // Tests that acquiring locks in the same order over and over is fine.
+    #[test]
+    fn test_lockdep_consistent_order() {
+        let (a, b, c) = (next_id(), next_id(), next_id());
+        for _i in 0..2 {
+            lock(a);
+            lock(b);
+            lock(c);
+            released(c);
+            released(b);
+            released(a);
+        }
+
+        // Not nested, so there's no order to violate.
+        lock(c);
+        released(c);
+        lock(a);
+        released(a);
+    }
+
+    // Tests that acquiring two locks in the opposite order panics.
+    #[test]
+    #[should_panic(expected = "Inconsistent combiner lock order")]
+    fn test_lockdep_inverted_order() {
+        let (a, b) = (next_id(), next_id());
+        lock(a);
+        lock(b);
+        released(b);
+        released(a);
+
+        lock(b);
+        lock(a);
+    }
+
+    // Tests that a cycle spanning more than two locks is detected.
+    #[test]
+    #[should_panic(expected = "Inconsistent combiner lock order")]
+    fn test_lockdep_transitive() {
+        let (a, b, c) = (next_id(), next_id(), next_id());
+        lock(a);
+        lock(b);
+        released(b);
+        released(a);
+        lock(b);
+        lock(c);
+        released(c);
+        released(b);
+
+        lock(c);
+        lock(a);
+    }
+}
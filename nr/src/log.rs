@@ -2,24 +2,36 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
 use core::default::Default;
 use core::fmt;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
 use core::mem::{align_of, size_of};
-use core::ops::{Drop, FnMut};
-use core::slice::from_raw_parts_mut;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::ops::{Drop, FnMut, Range};
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
 
 use crossbeam_utils::CachePadded;
 
+#[cfg(feature = "stats")]
+use crate::advisor::{SizingAdvice, SizingStats};
+use crate::cancel::{CancellationToken, Cancelled};
 use crate::context::MAX_PENDING_OPS;
+use crate::gc::{ExecSelf, GcContext, GcHelpPolicy, HelpAction};
+use crate::ids::{LogOffset, ReplicaId};
+use crate::metrics::ReplicaObserver;
+#[cfg(feature = "pmem")]
+use crate::pmem::{self, Header};
 use crate::replica::MAX_THREADS_PER_REPLICA;
 
 /// The default size of the shared log in bytes. If constructed using the
 /// default constructor, the log will be these many bytes in size. Currently
 /// set to 32 MiB based on the ASPLOS 2017 paper.
-const DEFAULT_LOG_BYTES: usize = 32 * 1024 * 1024;
+pub(crate) const DEFAULT_LOG_BYTES: usize = 32 * 1024 * 1024;
 const_assert!(DEFAULT_LOG_BYTES >= 1 && (DEFAULT_LOG_BYTES & (DEFAULT_LOG_BYTES - 1) == 0));
 
 /// The maximum number of replicas that can be registered with the log.
@@ -33,18 +45,485 @@ pub const MAX_REPLICAS_PER_LOG: usize = 192;
 /// largest possible append after deciding to perform GC. This largest possible
 /// append is when every thread within a replica has a full batch of writes
 /// to be appended to the shared log.
-const GC_FROM_HEAD: usize = MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA;
+pub(crate) const GC_FROM_HEAD: usize = MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA;
 const_assert!(GC_FROM_HEAD >= 1 && (GC_FROM_HEAD & (GC_FROM_HEAD - 1) == 0));
 
+/// Number of entries `exec()` executes before it publishes its progress to the
+/// replica's local tail. If the replica was holding back the head of the log,
+/// the head is advanced as well, so that a replica that's far behind frees up
+/// space while catching up instead of only once it is done.
+const EXEC_CHUNK: usize = GC_FROM_HEAD;
+const_assert!(EXEC_CHUNK >= 1 && EXEC_CHUNK <= GC_FROM_HEAD);
+
+/// Number of preceding entries an operation is compared against to find the
+/// ones it supersedes, see [Compactable](trait.Compactable.html). Distances
+/// are stored in a `u8` on every entry.
+const COMPACT_WINDOW: usize = 8;
+const_assert!(COMPACT_WINDOW >= 1 && COMPACT_WINDOW <= u8::MAX as usize);
+
 /// Threshold after how many iterations we log a warning for busy spinning loops.
 ///
 /// This helps with debugging to figure out where things may end up blocking.
 /// Should be a power of two to avoid divisions.
 const WARN_THRESHOLD: usize = 1 << 28;
 
-/// An entry that sits on the log. Each entry consists of three fields: The operation to
-/// be performed when a thread reaches this entry on the log, the replica that appended
-/// this operation, and a flag indicating whether this entry is valid.
+/// An append that lost the race for the tail of the log spins for twice as
+/// long as after its previous attempt before retrying, for at most
+/// `1 << MAX_RETRY_BACKOFF` iterations.
+const MAX_RETRY_BACKOFF: usize = 6;
+
+/// Errors that can occur when creating a [Log](struct.Log.html), when
+/// registering a replica against one, or when appending to it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogError {
+    /// The allocator failed to provide memory for the log.
+    OutOfMemory,
+
+    /// The requested size of the log can't be represented as a valid
+    /// memory layout.
+    InvalidSize,
+
+    /// The maximum number of replicas (`MAX_REPLICAS_PER_LOG`) are already
+    /// registered with the log.
+    TooManyReplicas,
+
+    /// The log was garbage collected past the offset a replica was supposed to
+    /// start executing from (e.g., because the checkpoint it was created from
+    /// is too old).
+    OffsetReclaimed,
+
+    /// The region passed to `Log::recover` doesn't hold a log of this
+    /// operation type.
+    InvalidRegion,
+
+    /// The region passed to `Log::recover` holds a log, but its metadata or
+    /// entries are inconsistent.
+    CorruptLog(LogCorruption),
+
+    /// The log is full and the replica's [GcHelpPolicy](trait.GcHelpPolicy.html)
+    /// gave up waiting for space.
+    LogFull,
+
+    /// `NodeReplicated::grow_log` can't stop every replica using the log,
+    /// because some were registered with it from outside.
+    ForeignReplicas,
+}
+
+/// Describes how a log found by `Log::recover` is inconsistent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogCorruption {
+    /// The number of entries isn't a power of two or is too small for garbage
+    /// collection.
+    InvalidSize,
+
+    /// The entries extend past the end of the region.
+    SizeOutOfBounds,
+
+    /// There are more live entries between head and tail than fit on the log.
+    TailOutOfBounds,
+
+    /// A live entry doesn't hold an operation.
+    MissingOperation,
+}
+
+/// Size of a cache line in bytes. Entries on the log are aligned to it.
+pub(crate) const CACHE_LINE: usize = 64;
+
+/// Layout of the memory of a [Log](struct.Log.html), returned by `Log::layout`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LogLayout {
+    /// Size of an entry in bytes. Entries are padded to a multiple of the cache
+    /// line size, so operations that are slightly too large double it.
+    pub entry_size: usize,
+
+    /// Number of entries on the log.
+    pub entries: usize,
+
+    /// Size of the log in bytes. Can be larger than requested, as the number
+    /// of entries is rounded up to a power of two.
+    pub bytes: usize,
+}
+
+/// Configuration for a [Log](struct.Log.html) created with `Log::with_config`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LogConfig {
+    /// Size of the log in bytes.
+    bytes: usize,
+
+    /// Whether the memory of the log should be locked into RAM.
+    #[cfg(feature = "std")]
+    lock_memory: bool,
+
+    /// Number of threads that initialize the entries of the log, or zero to
+    /// pick it based on the size of the log.
+    #[cfg(feature = "std")]
+    init_threads: usize,
+
+    /// Where and how the memory of the log is placed.
+    #[cfg(feature = "std")]
+    options: LogOptions,
+}
+
+/// Where and how the memory of a [Log](struct.Log.html) is placed, see
+/// `Log::with_options`. By default, the log is allocated on the heap like any
+/// other memory.
+///
+/// With any option set, the memory of the log is mapped with `mmap` instead
+/// (on Unix; elsewhere the options are ignored). Options that can't be
+/// honored (e.g., because no huge pages are reserved) are logged as a warning
+/// and the log is created without them.
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LogOptions {
+    /// Backs the log with huge pages (`MAP_HUGETLB`), so that replicas scanning
+    /// it don't thrash the TLB. Falls back to transparent huge pages if no huge
+    /// pages are reserved. The log is rounded up to a multiple of
+    /// `HUGE_PAGE_SIZE`.
+    pub hugepages: bool,
+
+    /// Binds the memory of the log to this NUMA node (`mbind`), regardless of
+    /// the threads that initialize it, e.g., the node of the replica that
+    /// appends the most.
+    pub numa_node: Option<usize>,
+
+    /// Populates the page tables of the log when it is mapped
+    /// (`MAP_POPULATE`), rather than page by page while its entries are
+    /// initialized. With `numa_node`, pages are only faulted in once they are
+    /// bound, by initializing the entries.
+    pub prefault: bool,
+}
+
+#[cfg(feature = "std")]
+impl LogOptions {
+    /// Returns true if a log created with these options is mapped with `mmap`.
+    fn maps(&self) -> bool {
+        cfg!(unix) && *self != LogOptions::default()
+    }
+
+    /// Returns the number of bytes mapped for a log of `bytes` bytes.
+    fn mapped_len(&self, bytes: usize) -> Option<usize> {
+        if !self.hugepages {
+            return Some(bytes);
+        }
+        bytes
+            .checked_add(HUGE_PAGE_SIZE - 1)
+            .map(|b| b & !(HUGE_PAGE_SIZE - 1))
+    }
+}
+
+/// Size of the huge pages a log created with `LogOptions::hugepages` is
+/// mapped with.
+#[cfg(feature = "std")]
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+impl LogConfig {
+    /// Creates a configuration for a log of size `bytes` bytes.
+    pub fn new(bytes: usize) -> LogConfig {
+        LogConfig {
+            bytes,
+            #[cfg(feature = "std")]
+            lock_memory: false,
+            #[cfg(feature = "std")]
+            init_threads: 0,
+            #[cfg(feature = "std")]
+            options: LogOptions::default(),
+        }
+    }
+
+    /// Locks the memory of the log into RAM (using `mlock`) once it is
+    /// initialized. This avoids stalls from page faults while replicas
+    /// execute operations from the log.
+    ///
+    /// If the memory can't be locked (e.g., because `RLIMIT_MEMLOCK` is too
+    /// low), a warning is logged and the log is used without locking it. Use
+    /// `Log::is_memory_locked` to find out if locking succeeded.
+    #[cfg(feature = "std")]
+    pub fn lock_memory(mut self, lock: bool) -> LogConfig {
+        self.lock_memory = lock;
+        self
+    }
+
+    /// Sets the number of threads that initialize the entries of the log when
+    /// it is created. By default, logs larger than `INIT_BYTES_PER_THREAD` are
+    /// initialized by several threads (at most one per CPU), which cuts the
+    /// time it takes to create logs of several GiB.
+    ///
+    /// The memory of the log is first touched by these threads, so it can end
+    /// up spread over NUMA nodes. Use one thread to place it on the node of
+    /// the thread creating the log.
+    ///
+    /// # Panics
+    /// If `threads` is zero.
+    #[cfg(feature = "std")]
+    pub fn init_threads(mut self, threads: usize) -> LogConfig {
+        assert!(
+            threads > 0,
+            "Need at least one thread to initialize the log."
+        );
+        self.init_threads = threads;
+        self
+    }
+
+    /// Places the memory of the log as described by `options`.
+    #[cfg(feature = "std")]
+    pub fn options(mut self, options: LogOptions) -> LogConfig {
+        self.options = options;
+        self
+    }
+
+    /// Returns the number of threads that initialize a log of `bytes` bytes
+    /// created with this configuration.
+    #[cfg(feature = "std")]
+    fn threads_for(&self, bytes: usize) -> usize {
+        if self.init_threads > 0 {
+            return self.init_threads;
+        }
+
+        #[cfg(unix)]
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+        #[cfg(not(unix))]
+        let cpus = 1;
+        (bytes / INIT_BYTES_PER_THREAD).max(1).min(cpus)
+    }
+}
+
+/// Logs are initialized by one thread per this many bytes (but at most one per
+/// CPU), unless configured otherwise with `LogConfig::init_threads`.
+#[cfg(feature = "std")]
+pub(crate) const INIT_BYTES_PER_THREAD: usize = 64 * 1024 * 1024;
+
+impl Default for LogConfig {
+    /// Configuration for a log of the default size.
+    fn default() -> Self {
+        LogConfig::new(DEFAULT_LOG_BYTES)
+    }
+}
+
+/// The pages `lock_memory` locked into RAM.
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LockedRange {
+    /// Start of the first locked page.
+    ptr: usize,
+
+    /// Length of the locked pages in bytes, a multiple of the page size.
+    len: usize,
+}
+
+/// Locks the pages that lie entirely within the `len` bytes starting at `ptr`
+/// into RAM. Pages at either end that the memory only partially covers may be
+/// shared with neighbouring allocations and are left alone, since unlocking
+/// them again would also unlock them for their neighbours.
+///
+/// Returns the locked range, which has to be passed to `unlock_memory`. Returns
+/// `None` and logs a warning if the memory couldn't be locked.
+#[cfg(feature = "std")]
+pub(crate) fn lock_memory(ptr: *const u8, len: usize) -> Option<LockedRange> {
+    #[cfg(unix)]
+    {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = (ptr as usize).checked_add(page - 1);
+        let end = (ptr as usize).checked_add(len);
+        if let (Some(start), Some(end)) = (start, end) {
+            let (start, end) = (start & !(page - 1), end & !(page - 1));
+            let range = LockedRange {
+                ptr: start,
+                len: end.saturating_sub(start),
+            };
+
+            if range.len == 0
+                || unsafe { libc::mlock(range.ptr as *const libc::c_void, range.len) == 0 }
+            {
+                return Some(range);
+            }
+        }
+    }
+
+    warn!(
+        "Failed to lock {} bytes at {:p} into memory, continuing without.",
+        len, ptr
+    );
+    None
+}
+
+/// Unlocks exactly the pages that `lock_memory` locked.
+#[cfg(feature = "std")]
+pub(crate) fn unlock_memory(range: LockedRange) {
+    #[cfg(unix)]
+    if range.len > 0 {
+        unsafe {
+            libc::munlock(range.ptr as *const libc::c_void, range.len);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = range;
+}
+
+/// `mbind` policy that only allocates memory on the given nodes.
+#[cfg(all(feature = "std", target_os = "linux"))]
+const MPOL_BIND: libc::c_ulong = 2;
+
+/// `mbind` flag that moves pages that are already faulted in.
+#[cfg(all(feature = "std", target_os = "linux"))]
+const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+
+/// Maps `len` bytes of anonymous memory as described by `options`. Returns
+/// null if the memory can't be mapped at all.
+#[cfg(all(feature = "std", unix))]
+fn map_memory(len: usize, options: &LogOptions) -> *mut u8 {
+    let map = |flags: libc::c_int| unsafe {
+        let mem = libc::mmap(
+            core::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+            -1,
+            0,
+        );
+        if mem == libc::MAP_FAILED {
+            core::ptr::null_mut()
+        } else {
+            mem as *mut u8
+        }
+    };
+
+    #[allow(unused_mut)]
+    let mut flags = 0;
+    #[cfg(target_os = "linux")]
+    if options.prefault && options.numa_node.is_none() {
+        flags |= libc::MAP_POPULATE;
+    }
+
+    #[cfg(target_os = "linux")]
+    let mem = if options.hugepages {
+        let mem = map(flags | libc::MAP_HUGETLB);
+        if !mem.is_null() {
+            mem
+        } else {
+            warn!(
+                "Failed to map {} bytes on huge pages, using transparent huge pages.",
+                len
+            );
+            let mem = map(flags);
+            if !mem.is_null() {
+                unsafe { libc::madvise(mem as *mut libc::c_void, len, libc::MADV_HUGEPAGE) };
+            }
+            mem
+        }
+    } else {
+        map(flags)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let mem = map(flags);
+
+    #[cfg(target_os = "linux")]
+    if let (false, Some(node)) = (mem.is_null(), options.numa_node) {
+        let bits = libc::c_ulong::BITS as usize;
+        let mut mask: Vec<libc::c_ulong> = alloc::vec![0; node / bits + 1];
+        mask[node / bits] |= 1 << (node % bits);
+
+        // The kernel only looks at the first `maxnode - 1` bits of the mask.
+        let bound = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                mem,
+                len,
+                MPOL_BIND,
+                mask.as_ptr(),
+                mask.len() * bits + 1,
+                MPOL_MF_MOVE,
+            ) == 0
+        };
+        if !bound {
+            warn!(
+                "Failed to bind {} bytes at {:p} to NUMA node {}, continuing without.",
+                len, mem, node
+            );
+        }
+    }
+
+    mem
+}
+
+/// Unmaps memory that was previously mapped with `map_memory`.
+#[cfg(all(feature = "std", unix))]
+fn unmap_memory(ptr: *mut u8, len: usize) {
+    unsafe {
+        libc::munmap(ptr as *mut libc::c_void, len);
+    }
+}
+
+/// Determines how operations of type `T` are stored on the [Log](struct.Log.html).
+///
+/// Operations appended to the log in one batch are usually similar (same opcode,
+/// keys with a common prefix, ...). A codec can store each of them relative to
+/// the operation preceding it in the batch, shrinking the entries on the log and
+/// the amount of memory touched while replaying it. `prev` is `None` for the
+/// first operation of a batch.
+///
+/// Custom codecs can be used with the `delta` feature, see `Log::with_codec`.
+pub trait DeltaCodec<T> {
+    /// The representation of an operation on the log.
+    type Encoded: Sized + Clone;
+
+    /// Whether `encode` and `decode` make use of `prev`. If not, the log doesn't
+    /// have to keep the previous operation around while executing entries.
+    const DELTA: bool = true;
+
+    /// Encodes `op`, which was appended to the log right after `prev`.
+    fn encode(prev: Option<&T>, op: &T) -> Self::Encoded;
+
+    /// Decodes an operation that was encoded relative to `prev`.
+    fn decode(prev: Option<&T>, encoded: &Self::Encoded) -> T;
+}
+
+/// The default codec; stores operations on the log as they are.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IdentityCodec;
+
+impl<T> DeltaCodec<T> for IdentityCodec
+where
+    T: Sized + Clone,
+{
+    type Encoded = T;
+
+    const DELTA: bool = false;
+
+    #[inline(always)]
+    fn encode(_prev: Option<&T>, op: &T) -> T {
+        op.clone()
+    }
+
+    #[inline(always)]
+    fn decode(_prev: Option<&T>, encoded: &T) -> T {
+        encoded.clone()
+    }
+}
+
+/// Implemented by operations that can make earlier operations on the log
+/// redundant, e.g., `Put(key, v)` overwrites what an earlier `Put(key, w)`
+/// did. Enabled with `Log::enable_compaction`.
+///
+/// The first replica to execute an entry compares it against the few entries
+/// before it and marks the ones it supersedes. Replicas that execute the
+/// entries later (i.e., replicas that are lagging behind) skip the marked
+/// entries of other replicas instead of dispatching them. A replica always
+/// executes its own operations, since it needs their responses.
+pub trait Compactable {
+    /// Returns true if executing `self` after `older` leaves the data structure
+    /// in the same state as executing `self` alone, no matter which operations
+    /// were executed in between. The operations in between also have to
+    /// return the same responses either way; an operation that returns the
+    /// value `older` stored (e.g., a `Put` that returns the previous value)
+    /// can't be elided.
+    fn supersedes(&self, older: &Self) -> bool;
+}
+
+/// An entry that sits on the log. Each entry consists of five fields: The (encoded)
+/// operation to be performed when a thread reaches this entry on the log, the replica
+/// that appended this operation, a flag indicating whether the operation is encoded
+/// relative to the previous entry, a flag indicating whether this entry is valid, and
+/// whether a later entry supersedes it.
 ///
 /// `T` is the type on the operation - typically an enum class containing opcodes as well as
 /// arguments. It is required that this type be sized and cloneable.
@@ -60,8 +539,16 @@ where
     /// Identifies the replica that issued the above operation.
     replica: usize,
 
+    /// Indicates whether the operation has to be decoded relative to the
+    /// operation in the previous entry.
+    delta: bool,
+
     /// Indicates whether this entry represents a valid operation when on the log.
     alivef: AtomicBool,
+
+    /// Distance to a later entry whose operation supersedes this one, or 0.
+    /// Cleared before the entry is published, see `Compactable`.
+    superseded: AtomicU8,
 }
 
 /// A log of operations that is typically accessed by multiple
@@ -75,32 +562,70 @@ where
 /// since the replica last called `exec()` will be executed by invoking the
 /// supplied closure for each one of them.
 ///
-/// Accepts one generic type parameter; `T` defines the type of operations and
+/// Accepts two generic type parameters; `T` defines the type of operations and
 /// their arguments that will go on the log and would typically be an enum
-/// class.
+/// class. `C` is the [DeltaCodec](trait.DeltaCodec.html) that determines how
+/// operations are stored on the log.
 ///
 /// This struct is aligned to 64 bytes optimizing cache access.\
 ///
+/// # Memory ordering
+/// The protocol doesn't rely on x86's total store order; it only needs these
+/// pairs of release and acquire operations:
+///
+/// - An entry is published by storing its `alivef` with release ordering after
+///   writing the operation; `exec()` loads `alivef` with acquire ordering before
+///   reading the operation.
+/// - A replica is done reading entries once it stores its local tail past them
+///   with release ordering. GC loads local tails with acquire ordering and moves
+///   the head with release ordering; `append()` loads the head with acquire
+///   ordering before it overwrites entries.
+/// - `ctail` is advanced with release ordering and read with acquire ordering.
+///
+/// Everything else (e.g., reserving entries by moving the tail) only needs to
+/// be atomic.
+///
 /// # Note
 /// As a client, typically there is no need to call any methods on the Log aside
 /// from `new`. Only in the rare circumstance someone would implement their own
 /// Replica would it be necessary to call any of the Log's methods.
 #[repr(align(64))]
-pub struct Log<'a, T>
+pub struct Log<'a, T, C = IdentityCodec>
 where
     T: Sized + Clone,
+    C: DeltaCodec<T>,
 {
-    /// Raw pointer to the actual underlying log. Required for dealloc.
-    rawp: *mut u8,
+    /// Raw pointer to the actual underlying log. Required for dealloc. Null if
+    /// the log doesn't own its memory (e.g., a log in persistent memory).
+    rawp: Cell<*mut u8>,
 
     /// Size of the underlying log in bytes. Required for dealloc.
-    rawb: usize,
+    rawb: Cell<usize>,
+
+    /// The pages of `rawp` that are locked into memory. Required for dealloc.
+    #[cfg(feature = "std")]
+    locked_range: Cell<Option<LockedRange>>,
 
-    /// The maximum number of entries that can be held inside the log.
-    size: usize,
+    /// How `rawp` (and the memory of a grown log) is placed. Required for
+    /// dealloc.
+    #[cfg(feature = "std")]
+    options: LogOptions,
 
-    /// A reference to the actual log. Nothing but a slice of entries.
-    slog: &'a [Cell<Entry<T>>],
+    /// The maximum number of entries that can be held inside the log. Only
+    /// changes when the log grows, after `slog` was updated; loaded before
+    /// `slog`, so it never exceeds the number of entries `slog` points to.
+    size: AtomicUsize,
+
+    /// Points to the actual log, `size` entries. Use `slog()` to access it.
+    slog: AtomicPtr<Cell<Entry<C::Encoded>>>,
+
+    /// `rawp`, `rawb` and the pages of it that are locked into memory for each
+    /// time the log grew. The old entries are only freed along with the log,
+    /// since a `LogIterator` may still be reading them.
+    retired: RefCell<Vec<(*mut u8, usize, Option<LockedRange>)>>,
+
+    /// The entries live as long as the log (e.g., in a persistent region).
+    _entries: PhantomData<&'a [Cell<Entry<C::Encoded>>]>,
 
     /// Logical index into the above slice at which the log starts.
     head: CachePadded<AtomicUsize>,
@@ -128,27 +653,54 @@ where
     /// because replicas make independent progress over the log, so we need to
     /// track log wrap-arounds for each of them separately.
     lmasks: [CachePadded<Cell<bool>>; MAX_REPLICAS_PER_LOG],
+
+    /// Invoked with the logical offsets of entries that the head moved past.
+    /// Installed with `on_reclaim()`.
+    reclaim: Option<Box<dyn Fn(Range<LogOffset>) + Send + Sync>>,
+
+    /// `Compactable::supersedes` of `T`, if compaction was enabled with
+    /// `enable_compaction()`.
+    supersedes: Option<fn(&T, &T) -> bool>,
+
+    /// What appends ran into during the warm-up window. Used by `advise()`.
+    #[cfg(feature = "stats")]
+    stats: SizingStats,
+
+    /// Metadata of the log if it lives in persistent memory.
+    #[cfg(feature = "pmem")]
+    pmem: Option<&'a Header>,
 }
 
-impl<'a, T> fmt::Debug for Log<'a, T>
+impl<'a, T, C> fmt::Debug for Log<'a, T, C>
 where
     T: Sized + Clone,
+    C: DeltaCodec<T>,
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Log")
             .field("head", &self.tail)
             .field("tail", &self.head)
-            .field("size", &self.size)
+            .field("size", &self.size())
             .finish()
     }
 }
 
 /// The Log is Send. The *mut u8 (`rawp`) is never dereferenced.
-unsafe impl<'a, T> Send for Log<'a, T> where T: Sized + Clone {}
+unsafe impl<'a, T, C> Send for Log<'a, T, C>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+}
 
 /// The Log is Sync. We know this because: `head` and `tail` are atomic variables, `append()`
 /// reserves entries using a CAS, and exec() does not concurrently mutate entries on the log.
-unsafe impl<'a, T> Sync for Log<'a, T> where T: Sized + Clone {}
+unsafe impl<'a, T, C> Sync for Log<'a, T, C>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+}
 
 impl<'a, T> Log<'a, T>
 where
@@ -170,16 +722,374 @@ where
     ///     Invalid,
     /// }
     ///
-    /// // Creates a 1 Mega Byte sized log.
-    /// let l = Log::<Operation>::new(1 * 1024 * 1024);
+    /// // Creates a 1 Mega Byte sized log.
+    /// let l = Log::<Operation>::new(1 * 1024 * 1024);
+    /// ```
+    ///
+    /// This method also allocates memory for the log upfront. No further allocations
+    /// will be performed once this method returns.
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_new` to handle
+    /// allocation failures gracefully.
+    pub fn new<'b>(bytes: usize) -> Log<'b, T> {
+        match Log::try_new(bytes) {
+            Ok(log) => log,
+            Err(LogError::InvalidSize) => {
+                panic!("Alignment error while allocating the shared log!")
+            }
+            Err(_) => panic!("Failed to allocate memory for the shared log!"),
+        }
+    }
+
+    /// Constructs and returns a log of size `bytes` bytes. Similar to `new`,
+    /// but returns an error instead of panicking if the memory for the log
+    /// can't be allocated.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Log, LogError};
+    ///
+    /// // Operation type that will go onto the log.
+    /// #[derive(Clone)]
+    /// enum Operation {
+    ///     Read,
+    ///     Write(u64),
+    /// }
+    ///
+    /// // Creates a 1 Mega Byte sized log.
+    /// let l = Log::<Operation>::try_new(1 * 1024 * 1024);
+    /// assert!(l.is_ok());
+    ///
+    /// // A log this large can never be allocated.
+    /// let l = Log::<Operation>::try_new(usize::MAX);
+    /// assert_eq!(l.err(), Some(LogError::InvalidSize));
+    /// ```
+    pub fn try_new<'b>(bytes: usize) -> Result<Log<'b, T>, LogError> {
+        Log::try_with_config(LogConfig::new(bytes))
+    }
+
+    /// Constructs and returns a log as described by `config`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Log, LogConfig};
+    ///
+    /// // Operation type that will go onto the log.
+    /// #[derive(Clone)]
+    /// enum Operation {
+    ///     Read,
+    ///     Write(u64),
+    /// }
+    ///
+    /// // Creates a 1 Mega Byte sized log.
+    /// let l = Log::<Operation>::with_config(LogConfig::new(1 * 1024 * 1024));
+    /// ```
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_with_config` to
+    /// handle allocation failures gracefully.
+    pub fn with_config<'b>(config: LogConfig) -> Log<'b, T> {
+        match Log::try_with_config(config) {
+            Ok(log) => log,
+            Err(LogError::InvalidSize) => {
+                panic!("Alignment error while allocating the shared log!")
+            }
+            Err(_) => panic!("Failed to allocate memory for the shared log!"),
+        }
+    }
+
+    /// Similar to `with_config`, but returns an error instead of panicking if
+    /// the memory for the log can't be allocated.
+    pub fn try_with_config<'b>(config: LogConfig) -> Result<Log<'b, T>, LogError> {
+        Log::try_create(config)
+    }
+
+    /// Constructs and returns a log of size `bytes` bytes, with its memory
+    /// placed as described by `options`. Shorthand for `with_config` with
+    /// `LogConfig::options`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Log, LogOptions};
+    ///
+    /// // Creates a 32 MiB log on huge pages, bound to NUMA node 0.
+    /// let options = LogOptions {
+    ///     hugepages: true,
+    ///     numa_node: Some(0),
+    ///     prefault: true,
+    /// };
+    /// let l = Log::<u64>::with_options(32 * 1024 * 1024, options);
+    /// ```
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_with_options` to
+    /// handle allocation failures gracefully.
+    #[cfg(feature = "std")]
+    pub fn with_options<'b>(bytes: usize, options: LogOptions) -> Log<'b, T> {
+        Log::with_config(LogConfig::new(bytes).options(options))
+    }
+
+    /// Similar to `with_options`, but returns an error instead of panicking if
+    /// the memory for the log can't be allocated.
+    #[cfg(feature = "std")]
+    pub fn try_with_options<'b>(bytes: usize, options: LogOptions) -> Result<Log<'b, T>, LogError> {
+        Log::try_with_config(LogConfig::new(bytes).options(options))
+    }
+}
+
+#[cfg(feature = "pmem")]
+impl<'a, T> Log<'a, T>
+where
+    T: Sized + Copy,
+{
+    /// Creates a log in `region`, which is expected to be mapped from persistent
+    /// memory (e.g., a file on a DAX file system). Operations are flushed to the
+    /// region before replicas execute them, and the log can be recovered with
+    /// `recover` after a crash. Previous contents of the region are discarded.
+    ///
+    /// The log takes up the largest power of two number of entries that fits
+    /// into `region` after a cache line of metadata. Operations are persisted
+    /// as they are in memory, so `T` must not refer to memory outside of the
+    /// region (e.g., with pointers or references).
+    ///
+    /// Fails with `LogError::InvalidSize` if `region` isn't aligned to a cache
+    /// line or too small to hold a log.
+    pub fn persistent(region: &'a mut [u8]) -> Result<Log<'a, T>, LogError> {
+        let (header, raw) = Log::<T>::split_region(region, None)?;
+
+        // Make sure a crash during initialization doesn't leave behind a region
+        // that looks like a valid log.
+        header.magic.store(0, Ordering::SeqCst);
+        pmem::persist(header as *const Header as *const u8, CACHE_LINE);
+
+        for e in raw.iter_mut() {
+            unsafe {
+                ::core::ptr::write(
+                    e,
+                    Cell::new(Entry {
+                        operation: None,
+                        replica: 0usize,
+                        delta: false,
+                        alivef: AtomicBool::new(false),
+                        superseded: AtomicU8::new(0),
+                    }),
+                );
+            }
+        }
+        pmem::persist(
+            raw.as_ptr() as *const u8,
+            raw.len() * Log::<T>::entry_size(),
+        );
+
+        header
+            .entry_size
+            .store(Log::<T>::entry_size(), Ordering::SeqCst);
+        header.entries.store(raw.len(), Ordering::SeqCst);
+        header.head.store(0, Ordering::SeqCst);
+        header.tail.store(0, Ordering::SeqCst);
+        pmem::persist(header as *const Header as *const u8, CACHE_LINE);
+        header.magic.store(pmem::MAGIC, Ordering::SeqCst);
+        pmem::persist(header as *const Header as *const u8, CACHE_LINE);
+
+        Ok(Log::from_region(header, raw))
+    }
+
+    /// Recovers a log created with `persistent` from `region` after a crash or
+    /// restart. The log holds the longest sequence of operations, starting at
+    /// its head, that made it to persistent memory; operations whose append
+    /// didn't complete before the crash are dropped.
+    ///
+    /// Replicas registered with the log before the crash are gone. If the head
+    /// of the log is still at the beginning, new replicas created with
+    /// `Replica::new` replay all operations. Otherwise, they have to be created
+    /// with `Replica::from_checkpoint` from a checkpoint that wasn't garbage
+    /// collected yet.
+    ///
+    /// Fails with `LogError::InvalidRegion` if `region` doesn't hold a log of
+    /// this operation type.
+    pub fn recover(region: &'a mut [u8]) -> Result<Log<'a, T>, LogError> {
+        // The region might have been written by another (possibly buggy)
+        // process, so don't trust anything in it before checking it.
+        let header = Log::<T>::region_header(region)?;
+        if header.magic.load(Ordering::SeqCst) != pmem::MAGIC
+            || header.entry_size.load(Ordering::SeqCst) != Log::<T>::entry_size()
+        {
+            return Err(LogError::InvalidRegion);
+        }
+
+        let size = header.entries.load(Ordering::SeqCst);
+        if !size.is_power_of_two() || size < 2 * GC_FROM_HEAD {
+            return Err(LogError::CorruptLog(LogCorruption::InvalidSize));
+        }
+        let (header, raw) = Log::<T>::split_region(region, Some(size))
+            .map_err(|_| LogError::CorruptLog(LogCorruption::SizeOutOfBounds))?;
+
+        // The durable tail can lag behind the head (e.g., if appends raced with
+        // garbage collection), but there can't ever be more live entries than
+        // fit on the log.
+        let head = header.head.load(Ordering::SeqCst);
+        let durable = header.tail.load(Ordering::SeqCst);
+        if head.checked_add(size).map_or(true, |end| durable > end) {
+            return Err(LogError::CorruptLog(LogCorruption::TailOutOfBounds));
+        }
+
+        // An entry is alive if its flag matches the pass over the log that it
+        // belongs to; the flag flips every time the log wraps around. Stop at
+        // the first entry that didn't make it.
+        let alive = |i: usize| (i / size) % 2 == 0;
+        let mut tail = head;
+        while tail < durable {
+            let e = raw[tail & (size - 1)].as_ptr();
+            if unsafe { (*e).alivef.load(Ordering::Relaxed) } != alive(tail) {
+                break;
+            }
+            if unsafe { (*e).operation.is_none() } {
+                return Err(LogError::CorruptLog(LogCorruption::MissingOperation));
+            }
+
+            // The replica that issued the operation is gone. Make sure a new
+            // replica doesn't mistake it for one of its own.
+            unsafe { (*e).replica = 0 };
+            tail += 1;
+        }
+
+        // Entries after a hole might have been persisted. Mark them as dead, so
+        // that appends and replicas don't mistake them for new operations.
+        for i in tail..head + size {
+            let e = raw[i & (size - 1)].as_ptr();
+            unsafe { (*e).alivef.store(!alive(i), Ordering::Relaxed) };
+        }
+        pmem::persist(raw.as_ptr() as *const u8, size * Log::<T>::entry_size());
+        header.tail.store(tail, Ordering::SeqCst);
+        pmem::persist(header as *const Header as *const u8, CACHE_LINE);
+
+        let log = Log::from_region(header, raw);
+        log.head.store(head, Ordering::SeqCst);
+        log.tail.store(tail, Ordering::SeqCst);
+        log.ctail.store(tail, Ordering::SeqCst);
+        Ok(log)
+    }
+
+    /// Returns the metadata at the start of a persistent region.
+    fn region_header(region: &[u8]) -> Result<&Header, LogError> {
+        if region.as_ptr() as usize % CACHE_LINE != 0 || region.len() < size_of::<Header>() {
+            return Err(LogError::InvalidSize);
+        }
+        Ok(unsafe { &*(region.as_ptr() as *const Header) })
+    }
+
+    /// Splits a persistent region into the metadata and the entries of a log.
+    /// The log takes up `entries` entries if given, otherwise as many as fit.
+    #[allow(clippy::type_complexity)]
+    fn split_region(
+        region: &'a mut [u8],
+        entries: Option<usize>,
+    ) -> Result<(&'a Header, &'a mut [Cell<Entry<T>>]), LogError> {
+        Log::<T>::region_header(region)?;
+
+        let header_size = size_of::<Header>();
+        let fit = (region.len() - header_size) / Log::<T>::entry_size();
+        let num = match entries {
+            Some(num) if num > fit => return Err(LogError::InvalidSize),
+            Some(num) => num,
+            None if fit < 2 * GC_FROM_HEAD => return Err(LogError::InvalidSize),
+            None if !fit.is_power_of_two() => fit.next_power_of_two() / 2,
+            None => fit,
+        };
+
+        let mem = region.as_mut_ptr();
+        unsafe {
+            Ok((
+                &*(mem as *const Header),
+                from_raw_parts_mut(mem.add(header_size) as *mut Cell<Entry<T>>, num),
+            ))
+        }
+    }
+
+    /// Creates a log around the entries of a persistent region.
+    fn from_region(header: &'a Header, slog: &'a [Cell<Entry<T>>]) -> Log<'a, T> {
+        let mut log = Log::from_entries(
+            core::ptr::null_mut(),
+            slog.len() * Log::<T>::entry_size(),
+            slog,
+        );
+        log.pmem = Some(header);
+        log
+    }
+}
+
+impl<'a, T, C> Log<'a, T, C>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+    /// Constructs and returns a log as described by `config` that stores
+    /// operations using the codec `C`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{DeltaCodec, Log, LogConfig};
+    ///
+    /// // Operations are mostly increasing keys; store the difference between them.
+    /// struct KeyDelta;
+    ///
+    /// impl DeltaCodec<u64> for KeyDelta {
+    ///     type Encoded = u64;
+    ///
+    ///     fn encode(prev: Option<&u64>, op: &u64) -> u64 {
+    ///         op.wrapping_sub(*prev.unwrap_or(&0))
+    ///     }
+    ///
+    ///     fn decode(prev: Option<&u64>, encoded: &u64) -> u64 {
+    ///         encoded.wrapping_add(*prev.unwrap_or(&0))
+    ///     }
+    /// }
+    ///
+    /// let l = Log::<u64, KeyDelta>::with_codec(LogConfig::new(1 * 1024 * 1024));
+    /// ```
+    ///
+    /// # Panics
+    /// If the memory for the log can't be allocated. Use `try_with_codec` to
+    /// handle allocation failures gracefully.
+    #[cfg(feature = "delta")]
+    pub fn with_codec<'b>(config: LogConfig) -> Log<'b, T, C> {
+        match Log::try_with_codec(config) {
+            Ok(log) => log,
+            Err(LogError::InvalidSize) => {
+                panic!("Alignment error while allocating the shared log!")
+            }
+            Err(_) => panic!("Failed to allocate memory for the shared log!"),
+        }
+    }
+
+    /// Similar to `with_codec`, but returns an error instead of panicking if
+    /// the memory for the log can't be allocated.
+    #[cfg(feature = "delta")]
+    pub fn try_with_codec<'b>(config: LogConfig) -> Result<Log<'b, T, C>, LogError> {
+        Log::try_create(config)
+    }
+
+    /// Returns the layout of a log created with `config`, without allocating it.
+    /// Useful to find out how large entries end up being for an operation type
+    /// and how many of them the log holds.
+    ///
+    /// # Example
+    ///
     /// ```
+    /// use node_replication::{Log, LogConfig};
     ///
-    /// This method also allocates memory for the log upfront. No further allocations
-    /// will be performed once this method returns.
-    pub fn new<'b>(bytes: usize) -> Log<'b, T> {
-        // Calculate the number of entries that will go into the log, and retrieve a
-        // slice to it from the allocated region of memory.
-        let mut num = bytes / Log::<T>::entry_size();
+    /// let layout = Log::<u64>::layout(LogConfig::new(1024 * 1024)).unwrap();
+    /// assert_eq!(layout.entry_size, 64);
+    /// assert_eq!(layout.entries, 16 * 1024);
+    /// ```
+    pub fn layout(config: LogConfig) -> Result<LogLayout, LogError> {
+        // Calculate the number of entries that will go into the log.
+        let mut num = config.bytes / Log::<T, C>::entry_size();
 
         // Make sure the log is large enough to allow for periodic garbage collection.
         if num < 2 * GC_FROM_HEAD {
@@ -193,55 +1103,282 @@ where
             num = num.checked_next_power_of_two().unwrap_or(2 * GC_FROM_HEAD)
         };
 
-        // Now that we have the actual number of entries, allocate the log.
-        let b = num * Log::<T>::entry_size();
-        let mem = unsafe {
-            alloc(
-                Layout::from_size_align(b, align_of::<Cell<Entry<T>>>())
-                    .expect("Alignment error while allocating the shared log!"),
-            )
+        let bytes = num
+            .checked_mul(Log::<T, C>::entry_size())
+            .ok_or(LogError::InvalidSize)?;
+
+        Ok(LogLayout {
+            entry_size: Log::<T, C>::entry_size(),
+            entries: num,
+            bytes,
+        })
+    }
+
+    /// Allocates and initializes a log as described by `config`.
+    fn try_create<'b>(config: LogConfig) -> Result<Log<'b, T, C>, LogError> {
+        #[cfg(feature = "size-checks")]
+        #[allow(clippy::let_unit_value)]
+        let _fits = Log::<T, C>::ENTRY_FITS_CACHE_LINE;
+
+        let LogLayout {
+            entry_size,
+            entries: num,
+            bytes: b,
+        } = Log::<T, C>::layout(config)?;
+        if entry_size > CACHE_LINE {
+            warn!(
+                "Log entries take up {} bytes, which is more than a cache line.",
+                entry_size
+            );
+        }
+
+        #[cfg(feature = "std")]
+        let threads = config.threads_for(b);
+        #[cfg(not(feature = "std"))]
+        let threads = 1;
+        #[cfg(feature = "std")]
+        let raw = Log::<T, C>::alloc_entries(b, num, threads, &config.options, |_i| false)?;
+        #[cfg(not(feature = "std"))]
+        let raw = Log::<T, C>::alloc_entries(b, num, threads, |_i| false)?;
+        let mem = raw.as_ptr() as *mut u8;
+
+        #[allow(unused_mut)]
+        let mut log = Log::from_entries(mem, b, raw);
+        #[cfg(feature = "std")]
+        {
+            log.options = config.options;
+        }
+
+        // Allocating wrote to every entry, so all pages of the log are faulted
+        // in by now and locking them doesn't have to fault them in again.
+        #[cfg(feature = "std")]
+        {
+            let range = config.lock_memory.then(|| lock_memory(mem, b)).flatten();
+            log.locked_range = Cell::new(range);
+        }
+
+        Ok(log)
+    }
+
+    /// Allocates `bytes` bytes for `num` empty entries, which are initialized by
+    /// `threads` threads. The flag of the entry at index `i` is initialized to
+    /// `alive(i)`. The memory is placed as described by `options`.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn alloc_entries<'b>(
+        bytes: usize,
+        num: usize,
+        threads: usize,
+        #[cfg(feature = "std")] options: &LogOptions,
+        alive: impl Fn(usize) -> bool + Sync,
+    ) -> Result<&'b [Cell<Entry<C::Encoded>>], LogError> {
+        // Now that we have the actual number of entries, allocate the log and
+        // retrieve a slice to it from the allocated region of memory.
+        let layout = Layout::from_size_align(bytes, align_of::<Cell<Entry<C::Encoded>>>())
+            .map_err(|_| LogError::InvalidSize)?;
+        #[cfg(all(feature = "std", unix))]
+        let mem = if options.maps() {
+            let len = options.mapped_len(bytes).ok_or(LogError::InvalidSize)?;
+            map_memory(len, options)
+        } else {
+            unsafe { alloc(layout) }
         };
+        #[cfg(not(all(feature = "std", unix)))]
+        let mem = unsafe { alloc(layout) };
         if mem.is_null() {
-            panic!("Failed to allocate memory for the shared log!");
+            return Err(LogError::OutOfMemory);
         }
-        let raw = unsafe { from_raw_parts_mut(mem as *mut Cell<Entry<T>>, num) };
+        let raw = unsafe { from_raw_parts_mut(mem as *mut Cell<Entry<C::Encoded>>, num) };
 
         // Initialize all log entries by calling the default constructor.
-        for e in raw.iter_mut() {
-            unsafe {
-                ::core::ptr::write(
-                    e,
-                    Cell::new(Entry {
-                        operation: None,
-                        replica: 0usize,
-                        alivef: AtomicBool::new(false),
-                    }),
-                );
+        // `first` is the index of the first entry in `entries`.
+        let init = |first: usize, entries: &mut [Cell<Entry<C::Encoded>>]| {
+            for (i, e) in entries.iter_mut().enumerate() {
+                unsafe {
+                    ::core::ptr::write(
+                        e,
+                        Cell::new(Entry {
+                            operation: None,
+                            replica: 0usize,
+                            delta: false,
+                            alivef: AtomicBool::new(alive(first + i)),
+                            superseded: AtomicU8::new(0),
+                        }),
+                    );
+                }
             }
+        };
+
+        #[cfg(feature = "std")]
+        if threads > 1 {
+            // Entries aren't necessarily `Send`, but every thread only writes
+            // empty entries into its own chunk of the (not yet shared) log.
+            struct Chunk(usize, usize, usize);
+            unsafe impl Send for Chunk {}
+
+            let chunk = (num + threads - 1) / threads;
+            let init = &init;
+            crossbeam_utils::thread::scope(|s| {
+                for first in (0..num).step_by(chunk) {
+                    let c = Chunk(first, core::cmp::min(chunk, num - first), mem as usize);
+                    s.spawn(move |_| {
+                        let entries = unsafe {
+                            from_raw_parts_mut((c.2 as *mut Cell<Entry<C::Encoded>>).add(c.0), c.1)
+                        };
+                        init(c.0, entries);
+                    });
+                }
+            })
+            .expect("Failed to initialize the log.");
+            return Ok(raw);
         }
 
+        init(0, raw);
+        Ok(raw)
+    }
+
+    /// Creates a log around the (initialized) entries in `slog`.
+    fn from_entries(
+        rawp: *mut u8,
+        rawb: usize,
+        slog: &'a [Cell<Entry<C::Encoded>>],
+    ) -> Log<'a, T, C> {
         #[allow(clippy::declare_interior_mutable_const)]
         const LMASK_DEFAULT: CachePadded<Cell<bool>> = CachePadded::new(Cell::new(true));
         #[allow(clippy::declare_interior_mutable_const)]
         const LTAIL_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::new(0));
 
         Log {
-            rawp: mem,
-            rawb: b,
-            size: num,
-            slog: raw,
+            rawp: Cell::new(rawp),
+            rawb: Cell::new(rawb),
+            #[cfg(feature = "std")]
+            locked_range: Cell::new(None),
+            #[cfg(feature = "std")]
+            options: LogOptions::default(),
+            size: AtomicUsize::new(slog.len()),
+            slog: AtomicPtr::new(slog.as_ptr() as *mut _),
+            retired: RefCell::new(Vec::new()),
+            _entries: PhantomData,
             head: CachePadded::new(AtomicUsize::new(0usize)),
             tail: CachePadded::new(AtomicUsize::new(0usize)),
             ctail: CachePadded::new(AtomicUsize::new(0usize)),
             ltails: [LTAIL_DEFAULT; MAX_REPLICAS_PER_LOG],
             next: CachePadded::new(AtomicUsize::new(1usize)),
             lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
+            reclaim: None,
+            supersedes: None,
+            #[cfg(feature = "stats")]
+            stats: SizingStats::new(),
+            #[cfg(feature = "pmem")]
+            pmem: None,
         }
     }
 
+    /// Fails to compile if an entry doesn't fit in a single cache line.
+    #[cfg(feature = "size-checks")]
+    const ENTRY_FITS_CACHE_LINE: () = assert!(
+        size_of::<Cell<Entry<C::Encoded>>>() <= CACHE_LINE,
+        "Log entries take up more than a cache line, use a smaller operation type."
+    );
+
     /// Returns the size of an entry in bytes.
-    fn entry_size() -> usize {
-        size_of::<Cell<Entry<T>>>()
+    pub(crate) fn entry_size() -> usize {
+        size_of::<Cell<Entry<C::Encoded>>>()
+    }
+
+    /// Returns true if the memory of the log is locked into RAM. This is only
+    /// the case if it was requested with `LogConfig::lock_memory` and locking
+    /// succeeded.
+    #[cfg(feature = "std")]
+    pub fn is_memory_locked(&self) -> bool {
+        self.locked_range.get().is_some()
+    }
+
+    /// Returns the number of entries on the log that haven't been garbage
+    /// collected yet, i.e., that some replica still has to execute or that
+    /// weren't reclaimed since.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.load(Ordering::Relaxed).saturating_sub(head)
+    }
+
+    /// Returns true if there are no entries on the log.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of entries the log can hold.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.size()
+    }
+
+    /// Returns the fraction of the log taken up by entries, between 0 and 1.
+    /// Appends start waiting for garbage collection before the log is
+    /// completely full.
+    pub fn utilization(&self) -> f64 {
+        self.len() as f64 / self.capacity() as f64
+    }
+
+    /// Waits until every replica registered with the log executed the
+    /// operations up to the completed tail of the log at the time of the call
+    /// (i.e., every operation some replica finished executing), and returns
+    /// that offset.
+    ///
+    /// `help` is called repeatedly while waiting. It has to keep the replicas
+    /// that are behind making progress, e.g., by calling `Replica::sync` on
+    /// replicas without active threads; otherwise this method never returns.
+    pub fn wait_for_ctail_on_all<F: FnMut()>(&self, mut help: F) -> LogOffset {
+        let ctail = self.get_ctail();
+        while !self.is_synced(ctail) {
+            help();
+            spin_loop();
+        }
+
+        LogOffset::new(ctail)
+    }
+
+    /// Recommends a size for the log based on what appends ran into during the
+    /// first two minutes after the log was created (or last grew): how many
+    /// operations were appended, how far replicas fell behind, how often
+    /// appends waited for garbage collection, and for which replica.
+    ///
+    /// Can be called any time; the advice is only final once
+    /// `SizingAdvice::complete` is set. `NodeReplicated::grow_log_as_advised`
+    /// applies it to replicas that are in use.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Bottleneck, Log};
+    ///
+    /// let log = Log::<u64>::new(1024 * 1024);
+    /// let advice = log.advise();
+    /// assert_eq!(advice.bottleneck, Bottleneck::None);
+    /// assert_eq!(advice.suggested_bytes, 1024 * 1024);
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn advise(&self) -> SizingAdvice {
+        self.stats.advise(
+            self.capacity() * Log::<T, C>::entry_size(),
+            Log::<T, C>::entry_size(),
+            self.registered(),
+        )
+    }
+
+    /// Installs a callback that is invoked with the range of logical offsets
+    /// every time the head of the log moves past entries. No replica will read
+    /// these entries again, so resources associated with them (e.g., buffers
+    /// referenced by an operation) can be freed right away instead of waiting
+    /// for the entries to be overwritten.
+    ///
+    /// Every offset is reported exactly once, though ranges can be reported
+    /// out of order if several replicas advance the head concurrently. The
+    /// callback is invoked by whichever replica advanced the head, on its
+    /// critical path, and must not issue operations against the log.
+    pub fn on_reclaim<F: Fn(Range<LogOffset>) + Send + Sync + 'static>(&mut self, f: F) {
+        self.reclaim = Some(Box::new(f));
     }
 
     /// Registers a replica with the log. Returns an identifier that the replica
@@ -267,7 +1404,7 @@ where
     /// // to the log, and execute these operations.
     /// let idx = l.register().expect("Failed to register with the Log.");
     /// ```
-    pub(crate) fn register(&self) -> Option<usize> {
+    pub(crate) fn register(&self) -> Option<ReplicaId> {
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let n = self.next.load(Ordering::Relaxed);
@@ -285,8 +1422,38 @@ where
                 continue;
             };
 
-            return Some(n);
+            return Some(ReplicaId::new(n));
+        }
+    }
+
+    /// Registers a replica that starts executing the log at logical offset
+    /// `offset` instead of at the beginning, e.g., because its state was restored
+    /// from a checkpoint that already includes all operations before `offset`.
+    ///
+    /// Fails if the entries at `offset` were already garbage collected (or were
+    /// never appended). The slot on the log is used up in that case.
+    pub(crate) fn register_at(&self, offset: LogOffset) -> Result<ReplicaId, LogError> {
+        let idx = self.register().ok_or(LogError::TooManyReplicas)?;
+        let offset = offset.get();
+
+        // The alive mask flips every time a replica wraps around the log.
+        self.lmasks[idx.index()].set((offset / self.size()) % 2 == 0);
+        self.ltails[idx.index()].store(offset, Ordering::SeqCst);
+
+        // Now that our local tail is visible, the head can't move past it anymore.
+        if offset < self.head.load(Ordering::SeqCst) || offset > self.tail.load(Ordering::SeqCst) {
+            // Don't hold back garbage collection with a slot that will never be used.
+            self.ltails[idx.index()].store(usize::MAX, Ordering::SeqCst);
+            return Err(LogError::OffsetReclaimed);
         }
+
+        Ok(idx)
+    }
+
+    /// Gives up the slot of replica `idx` on the log (e.g., because the replica
+    /// was dropped), so that garbage collection doesn't wait for it anymore.
+    pub(crate) fn retire(&self, idx: ReplicaId) {
+        self.ltails[idx.index()].store(usize::MAX, Ordering::Release);
     }
 
     /// Adds a batch of operations to the shared log.
@@ -314,7 +1481,7 @@ where
     /// // it might encounter operations added in by another replica/thread.
     /// // This closure allows us to consume those operations. `id` identifies
     /// // the replica that added in those operations.
-    /// let f = |op: Operation, id: usize| {
+    /// let f = |op: Operation, id: ReplicaId| {
     ///     match(op) {
     ///         Operation::Read => println!("Read by {}", id),
     ///         Operation::Write(x) => println!("Write({}) by {}", x, id),
@@ -338,10 +1505,72 @@ where
     /// used by the benchmarking code.
     #[inline(always)]
     #[doc(hidden)]
-    pub fn append<F: FnMut(T, usize)>(&self, ops: &[T], idx: usize, mut s: F) {
+    pub fn append<F: FnMut(T, ReplicaId)>(&self, ops: &[T], idx: ReplicaId, mut s: F) {
+        let s = |o: T, i: ReplicaId, _offset: LogOffset| s(o, i);
+        let r = self.append_observed(ops, idx, s, &(), &ExecSelf);
+        debug_assert!(r.is_ok(), "ExecSelf never gives up waiting for GC.");
+    }
+
+    /// Same as `append()`, but stops waiting for space on the log once `token`
+    /// is cancelled, without appending any of the operations. Otherwise, returns
+    /// the logical offset of the first appended operation.
+    #[inline(always)]
+    #[doc(hidden)]
+    pub fn append_cancellable<F: FnMut(T, ReplicaId)>(
+        &self,
+        ops: &[T],
+        idx: ReplicaId,
+        mut s: F,
+        token: &CancellationToken,
+    ) -> Result<LogOffset, Cancelled> {
+        let s = |o: T, i: ReplicaId, _offset: LogOffset| s(o, i);
+        self.append_observed(ops, idx, s, &(), token)
+            .map_err(|_| Cancelled::Requested { enqueued: false })
+    }
+
+    /// Same as `append()`, but reports retries and GC stalls to `o` and asks
+    /// `policy` what to do while the log is full. Fails with `LogError::LogFull`
+    /// without appending anything if `policy` gives up. Otherwise, returns the
+    /// logical offset of the first appended operation; the rest follow it.
+    ///
+    /// `s` is also passed the logical offset of the entry it executes.
+    #[inline(always)]
+    pub(crate) fn append_observed<
+        F: FnMut(T, ReplicaId, LogOffset),
+        O: ReplicaObserver + ?Sized,
+        P: GcHelpPolicy + ?Sized,
+    >(
+        &self,
+        ops: &[T],
+        idx: ReplicaId,
+        s: F,
+        o: &O,
+        policy: &P,
+    ) -> Result<LogOffset, LogError> {
+        self.append_as(ops, idx, idx, s, o, policy)
+    }
+
+    /// Same as `append_observed()`, but marks the entries as appended by
+    /// replica `origin` instead of `idx` (e.g., because they were copied from
+    /// another log). `idx` is still the replica that helps with GC.
+    #[inline(always)]
+    pub(crate) fn append_as<
+        F: FnMut(T, ReplicaId, LogOffset),
+        O: ReplicaObserver + ?Sized,
+        P: GcHelpPolicy + ?Sized,
+    >(
+        &self,
+        ops: &[T],
+        idx: ReplicaId,
+        origin: ReplicaId,
+        mut s: F,
+        o: &O,
+        policy: &P,
+    ) -> Result<LogOffset, LogError> {
         let nops = ops.len();
         let mut iteration = 1;
         let mut waitgc = 1;
+        let mut retries = 0;
 
         // Keep trying to reserve entries and add operations to the log until
         // we succeed in doing so.
@@ -356,14 +1585,17 @@ where
             }
             iteration += 1;
 
+            // Acquire pairs with the release in `move_head()`: once we see the head
+            // past an entry, every replica is done reading it and we can overwrite it.
             let tail = self.tail.load(Ordering::Relaxed);
-            let head = self.head.load(Ordering::Relaxed);
+            let head = self.head.load(Ordering::Acquire);
 
             // If there are fewer than `GC_FROM_HEAD` entries on the log, then just
-            // try again. The replica that reserved entry (h + self.size - GC_FROM_HEAD)
-            // is currently trying to advance the head of the log. Keep refreshing the
-            // replica against the log to make sure that it isn't deadlocking GC.
-            if tail > head + self.size - GC_FROM_HEAD {
+            // try again. The replica that reserved entry (h + self.size() - GC_FROM_HEAD)
+            // is currently trying to advance the head of the log. By default, keep
+            // refreshing the replica against the log to make sure that it isn't
+            // deadlocking GC.
+            if tail > head + self.size() - GC_FROM_HEAD {
                 if waitgc % WARN_THRESHOLD == 0 {
                     warn!(
                         "append(ops.len()={}, {}) takes too many iterations ({}) waiting for gc...",
@@ -372,17 +1604,25 @@ where
                         waitgc,
                     );
                 }
+                if !self.help_gc(idx, waitgc, &mut s, policy) {
+                    o.on_gc_stall(waitgc);
+                    return Err(LogError::LogFull);
+                }
                 waitgc += 1;
-                self.exec(idx, &mut s);
                 continue;
             }
 
             // If on adding in the above entries there would be fewer than `GC_FROM_HEAD`
             // entries left on the log, then we need to advance the head of the log.
-            let mut advance = false;
-            if tail + nops > head + self.size - GC_FROM_HEAD {
-                advance = true
-            };
+            // Try to do so before reserving them: once the tail is past that mark,
+            // every other append waits for the head to move. Only if replicas have
+            // yet to execute enough entries, advance the head after appending.
+            let mut head = head;
+            if tail + nops > head + self.size() - GC_FROM_HEAD {
+                self.try_advance_head();
+                head = self.head.load(Ordering::Acquire);
+            }
+            let advance = tail + nops > head + self.size() - GC_FROM_HEAD;
 
             // Try reserving slots for the operations. If that fails, then restart
             // from the beginning of this loop.
@@ -393,35 +1633,117 @@ where
                 Ordering::Acquire,
             ) != Ok(tail)
             {
+                o.on_append_retry();
+                #[cfg(feature = "stats")]
+                self.stats.on_retry();
+
+                // Give the appends that won the race a chance to get out of
+                // the way instead of hammering the tail.
+                for _i in 0..1 << retries {
+                    spin_loop();
+                }
+                retries = (retries + 1).min(MAX_RETRY_BACKOFF);
                 continue;
             };
 
+            #[cfg(feature = "stats")]
+            self.stats.on_append(nops, tail + nops - head);
+
+            if waitgc > 1 {
+                o.on_gc_stall(waitgc - 1);
+            }
+
             // Successfully reserved entries on the shared log. Add the operations in.
             for (i, op) in ops.iter().enumerate().take(nops) {
-                let e = self.slog[self.index(tail + i)].as_ptr();
-                let mut m = self.lmasks[idx - 1].get();
+                invariant!(
+                    entry_dead_before_reuse,
+                    tail + i < self.head.load(Ordering::Acquire) + self.size(),
+                    "entry {} reserved before the head moved past {}",
+                    tail + i,
+                    tail + i - self.size()
+                );
+                let e = self.entry(tail + i);
+                let mut m = self.lmasks[idx.index()].get();
 
                 // This entry was just reserved so it should be dead (!= m). However, if
                 // the log has wrapped around, then the alive mask has flipped. In this
                 // case, we flip the mask we were originally going to write into the
-                // allocated entry. We cannot flip lmasks[idx - 1] because this replica
+                // allocated entry. We cannot flip lmasks[idx.index()] because this replica
                 // might still need to execute a few entries before the wrap around.
                 if unsafe { (*e).alivef.load(Ordering::Relaxed) == m } {
                     m = !m;
                 }
 
-                unsafe { (*e).operation = Some(op.clone()) };
-                unsafe { (*e).replica = idx };
+                // Only operations within this batch are guaranteed to be next to each
+                // other on the log, so the first one is never encoded as a delta.
+                let prev = if C::DELTA && i > 0 {
+                    Some(&ops[i - 1])
+                } else {
+                    None
+                };
+
+                unsafe { (*e).operation = Some(C::encode(prev, op)) };
+                unsafe { (*e).delta = prev.is_some() };
+                unsafe { (*e).replica = origin.get() };
+                unsafe { (*e).superseded.store(0, Ordering::Relaxed) };
                 unsafe { (*e).alivef.store(m, Ordering::Release) };
             }
 
+            // Replicas only see the operations once they're durable.
+            #[cfg(feature = "pmem")]
+            if let Some(header) = self.pmem {
+                for i in 0..nops {
+                    let e = self.entry(tail + i);
+                    pmem::flush(e as *const u8, Log::<T, C>::entry_size());
+                }
+                pmem::fence();
+                header.persist_tail(tail + nops);
+            }
+
             // If needed, advance the head of the log forward to make room on the log.
             if advance {
-                self.advance_head(idx, &mut s);
+                self.advance_head(idx, &mut s, o, policy);
             }
 
-            return;
+            return Ok(LogOffset::new(tail));
+        }
+    }
+
+    /// Asks `policy` what replica `idx` should do while it waits for space on
+    /// the log for the `iteration`th time, and does it. Returns false if
+    /// `policy` gave up.
+    fn help_gc<F: FnMut(T, ReplicaId, LogOffset), P: GcHelpPolicy + ?Sized>(
+        &self,
+        idx: ReplicaId,
+        iteration: usize,
+        s: &mut F,
+        policy: &P,
+    ) -> bool {
+        let ctx = GcContext {
+            replica: idx,
+            iteration,
+            head: LogOffset::new(self.head.load(Ordering::Relaxed)),
+            tail: LogOffset::new(self.tail.load(Ordering::Relaxed)),
+            slowest: self.slowest_replica(),
+        };
+
+        #[cfg(feature = "stats")]
+        if iteration == 1 {
+            self.stats.on_stall(ctx.slowest);
+        }
+
+        match policy.on_log_full(ctx) {
+            HelpAction::Exec => self.exec_traced(idx, s),
+            HelpAction::Backoff(spins) => {
+                self.exec_traced(idx, s);
+                for _i in 0..spins {
+                    spin_loop();
+                }
+            }
+            HelpAction::Error => return false,
         }
+
+        true
     }
 
     /// Executes a passed in closure (`d`) on all operations starting from
@@ -444,7 +1766,7 @@ where
     /// let idx = l.register().expect("Failed to register with the Log.");
     /// let ops = [Operation::Write(100), Operation::Read];
     ///
-    /// let f = |op: Operation, id: usize| {
+    /// let f = |op: Operation, id: ReplicaId| {
     ///     match(op) {
     ///         Operation::Read => println!("Read by {}", id),
     ///         Operation::Write(x) => println!("Write({}) by {}", x, id),
@@ -455,7 +1777,7 @@ where
     /// // This closure is executed on every operation appended to the
     /// // since the last call to `exec()` by this replica/thread.
     /// let mut d = 0;
-    /// let mut g = |op: Operation, id: usize| {
+    /// let mut g = |op: Operation, id: ReplicaId| {
     ///     match(op) {
     ///         // The write happened before the read.
     ///         Operation::Read => assert_eq!(100, d),
@@ -467,10 +1789,18 @@ where
     ///
     /// The passed in closure is expected to take in two arguments: The operation
     /// from the shared log to be executed and the replica that issued it.
+    #[cfg(any(test, feature = "std"))]
+    #[inline(always)]
+    pub(crate) fn exec<F: FnMut(T, ReplicaId)>(&self, idx: ReplicaId, d: &mut F) {
+        self.exec_traced(idx, &mut |o: T, i: ReplicaId, _offset: LogOffset| d(o, i));
+    }
+
+    /// Same as `exec()`, but also passes the logical offset of every executed
+    /// entry to `d`.
     #[inline(always)]
-    pub(crate) fn exec<F: FnMut(T, usize)>(&self, idx: usize, d: &mut F) {
+    pub(crate) fn exec_traced<F: FnMut(T, ReplicaId, LogOffset)>(&self, idx: ReplicaId, d: &mut F) {
         // Load the logical log offset from which we must execute operations.
-        let ltail = self.ltails[idx - 1].load(Ordering::Relaxed);
+        let ltail = self.ltails[idx.index()].load(Ordering::Relaxed);
 
         // Check if we have any work to do by comparing our local tail with the log's
         // global tail. If they're equal, then we're done here and can simply return.
@@ -481,7 +1811,8 @@ where
 
         let h = self.head.load(Ordering::Relaxed);
 
-        // Make sure we're within the shared log. If we aren't, then panic.
+        // Make sure we're within the shared log. If we aren't, then panic. Replicas
+        // check `is_within` before executing the log, so this is a bug.
         if ltail > gtail || ltail < h {
             panic!("Local tail not within the shared log!")
         };
@@ -489,49 +1820,264 @@ where
         // Execute all operations from the passed in offset to the shared log's tail. Check if
         // the entry is live first; we could have a replica that has reserved entries, but not
         // filled them into the log yet.
+        // Entries past the completed tail haven't been executed by any other
+        // replica yet; this replica marks the ones that are superseded.
+        let fresh = self.ctail.load(Ordering::Relaxed);
+        let mut window: [Option<(usize, T)>; COMPACT_WINDOW] = Default::default();
+
+        let mut published = ltail;
+        let mut prev: Option<T> = None;
         for i in ltail..gtail {
             let mut iteration = 1;
-            let e = self.slog[self.index(i)].as_ptr();
+            let e = self.entry(i);
 
-            while unsafe { (*e).alivef.load(Ordering::Acquire) != self.lmasks[idx - 1].get() } {
+            while unsafe { (*e).alivef.load(Ordering::Acquire) != self.lmasks[idx.index()].get() } {
                 if iteration % WARN_THRESHOLD == 0 {
                     warn!(
                         "alivef not being set for self.index(i={}) = {} (self.lmasks[{}] is {})...",
                         i,
                         self.index(i),
-                        idx - 1,
-                        self.lmasks[idx - 1].get()
+                        idx.index(),
+                        self.lmasks[idx.index()].get()
                     );
                 }
                 iteration += 1;
             }
 
-            unsafe { d((*e).operation.as_ref().unwrap().clone(), (*e).replica) };
+            let op = unsafe {
+                let prev = if (*e).delta { prev.as_ref() } else { None };
+                debug_assert!(prev.is_some() || !(*e).delta);
+                C::decode(prev, (*e).operation.as_ref().unwrap())
+            };
+            if C::DELTA {
+                prev = Some(op.clone());
+            }
+
+            let mut skip = false;
+            if let Some(supersedes) = self.supersedes {
+                if i >= fresh {
+                    for (offset, older) in window.iter().flatten() {
+                        if supersedes(&op, older) {
+                            let o = self.entry(*offset);
+                            unsafe { (*o).superseded.store((i - offset) as u8, Ordering::Relaxed) };
+                        }
+                    }
+                    window[i % COMPACT_WINDOW] = Some((i, op.clone()));
+                }
+
+                // The superseding entry is executed in this call as well, so
+                // skipping the operation of another replica is safe.
+                let distance = unsafe { (*e).superseded.load(Ordering::Relaxed) } as usize;
+                skip = distance > 0 && i + distance < gtail && unsafe { (*e).replica } != idx.get();
+            }
+
+            if !skip {
+                unsafe { d(op, ReplicaId::new((*e).replica), LogOffset::new(i)) };
+            }
 
             // Looks like we're going to wrap around now; flip this replica's local mask.
-            if self.index(i) == self.size - 1 {
-                self.lmasks[idx - 1].set(!self.lmasks[idx - 1].get());
-                //trace!("idx: {} lmask: {}", idx, self.lmasks[idx - 1].get());
+            if self.index(i) == self.size() - 1 {
+                self.lmasks[idx.index()].set(!self.lmasks[idx.index()].get());
+                //trace!("idx: {} lmask: {}", idx, self.lmasks[idx.index()].get());
+            }
+
+            // Publish our progress every once in a while so that GC doesn't have
+            // to wait for us to execute everything up to `gtail`.
+            if i + 1 - published == EXEC_CHUNK && i + 1 < gtail {
+                invariant!(
+                    ltail_in_log,
+                    self.head.load(Ordering::Relaxed) <= i + 1,
+                    "replica {} publishes local tail {} behind the head",
+                    idx,
+                    i + 1
+                );
+                self.ltails[idx.index()].store(i + 1, Ordering::Release);
+                if published <= self.head.load(Ordering::Relaxed) {
+                    self.try_advance_head();
+                }
+                published = i + 1;
+
+                // The head may now move past the entries in the window.
+                window = Default::default();
             }
         }
 
         // Update the completed tail after we've executed these operations.
         // Also update this replica's local tail.
-        self.ctail.fetch_max(gtail, Ordering::Relaxed);
-        self.ltails[idx - 1].store(gtail, Ordering::Relaxed);
+        // Release so that GC (which reads the local tails) doesn't let appends
+        // overwrite entries before we're done reading them.
+        self.ctail.fetch_max(gtail, Ordering::Release);
+        invariant!(
+            ctail_in_log,
+            self.ctail.load(Ordering::Relaxed) <= self.tail.load(Ordering::Relaxed)
+        );
+        invariant!(
+            ltail_in_log,
+            self.head.load(Ordering::Relaxed) <= gtail
+                && gtail <= self.tail.load(Ordering::Relaxed),
+            "replica {} publishes local tail {} outside of the log",
+            idx,
+            gtail
+        );
+        invariant!(
+            lmask_matches_ltail,
+            self.lmasks[idx.index()].get() == ((gtail / self.size()) % 2 == 0),
+            "replica {} at local tail {} with a stale mask",
+            idx,
+            gtail
+        );
+        self.ltails[idx.index()].store(gtail, Ordering::Release);
+        if published <= self.head.load(Ordering::Relaxed) {
+            self.try_advance_head();
+        }
+    }
+
+    /// Returns an iterator over the operations on the log from logical offset
+    /// `ltail` up to the tail of the log at the time of the call. Every item is
+    /// the operation, the identifier of the replica that appended it, and its
+    /// logical offset. Useful for custom replica implementations and debugging
+    /// tools that want to consume the log without registering with it.
+    ///
+    /// The iterator waits for entries that were reserved but not filled in yet.
+    /// It doesn't hold back garbage collection; if the head of the log moves
+    /// past an entry before the iterator gets to it, the iterator ends early
+    /// (and it's empty if `ltail` was garbage collected already).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Log, LogOffset, ReplicaId};
+    ///
+    /// let l = Log::<u64>::default();
+    /// let replica = ReplicaId::new(1);
+    /// l.append(&[10, 20, 30], replica, |_o: u64, _i: ReplicaId| {});
+    ///
+    /// let ops: Vec<(u64, ReplicaId, LogOffset)> = l.iter_from(LogOffset::new(1)).collect();
+    /// assert_eq!(
+    ///     ops,
+    ///     vec![(20, replica, LogOffset::new(1)), (30, replica, LogOffset::new(2))]
+    /// );
+    /// ```
+    pub fn iter_from(&self, ltail: LogOffset) -> LogIterator<'_, 'a, T, C> {
+        let ltail = ltail.get();
+        let head = self.head.load(Ordering::Relaxed);
+        let end = self.tail.load(Ordering::Relaxed);
+
+        // Operations encoded relative to their predecessor can only be decoded
+        // from the start of the batch they were appended in.
+        let mut pos = ltail;
+        while pos > head && pos < end && self.entry_is_delta(pos) {
+            pos -= 1;
+        }
+
+        LogIterator {
+            log: self,
+            pos,
+            start: ltail,
+            end,
+            prev: None,
+        }
+    }
+
+    /// Returns true if the entry at logical offset `i` is encoded relative to
+    /// the previous one. Waits for the entry to be filled in, unless the entry
+    /// gets garbage collected in the meantime.
+    fn entry_is_delta(&self, i: usize) -> bool {
+        let e = self.entry(i);
+        while unsafe { (*e).alivef.load(Ordering::Acquire) } != ((i / self.size()) % 2 == 0) {
+            if i < self.head.load(Ordering::Relaxed) {
+                return false;
+            }
+            spin_loop();
+        }
+        unsafe { (*e).delta }
     }
 
     /// Returns a physical index given a logical index into the shared log.
     #[inline(always)]
     fn index(&self, logical: usize) -> usize {
-        logical & (self.size - 1)
+        logical & (self.size() - 1)
+    }
+
+    /// Returns the number of entries the log currently holds.
+    #[inline(always)]
+    fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the entries of the log. Pairs with the release in `grow()`: if
+    /// we see the new size, we see the new entries as well.
+    #[inline(always)]
+    fn slog(&self) -> &[Cell<Entry<C::Encoded>>] {
+        let size = self.size.load(Ordering::Acquire);
+        unsafe { from_raw_parts(self.slog.load(Ordering::Relaxed), size) }
+    }
+
+    /// Returns a pointer to the entry at logical index `logical`.
+    #[inline(always)]
+    fn entry(&self, logical: usize) -> *mut Entry<C::Encoded> {
+        let slog = self.slog();
+        slog[logical & (slog.len() - 1)].as_ptr()
+    }
+
+    /// Advances the head of the log to the smallest local tail across all replicas
+    /// if that frees up any entries. Unlike `advance_head()`, this never blocks.
+    #[inline(always)]
+    fn try_advance_head(&self) {
+        let r = self.next.load(Ordering::Relaxed);
+        let min_local_tail = self.ltails[..r - 1]
+            .iter()
+            .map(|ltail| ltail.load(Ordering::Acquire))
+            .min();
+
+        if let Some(min_local_tail) = min_local_tail {
+            self.move_head(min_local_tail);
+        }
+    }
+
+    /// Moves the head of the log forward to `to` and reports the entries that
+    /// were freed up to the callback installed with `on_reclaim()`.
+    #[inline(always)]
+    fn move_head(&self, to: usize) {
+        // Recovery must not see entries that get overwritten once the head moved.
+        #[cfg(feature = "pmem")]
+        if let Some(header) = self.pmem {
+            header.persist_head(to);
+        }
+
+        invariant!(
+            head_behind_tail,
+            to <= self.tail.load(Ordering::Relaxed),
+            "head moved to {}",
+            to
+        );
+
+        // The head only ever moves forward, even if we race with another replica.
+        let from = self.head.fetch_max(to, Ordering::Release);
+        if from < to {
+            #[cfg(feature = "stats")]
+            self.stats.on_gc();
+            if let Some(reclaim) = self.reclaim.as_ref() {
+                reclaim(LogOffset::new(from)..LogOffset::new(to));
+            }
+        }
     }
 
     /// Advances the head of the log forward. If a replica has stopped making progress,
-    /// then this method will never return. Accepts a closure that is passed into exec()
-    /// to ensure that this replica does not deadlock GC.
+    /// then this method will never return, unless `policy` gives up. Accepts a closure that
+    /// is passed into exec() to ensure that this replica does not deadlock GC.
     #[inline(always)]
-    fn advance_head<F: FnMut(T, usize)>(&self, rid: usize, mut s: &mut F) {
+    fn advance_head<
+        F: FnMut(T, ReplicaId, LogOffset),
+        O: ReplicaObserver + ?Sized,
+        P: GcHelpPolicy + ?Sized,
+    >(
+        &self,
+        rid: ReplicaId,
+        mut s: &mut F,
+        o: &O,
+        policy: &P,
+    ) {
         // Keep looping until we can advance the head and create some free space
         // on the log. If one of the replicas has stopped making progress, then
         // this method might never return.
@@ -541,11 +2087,11 @@ where
             let global_head = self.head.load(Ordering::Relaxed);
             let f = self.tail.load(Ordering::Relaxed);
 
-            let mut min_local_tail = self.ltails[0].load(Ordering::Relaxed);
+            let mut min_local_tail = self.ltails[0].load(Ordering::Acquire);
 
             // Find the smallest local tail across all replicas.
-            for idx in 1..r {
-                let cur_local_tail = self.ltails[idx - 1].load(Ordering::Relaxed);
+            for ltail in self.ltails[..r - 1].iter() {
+                let cur_local_tail = ltail.load(Ordering::Acquire);
                 if min_local_tail > cur_local_tail {
                     min_local_tail = cur_local_tail
                 };
@@ -555,25 +2101,45 @@ where
             // from the beginning of this loop again. Before doing so, try consuming
             // any new entries on the log to prevent deadlock.
             if min_local_tail == global_head {
+                // Someone else (e.g., `exec`) may have moved the head far enough
+                // already, in which case there is nothing left to wait for.
+                if f < global_head + self.size() - GC_FROM_HEAD {
+                    if iteration > 1 {
+                        o.on_gc_stall(iteration - 1);
+                    }
+                    return;
+                }
                 if iteration % WARN_THRESHOLD == 0 {
                     warn!("Spending a long time in `advance_head`, are we starving?");
                 }
+                // The entries are on the log already; giving up only means that
+                // the next append has to wait for space instead.
+                if !self.help_gc(rid, iteration, &mut s, policy) {
+                    o.on_gc_stall(iteration);
+                    return;
+                }
                 iteration += 1;
-                self.exec(rid, &mut s);
                 continue;
             }
 
-            // There are entries that can be freed up; update the head offset.
-            self.head.store(min_local_tail, Ordering::Relaxed);
+            // There are entries that can be freed up; update the head offset. Replicas
+            // executing on the log might have moved it forward concurrently.
+            self.move_head(min_local_tail);
 
             // Make sure that we freed up enough space so that threads waiting for
             // GC in append can make progress. Otherwise, try to make progress again.
             // If we're making progress again, then try consuming entries on the log.
-            if f < min_local_tail + self.size - GC_FROM_HEAD {
+            if f < min_local_tail + self.size() - GC_FROM_HEAD {
+                if iteration > 1 {
+                    o.on_gc_stall(iteration - 1);
+                }
+                return;
+            }
+            if !self.help_gc(rid, iteration, &mut s, policy) {
+                o.on_gc_stall(iteration);
                 return;
-            } else {
-                self.exec(rid, &mut s);
             }
+            iteration += 1;
         }
     }
 
@@ -602,10 +2168,103 @@ where
 
         // Next, free up all log entries. Use pointers to avoid memcpy and speed up
         // the reset of the log here.
-        for i in 0..self.size {
-            let e = self.slog[self.index(i)].as_ptr();
+        for i in 0..self.size() {
+            let e = self.entry(i);
             (*e).alivef.store(false, Ordering::Release);
         }
+
+        #[cfg(feature = "pmem")]
+        if let Some(header) = self.pmem {
+            pmem::persist(self.slog().as_ptr() as *const u8, self.rawb.get());
+            header.head.store(0, Ordering::SeqCst);
+            header.tail.store(0, Ordering::SeqCst);
+            pmem::persist(header as *const Header as *const u8, CACHE_LINE);
+        }
+    }
+
+    /// Grows the log to `bytes` bytes (rounded up to a power of two number of
+    /// entries, like `new`), so that bursts of operations don't stall replicas
+    /// on garbage collection as much. All entries on the log are garbage
+    /// collected in the process, and appends continue at the same logical
+    /// offset. The memory the entries were in before is only freed along with
+    /// the log.
+    ///
+    /// Fails with `LogError::InvalidSize` if the log wouldn't hold more entries
+    /// than before or if it doesn't own its memory (e.g., it is persistent),
+    /// and with `LogError::OutOfMemory` if the memory can't be allocated. The
+    /// log is left as it was in that case.
+    ///
+    /// Use `NodeReplicated::grow_log` to grow the log of replicas that are in
+    /// use; it stops them while the log grows.
+    ///
+    /// # Safety
+    ///
+    /// Before calling this method, make sure that no replica appends to or
+    /// executes operations from this log during the call, and that every
+    /// replica registered with the log executed all operations on it.
+    pub unsafe fn grow(&self, bytes: usize) -> Result<(), LogError> {
+        if self.rawp.get().is_null() {
+            return Err(LogError::InvalidSize);
+        }
+
+        let LogLayout {
+            entries: num,
+            bytes: b,
+            ..
+        } = Log::<T, C>::layout(LogConfig::new(bytes))?;
+        if num <= self.size() {
+            return Err(LogError::InvalidSize);
+        }
+
+        // Appends continue at `tail`. The entry at index `i` must look dead
+        // until the first operation that goes there after `tail` is written,
+        // i.e., its flag must not match the pass over the log of that offset.
+        let tail = self.tail.load(Ordering::Relaxed);
+        #[cfg(feature = "std")]
+        let threads = LogConfig::new(bytes).threads_for(b);
+        #[cfg(not(feature = "std"))]
+        let threads = 1;
+        let alive = |i: usize| {
+            let mut next = (tail & !(num - 1)) + i;
+            if next < tail {
+                next += num;
+            }
+            (next / num) % 2 != 0
+        };
+        #[cfg(feature = "std")]
+        let raw = Log::<T, C>::alloc_entries(b, num, threads, &self.options, alive)?;
+        #[cfg(not(feature = "std"))]
+        let raw = Log::<T, C>::alloc_entries(b, num, threads, alive)?;
+        let mem = raw.as_ptr() as *mut u8;
+
+        // Every replica executed all entries, so they can all be reclaimed.
+        self.move_head(tail);
+        let r = self.next.load(Ordering::Relaxed);
+        for lmask in self.lmasks[..r - 1].iter() {
+            lmask.set((tail / num) % 2 == 0);
+        }
+
+        #[cfg(feature = "std")]
+        let locked = self.locked_range.get();
+        #[cfg(not(feature = "std"))]
+        let locked: Option<LockedRange> = None;
+        self.retired
+            .borrow_mut()
+            .push((self.rawp.get(), self.rawb.get(), locked));
+
+        #[cfg(feature = "std")]
+        self.locked_range
+            .set(locked.and_then(|_| lock_memory(mem, b)));
+        self.rawp.set(mem);
+        self.rawb.set(b);
+        self.slog.store(raw.as_ptr() as *mut _, Ordering::Relaxed);
+        self.size.store(num, Ordering::Release);
+
+        // What the log ran into at its old size doesn't apply anymore.
+        #[cfg(feature = "stats")]
+        self.stats.restart();
+
+        Ok(())
     }
 
     /// This method checks if the replica is in sync to execute a read-only operation
@@ -630,50 +2289,245 @@ where
     /// let idx2 = l.register().expect("Failed to register with the Log.");
     /// let ops = [Operation::Write(100), Operation::Read];
     ///
-    /// let f = |op: Operation, id: usize| {
-    ///     match(op) {
-    ///         Operation::Read => println!("Read by {}", id),
-    ///         Operation::Write(x) => println!("Write({}) by {}", x, id),
-    ///     }
-    /// };
-    /// l.append(&ops, idx2, f);
+    /// let f = |op: Operation, id: ReplicaId| {
+    ///     match(op) {
+    ///         Operation::Read => println!("Read by {}", id),
+    ///         Operation::Write(x) => println!("Write({}) by {}", x, id),
+    ///     }
+    /// };
+    /// l.append(&ops, idx2, f);
+    ///
+    /// let mut d = 0;
+    /// let mut g = |op: Operation, id: ReplicaId| {
+    ///     match(op) {
+    ///         // The write happened before the read.
+    ///         Operation::Read => assert_eq!(100, d),
+    ///         Operation::Write(x) => d += 100,
+    ///     }
+    /// };
+    /// l.exec(idx2, &mut g);
+    ///
+    /// // This assertion fails because `idx1` has not executed operations
+    /// // that were appended by `idx2`.
+    /// assert_eq!(false, l.is_replica_synced_for_reads(idx1, l.get_ctail()));
+    ///
+    /// let mut e = 0;
+    /// let mut g = |op: Operation, id: ReplicaId| {
+    ///     match(op) {
+    ///         // The write happened before the read.
+    ///         Operation::Read => assert_eq!(100, e),
+    ///         Operation::Write(x) => e += 100,
+    ///     }
+    /// };
+    /// l.exec(idx1, &mut g);
+    ///
+    /// // `idx1` is all synced up, so this assertion passes.
+    /// assert_eq!(true, l.is_replica_synced_for_reads(idx1, l.get_ctail()));
+    /// ```
+    #[inline(always)]
+    pub(crate) fn is_replica_synced_for_reads(&self, idx: ReplicaId, ctail: usize) -> bool {
+        self.ltails[idx.index()].load(Ordering::Relaxed) >= ctail
+    }
+
+    /// This method returns the current head of the log.
+    #[cfg(any(feature = "export", feature = "std"))]
+    #[inline(always)]
+    pub(crate) fn get_head(&self) -> usize {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// This method returns the current tail of the log.
+    #[inline(always)]
+    pub(crate) fn get_tail(&self) -> usize {
+        self.tail.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of replicas registered with the log.
+    #[cfg(feature = "std")]
+    pub(crate) fn registered(&self) -> usize {
+        self.next.load(Ordering::Relaxed) - 1
+    }
+
+    /// Returns true if every replica registered with the log has executed all
+    /// operations before logical offset `tail`.
+    pub(crate) fn is_synced(&self, tail: usize) -> bool {
+        let r = self.next.load(Ordering::Relaxed);
+        self.ltails[..r - 1]
+            .iter()
+            .all(|ltail| ltail.load(Ordering::Relaxed) >= tail)
+    }
+
+    /// Returns true if the local tail of replica `idx` lies between the head and
+    /// the tail of the log, i.e., the replica can execute the log from where it
+    /// left off. Entries behind the head may have been overwritten already.
+    #[inline(always)]
+    pub(crate) fn is_within(&self, idx: ReplicaId) -> bool {
+        let ltail = self.ltails[idx.index()].load(Ordering::Relaxed);
+        ltail >= self.head.load(Ordering::Relaxed) && ltail <= self.tail.load(Ordering::Relaxed)
+    }
+
+    /// Moves the local tail of replica `idx` to `offset`, to simulate a replica
+    /// that fell off the log.
+    #[cfg(test)]
+    pub(crate) fn set_ltail(&self, idx: ReplicaId, offset: usize) {
+        self.ltails[idx.index()].store(offset, Ordering::Relaxed);
+    }
+
+    /// This method returns the current local tail of replica `idx`.
+    #[inline(always)]
+    pub(crate) fn get_ltail(&self, idx: ReplicaId) -> LogOffset {
+        LogOffset::new(self.ltails[idx.index()].load(Ordering::Relaxed))
+    }
+
+    /// This method returns the current ctail value for the log.
+    #[inline(always)]
+    pub(crate) fn get_ctail(&self) -> usize {
+        self.ctail.load(Ordering::Acquire)
+    }
+
+    /// Returns true if `append` can add a batch of operations from every thread
+    /// of a replica without waiting for other replicas to free up entries. Frees
+    /// up entries that all replicas executed already if needed. Only a hint;
+    /// other replicas can take up the space concurrently.
+    pub(crate) fn has_room(&self) -> bool {
+        let fits = || {
+            let head = self.head.load(Ordering::Relaxed);
+            self.tail.load(Ordering::Relaxed) + 2 * GC_FROM_HEAD <= head + self.size()
+        };
+
+        if fits() {
+            return true;
+        }
+
+        self.try_advance_head();
+        fits()
+    }
+
+    /// Returns the identifier of the replica that is furthest behind on the log.
+    pub(crate) fn slowest_replica(&self) -> ReplicaId {
+        let r = self.next.load(Ordering::Relaxed);
+        (1..r)
+            .map(ReplicaId::new)
+            .min_by_key(|idx| self.ltails[idx.index()].load(Ordering::Relaxed))
+            .unwrap_or_else(|| ReplicaId::new(1))
+    }
+}
+
+impl<'a, T, C> Log<'a, T, C>
+where
+    T: Sized + Clone + Compactable,
+    C: DeltaCodec<T>,
+{
+    /// Lets replicas that are lagging behind skip operations of other replicas
+    /// that a later operation on the log supersedes, see
+    /// [Compactable](trait.Compactable.html). Has to be called before any
+    /// replica registers with the log.
+    ///
+    /// # Example
     ///
-    /// let mut d = 0;
-    /// let mut g = |op: Operation, id: usize| {
-    ///     match(op) {
-    ///         // The write happened before the read.
-    ///         Operation::Read => assert_eq!(100, d),
-    ///         Operation::Write(x) => d += 100,
-    ///     }
-    /// };
-    /// l.exec(idx2, &mut g);
+    /// ```
+    /// use node_replication::{Compactable, Log};
     ///
-    /// // This assertion fails because `idx1` has not executed operations
-    /// // that were appended by `idx2`.
-    /// assert_eq!(false, l.is_replica_synced_for_reads(idx1, l.get_ctail()));
+    /// #[derive(Clone)]
+    /// enum Op {
+    ///     Put(u64, u64),
+    ///     Remove(u64),
+    /// }
     ///
-    /// let mut e = 0;
-    /// let mut g = |op: Operation, id: usize| {
-    ///     match(op) {
-    ///         // The write happened before the read.
-    ///         Operation::Read => assert_eq!(100, e),
-    ///         Operation::Write(x) => e += 100,
+    /// impl Compactable for Op {
+    ///     fn supersedes(&self, older: &Self) -> bool {
+    ///         match (self, older) {
+    ///             (Op::Put(k, _) | Op::Remove(k), Op::Put(o, _) | Op::Remove(o)) => k == o,
+    ///         }
     ///     }
-    /// };
-    /// l.exec(idx1, &mut g);
+    /// }
     ///
-    /// // `idx1` is all synced up, so this assertion passes.
-    /// assert_eq!(true, l.is_replica_synced_for_reads(idx1, l.get_ctail()));
+    /// let mut l = Log::<Op>::default();
+    /// l.enable_compaction();
     /// ```
-    #[inline(always)]
-    pub(crate) fn is_replica_synced_for_reads(&self, idx: usize, ctail: usize) -> bool {
-        self.ltails[idx - 1].load(Ordering::Relaxed) >= ctail
+    pub fn enable_compaction(&mut self) {
+        self.supersedes = Some(T::supersedes);
     }
+}
 
-    /// This method returns the current ctail value for the log.
-    #[inline(always)]
-    pub(crate) fn get_ctail(&self) -> usize {
-        self.ctail.load(Ordering::Relaxed)
+/// An iterator over the operations on a [Log](struct.Log.html), returned by
+/// `Log::iter_from`. Yields the operation, the identifier of the replica that
+/// appended it, and its logical offset on the log.
+pub struct LogIterator<'l, 'a, T, C = IdentityCodec>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+    log: &'l Log<'a, T, C>,
+
+    /// Logical offset of the next entry to decode.
+    pos: usize,
+
+    /// Logical offset of the first entry to yield.
+    start: usize,
+
+    /// Logical offset at which the iterator ends.
+    end: usize,
+
+    /// The last decoded operation, required to decode the next one.
+    prev: Option<T>,
+}
+
+impl<'l, 'a, T, C> Iterator for LogIterator<'l, 'a, T, C>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+    type Item = (T, ReplicaId, LogOffset);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let log = self.log;
+        while self.pos < self.end {
+            let i = self.pos;
+            if i < log.head.load(Ordering::Relaxed) {
+                self.end = i;
+                return None;
+            }
+
+            // Entries are alive if their flag matches the pass over the log that
+            // they belong to; it flips every time the log wraps around.
+            let e = log.entry(i);
+            while unsafe { (*e).alivef.load(Ordering::Acquire) } != ((i / log.size()) % 2 == 0) {
+                // The log may have grown, leaving us with an entry that's reset.
+                if i < log.head.load(Ordering::Relaxed) {
+                    self.end = i;
+                    return None;
+                }
+                spin_loop();
+            }
+
+            let (op, replica) = unsafe {
+                let prev = if (*e).delta { self.prev.as_ref() } else { None };
+                (
+                    C::decode(prev, (*e).operation.as_ref().unwrap()),
+                    (*e).replica,
+                )
+            };
+
+            // The entry might have been garbage collected and overwritten while
+            // we were reading it. The fence keeps the reads above from moving past
+            // the check.
+            fence(Ordering::Acquire);
+            if i < log.head.load(Ordering::Relaxed) {
+                self.end = i;
+                return None;
+            }
+
+            self.pos += 1;
+            if C::DELTA {
+                self.prev = Some(op.clone());
+            }
+            if i >= self.start {
+                return Some((op, ReplicaId::new(replica), LogOffset::new(i)));
+            }
+        }
+
+        None
     }
 }
 
@@ -687,19 +2541,53 @@ where
     }
 }
 
-impl<'a, T> Drop for Log<'a, T>
+impl<'a, T, C> Drop for Log<'a, T, C>
 where
     T: Sized + Clone,
+    C: DeltaCodec<T>,
 {
     /// Destructor for the shared log.
     fn drop(&mut self) {
-        unsafe {
-            dealloc(
-                self.rawp,
-                Layout::from_size_align(self.rawb, align_of::<Cell<Entry<T>>>())
-                    .expect("Alignment error while deallocating the shared log!"),
-            )
-        };
+        // The memory belongs to someone else.
+        if self.rawp.get().is_null() {
+            return;
+        }
+
+        #[cfg(feature = "std")]
+        let locked = self.locked_range.get();
+        #[cfg(not(feature = "std"))]
+        let locked: Option<LockedRange> = None;
+
+        let current = (self.rawp.get(), self.rawb.get(), locked);
+        for &(rawp, rawb, locked) in self.retired.get_mut().iter().chain(Some(&current)) {
+            #[cfg(feature = "std")]
+            if let Some(range) = locked {
+                unlock_memory(range);
+            }
+            #[cfg(not(feature = "std"))]
+            let _ = locked;
+
+            // Operations can own memory of their own (e.g., a `String` payload).
+            let entries = rawb / Log::<T, C>::entry_size();
+            for i in 0..entries {
+                unsafe { core::ptr::drop_in_place((rawp as *mut Cell<Entry<C::Encoded>>).add(i)) };
+            }
+
+            #[cfg(all(feature = "std", unix))]
+            if self.options.maps() {
+                let len = self.options.mapped_len(rawb).unwrap();
+                unmap_memory(rawp, len);
+                continue;
+            }
+
+            unsafe {
+                dealloc(
+                    rawp,
+                    Layout::from_size_align(rawb, align_of::<Cell<Entry<C::Encoded>>>())
+                        .expect("Alignment error while deallocating the shared log!"),
+                )
+            };
+        }
     }
 }
 
@@ -710,6 +2598,7 @@ mod tests {
 
     use super::*;
     use std::sync::Arc;
+    use std::vec;
 
     // Define operations along with their arguments that go onto the log.
     #[derive(Clone)] // Traits required by the log interface.
@@ -747,9 +2636,9 @@ mod tests {
     fn test_log_create() {
         let l = Log::<Operation>::new(1024 * 1024);
         let n = (1024 * 1024) / Log::<Operation>::entry_size();
-        assert_eq!(l.rawb, 1024 * 1024);
-        assert_eq!(l.size, n);
-        assert_eq!(l.slog.len(), n);
+        assert_eq!(l.rawb.get(), 1024 * 1024);
+        assert_eq!(l.size(), n);
+        assert_eq!(l.slog().len(), n);
         assert_eq!(l.head.load(Ordering::Relaxed), 0);
         assert_eq!(l.tail.load(Ordering::Relaxed), 0);
         assert_eq!(l.next.load(Ordering::Relaxed), 1);
@@ -768,9 +2657,12 @@ mod tests {
     #[test]
     fn test_log_min_size() {
         let l = Log::<Operation>::new(1024);
-        assert_eq!(l.rawb, 2 * GC_FROM_HEAD * Log::<Operation>::entry_size());
-        assert_eq!(l.size, 2 * GC_FROM_HEAD);
-        assert_eq!(l.slog.len(), 2 * GC_FROM_HEAD);
+        assert_eq!(
+            l.rawb.get(),
+            2 * GC_FROM_HEAD * Log::<Operation>::entry_size()
+        );
+        assert_eq!(l.size(), 2 * GC_FROM_HEAD);
+        assert_eq!(l.slog().len(), 2 * GC_FROM_HEAD);
     }
 
     // Tests that the constructor allocates a log whose number of entries
@@ -779,9 +2671,120 @@ mod tests {
     fn test_log_power_of_two() {
         let l = Log::<Operation>::new(524 * 1024);
         let n = ((524 * 1024) / Log::<Operation>::entry_size()).checked_next_power_of_two();
-        assert_eq!(l.rawb, n.unwrap() * Log::<Operation>::entry_size());
-        assert_eq!(l.size, n.unwrap());
-        assert_eq!(l.slog.len(), n.unwrap());
+        assert_eq!(l.rawb.get(), n.unwrap() * Log::<Operation>::entry_size());
+        assert_eq!(l.size(), n.unwrap());
+        assert_eq!(l.slog().len(), n.unwrap());
+    }
+
+    // Tests that try_new() reports an error for sizes that can't be allocated.
+    #[test]
+    fn test_log_try_new_invalid_size() {
+        assert_eq!(
+            Log::<Operation>::try_new(usize::MAX).err(),
+            Some(LogError::InvalidSize)
+        );
+        assert!(Log::<Operation>::try_new(isize::MAX as usize + 1).is_err());
+        assert!(Log::<Operation>::try_new(1024).is_ok());
+    }
+
+    // Tests that a log created with `lock_memory` reports whether it is locked.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_log_lock_memory() {
+        let l = Log::<Operation>::with_config(LogConfig::new(1024));
+        assert!(!l.is_memory_locked());
+
+        // Locking may fail depending on RLIMIT_MEMLOCK, but if it succeeded
+        // only whole pages within the log's memory are locked.
+        let l = Log::<Operation>::with_config(LogConfig::new(1024).lock_memory(true));
+        if let Some(range) = l.locked_range.get() {
+            let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            let mem = l.rawp.get() as usize;
+            assert_eq!(range.ptr % page, 0);
+            assert_eq!(range.len % page, 0);
+            assert!(range.ptr >= mem);
+            assert!(range.ptr + range.len <= mem + l.rawb.get());
+        }
+    }
+
+    // Tests that `lock_memory` leaves pages alone that the memory only
+    // partially covers.
+    #[cfg(all(feature = "std", unix))]
+    #[test]
+    fn test_log_lock_memory_range() {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mem = vec![0u8; 4 * page];
+        let first = (mem.as_ptr() as usize + page - 1) & !(page - 1);
+
+        // Straddles two pages without covering either one.
+        let ptr = (first + page / 2) as *const u8;
+        assert_eq!(
+            lock_memory(ptr, page),
+            Some(LockedRange {
+                ptr: first + page,
+                len: 0
+            })
+        );
+
+        // Ends past the end of the address space.
+        let ptr = (usize::MAX - page / 2) as *const u8;
+        assert_eq!(lock_memory(ptr, page), None);
+
+        // Covers exactly one page and half of the next.
+        if let Some(range) = lock_memory(first as *const u8, page + page / 2) {
+            assert_eq!(
+                range,
+                LockedRange {
+                    ptr: first,
+                    len: page
+                }
+            );
+            unlock_memory(range);
+        }
+    }
+
+    // Tests that a log mapped with every option set (falling back to what the
+    // system supports) works, also once it grew.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_log_with_options() {
+        let options = LogOptions {
+            hugepages: true,
+            numa_node: Some(0),
+            prefault: true,
+        };
+        let l = Log::<Operation>::with_options(1024, options);
+        let r = l.register().unwrap();
+        let mut ops = vec![];
+        let mut f = |o: Operation, _i: ReplicaId| ops.push(o);
+
+        l.append(&[Operation::Read], r, &mut f);
+        l.exec(r, &mut f);
+        assert_eq!(unsafe { l.grow(4 * l.rawb.get()) }, Ok(()));
+        l.append(&[Operation::Write(119)], r, &mut f);
+        l.exec(r, &mut f);
+        assert_eq!(ops, vec![Operation::Read, Operation::Write(119)]);
+
+        assert!(Log::<Operation>::try_with_options(usize::MAX, options).is_err());
+    }
+
+    // Tests that a log initialized by several threads (with an uneven split of
+    // entries among them) starts out with every entry empty.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_log_init_threads() {
+        let l = Log::<Operation>::with_config(LogConfig::new(1024 * 1024).init_threads(3));
+        let n = (1024 * 1024) / Log::<Operation>::entry_size();
+        assert_eq!(l.size(), n);
+
+        for e in l.slog().iter() {
+            let e = e.take();
+            assert_eq!(e.operation, None);
+            assert_eq!(e.replica, 0);
+            assert_eq!(e.alivef.load(Ordering::Relaxed), false);
+        }
+        assert_eq!(LogConfig::new(1024).threads_for(1024), 1);
+        assert_eq!(LogConfig::new(1024).init_threads(5).threads_for(1024), 5);
     }
 
     // Tests if the log can be successfully default constructed.
@@ -789,9 +2792,9 @@ mod tests {
     fn test_log_create_default() {
         let l = Log::<Operation>::default();
         let n = DEFAULT_LOG_BYTES / Log::<Operation>::entry_size();
-        assert_eq!(l.rawb, DEFAULT_LOG_BYTES);
-        assert_eq!(l.size, n);
-        assert_eq!(l.slog.len(), n);
+        assert_eq!(l.rawb.get(), DEFAULT_LOG_BYTES);
+        assert_eq!(l.size(), n);
+        assert_eq!(l.slog().len(), n);
         assert_eq!(l.head.load(Ordering::Relaxed), 0);
         assert_eq!(l.tail.load(Ordering::Relaxed), 0);
         assert_eq!(l.next.load(Ordering::Relaxed), 1);
@@ -817,7 +2820,7 @@ mod tests {
     #[test]
     fn test_log_register() {
         let l = Log::<Operation>::new(1024);
-        assert_eq!(l.register(), Some(1));
+        assert_eq!(l.register(), Some(ReplicaId::new(1)));
         assert_eq!(l.next.load(Ordering::Relaxed), 2);
     }
 
@@ -830,16 +2833,35 @@ mod tests {
         assert_eq!(l.next.load(Ordering::Relaxed), MAX_REPLICAS_PER_LOG);
     }
 
+    // Tests that the length, capacity and utilization of the log follow appends
+    // and garbage collection.
+    #[test]
+    fn test_log_utilization() {
+        let l = Log::<u64>::new(1024);
+        assert!(l.is_empty());
+        assert_eq!(l.capacity(), 2 * GC_FROM_HEAD);
+        assert!(l.utilization() < f64::EPSILON);
+
+        let idx = l.register().unwrap();
+        let ops = vec![1; GC_FROM_HEAD / 2];
+        l.append(&ops, idx, |_o: u64, _i: ReplicaId| {});
+        assert_eq!(l.len(), GC_FROM_HEAD / 2);
+        assert!((l.utilization() - 0.25).abs() < f64::EPSILON);
+
+        l.exec(idx, &mut |_o: u64, _i: ReplicaId| {});
+        assert!(l.is_empty());
+    }
+
     // Test that we can correctly append an entry into the log.
     #[test]
     fn test_log_append() {
         let l = Log::<Operation>::default();
         let o = [Operation::Read];
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
 
         assert_eq!(l.head.load(Ordering::Relaxed), 0);
         assert_eq!(l.tail.load(Ordering::Relaxed), 1);
-        let slog = l.slog[0].take();
+        let slog = l.slog()[0].take();
         assert_eq!(slog.operation, Some(Operation::Read));
         assert_eq!(slog.replica, 1);
     }
@@ -849,27 +2871,319 @@ mod tests {
     fn test_log_append_multiple() {
         let l = Log::<Operation>::default();
         let o = [Operation::Read, Operation::Write(119)];
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
 
         assert_eq!(l.head.load(Ordering::Relaxed), 0);
         assert_eq!(l.tail.load(Ordering::Relaxed), 2);
     }
 
+    // Tests that layout() reports the entries and memory of a log without
+    // allocating it, matching what a log created with the same config uses.
+    #[test]
+    fn test_log_layout() {
+        let layout = Log::<Operation>::layout(LogConfig::new(1024 * 1024)).unwrap();
+        assert_eq!(layout.entry_size, Log::<Operation>::entry_size());
+        assert_eq!(layout.entries * layout.entry_size, layout.bytes);
+
+        let l = Log::<Operation>::with_config(LogConfig::new(1024 * 1024));
+        assert_eq!(l.size(), layout.entries);
+        assert_eq!(l.rawb.get(), layout.bytes);
+
+        // Small logs are rounded up to what garbage collection requires.
+        let layout = Log::<Operation>::layout(LogConfig::new(1)).unwrap();
+        assert_eq!(layout.entries, 2 * GC_FROM_HEAD);
+    }
+
     // Tests that we can advance the head of the log to the smallest of all replica-local tails.
     #[test]
     fn test_log_advance_head() {
         let l = Log::<Operation>::default();
 
         l.next.store(5, Ordering::Relaxed);
+        l.tail.store(4096, Ordering::Relaxed);
         l.ltails[0].store(1023, Ordering::Relaxed);
         l.ltails[1].store(224, Ordering::Relaxed);
         l.ltails[2].store(4096, Ordering::Relaxed);
         l.ltails[3].store(799, Ordering::Relaxed);
 
-        l.advance_head(0, &mut |_o: Operation, _i: usize| {});
+        l.advance_head(
+            ReplicaId::new(1),
+            &mut |_o: Operation, _i: ReplicaId, _offset: LogOffset| {},
+            &(),
+            &ExecSelf,
+        );
         assert_eq!(l.head.load(Ordering::Relaxed), 224);
     }
 
+    // Tests that exec() advances the head of the log while the slowest replica is
+    // catching up, and not only once it has executed everything.
+    #[test]
+    fn test_log_exec_advances_head() {
+        let l = Log::<Operation>::new(16 * GC_FROM_HEAD * Log::<Operation>::entry_size());
+        let o = vec![Operation::Read; 16];
+        let r1 = l.register().unwrap();
+        let r2 = l.register().unwrap();
+
+        for _i in 0..(4 * EXEC_CHUNK) / o.len() {
+            l.append(&o, r1, |_o: Operation, _i: ReplicaId| {});
+        }
+        l.exec(r1, &mut |_o: Operation, _i: ReplicaId| {});
+        assert_eq!(l.head.load(Ordering::Relaxed), 0);
+
+        let mut executed = 0;
+        l.exec(r2, &mut |_o: Operation, _i: ReplicaId| {
+            if executed > EXEC_CHUNK {
+                assert!(l.head.load(Ordering::Relaxed) >= EXEC_CHUNK);
+            }
+            executed += 1;
+        });
+        assert_eq!(executed, 4 * EXEC_CHUNK);
+        assert_eq!(l.head.load(Ordering::Relaxed), 4 * EXEC_CHUNK);
+    }
+
+    // Returns a zeroed region aligned to a cache line that fits a log of the
+    // minimum size. The caller keeps the returned vector alive.
+    #[cfg(feature = "pmem")]
+    fn pmem_region() -> std::vec::Vec<u8> {
+        vec![0u8; (2 * GC_FROM_HEAD + 2) * Log::<u64>::entry_size()]
+    }
+
+    #[cfg(feature = "pmem")]
+    fn aligned(mem: &mut [u8]) -> &mut [u8] {
+        let off = mem.as_ptr().align_offset(CACHE_LINE);
+        &mut mem[off..]
+    }
+
+    // Tests that a persistent log can be recovered with all appended operations.
+    #[cfg(feature = "pmem")]
+    #[test]
+    fn test_log_persistent_recover() {
+        let mut mem = pmem_region();
+        {
+            let l = Log::<u64>::persistent(aligned(&mut mem)).unwrap();
+            assert_eq!(l.size(), 2 * GC_FROM_HEAD);
+            let r = l.register().unwrap();
+            for i in 0..10 {
+                l.append(&[2 * i, 2 * i + 1], r, |_o: u64, _i: ReplicaId| {});
+            }
+        }
+
+        let l = Log::<u64>::recover(aligned(&mut mem)).unwrap();
+        assert_eq!(l.head.load(Ordering::Relaxed), 0);
+        assert_eq!(l.tail.load(Ordering::Relaxed), 20);
+
+        let r = l.register().unwrap();
+        let mut ops = vec![];
+        l.exec(r, &mut |o: u64, i: ReplicaId| {
+            assert_eq!(i, ReplicaId::new(0));
+            ops.push(o);
+        });
+        assert_eq!(ops, (0..20).collect::<std::vec::Vec<u64>>());
+    }
+
+    // Tests that recovery stops at the first operation that didn't make it to
+    // persistent memory, and that the log can be appended to afterwards.
+    #[cfg(feature = "pmem")]
+    #[test]
+    fn test_log_persistent_recover_hole() {
+        let mut mem = pmem_region();
+        {
+            let l = Log::<u64>::persistent(aligned(&mut mem)).unwrap();
+            let r = l.register().unwrap();
+            l.append(&[0, 1, 2, 3, 4, 5, 6, 7], r, |_o: u64, _i: ReplicaId| {});
+
+            // Pretend the append of entry 5 never completed.
+            unsafe {
+                (*l.slog()[5].as_ptr())
+                    .alivef
+                    .store(false, Ordering::Relaxed)
+            };
+        }
+
+        let l = Log::<u64>::recover(aligned(&mut mem)).unwrap();
+        assert_eq!(l.tail.load(Ordering::Relaxed), 5);
+
+        let r = l.register().unwrap();
+        l.append(&[100], r, |_o: u64, _i: ReplicaId| {});
+        let mut ops = vec![];
+        l.exec(r, &mut |o: u64, _i: ReplicaId| ops.push(o));
+        assert_eq!(ops, vec![0, 1, 2, 3, 4, 100]);
+    }
+
+    // Tests that the head of a persistent log survives a restart.
+    #[cfg(feature = "pmem")]
+    #[test]
+    fn test_log_persistent_recover_head() {
+        let mut mem = pmem_region();
+        {
+            let l = Log::<u64>::persistent(aligned(&mut mem)).unwrap();
+            let r = l.register().unwrap();
+            l.append(&[0, 1, 2, 3], r, |_o: u64, _i: ReplicaId| {});
+            l.exec(r, &mut |_o: u64, _i: ReplicaId| {});
+            l.append(&[4, 5], r, |_o: u64, _i: ReplicaId| {});
+        }
+
+        let l = Log::<u64>::recover(aligned(&mut mem)).unwrap();
+        assert_eq!(l.head.load(Ordering::Relaxed), 4);
+        assert_eq!(l.tail.load(Ordering::Relaxed), 6);
+        assert_eq!(l.register_at(LogOffset::new(4)), Ok(ReplicaId::new(1)));
+    }
+
+    // Tests that recovering a region that doesn't hold a log fails.
+    #[cfg(feature = "pmem")]
+    #[test]
+    fn test_log_recover_invalid() {
+        let mut mem = pmem_region();
+        assert_eq!(
+            Log::<u64>::recover(aligned(&mut mem)).unwrap_err(),
+            LogError::InvalidRegion
+        );
+
+        {
+            Log::<u64>::persistent(aligned(&mut mem)).unwrap();
+        }
+        assert!(Log::<[u64; 8]>::recover(aligned(&mut mem)).is_err());
+        assert!(Log::<u64>::recover(aligned(&mut mem)).is_ok());
+    }
+
+    // Tests that recovering a log with inconsistent metadata or entries fails
+    // instead of panicking once replicas use the log.
+    #[cfg(feature = "pmem")]
+    #[test]
+    fn test_log_recover_corrupt() {
+        let mut mem = pmem_region();
+        let corrupt = |mem: &mut [u8], f: &dyn Fn(&Header)| {
+            {
+                let l = Log::<u64>::persistent(aligned(mem)).unwrap();
+                l.append(&[1, 2, 3], ReplicaId::new(1), |_o: u64, _i: ReplicaId| {});
+                f(l.pmem.unwrap());
+                unsafe { (*l.slog()[1].as_ptr()).operation = None };
+            }
+            Log::<u64>::recover(aligned(mem)).unwrap_err()
+        };
+
+        let err = corrupt(&mut mem, &|h: &Header| h.entries.store(3, Ordering::SeqCst));
+        assert_eq!(err, LogError::CorruptLog(LogCorruption::InvalidSize));
+        let err = corrupt(&mut mem, &|h: &Header| {
+            h.entries.store(4 * GC_FROM_HEAD, Ordering::SeqCst)
+        });
+        assert_eq!(err, LogError::CorruptLog(LogCorruption::SizeOutOfBounds));
+        let err = corrupt(&mut mem, &|h: &Header| {
+            h.tail.store(usize::MAX, Ordering::SeqCst)
+        });
+        assert_eq!(err, LogError::CorruptLog(LogCorruption::TailOutOfBounds));
+        let err = corrupt(&mut mem, &|_h: &Header| {});
+        assert_eq!(err, LogError::CorruptLog(LogCorruption::MissingOperation));
+    }
+
+    // Tests that iter_from() yields the operations between a local tail and the
+    // tail of the log, across a wrap-around.
+    #[test]
+    fn test_log_iter_from() {
+        let l = Log::<Operation>::new(1024);
+        let r = l.register().unwrap();
+        let o = vec![Operation::Write(1), Operation::Write(2)];
+        for _i in 0..l.size() {
+            l.append(&o, r, |_o: Operation, _i: ReplicaId| {});
+            l.exec(r, &mut |_o: Operation, _i: ReplicaId| {});
+        }
+        l.append(&o, r, |_o: Operation, _i: ReplicaId| {});
+        l.append(&[Operation::Read], r, |_o: Operation, _i: ReplicaId| {});
+
+        let ltail = l.head.load(Ordering::Relaxed);
+        let at = |offset: usize| LogOffset::new(offset);
+        let ops: std::vec::Vec<(Operation, ReplicaId, LogOffset)> =
+            l.iter_from(at(ltail + 1)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Operation::Write(2), r, at(ltail + 1)),
+                (Operation::Read, r, at(ltail + 2)),
+            ]
+        );
+
+        // Entries behind the head were garbage collected.
+        assert_eq!(l.iter_from(at(ltail - 1)).count(), 0);
+        assert_eq!(l.iter_from(at(ltail + 3)).count(), 0);
+    }
+
+    // Tests that a log that grew keeps appending at the same logical offset, and
+    // that replicas execute operations in order across the wrap-around of the
+    // old and the new log.
+    #[test]
+    fn test_log_grow() {
+        // Appends `n` operations counting up from `next` in batches, executing
+        // them with `f` after every batch.
+        fn run(
+            l: &Log<u64>,
+            r: ReplicaId,
+            next: &mut u64,
+            n: usize,
+            f: &mut impl FnMut(u64, ReplicaId),
+        ) {
+            for _i in 0..n / 1024 {
+                let ops: std::vec::Vec<u64> = (*next..*next + 1024).collect();
+                *next += 1024;
+                l.append(&ops, r, &mut *f);
+                l.exec(r, f);
+            }
+        }
+
+        let l = Log::<u64>::new(1024);
+        let size = l.size();
+        let r = l.register().unwrap();
+        let executed = core::cell::RefCell::new(std::vec::Vec::new());
+        let mut f = |o: u64, _i: ReplicaId| executed.borrow_mut().push(o);
+        let mut next = 0;
+
+        run(&l, r, &mut next, size + size / 2, &mut f);
+        assert_eq!(unsafe { l.grow(1024) }, Err(LogError::InvalidSize));
+        assert_eq!(unsafe { l.grow(4 * l.rawb.get()) }, Ok(()));
+        assert_eq!(l.capacity(), 4 * size);
+        assert_eq!(l.head.load(Ordering::Relaxed), size + size / 2);
+        assert!(l.iter_from(LogOffset::new(size)).next().is_none());
+
+        run(&l, r, &mut next, 5 * size, &mut f);
+        assert_eq!(
+            *executed.borrow(),
+            (0..next).collect::<std::vec::Vec<u64>>()
+        );
+        assert_eq!(next as usize, 6 * size + size / 2);
+
+        // The replica executed everything, so only a new entry is left.
+        l.append(&[next], r, &mut f);
+        let ops: std::vec::Vec<u64> = l
+            .iter_from(LogOffset::new(next as usize))
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(ops, vec![next]);
+    }
+
+    // Tests that the callback installed with on_reclaim() sees every entry the
+    // head moved past exactly once.
+    #[test]
+    fn test_log_on_reclaim() {
+        let mut l = Log::<Operation>::new(16 * GC_FROM_HEAD * Log::<Operation>::entry_size());
+        let reclaimed = Arc::new(AtomicUsize::new(0));
+        let r = reclaimed.clone();
+        l.on_reclaim(move |range: Range<LogOffset>| {
+            assert_eq!(r.load(Ordering::Relaxed), range.start.get());
+            r.store(range.end.get(), Ordering::Relaxed);
+        });
+
+        let o = vec![Operation::Read; 16];
+        let r1 = l.register().unwrap();
+        let r2 = l.register().unwrap();
+        for _i in 0..(4 * EXEC_CHUNK) / o.len() {
+            l.append(&o, r1, |_o: Operation, _i: ReplicaId| {});
+        }
+        l.exec(r1, &mut |_o: Operation, _i: ReplicaId| {});
+        assert_eq!(reclaimed.load(Ordering::Relaxed), 0);
+
+        l.exec(r2, &mut |_o: Operation, _i: ReplicaId| {});
+        assert_eq!(reclaimed.load(Ordering::Relaxed), 4 * EXEC_CHUNK);
+    }
+
     // Tests that the head of the log is advanced when we're close to filling up the entire log.
     #[test]
     fn test_log_append_gc() {
@@ -883,12 +3197,66 @@ mod tests {
         };
 
         l.next.store(2, Ordering::Relaxed);
-        l.tail.store(l.size - GC_FROM_HEAD - 1, Ordering::Relaxed);
+        l.tail.store(l.size() - GC_FROM_HEAD - 1, Ordering::Relaxed);
         l.ltails[0].store(1024, Ordering::Relaxed);
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
 
         assert_eq!(l.head.load(Ordering::Relaxed), 1024);
-        assert_eq!(l.tail.load(Ordering::Relaxed), l.size - GC_FROM_HEAD + 3);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() - GC_FROM_HEAD + 3);
+    }
+
+    // Tests that an append moves the head before reserving entries if its batch
+    // would leave fewer than `GC_FROM_HEAD` free entries, so that the tail
+    // never gets past that mark and other appends don't wait.
+    #[test]
+    fn test_log_append_gc_before_reserve() {
+        let mut l = Log::<Operation>::default();
+        let tail_at_gc = Arc::new(AtomicUsize::new(0));
+        {
+            // The log outlives the callback, which is only invoked below.
+            let tail = &*l.tail as *const AtomicUsize as usize;
+            let tail_at_gc = tail_at_gc.clone();
+            l.on_reclaim(move |_r: Range<LogOffset>| {
+                let tail = unsafe { &*(tail as *const AtomicUsize) };
+                tail_at_gc.store(tail.load(Ordering::Relaxed), Ordering::Relaxed);
+            });
+        }
+
+        let o = vec![Operation::Read; GC_FROM_HEAD];
+        l.next.store(2, Ordering::Relaxed);
+        l.tail.store(l.size() - GC_FROM_HEAD - 1, Ordering::Relaxed);
+        l.ltails[0].store(l.size() - GC_FROM_HEAD - 1, Ordering::Relaxed);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
+
+        assert_eq!(
+            tail_at_gc.load(Ordering::Relaxed),
+            l.size() - GC_FROM_HEAD - 1
+        );
+        assert_eq!(l.head.load(Ordering::Relaxed), l.size() - GC_FROM_HEAD - 1);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() - 1);
+    }
+
+    // Tests that a batch filling the log right up to `GC_FROM_HEAD` free entries
+    // leaves the head alone, and one more entry moves it.
+    #[test]
+    fn test_log_append_gc_boundary() {
+        let l = Log::<Operation>::default();
+        let o = vec![Operation::Read; 4];
+
+        l.next.store(2, Ordering::Relaxed);
+        l.tail.store(l.size() - GC_FROM_HEAD - 4, Ordering::Relaxed);
+        l.ltails[0].store(1024, Ordering::Relaxed);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
+        assert_eq!(l.head.load(Ordering::Relaxed), 0);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() - GC_FROM_HEAD);
+
+        l.append(
+            &o[..1],
+            ReplicaId::new(1),
+            |_o: Operation, _i: ReplicaId| {},
+        );
+        assert_eq!(l.head.load(Ordering::Relaxed), 1024);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() - GC_FROM_HEAD + 1);
     }
 
     // Tests that on log wrap around, the local mask stays
@@ -906,11 +3274,11 @@ mod tests {
 
         l.next.store(2, Ordering::Relaxed);
         l.head.store(2 * 8192, Ordering::Relaxed);
-        l.tail.store(l.size - 10, Ordering::Relaxed);
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.tail.store(l.size() - 10, Ordering::Relaxed);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
 
         assert_eq!(l.lmasks[0].get(), true);
-        assert_eq!(l.tail.load(Ordering::Relaxed), l.size + 1014);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() + 1014);
     }
 
     // Test that we can execute operations appended to the log.
@@ -918,13 +3286,13 @@ mod tests {
     fn test_log_exec() {
         let l = Log::<Operation>::default();
         let o = [Operation::Read];
-        let mut f = |op: Operation, i: usize| {
+        let mut f = |op: Operation, i: ReplicaId| {
             assert_eq!(op, Operation::Read);
-            assert_eq!(i, 1);
+            assert_eq!(i, ReplicaId::new(1));
         };
 
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
-        l.exec(1, &mut f);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
+        l.exec(ReplicaId::new(1), &mut f);
 
         assert_eq!(
             l.tail.load(Ordering::Relaxed),
@@ -936,15 +3304,39 @@ mod tests {
         );
     }
 
+    // Tests that wait_for_ctail_on_all() returns once a replica that was behind
+    // executed the operations another replica completed.
+    #[test]
+    fn test_log_wait_for_ctail_on_all() {
+        let l = Log::<Operation>::default();
+        let (r1, r2) = (l.register().unwrap(), l.register().unwrap());
+        let o = [Operation::Read, Operation::Write(1)];
+        let mut f = |_o: Operation, _i: ReplicaId| {};
+
+        l.append(&o, r1, |_o: Operation, _i: ReplicaId| {});
+        l.exec(r1, &mut f);
+        assert_eq!(l.get_ctail(), 2);
+        assert!(!l.is_synced(2));
+
+        let mut calls = 0;
+        let offset = l.wait_for_ctail_on_all(|| {
+            calls += 1;
+            l.exec(r2, &mut f);
+        });
+        assert_eq!(offset, LogOffset::new(2));
+        assert_eq!(calls, 1);
+        assert_eq!(l.get_ltail(r2), LogOffset::new(2));
+    }
+
     // Test that exec() doesn't do anything when the log is empty.
     #[test]
     fn test_log_exec_empty() {
         let l = Log::<Operation>::default();
-        let mut f = |_o: Operation, _i: usize| {
+        let mut f = |_o: Operation, _i: ReplicaId| {
             assert!(false);
         };
 
-        l.exec(1, &mut f);
+        l.exec(ReplicaId::new(1), &mut f);
     }
 
     // Test that exec() doesn't do anything if we're already up-to-date.
@@ -952,17 +3344,17 @@ mod tests {
     fn test_log_exec_zero() {
         let l = Log::<Operation>::default();
         let o = [Operation::Read];
-        let mut f = |op: Operation, i: usize| {
+        let mut f = |op: Operation, i: ReplicaId| {
             assert_eq!(op, Operation::Read);
-            assert_eq!(i, 1);
+            assert_eq!(i, ReplicaId::new(1));
         };
-        let mut g = |_op: Operation, _i: usize| {
+        let mut g = |_op: Operation, _i: ReplicaId| {
             assert!(false);
         };
 
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
-        l.exec(1, &mut f);
-        l.exec(1, &mut g);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
+        l.exec(ReplicaId::new(1), &mut f);
+        l.exec(ReplicaId::new(1), &mut g);
     }
 
     // Test that multiple entries on the log can be executed correctly.
@@ -971,14 +3363,14 @@ mod tests {
         let l = Log::<Operation>::default();
         let o = [Operation::Read, Operation::Write(119)];
         let mut s = 0;
-        let mut f = |op: Operation, _i: usize| match op {
+        let mut f = |op: Operation, _i: ReplicaId| match op {
             Operation::Read => s += 121,
             Operation::Write(v) => s += v,
             Operation::Invalid => assert!(false),
         };
 
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
-        l.exec(1, &mut f);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
+        l.exec(ReplicaId::new(1), &mut f);
         assert_eq!(s, 240);
 
         assert_eq!(
@@ -991,6 +3383,82 @@ mod tests {
         );
     }
 
+    // Tests that a replica executing the log after another one skips the operations
+    // of other replicas that a later operation supersedes, but not its own.
+    #[test]
+    fn test_log_exec_compaction() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Put(u64, u64);
+
+        impl Compactable for Put {
+            fn supersedes(&self, older: &Self) -> bool {
+                self.0 == older.0
+            }
+        }
+
+        let mut l = Log::<Put>::default();
+        l.enable_compaction();
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+
+        l.append(&[Put(1, 10)], one, |_o: Put, _i: ReplicaId| {});
+        l.append(&[Put(2, 20)], two, |_o: Put, _i: ReplicaId| {});
+        l.append(&[Put(2, 21), Put(1, 11)], one, |_o: Put, _i: ReplicaId| {});
+
+        let mut executed = vec![];
+        l.exec_traced(one, &mut |_o: Put, _i: ReplicaId, offset: LogOffset| {
+            executed.push(offset.get())
+        });
+        assert_eq!(executed, [0, 1, 2, 3]);
+
+        let mut executed = vec![];
+        let mut state = [0u64; 3];
+        l.exec_traced(two, &mut |o: Put, _i: ReplicaId, offset: LogOffset| {
+            executed.push(offset.get());
+            state[o.0 as usize] = o.1;
+        });
+        assert_eq!(executed, [1, 2, 3]);
+        assert_eq!(state, [0, 11, 21]);
+    }
+
+    // Tests that operations stored with a delta codec are decoded to the operations
+    // that were appended, and that batches are encoded independently of each other.
+    #[test]
+    fn test_log_exec_delta_codec() {
+        struct Diff;
+
+        impl DeltaCodec<u64> for Diff {
+            type Encoded = u64;
+
+            fn encode(prev: Option<&u64>, op: &u64) -> u64 {
+                op.wrapping_sub(*prev.unwrap_or(&0))
+            }
+
+            fn decode(prev: Option<&u64>, encoded: &u64) -> u64 {
+                encoded.wrapping_add(*prev.unwrap_or(&0))
+            }
+        }
+
+        let l = Log::<u64, Diff>::try_create(LogConfig::default()).unwrap();
+        l.append(
+            &[100, 101, 103],
+            ReplicaId::new(1),
+            |_o: u64, _i: ReplicaId| {},
+        );
+        l.append(&[7, 5], ReplicaId::new(1), |_o: u64, _i: ReplicaId| {});
+
+        let stored: vec::Vec<u64> = (0..5)
+            .map(|i| unsafe { (*l.slog()[i].as_ptr()).operation.unwrap() })
+            .collect();
+        assert_eq!(stored, [100, 1, 2, 7, 5u64.wrapping_sub(7)]);
+
+        let mut ops = vec![];
+        l.exec(ReplicaId::new(1), &mut |op: u64, _i: ReplicaId| {
+            ops.push(op)
+        });
+        assert_eq!(ops, [100, 101, 103, 7, 5]);
+    }
+
     // Test that the replica local mask is updated correctly when executing over
     // a wrapped around log.
     #[test]
@@ -1003,22 +3471,22 @@ mod tests {
             }
             a
         };
-        let mut f = |op: Operation, i: usize| {
+        let mut f = |op: Operation, i: ReplicaId| {
             assert_eq!(op, Operation::Read);
-            assert_eq!(i, 1);
+            assert_eq!(i, ReplicaId::new(1));
         };
 
-        l.append(&o, 1, |_o: Operation, _i: usize| {}); // Required for GC to work correctly.
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {}); // Required for GC to work correctly.
         l.next.store(2, Ordering::SeqCst);
         l.head.store(2 * 8192, Ordering::SeqCst);
-        l.tail.store(l.size - 10, Ordering::SeqCst);
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.tail.store(l.size() - 10, Ordering::SeqCst);
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
 
-        l.ltails[0].store(l.size - 10, Ordering::SeqCst);
-        l.exec(1, &mut f);
+        l.ltails[0].store(l.size() - 10, Ordering::SeqCst);
+        l.exec(ReplicaId::new(1), &mut f);
 
         assert_eq!(l.lmasks[0].get(), false);
-        assert_eq!(l.tail.load(Ordering::Relaxed), l.size + 1014);
+        assert_eq!(l.tail.load(Ordering::Relaxed), l.size() + 1014);
     }
 
     // Tests that exec() panics if the head of the log advances beyond the tail.
@@ -1033,14 +3501,14 @@ mod tests {
             }
             a
         };
-        let mut f = |_op: Operation, _i: usize| {
+        let mut f = |_op: Operation, _i: ReplicaId| {
             assert!(false);
         };
 
-        l.append(&o, 1, |_o: Operation, _i: usize| {});
+        l.append(&o, ReplicaId::new(1), |_o: Operation, _i: ReplicaId| {});
         l.head.store(8192, Ordering::SeqCst);
 
-        l.exec(1, &mut f);
+        l.exec(ReplicaId::new(1), &mut f);
     }
 
     // Tests that operations are cloned when added to the log, and that
@@ -1053,9 +3521,17 @@ mod tests {
         assert_eq!(Arc::strong_count(&o1[0]), 1);
         assert_eq!(Arc::strong_count(&o2[0]), 1);
 
-        l.append(&o1[..], 1, |_o: Arc<Operation>, _i: usize| {});
+        l.append(
+            &o1[..],
+            ReplicaId::new(1),
+            |_o: Arc<Operation>, _i: ReplicaId| {},
+        );
         assert_eq!(Arc::strong_count(&o1[0]), 2);
-        l.append(&o1[..], 1, |_o: Arc<Operation>, _i: usize| {});
+        l.append(
+            &o1[..],
+            ReplicaId::new(1),
+            |_o: Arc<Operation>, _i: ReplicaId| {},
+        );
         assert_eq!(Arc::strong_count(&o1[0]), 3);
 
         unsafe { l.reset() };
@@ -1063,14 +3539,77 @@ mod tests {
         // Over here, we overwrite entries that were written to by the two
         // previous appends. This decreases the refcount of o1 and increases
         // the refcount of o2.
-        l.append(&o2[..], 1, |_o: Arc<Operation>, _i: usize| {});
+        l.append(
+            &o2[..],
+            ReplicaId::new(1),
+            |_o: Arc<Operation>, _i: ReplicaId| {},
+        );
         assert_eq!(Arc::strong_count(&o1[0]), 2);
         assert_eq!(Arc::strong_count(&o2[0]), 2);
-        l.append(&o2[..], 1, |_o: Arc<Operation>, _i: usize| {});
+        l.append(
+            &o2[..],
+            ReplicaId::new(1),
+            |_o: Arc<Operation>, _i: ReplicaId| {},
+        );
         assert_eq!(Arc::strong_count(&o1[0]), 1);
         assert_eq!(Arc::strong_count(&o2[0]), 3);
     }
 
+    // An operation with a heap-allocated payload that counts how many copies of
+    // it are alive.
+    struct Tracked(std::vec::Vec<u8>, Arc<AtomicUsize>);
+
+    impl Tracked {
+        fn new(len: usize, live: &Arc<AtomicUsize>) -> Tracked {
+            live.fetch_add(1, Ordering::Relaxed);
+            Tracked(vec![len as u8; len], live.clone())
+        }
+    }
+
+    impl Clone for Tracked {
+        fn clone(&self) -> Tracked {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            Tracked(self.0.clone(), self.1.clone())
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Tests that large heap-allocated operations go through append, exec and GC
+    // intact, and are dropped once overwritten and when the log is dropped.
+    #[test]
+    fn test_log_heap_operations() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let l = Log::<Tracked>::new(64 * Log::<Tracked>::entry_size());
+        let r = l.register().unwrap();
+
+        let mut executed = 0;
+        for i in 0..4 * l.size() {
+            let len = 1024 + i % 7;
+            l.append(
+                &[Tracked::new(len, &live)],
+                r,
+                |_o: Tracked, _i: ReplicaId| {},
+            );
+            l.exec(r, &mut |o: Tracked, _i: ReplicaId| {
+                assert_eq!(o.0.len(), len);
+                assert!(o.0.iter().all(|b| *b == len as u8));
+                executed += 1;
+            });
+
+            // The log holds on to at most one copy per entry.
+            assert!(live.load(Ordering::Relaxed) <= l.size());
+        }
+        assert_eq!(executed, 4 * l.size());
+
+        drop(l);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    }
+
     // Tests that operations are cloned when added to the log, and that
     // they are correctly dropped once overwritten after the GC.
     #[test]
@@ -1087,13 +3626,21 @@ mod tests {
         assert_eq!(Arc::strong_count(&o2[0]), 1);
 
         for i in 1..(total_entries + 1) {
-            l.append(&o1[..], 1, |_o: Arc<Operation>, _i: usize| {});
+            l.append(
+                &o1[..],
+                ReplicaId::new(1),
+                |_o: Arc<Operation>, _i: ReplicaId| {},
+            );
             assert_eq!(Arc::strong_count(&o1[0]), i + 1);
         }
         assert_eq!(Arc::strong_count(&o1[0]), total_entries + 1);
 
         for i in 1..(total_entries + 1) {
-            l.append(&o2[..], 1, |_o: Arc<Operation>, _i: usize| {});
+            l.append(
+                &o2[..],
+                ReplicaId::new(1),
+                |_o: Arc<Operation>, _i: ReplicaId| {},
+            );
             assert_eq!(Arc::strong_count(&o1[0]), (total_entries + 1) - i);
             assert_eq!(Arc::strong_count(&o2[0]), i + 1);
         }
@@ -1109,16 +3656,16 @@ mod tests {
         let one = l.register().unwrap();
         let two = l.register().unwrap();
 
-        assert_eq!(one, 1);
-        assert_eq!(two, 2);
+        assert_eq!(one, ReplicaId::new(1));
+        assert_eq!(two, ReplicaId::new(2));
 
         let o = [Operation::Read];
-        let mut f = |op: Operation, i: usize| {
+        let mut f = |op: Operation, i: ReplicaId| {
             assert_eq!(op, Operation::Read);
-            assert_eq!(i, 1);
+            assert_eq!(i, ReplicaId::new(1));
         };
 
-        l.append(&o, one, |_o: Operation, _i: usize| {});
+        l.append(&o, one, |_o: Operation, _i: ReplicaId| {});
         l.exec(one, &mut f);
         assert_eq!(l.is_replica_synced_for_reads(one, l.get_ctail()), true);
         assert_eq!(l.is_replica_synced_for_reads(two, l.get_ctail()), false);
@@ -0,0 +1,145 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Hooks and counters that give insight into how a replica and the shared log
+//! behave at runtime (combiner batch sizes, log occupancy, GC stalls).
+
+use alloc::sync::Arc;
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::ids::{LogOffset, ReplicaId};
+
+/// Callbacks invoked by a [Replica](struct.Replica.html) (and the
+/// [Log](struct.Log.html) on its behalf) while it makes progress. Install one
+/// with [`Replica::set_observer`](struct.Replica.html#method.set_observer).
+///
+/// The callbacks are invoked by the combiner of the replica, i.e., on the
+/// critical path. Implementations should be cheap (e.g., bump a counter) and
+/// must not issue operations against the replica.
+pub trait ReplicaObserver {
+    /// A round of flat combining appended `batch_len` operations to the log
+    /// and took `duration`, including executing outstanding log entries.
+    /// `duration` is always zero without the `std` feature.
+    fn on_combine(&self, _batch_len: usize, _duration: Duration) {}
+
+    /// An append had to wait `iterations` rounds for the log to free up space
+    /// (either for another replica to advance the head or for the slowest
+    /// replica to catch up).
+    fn on_gc_stall(&self, _iterations: usize) {}
+
+    /// An append lost the race to reserve entries at the tail of the log and
+    /// had to retry.
+    fn on_append_retry(&self) {}
+
+    /// Replica `replica` applied the operation at logical offset `offset` on
+    /// the shared log to its copy of the data structure. Every replica applies
+    /// the operations in offset order, so the offsets reported by all replicas
+    /// (together with the offsets returned by
+    /// [`Replica::execute_mut_traced`](struct.Replica.html#method.execute_mut_traced))
+    /// are enough to reconstruct the global order of operations.
+    fn on_apply(&self, _offset: LogOffset, _replica: ReplicaId) {}
+}
+
+/// The observer used by the log when no replica is interested in callbacks.
+impl ReplicaObserver for () {}
+
+/// A snapshot of the counters of a replica, returned by
+/// [`Replica::metrics`](struct.Replica.html#method.metrics).
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReplicaMetrics {
+    /// Number of flat combining rounds performed.
+    pub combines: u64,
+
+    /// Number of operations appended to the log by the combiner.
+    pub combined_ops: u64,
+
+    /// Total time spent combining. Always zero without the `std` feature.
+    pub combine_time: Duration,
+
+    /// Number of appends that had to wait for garbage collection.
+    pub gc_stalls: u64,
+
+    /// Number of rounds spent waiting for garbage collection.
+    pub gc_stall_iterations: u64,
+
+    /// Number of failed attempts to reserve entries at the tail of the log.
+    pub append_retries: u64,
+
+    /// Number of entries on the shared log that haven't been garbage
+    /// collected yet, at the time of the snapshot.
+    pub log_entries: usize,
+}
+
+/// Counters maintained by every replica. Also forwards callbacks to the
+/// observer installed by the user (if any).
+#[derive(Default)]
+pub(crate) struct Metrics {
+    combines: AtomicU64,
+    combined_ops: AtomicU64,
+    combine_nanos: AtomicU64,
+    gc_stalls: AtomicU64,
+    gc_stall_iterations: AtomicU64,
+    append_retries: AtomicU64,
+
+    /// Observer installed by the user. Only accessed while holding the
+    /// combiner lock of the replica.
+    pub(crate) observer: RefCell<Option<Arc<dyn ReplicaObserver + Send + Sync>>>,
+}
+
+// Only the combiner updates the counters, so a load followed by a store is
+// enough; readers taking a snapshot only ever need atomic loads.
+fn bump(counter: &AtomicU64, by: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + by, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// Returns the current value of all counters. `log_entries` is filled in
+    /// by the caller.
+    pub(crate) fn snapshot(&self) -> ReplicaMetrics {
+        ReplicaMetrics {
+            combines: self.combines.load(Ordering::Relaxed),
+            combined_ops: self.combined_ops.load(Ordering::Relaxed),
+            combine_time: Duration::from_nanos(self.combine_nanos.load(Ordering::Relaxed)),
+            gc_stalls: self.gc_stalls.load(Ordering::Relaxed),
+            gc_stall_iterations: self.gc_stall_iterations.load(Ordering::Relaxed),
+            append_retries: self.append_retries.load(Ordering::Relaxed),
+            log_entries: 0,
+        }
+    }
+}
+
+/// Must only be invoked by the combiner of the replica owning the counters.
+impl ReplicaObserver for Metrics {
+    fn on_combine(&self, batch_len: usize, duration: Duration) {
+        bump(&self.combines, 1);
+        bump(&self.combined_ops, batch_len as u64);
+        bump(&self.combine_nanos, duration.as_nanos() as u64);
+        if let Some(observer) = self.observer.borrow().as_ref() {
+            observer.on_combine(batch_len, duration);
+        }
+    }
+
+    fn on_gc_stall(&self, iterations: usize) {
+        bump(&self.gc_stalls, 1);
+        bump(&self.gc_stall_iterations, iterations as u64);
+        if let Some(observer) = self.observer.borrow().as_ref() {
+            observer.on_gc_stall(iterations);
+        }
+    }
+
+    fn on_append_retry(&self) {
+        bump(&self.append_retries, 1);
+        if let Some(observer) = self.observer.borrow().as_ref() {
+            observer.on_append_retry();
+        }
+    }
+
+    fn on_apply(&self, offset: LogOffset, replica: ReplicaId) {
+        if let Some(observer) = self.observer.borrow().as_ref() {
+            observer.on_apply(offset, replica);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for hierarchical replication, i.e., using a replicated data structure
+//! as the data structure of another [Replica](struct.Replica.html).
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::replica::{Replica, ReplicaToken, MAX_THREADS_PER_REPLICA};
+use crate::Dispatch;
+
+/// Forwards operations to an inner [Replica](struct.Replica.html), so that it can
+/// be replicated once more by an outer one. This allows composing domains, e.g.,
+/// replicating a data structure per process and, within each process, per NUMA
+/// node.
+///
+/// Every outer replica needs its own `Nested` (each wrapping its own inner
+/// domain), created with `Replica::with_data`. Write operations must only be
+/// issued against the outer replicas; the inner replica can be used directly for
+/// reads.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{Dispatch, Log, Nested, Replica};
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// // The inner domain, e.g., replicated within a process.
+/// let inner_log = Arc::new(Log::<u64>::default());
+/// let inner = Replica::<Data>::new(&inner_log);
+///
+/// // The outer domain, e.g., replicated across processes.
+/// let outer_log = Arc::new(Log::<u64>::default());
+/// let outer = Replica::with_data(&outer_log, Nested::new(inner).unwrap());
+///
+/// let idx = outer.register().unwrap();
+/// assert_eq!(outer.execute_mut(2, idx), Ok(None));
+/// assert_eq!(outer.execute((), idx), Ok(Some(2)));
+/// ```
+pub struct Nested<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// The replica of the inner domain.
+    replica: Arc<Replica<'a, D>>,
+
+    /// Token used to issue write operations. `dispatch_mut` has exclusive access
+    /// to `self`, so one token is enough.
+    writer: ReplicaToken,
+
+    /// Identifiers of tokens used to issue read operations. `dispatch` is called
+    /// concurrently, so every caller takes a token out of here (or registers a
+    /// new one) and puts it back when done. Zero marks an empty slot.
+    readers: Vec<AtomicUsize>,
+}
+
+impl<'a, D> Nested<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Wraps `replica` so that it can be used as the data structure of another
+    /// replica. Returns None if no thread can be registered with `replica`.
+    pub fn new(replica: Arc<Replica<'a, D>>) -> Option<Nested<'a, D>> {
+        let writer = replica.register()?;
+        let mut readers = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
+        for _i in 0..MAX_THREADS_PER_REPLICA {
+            readers.push(AtomicUsize::new(0));
+        }
+
+        Some(Nested {
+            replica,
+            writer,
+            readers,
+        })
+    }
+
+    /// Returns the replica of the inner domain.
+    pub fn replica(&self) -> &Arc<Replica<'a, D>> {
+        &self.replica
+    }
+
+    /// Takes a token for a read operation; registers a new one if none is free.
+    fn acquire(&self) -> ReplicaToken {
+        loop {
+            for slot in self.readers.iter() {
+                let id = slot.swap(0, Ordering::Acquire);
+                if id != 0 {
+                    return unsafe { ReplicaToken::new(id) };
+                }
+            }
+
+            if let Some(idx) = self.replica.register() {
+                return idx;
+            }
+
+            // All threads of the inner replica are taken; wait for a reader to
+            // hand its token back.
+            spin_loop();
+        }
+    }
+
+    /// Returns a token taken with `acquire`.
+    fn release(&self, idx: ReplicaToken) {
+        // There are at most `MAX_THREADS_PER_REPLICA` tokens, so there's always
+        // an empty slot.
+        for slot in self.readers.iter() {
+            if slot
+                .compare_exchange(0, idx.id().get(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+        }
+        unreachable!("No empty slot for a reader token.");
+    }
+}
+
+impl<'a, D> Dispatch for Nested<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    type ReadOperation = <D as Dispatch>::ReadOperation;
+    type WriteOperation = <D as Dispatch>::WriteOperation;
+    type Response = <D as Dispatch>::Response;
+
+    /// Executes the operation against the inner replica, syncing it with the
+    /// inner log first.
+    ///
+    /// # Panics
+    /// If the inner replica can no longer execute operations.
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let idx = self.acquire();
+        let resp = self
+            .replica
+            .execute(op, idx)
+            .expect("Inner replica can no longer execute operations!");
+        self.release(idx);
+        resp
+    }
+
+    /// Appends the operation to the inner log and executes it.
+    ///
+    /// # Panics
+    /// If the inner replica can no longer execute operations. This poisons the
+    /// outer replica in turn.
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        // Whichever thread combines on the outer replica calls this; `&mut self`
+        // guarantees they take turns, so the token can be handed over to it.
+        let idx = unsafe { ReplicaToken::new(self.writer.id().get()) };
+        self.replica
+            .execute_mut(op, idx)
+            .expect("Inner replica can no longer execute operations!")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::Log;
+
+    use std::thread;
+    use std::vec;
+
+    #[derive(Default)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.junk
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.junk += op;
+            self.junk
+        }
+    }
+
+    // Tests that a two-level hierarchy keeps all outer and inner replicas in sync.
+    #[test]
+    fn test_nested_sync() {
+        let outer_log = Arc::new(Log::<u64>::default());
+        let mut inner_logs = vec![];
+        let mut outer = vec![];
+        for _i in 0..2 {
+            let inner_log = Arc::new(Log::<u64>::default());
+            let inner = Replica::<Data>::new(&inner_log);
+            let nested = Nested::new(inner).unwrap();
+            outer.push(Replica::with_data(&outer_log, nested));
+            inner_logs.push(inner_log);
+        }
+
+        let mut threads = vec![];
+        for replica in outer.iter() {
+            for _t in 0..2 {
+                let replica = replica.clone();
+                threads.push(thread::spawn(move || {
+                    let idx = replica.register().unwrap();
+                    for _i in 0..1000 {
+                        replica.execute_mut(1, idx).unwrap();
+                        assert!(replica.execute((), idx).unwrap() <= 4000);
+                    }
+                }));
+            }
+        }
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+
+        for replica in outer.iter() {
+            replica.verify(|nested: &Nested<Data>| {
+                nested.replica().verify(|d: &Data| assert_eq!(d.junk, 4000));
+            });
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for keeping the shared log in persistent memory (e.g., Intel Optane
+//! DC in App Direct mode) so that it survives crashes. Enabled with the `pmem`
+//! feature.
+
+use core::arch::x86_64::{__cpuid_count, _mm_clflush, _mm_sfence};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::log::CACHE_LINE;
+
+/// Identifies a region that holds a persistent log.
+pub(crate) const MAGIC: usize = 0x6e72_6c6f_675f_7631;
+
+/// Metadata at the start of a region that holds a persistent log. The entries
+/// of the log follow right after it.
+#[repr(C, align(64))]
+pub(crate) struct Header {
+    /// Set to `MAGIC` once the region is fully initialized.
+    pub(crate) magic: AtomicUsize,
+
+    /// Size of an entry in bytes. Used to reject regions written with a
+    /// different operation type.
+    pub(crate) entry_size: AtomicUsize,
+
+    /// Number of entries on the log.
+    pub(crate) entries: AtomicUsize,
+
+    /// Logical index at which the log starts. Never behind the head of the
+    /// log in DRAM, so that recovery doesn't replay overwritten entries.
+    pub(crate) head: AtomicUsize,
+
+    /// Logical index up to which appends persisted their entries. There can be
+    /// holes below it if appends raced, which recovery has to check for.
+    pub(crate) tail: AtomicUsize,
+}
+
+const_assert!(core::mem::size_of::<Header>() == CACHE_LINE);
+
+impl Header {
+    /// Makes the head durable before the log moves past `head` in DRAM.
+    pub(crate) fn persist_head(&self, head: usize) {
+        self.head.fetch_max(head, Ordering::Relaxed);
+        persist(self as *const Header as *const u8, CACHE_LINE);
+    }
+
+    /// Makes the tail durable once the entries below `tail` were persisted.
+    pub(crate) fn persist_tail(&self, tail: usize) {
+        self.tail.fetch_max(tail, Ordering::Relaxed);
+        persist(self as *const Header as *const u8, CACHE_LINE);
+    }
+}
+
+/// Whether the CPU supports `clwb`: 0 if we don't know yet, 1 if it does, 2
+/// if it doesn't.
+static CLWB: AtomicU8 = AtomicU8::new(0);
+
+fn has_clwb() -> bool {
+    match CLWB.load(Ordering::Relaxed) {
+        0 => {
+            // CPUID leaf 7 reports `clwb` in bit 24 of ebx.
+            let clwb = unsafe { __cpuid_count(7, 0).ebx & (1 << 24) != 0 };
+            CLWB.store(if clwb { 1 } else { 2 }, Ordering::Relaxed);
+            clwb
+        }
+        c => c == 1,
+    }
+}
+
+/// Writes the cache lines covering `len` bytes at `ptr` back to memory. Uses
+/// `clwb` (which keeps the lines cached) if the CPU supports it, `clflush`
+/// otherwise. Not ordered with later stores; use `fence` for that.
+pub(crate) fn flush(ptr: *const u8, len: usize) {
+    let start = ptr as usize & !(CACHE_LINE - 1);
+    let end = ptr as usize + len;
+    let clwb = has_clwb();
+
+    for line in (start..end).step_by(CACHE_LINE) {
+        unsafe {
+            if clwb {
+                asm!("clwb [{}]", in(reg) line, options(nostack, preserves_flags));
+            } else {
+                _mm_clflush(line as *const u8);
+            }
+        }
+    }
+}
+
+/// Waits for all flushes issued so far to reach persistent memory.
+pub(crate) fn fence() {
+    unsafe { _mm_sfence() };
+}
+
+/// Flushes `len` bytes at `ptr` and waits for them to be persisted.
+pub(crate) fn persist(ptr: *const u8, len: usize) {
+    flush(ptr, len);
+    fence();
+}
@@ -0,0 +1,140 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Copies of the data structure of a replica that the combiner publishes
+//! periodically, so that reads can be served from them without taking the
+//! locks of the replica.
+
+use alloc::sync::Arc;
+
+use core::cell::{Cell, UnsafeCell};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ids::LogOffset;
+
+/// Clones a data structure; `Clone::clone` of the data structure.
+type CloneFn<D> = fn(&D) -> D;
+
+/// A copy of the data structure, along with the offset on the shared log up to
+/// which operations were executed against it.
+pub(crate) struct Version<D> {
+    pub(crate) offset: LogOffset,
+    pub(crate) data: D,
+}
+
+/// Holds the most recently published copy of a data structure.
+///
+/// Readers and the combiner only hold `lock` to clone or replace the `Arc`,
+/// never while dispatching operations against the copy.
+pub(crate) struct Published<D> {
+    lock: AtomicBool,
+    current: UnsafeCell<Option<Arc<Version<D>>>>,
+
+    /// Clones the data structure; `None` if publishing is disabled. Only
+    /// accessed by the combiner.
+    clone: Cell<Option<CloneFn<D>>>,
+
+    /// Number of operations after which the combiner publishes a new copy.
+    /// Only accessed by the combiner.
+    every: Cell<usize>,
+
+    /// Offset of the most recently published copy. Only accessed by the
+    /// combiner.
+    last: Cell<usize>,
+}
+
+/// Access to `current` is serialized by `lock`; the other fields are only
+/// accessed by the combiner of the replica.
+unsafe impl<D> Sync for Published<D> where D: Send + Sync {}
+
+impl<D> Default for Published<D> {
+    fn default() -> Self {
+        Published {
+            lock: AtomicBool::new(false),
+            current: UnsafeCell::new(None),
+            clone: Cell::new(None),
+            every: Cell::new(0),
+            last: Cell::new(0),
+        }
+    }
+}
+
+impl<D> Published<D> {
+    /// Runs `f` with exclusive access to the published copy.
+    fn with_current<R, F: FnOnce(&mut Option<Arc<Version<D>>>) -> R>(&self, f: F) -> R {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let r = f(unsafe { &mut *self.current.get() });
+        self.lock.store(false, Ordering::Release);
+        r
+    }
+
+    /// Returns the most recently published copy, if any.
+    pub(crate) fn load(&self) -> Option<Arc<Version<D>>> {
+        self.with_current(|current| current.clone())
+    }
+
+    /// Makes the combiner publish a copy of the data structure, cloned with
+    /// `clone`, every `every` operations, starting with `data` at `offset`.
+    /// Must be called by the combiner.
+    pub(crate) fn enable(&self, clone: CloneFn<D>, every: usize, data: &D, offset: LogOffset) {
+        self.clone.set(Some(clone));
+        self.every.set(every);
+        self.publish(clone, data, offset);
+    }
+
+    /// Publishes a copy of `data` if at least the configured number of
+    /// operations were executed since the last one. Must be called by the
+    /// combiner, with `offset` being the offset up to which `data` executed the
+    /// log.
+    #[inline(always)]
+    pub(crate) fn maybe_publish(&self, data: &D, offset: LogOffset) {
+        if let Some(clone) = self.clone.get() {
+            if offset.get() >= self.last.get() + self.every.get() {
+                self.publish(clone, data, offset);
+            }
+        }
+    }
+
+    fn publish(&self, clone: CloneFn<D>, data: &D, offset: LogOffset) {
+        let version = Arc::new(Version {
+            offset,
+            data: clone(data),
+        });
+        self.last.set(offset.get());
+
+        // Drop the previous copy outside the lock; readers may still hold it.
+        let previous = self.with_current(|current| current.replace(version));
+        drop(previous);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that a copy is only published once enough operations were executed
+    // since the last one.
+    #[test]
+    fn test_published_every() {
+        let p = Published::<u64>::default();
+        p.maybe_publish(&1, LogOffset::new(100));
+        assert!(p.load().is_none());
+
+        p.enable(u64::clone, 4, &1, LogOffset::new(1));
+        assert_eq!(p.load().unwrap().data, 1);
+
+        p.maybe_publish(&2, LogOffset::new(4));
+        assert_eq!(p.load().unwrap().data, 1);
+
+        p.maybe_publish(&3, LogOffset::new(5));
+        let v = p.load().unwrap();
+        assert_eq!((v.data, v.offset), (3, LogOffset::new(5)));
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Per-thread rate limiting of write operations. Requires the `std` feature.
+
+use core::time::Duration;
+
+use std::time::Instant;
+
+/// Limits the rate at which a thread can issue write operations against a
+/// [Replica](struct.Replica.html). Passed to `Replica::register_with_limit`.
+///
+/// A thread can issue `burst` operations at once, after which it is limited to
+/// `ops_per_sec` operations per second on average.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    ops_per_sec: u64,
+    burst: u64,
+}
+
+impl RateLimit {
+    /// Creates a rate limit of `ops_per_sec` operations per second with bursts
+    /// of up to `burst` operations.
+    ///
+    /// # Panics
+    /// If `ops_per_sec` or `burst` is zero.
+    pub fn new(ops_per_sec: u64, burst: u64) -> RateLimit {
+        assert!(
+            ops_per_sec > 0,
+            "Rate limit must allow at least one op/sec."
+        );
+        assert!(burst > 0, "Burst must allow at least one operation.");
+        RateLimit { ops_per_sec, burst }
+    }
+}
+
+/// Returned by `Replica::try_execute_mut` if the thread exceeded its
+/// [RateLimit](struct.RateLimit.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Throttled {
+    /// Time after which the thread may issue its next operation.
+    pub retry_after: Duration,
+}
+
+/// A token bucket, implemented as a generic cell rate algorithm: rather than
+/// refilling tokens, it tracks the time at which the next operation would be
+/// due if the thread issued operations at exactly the configured rate.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct TokenBucket {
+    /// Time between two operations at the configured rate.
+    interval: Duration,
+
+    /// How far the due time may be ahead of the current time; this is what
+    /// allows bursts.
+    tolerance: Duration,
+
+    /// Time at which the next operation is due. `None` until the first one.
+    due: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> TokenBucket {
+        let interval = (1_000_000_000 / limit.ops_per_sec).max(1);
+        TokenBucket {
+            interval: Duration::from_nanos(interval),
+            tolerance: Duration::from_nanos(interval.saturating_mul(limit.burst)),
+            due: None,
+        }
+    }
+
+    /// Takes a token for an operation issued at `now`, or returns how long the
+    /// caller has to wait for one.
+    pub(crate) fn acquire(&mut self, now: Instant) -> Result<(), Throttled> {
+        let due = match self.due {
+            Some(due) if due > now => due,
+            _ => now,
+        };
+
+        let next = due + self.interval;
+        let ahead = next - now;
+        if ahead > self.tolerance {
+            return Err(Throttled {
+                retry_after: ahead - self.tolerance,
+            });
+        }
+
+        self.due = Some(next);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that a bucket allows a full burst and then throttles until a token
+    // becomes available again.
+    #[test]
+    fn test_token_bucket_burst() {
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 4));
+        let now = Instant::now();
+
+        for _i in 0..4 {
+            assert_eq!(bucket.acquire(now), Ok(()));
+        }
+        assert_eq!(
+            bucket.acquire(now),
+            Err(Throttled {
+                retry_after: Duration::from_millis(1)
+            })
+        );
+
+        assert_eq!(bucket.acquire(now + Duration::from_millis(1)), Ok(()));
+        assert!(bucket.acquire(now + Duration::from_millis(1)).is_err());
+    }
+
+    // Tests that an idle thread doesn't accumulate more than one burst.
+    #[test]
+    fn test_token_bucket_idle() {
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 2));
+        let now = Instant::now();
+        assert_eq!(bucket.acquire(now), Ok(()));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.acquire(later), Ok(()));
+        assert_eq!(bucket.acquire(later), Ok(()));
+        assert!(bucket.acquire(later).is_err());
+    }
+
+    // Tests that an invalid rate limit is rejected.
+    #[test]
+    #[should_panic]
+    fn test_rate_limit_zero() {
+        RateLimit::new(0, 1);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Read-only operations that record something on the side (e.g., an audit
+//! trail of the reads), without mutating the data structure.
+
+use alloc::vec::Vec;
+
+use crate::Dispatch;
+
+/// Implemented by data structures whose read-only operations record something
+/// besides their response, to be executed with
+/// [`Replica::execute_read_mut`](struct.Replica.html#method.execute_read_mut).
+///
+/// `dispatch` only gets `&self`, and replicas execute it concurrently, so
+/// recording in the data structure itself takes interior mutability (and a
+/// lock to stay `Sync`). Records go to a `ReadCtx` owned by the calling thread
+/// instead.
+pub trait DispatchReadMut: Dispatch {
+    /// What a read-only operation records.
+    type ReadRecord;
+
+    /// Method on the data structure that allows a read-only operation to be
+    /// executed against it, recording into `ctx`. Must return the same response
+    /// as `dispatch` would for `op`.
+    fn dispatch_read_mut(
+        &self,
+        op: Self::ReadOperation,
+        ctx: &mut ReadCtx<Self::ReadRecord>,
+    ) -> Self::Response;
+}
+
+/// Records of read-only operations, see `DispatchReadMut`.
+///
+/// Reads execute against a single replica, so unlike the effects of write
+/// operations, records aren't replicated: they stay with the thread that
+/// passed the context in, in the order of its reads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadCtx<T> {
+    records: Vec<T>,
+}
+
+impl<T> Default for ReadCtx<T> {
+    fn default() -> ReadCtx<T> {
+        ReadCtx::new()
+    }
+}
+
+impl<T> ReadCtx<T> {
+    /// Creates an empty context.
+    pub fn new() -> ReadCtx<T> {
+        ReadCtx {
+            records: Vec::new(),
+        }
+    }
+
+    /// Appends `record` to the context.
+    pub fn record(&mut self, record: T) {
+        self.records.push(record);
+    }
+
+    /// Returns the records, oldest first.
+    pub fn records(&self) -> &[T] {
+        &self.records
+    }
+
+    /// Takes the records out of the context, oldest first, leaving it empty.
+    pub fn take(&mut self) -> Vec<T> {
+        core::mem::take(&mut self.records)
+    }
+
+    /// Returns the number of records.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns true if nothing was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use crate::{Log, Replica};
+
+    use alloc::sync::Arc;
+    use std::vec;
+
+    // Records the values the counter was read at.
+    impl DispatchReadMut for Counter {
+        type ReadRecord = u64;
+
+        fn dispatch_read_mut(&self, op: Self::ReadOperation, ctx: &mut ReadCtx<u64>) -> u64 {
+            let resp = self.dispatch(op);
+            ctx.record(resp);
+            resp
+        }
+    }
+
+    // Tests that reads record into the context they are passed, after the
+    // replica caught up with the writes of another replica.
+    #[test]
+    fn test_replica_execute_read_mut() {
+        let log = Arc::new(Log::<u64>::default());
+        let replica = Replica::<Counter>::new(&log);
+        let other = Replica::<Counter>::new(&log);
+        let idx = replica.register().unwrap();
+        let oidx = other.register().unwrap();
+
+        let mut ctx = ReadCtx::new();
+        assert_eq!(replica.execute_read_mut((), idx, &mut ctx), Ok(0));
+        other.execute_mut(2, oidx).unwrap();
+        assert_eq!(replica.execute_read_mut((), idx, &mut ctx), Ok(2));
+        assert_eq!(ctx.records(), &[0, 2]);
+
+        assert_eq!(ctx.take(), vec![0, 2]);
+        assert!(ctx.is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Helpers to bound how long bringing up a replica after a restart takes, i.e.,
+//! how many operations on the shared log it has to replay on top of the last
+//! [Checkpoint](../struct.Checkpoint.html). Requires the `std` feature.
+
+use core::time::Duration;
+
+use std::time::Instant;
+
+use crate::log::{DeltaCodec, Log};
+use crate::replica::Replica;
+use crate::{Checkpoint, Dispatch, LogOffset, OpOrigin, Snapshot};
+
+/// Replays up to `sample` operations on `log` from offset `from` against a
+/// fresh data structure. Returns how long this took and how many operations
+/// were replayed, or `None` if there was nothing to replay.
+fn measure<D, C>(
+    log: &Log<'_, <D as Dispatch>::WriteOperation, C>,
+    from: LogOffset,
+    sample: usize,
+) -> Option<(Duration, usize)>
+where
+    D: Dispatch + Default,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    let mut data = D::default();
+    let mut replayed = 0;
+
+    let start = Instant::now();
+    for (op, replica, offset) in log.iter_from(from).take(sample) {
+        data.dispatch_mut_ctx(op, OpOrigin { replica, offset });
+        replayed += 1;
+    }
+    let elapsed = start.elapsed();
+
+    if replayed == 0 {
+        return None;
+    }
+    Some((elapsed, replayed))
+}
+
+/// Scales the time it took to replay some operations, as returned by
+/// `measure`, to `total` operations.
+fn extrapolate((elapsed, replayed): (Duration, usize), total: usize) -> Duration {
+    let nanos = elapsed.as_nanos() * total as u128 / replayed as u128;
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+/// Estimates how long replaying every operation retained on `log` (from its
+/// head to its tail) takes, e.g., when a replica recovers a persistent log
+/// without a checkpoint. Operations that every replica executed are garbage
+/// collected and no longer retained.
+///
+/// Times the replay of up to `sample` of these operations against a fresh
+/// `D::default()` and extrapolates, so the estimate assumes that operations
+/// cost about the same regardless of the state of the data structure. Returns
+/// zero if no operations are retained.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{recovery, Dispatch, Log, Replica};
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::default());
+/// let replica = Replica::<Data>::new(&log);
+/// let idx = replica.register().unwrap();
+///
+/// // Operations stay on the log at least until this replica executes them.
+/// let _lagging = Replica::<Data>::new(&log);
+///
+/// for i in 0..100 {
+///     replica.execute_mut(i, idx).unwrap();
+/// }
+///
+/// let replay = recovery::estimate_replay::<Data, _>(&log, 10);
+/// println!("Replaying the log takes about {:?}.", replay);
+/// ```
+pub fn estimate_replay<D, C>(
+    log: &Log<'_, <D as Dispatch>::WriteOperation, C>,
+    sample: usize,
+) -> Duration
+where
+    D: Dispatch + Default,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    let head = log.get_head();
+    let retained = log.get_tail().saturating_sub(head);
+    match measure::<D, C>(log, LogOffset::new(head), sample) {
+        Some(cost) => extrapolate(cost, retained),
+        None => Duration::default(),
+    }
+}
+
+/// Keeps the time it takes to replay the shared log after a restart below a
+/// target by checkpointing a replica once the operations appended since its
+/// last checkpoint would take longer to replay.
+///
+/// The application calls `checkpoint_if_needed` periodically (e.g., from a
+/// background thread) and persists the checkpoints it returns; a replica
+/// restored from the latest one with `Replica::from_checkpoint` then replays at
+/// most about `target` worth of operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ReplayLimit {
+    target: Duration,
+    sample: usize,
+    last: LogOffset,
+    cost: Option<(Duration, usize)>,
+}
+
+impl ReplayLimit {
+    /// Creates a limit that keeps the replay time below `target`, estimated by
+    /// timing the replay of up to `sample` operations (see `estimate_replay`).
+    ///
+    /// # Panics
+    /// If `sample` is zero.
+    pub fn new(target: Duration, sample: usize) -> ReplayLimit {
+        assert!(sample > 0, "Estimate must replay at least one operation.");
+        ReplayLimit {
+            target,
+            sample,
+            last: LogOffset::new(0),
+            cost: None,
+        }
+    }
+
+    /// Returns the offset of the last checkpoint taken by this limit, zero if
+    /// there wasn't one yet.
+    pub fn last_checkpoint(&self) -> LogOffset {
+        self.last
+    }
+
+    /// Estimates how long replaying the operations on the log after the last
+    /// checkpoint takes. If that's longer than the target, checkpoints
+    /// `replica` (see `Replica::checkpoint`) and returns the checkpoint.
+    ///
+    /// The cost of an operation is sampled from the operations after the last
+    /// checkpoint that are still retained on the log. If all of them were
+    /// garbage collected, the cost sampled by a previous call is used; without
+    /// one, the replica isn't checkpointed.
+    pub fn checkpoint_if_needed<D, C>(
+        &mut self,
+        replica: &Replica<'_, D, C>,
+    ) -> Option<Checkpoint<<D as Snapshot>::Snapshot>>
+    where
+        D: Sized + Default + Dispatch + Snapshot + Sync,
+        C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+    {
+        let log = replica.log();
+
+        // Operations before the head of the log were garbage collected, so the
+        // sample starts at the head if the last checkpoint is behind it.
+        let from = LogOffset::new(self.last.get().max(log.get_head()));
+        if let Some(cost) = measure::<D, C>(log, from, self.sample) {
+            self.cost = Some(cost);
+        }
+
+        let pending = log.get_tail().saturating_sub(self.last.get());
+        if extrapolate(self.cost?, pending) <= self.target {
+            return None;
+        }
+
+        let checkpoint = replica.checkpoint();
+        self.last = checkpoint.offset;
+        Some(checkpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    // Data structure whose operations take at least a millisecond each.
+    #[derive(Default)]
+    struct Slow(u64);
+
+    impl Dispatch for Slow {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.0
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            thread::sleep(Duration::from_millis(1));
+            self.0 += op;
+            self.0
+        }
+    }
+
+    impl Snapshot for Slow {
+        type Snapshot = u64;
+
+        fn snapshot(&self) -> Self::Snapshot {
+            self.0
+        }
+
+        fn restore(snapshot: Self::Snapshot) -> Self {
+            Slow(snapshot)
+        }
+    }
+
+    // Tests that the replay estimate covers every operation on the log, not
+    // just the sampled ones.
+    #[test]
+    fn test_recovery_estimate_replay() {
+        let log = Arc::new(Log::<u64>::default());
+        assert_eq!(estimate_replay::<Slow, _>(&log, 2), Duration::default());
+
+        let replica = Replica::<Slow>::new(&log);
+        let idx = replica.register().unwrap();
+        let _lagging = Replica::<Slow>::new(&log);
+        for _i in 0..10 {
+            replica.execute_mut(1, idx).unwrap();
+        }
+
+        assert!(estimate_replay::<Slow, _>(&log, 2) >= Duration::from_millis(10));
+    }
+
+    // Tests that a replica is checkpointed once replaying the operations since
+    // the last checkpoint would exceed the target, and not before.
+    #[test]
+    fn test_recovery_replay_limit() {
+        let log = Arc::new(Log::<u64>::default());
+        let replica = Replica::<Slow>::new(&log);
+        let idx = replica.register().unwrap();
+        let _lagging = Replica::<Slow>::new(&log);
+        let mut limit = ReplayLimit::new(Duration::from_millis(50), 2);
+
+        assert_eq!(limit.checkpoint_if_needed(&replica), None);
+        for _i in 0..2 {
+            replica.execute_mut(1, idx).unwrap();
+        }
+        assert_eq!(limit.checkpoint_if_needed(&replica), None);
+
+        for _i in 0..98 {
+            replica.execute_mut(1, idx).unwrap();
+        }
+        let checkpoint = limit.checkpoint_if_needed(&replica).unwrap();
+        assert_eq!(checkpoint.offset, LogOffset::new(100));
+        assert_eq!(checkpoint.snapshot, 100);
+        assert_eq!(limit.last_checkpoint(), LogOffset::new(100));
+
+        assert_eq!(limit.checkpoint_if_needed(&replica), None);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recording of the write operations replicas append to the shared log, along
+//! with the replica and thread that issued them, and single-threaded replay of
+//! such a recording to reproduce bugs (e.g., replicas that diverge)
+//! deterministically. Requires the `std` feature.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use std::sync::Mutex;
+
+use crate::ids::{LogOffset, OpOrigin, ReplicaId, ThreadId};
+use crate::Dispatch;
+
+/// A write operation as it was appended to the log by a replica.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Recorded<T> {
+    /// Logical offset of the operation on the log. Replicas execute operations
+    /// in the order of their offsets.
+    pub offset: LogOffset,
+
+    /// The operation.
+    pub op: T,
+
+    /// The replica whose combiner appended the operation.
+    pub replica: ReplicaId,
+
+    /// The thread of `replica` that issued the operation.
+    pub thread: ThreadId,
+}
+
+/// Where a `Recorder` puts the operations.
+enum Sink<T> {
+    /// Keeps the last `capacity` operations.
+    Ring {
+        records: Mutex<VecDeque<Recorded<T>>>,
+        capacity: usize,
+    },
+
+    /// Hands every operation to a callback.
+    Callback(Box<dyn Fn(Recorded<T>) + Send + Sync>),
+}
+
+/// Records the write operations appended to the log by the replicas it is
+/// installed on with `Replica::set_recorder`. Install the same recorder on
+/// every replica of the log to record all operations on it.
+///
+/// Recording happens on the critical path of the combiner: every operation is
+/// cloned, and a ring buffer is shared by all replicas behind a lock.
+pub struct Recorder<T> {
+    sink: Sink<T>,
+}
+
+impl<T: Clone> Recorder<T> {
+    /// Creates a recorder that keeps the last `capacity` operations.
+    pub fn ring(capacity: usize) -> Recorder<T> {
+        Recorder {
+            sink: Sink::Ring {
+                records: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+            },
+        }
+    }
+
+    /// Creates a recorder that passes every operation to `f`, e.g., to write
+    /// it to a file. `f` is called by the combiners of the replicas, so it has
+    /// to be quick and must not issue operations against them.
+    pub fn with_sink<F: Fn(Recorded<T>) + Send + Sync + 'static>(f: F) -> Recorder<T> {
+        Recorder {
+            sink: Sink::Callback(Box::new(f)),
+        }
+    }
+
+    /// Returns the operations a ring buffer recorder holds, in the order of
+    /// their offsets on the log. Always empty for recorders with a sink.
+    pub fn records(&self) -> Vec<Recorded<T>> {
+        match &self.sink {
+            Sink::Ring { records, .. } => {
+                let mut records: Vec<Recorded<T>> =
+                    records.lock().unwrap().iter().cloned().collect();
+                records.sort_by_key(|r| r.offset);
+                records
+            }
+            Sink::Callback(_f) => Vec::new(),
+        }
+    }
+
+    /// Records operation `op`, appended at `offset` by thread `thread` of
+    /// replica `replica`.
+    pub(crate) fn record(&self, offset: LogOffset, op: &T, replica: ReplicaId, thread: ThreadId) {
+        let record = Recorded {
+            offset,
+            op: op.clone(),
+            replica,
+            thread,
+        };
+
+        match &self.sink {
+            Sink::Ring { records, capacity } => {
+                if *capacity == 0 {
+                    return;
+                }
+                let mut records = records.lock().unwrap();
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+            Sink::Callback(f) => f(record),
+        }
+    }
+}
+
+/// Returned by `replay` if the recording misses operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ReplayGap {
+    /// The offset of the operation that `replay` expected next.
+    pub expected: LogOffset,
+
+    /// The offset of the operation that came instead.
+    pub found: LogOffset,
+}
+
+/// Executes the recorded operations `records` against `d` on the calling
+/// thread, in the order of their offsets (like every replica does), and
+/// returns their responses in that order.
+///
+/// Fails without executing anything if the offsets of the operations aren't
+/// consecutive, e.g., because the recorder wasn't installed on every replica.
+/// The first operation has to be the first one `d` misses; start from a fresh
+/// data structure for recordings that start at offset zero.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::replay::{replay, Recorder};
+/// use node_replication::{Dispatch, Log, Replica};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::default());
+/// let replica = Replica::<Data>::new(&log);
+/// let recorder = Arc::new(Recorder::ring(1024));
+/// replica.set_recorder(recorder.clone());
+///
+/// let idx = replica.register().unwrap();
+/// replica.execute_mut(2, idx).unwrap();
+/// replica.execute_mut(3, idx).unwrap();
+///
+/// let mut fresh = Data::default();
+/// assert_eq!(replay(&mut fresh, &recorder.records()), Ok(vec![None, None]));
+/// assert_eq!(fresh.junk, 3);
+/// ```
+pub fn replay<D: Dispatch>(
+    d: &mut D,
+    records: &[Recorded<<D as Dispatch>::WriteOperation>],
+) -> Result<Vec<<D as Dispatch>::Response>, ReplayGap> {
+    let mut records: Vec<&Recorded<<D as Dispatch>::WriteOperation>> = records.iter().collect();
+    records.sort_by_key(|r| r.offset);
+
+    for pair in records.windows(2) {
+        let expected = LogOffset::new(pair[0].offset.get() + 1);
+        if pair[1].offset != expected {
+            return Err(ReplayGap {
+                expected,
+                found: pair[1].offset,
+            });
+        }
+    }
+
+    Ok(records
+        .into_iter()
+        .map(|r| {
+            let origin = OpOrigin {
+                replica: r.replica,
+                offset: r.offset,
+            };
+            d.dispatch_mut_ctx(r.op.clone(), origin)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use crate::log::Log;
+    use crate::replica::Replica;
+    use std::sync::Arc;
+    use std::vec;
+
+    // Tests that a ring buffer keeps the last operations in offset order, and
+    // that a sink sees every operation.
+    #[test]
+    fn test_replay_recorder() {
+        let ring = Recorder::ring(2);
+        for i in [2usize, 0, 1].iter() {
+            ring.record(
+                LogOffset::new(*i),
+                &(*i as u64),
+                ReplicaId::new(1),
+                ThreadId::new(1),
+            );
+        }
+        let offsets: Vec<usize> = ring.records().iter().map(|r| r.offset.get()).collect();
+        assert_eq!(offsets, vec![0, 1]);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let seen = seen.clone();
+            Recorder::with_sink(move |r: Recorded<u64>| seen.lock().unwrap().push(r.op))
+        };
+        sink.record(LogOffset::new(0), &7, ReplicaId::new(1), ThreadId::new(1));
+        assert_eq!(*seen.lock().unwrap(), vec![7]);
+        assert!(sink.records().is_empty());
+    }
+
+    // Tests that replay executes operations in offset order and refuses
+    // recordings with missing operations.
+    #[test]
+    fn test_replay_gap() {
+        let record = |offset: usize, op: u64| Recorded {
+            offset: LogOffset::new(offset),
+            op,
+            replica: ReplicaId::new(1),
+            thread: ThreadId::new(1),
+        };
+
+        let mut d = Counter::default();
+        assert_eq!(
+            replay(&mut d, &[record(1, 3), record(0, 2)]),
+            Ok(vec![2, 5])
+        );
+
+        let mut d = Counter::default();
+        assert_eq!(
+            replay(&mut d, &[record(0, 2), record(2, 3)]),
+            Err(ReplayGap {
+                expected: LogOffset::new(1),
+                found: LogOffset::new(2),
+            })
+        );
+        assert_eq!(d.0, 0);
+    }
+
+    // Tests that replaying what the replicas of a log recorded ends up in the
+    // state of the replicas, and that every operation is attributed to the
+    // thread that issued it.
+    #[test]
+    fn test_replay_replicas() {
+        let log = Arc::new(Log::<u64>::default());
+        let recorder = Arc::new(Recorder::ring(1024));
+        let replicas: Vec<_> = (0..2).map(|_i| Replica::<Counter>::new(&log)).collect();
+        for replica in replicas.iter() {
+            replica.set_recorder(recorder.clone());
+        }
+
+        let threads: Vec<_> = (0..4)
+            .map(|t: u64| {
+                let replica = replicas[t as usize % 2].clone();
+                std::thread::spawn(move || {
+                    let idx = replica.register().unwrap();
+                    for _i in 0..100 {
+                        replica.execute_mut(t + 1, idx).unwrap();
+                    }
+                    idx.id()
+                })
+            })
+            .collect();
+        let ids: Vec<ThreadId> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        let records = recorder.records();
+        assert_eq!(records.len(), 400);
+        let replica_of = |t: u64| records.iter().find(|r| r.op == t + 1).unwrap().replica;
+        assert_eq!(replica_of(0), replica_of(2));
+        assert_eq!(replica_of(1), replica_of(3));
+        assert_ne!(replica_of(0), replica_of(1));
+        for r in records.iter() {
+            assert_eq!(r.replica, replica_of(r.op - 1));
+            assert_eq!(r.thread, ids[r.op as usize - 1]);
+        }
+
+        let mut d = Counter::default();
+        replay(&mut d, &records).unwrap();
+        for replica in replicas.iter() {
+            let idx = replica.register().unwrap();
+            assert_eq!(replica.execute((), idx), Ok(d.0));
+        }
+    }
+}
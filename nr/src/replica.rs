@@ -1,28 +1,360 @@
 // Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use core::mem::size_of;
+use core::mem::{self, MaybeUninit};
+use core::pin::Pin;
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::Poll;
 
+use alloc::alloc::{alloc, Layout};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use crossbeam_utils::CachePadded;
 
-use super::context::Context;
-use super::log::Log;
+#[cfg(debug_assertions)]
+use super::affinity::current_node;
+use super::borrowed::{DispatchRef, ReadRef};
+use super::brand::BrandedReplica;
+use super::cancel::{CancellationToken, Cancelled};
+#[cfg(feature = "std")]
+use super::coalesce::{Coalescer, Turn};
+use super::context::{push_within, Context, MAX_PENDING_OPS};
+use super::gc::{ExecSelf, GcHelpPolicy};
+use super::ids::{LogOffset, OpId, OpOrigin, ReplicaId, ThreadId};
+#[cfg(feature = "deadlock-detection")]
+use super::lockdep;
+#[cfg(feature = "std")]
+use super::log::{lock_memory, unlock_memory, LockedRange};
+use super::log::{DeltaCodec, IdentityCodec, Log, LogError};
+use super::metrics::{Metrics, ReplicaMetrics, ReplicaObserver};
+use super::published::Published;
+#[cfg(feature = "std")]
+use super::ratelimit::{RateLimit, Throttled, TokenBucket};
+use super::readlog::{DispatchReadMut, ReadCtx};
+#[cfg(feature = "std")]
+use super::replay::Recorder;
 use super::rwlock::RwLock;
+use super::snapshot::{Checkpoint, Snapshot};
+use super::wire::{ImportGap, WireEntry};
 use super::Dispatch;
 
+/// Returned by `Replica::quiesce` once the operations issued before the call
+/// were executed by every replica.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QuiesceReport {
+    /// Logical offset on the shared log up to which every replica executed
+    /// operations. Operations appended from here on were issued after the call.
+    pub offset: LogOffset,
+}
+
+/// An amount of work that `Replica::run` may perform, in rounds. A round is one
+/// attempt at combining the pending operations of the replica's threads and
+/// executing the shared log against the replica (or, if the log is full, only
+/// the latter).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Work(pub usize);
+
+/// Returned by `Replica::run`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RunReport {
+    /// Part of the budget that wasn't used, because the replica ran out of
+    /// work first.
+    pub remaining: Work,
+
+    /// Whether there is work left on the replica: operations its threads
+    /// enqueued that no combiner picked up yet, or entries on the shared log it
+    /// didn't execute yet.
+    pub pending: bool,
+}
+
+/// Identifies the state of the data structure of a replica, as seen by a read;
+/// returned by `Replica::version_token`. Only meaningful for the replica that
+/// handed it out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VersionToken(LogOffset);
+
+/// Returned by `Replica::execute_mut_timeout` and `Replica::execute_mut_until`
+/// if the operation didn't complete in time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Timeout {
+    /// The call gave up before the operation completed.
+    Elapsed {
+        /// Whether the operation was handed to the replica before the call
+        /// gave up. If so, it still gets executed at some point, but its
+        /// response is dropped.
+        enqueued: bool,
+
+        /// The replica that was furthest behind on the shared log, if the log
+        /// was full when the call gave up. This is the replica holding up the
+        /// others.
+        stalled: Option<ReplicaId>,
+    },
+
+    /// The replica can no longer execute operations.
+    Failed(ReplicaError),
+}
+
+impl From<ReplicaError> for Timeout {
+    fn from(e: ReplicaError) -> Timeout {
+        Timeout::Failed(e)
+    }
+}
+
+/// What a thread waiting for the response to one of its operations does while
+/// another thread is combining, after spinning for
+/// [`CombinerConfig::spin_before_yield`] iterations without a response.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ParkStrategy {
+    /// Keep spinning.
+    Spin,
+
+    /// Yield the CPU to other threads before spinning again.
+    #[cfg(feature = "std")]
+    Yield,
+
+    /// Block the thread until the combiner hands out its response, but at most
+    /// for the given duration, so idle waiters don't burn a core. The combiner
+    /// wakes up parked threads, which adds a bit of work to every round.
+    #[cfg(feature = "std")]
+    Park(core::time::Duration),
+}
+
+/// What a thread does if it issues an operation while the context holding its
+/// pending operations is full, e.g., with operations an earlier call gave up on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Try to combine and spin until there is room.
+    Spin,
+
+    /// Try to combine and yield the CPU to other threads until there is room.
+    #[cfg(feature = "std")]
+    Yield,
+
+    /// Fail with `ReplicaError::QueueFull` without executing the operation.
+    Error,
+}
+
+/// Tunes how threads of a [Replica](struct.Replica.html) created with
+/// `Replica::with_config` contend for the combiner lock and wait for responses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CombinerConfig {
+    /// Iterations a waiting thread spins on its context before it tries to
+    /// combine itself and then follows `park_strategy`.
+    spin_before_yield: usize,
+
+    /// Times a thread reads the combiner lock before trying to acquire it.
+    /// Returns early if the lock is taken during any of these reads.
+    backoff: usize,
+
+    /// What a waiting thread does after spinning without a response.
+    park_strategy: ParkStrategy,
+
+    /// Maximum number of operations pending on the context of a thread.
+    batch_size: usize,
+
+    /// What a thread does if its context is full.
+    overflow_policy: OverflowPolicy,
+}
+
+impl CombinerConfig {
+    /// Sets the number of iterations a thread spins while waiting for a
+    /// response before it tries to combine and follows the `ParkStrategy`.
+    ///
+    /// # Panics
+    /// If `iterations` is zero.
+    pub fn spin_before_yield(mut self, iterations: usize) -> CombinerConfig {
+        assert!(
+            iterations > 0,
+            "Must spin at least once between combine attempts."
+        );
+        self.spin_before_yield = iterations;
+        self
+    }
+
+    /// Sets how often a thread reads the combiner lock before it tries to
+    /// acquire it. Zero tries to acquire the lock right away.
+    pub fn backoff(mut self, checks: usize) -> CombinerConfig {
+        self.backoff = checks;
+        self
+    }
+
+    /// Sets what a thread does while it waits for a response.
+    pub fn park_strategy(mut self, strategy: ParkStrategy) -> CombinerConfig {
+        self.park_strategy = strategy;
+        self
+    }
+
+    /// Sets how many operations can be pending on the context of a thread, i.e.,
+    /// how many operations a thread hands to the combiner at once. Smaller
+    /// batches bound the work a combiner does on behalf of a single thread.
+    ///
+    /// # Panics
+    /// If `size` is zero or larger than the default (see `Capabilities::batch_size`).
+    pub fn batch_size(mut self, size: usize) -> CombinerConfig {
+        assert!(
+            size > 0 && size <= MAX_PENDING_OPS,
+            "Batch size must be within 1..={}.",
+            MAX_PENDING_OPS
+        );
+        self.batch_size = size;
+        self
+    }
+
+    /// Sets what a thread does if it issues an operation while its context is
+    /// full.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> CombinerConfig {
+        self.overflow_policy = policy;
+        self
+    }
+}
+
+impl Default for CombinerConfig {
+    /// Spins for a long time and never yields or parks, which gives the lowest
+    /// latency if every thread has a core to itself.
+    fn default() -> Self {
+        CombinerConfig {
+            spin_before_yield: 1 << 29,
+            backoff: 4,
+            park_strategy: ParkStrategy::Spin,
+            batch_size: MAX_PENDING_OPS,
+            overflow_policy: OverflowPolicy::Spin,
+        }
+    }
+}
+
+/// Returned by `Replica::try_execute_mut` if the operation can't be executed
+/// without waiting. The operation isn't executed in that case.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WouldBlock {
+    /// The shared log is nearly full: appending a round of flat combining would
+    /// wait for other replicas to execute outstanding entries first. How full
+    /// the log is can be watched with `Log::utilization`.
+    LogFull,
+
+    /// The thread exceeded the [RateLimit](struct.RateLimit.html) it was
+    /// registered with.
+    #[cfg(feature = "std")]
+    Throttled(Throttled),
+
+    /// The context holding the pending operations of the thread is full.
+    QueueFull,
+
+    /// The replica can no longer execute operations. Unlike the other
+    /// variants, waiting doesn't help.
+    Failed(ReplicaError),
+}
+
+impl From<ReplicaError> for WouldBlock {
+    fn from(e: ReplicaError) -> WouldBlock {
+        WouldBlock::Failed(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Throttled> for WouldBlock {
+    fn from(t: Throttled) -> WouldBlock {
+        WouldBlock::Throttled(t)
+    }
+}
+
+/// Returned by `Replica::register_at` if the identifier can't be handed out,
+/// because another thread holds it or it is out of range.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SlotTaken(pub ThreadId);
+
+/// Errors returned when a replica can no longer execute operations. Once a
+/// replica failed, every further operation on it fails with the same error;
+/// threads have to move to another replica of the log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum ReplicaError {
+    /// The replica fell off the shared log: entries it hadn't executed yet were
+    /// reclaimed, so it can't catch up anymore.
+    Desync = 1,
+
+    /// A thread panicked while combining on the replica (e.g., in
+    /// `Dispatch::dispatch_mut`), which may have left the data structure in an
+    /// inconsistent state.
+    Poisoned = 2,
+
+    /// The context holding the pending operations of the thread is full, and
+    /// the replica was created with `OverflowPolicy::Error`. Unlike the other
+    /// variants, this doesn't mean the replica failed: the operation wasn't
+    /// executed and can be issued again.
+    QueueFull = 3,
+
+    /// The replica was shut down with `NodeReplicated::shutdown`. Operations
+    /// that weren't executed before are dropped.
+    ShuttingDown = 4,
+}
+
+/// Marks a replica as poisoned if the combiner unwinds. Forgotten once the
+/// combiner finished its work. The `CombinerGuard` of the combiner releases
+/// the combiner lock.
+struct PoisonOnUnwind<'r> {
+    failure: &'r AtomicUsize,
+}
+
+impl Drop for PoisonOnUnwind<'_> {
+    fn drop(&mut self) {
+        let _ = self.failure.compare_exchange(
+            0,
+            ReplicaError::Poisoned as usize,
+            Ordering::Release,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Holds the combiner lock of a replica; returned by
+/// `Replica::acquire_combiner_lock` and `Replica::try_acquire_combiner_lock`.
+/// Releases the lock when dropped, also if the combiner unwinds.
+/// Also wakes the tasks waiting for the lock to be released.
+struct CombinerGuard<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    replica: &'r Replica<'a, D, C>,
+}
+
+impl<D, C> Drop for CombinerGuard<'_, '_, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    fn drop(&mut self) {
+        self.replica.combiner.store(0, Ordering::Release);
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::released(self.replica.lock_id);
+        self.replica.wake_tasks();
+    }
+}
+
 /// A token handed out to threads registered with replicas.
 ///
 /// # Note
 /// Ideally this would be an affine type and returned again by
 /// `execute` and `execute_ro`. However it feels like this would
 /// hurt API ergonomics a lot.
+///
+/// With the `strict-tokens` feature, a token remembers the thread it was
+/// handed out to, and using it on any other thread panics. Two threads sharing
+/// a token would otherwise corrupt the context they share on the replica.
+///
+/// `Replica::with_brand` hands out tokens that the compiler only accepts for
+/// the replica that handed them out.
 #[derive(Copy, Clone, Debug, PartialEq)]
-pub struct ReplicaToken(usize);
+pub struct ReplicaToken(
+    ThreadId,
+    #[cfg(feature = "strict-tokens")] std::thread::ThreadId,
+);
 
 /// To make it harder to use the same ReplicaToken on multiple threads.
 #[cfg(features = "unstable")]
@@ -37,13 +369,35 @@ impl ReplicaToken {
     /// If we had a means to declare this not-pub we should do that instead.
     #[doc(hidden)]
     pub unsafe fn new(ident: usize) -> Self {
-        ReplicaToken(ident)
+        ReplicaToken::issue(ident)
+    }
+
+    /// Creates a token for identifier `idx`, owned by the calling thread.
+    pub(crate) fn issue(idx: usize) -> Self {
+        #[cfg(feature = "strict-tokens")]
+        return ReplicaToken(ThreadId::new(idx), std::thread::current().id());
+        #[cfg(not(feature = "strict-tokens"))]
+        return ReplicaToken(ThreadId::new(idx));
     }
 
     /// Getter for id
-    pub fn id(&self) -> usize {
+    pub fn id(&self) -> ThreadId {
         self.0
     }
+
+    /// Panics if the token is used on another thread than the one it was handed
+    /// out to. Does nothing without the `strict-tokens` feature.
+    #[inline(always)]
+    pub(crate) fn assert_owner(&self) {
+        #[cfg(feature = "strict-tokens")]
+        assert!(
+            self.1 == std::thread::current().id(),
+            "Token {} was handed out to thread {:?}, but is used on thread {:?}!",
+            self.0,
+            self.1,
+            std::thread::current().id()
+        );
+    }
 }
 
 /// The maximum number of threads that can be registered with a replica. If more than
@@ -57,6 +411,95 @@ const_assert!(
     MAX_THREADS_PER_REPLICA >= 1 && (MAX_THREADS_PER_REPLICA & (MAX_THREADS_PER_REPLICA - 1) == 0)
 );
 
+/// Number of words in the bitmap of free thread identifiers.
+const FREE_WORDS: usize = (MAX_THREADS_PER_REPLICA + 63) / 64;
+
+/// Identifier the dedicated combiner thread of a replica (see
+/// `NodeReplicated::spawn_combiners`) holds the combiner lock with.
+#[cfg(feature = "std")]
+const BACKGROUND_COMBINER: ThreadId = ThreadId::new(MAX_THREADS_PER_REPLICA + 3);
+
+/// How long threads wait for a dedicated combiner thread before they check
+/// whether it still runs.
+#[cfg(feature = "std")]
+const BACKGROUND_PARK_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(1);
+
+/// Future that returns `Pending` the first time it is polled and `Ready` the
+/// second time. Used to hand control back to the executor while waiting for
+/// something no combiner wakes the task up for (e.g., a rate limit).
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Future that resolves once a combiner released the combiner lock of
+/// `replica` or handed out a response to thread `tid`, or right away if nobody
+/// combines. Registers the waker of the task with the context of `tid` while
+/// it waits, which `Replica::wake_tasks` wakes up.
+struct WaitForCombiner<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    replica: &'r Replica<'a, D, C>,
+    tid: ThreadId,
+    waiting: bool,
+}
+
+impl<D, C> Future for WaitForCombiner<'_, '_, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        if self.waiting {
+            return Poll::Ready(());
+        }
+
+        let replica = self.replica;
+        replica.wakers.store(true, Ordering::Relaxed);
+        let combining =
+            || replica.has_background_combiner() || replica.combiner.load(Ordering::Relaxed) != 0;
+        if replica.contexts[self.tid.index()].register_waker(cx.waker(), combining) {
+            self.waiting = true;
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Allocates an empty vector with space for `capacity` elements. Unlike
+/// `Vec::with_capacity`, this reports allocation failures to the caller
+/// instead of aborting.
+fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, LogError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| LogError::InvalidSize)?;
+    if layout.size() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(LogError::OutOfMemory);
+    }
+
+    // The memory was allocated by the global allocator with the layout of
+    // `[T; capacity]` which is what `Vec` expects for its buffer.
+    Ok(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
+
 /// An instance of a replicated data structure. Uses a shared log to scale
 /// operations on the data structure across cores and processors.
 ///
@@ -68,13 +511,27 @@ const_assert!(
 /// `execute`). A mutable operation will be eventually executed against the replica
 /// along with any operations that were received on other replicas that share
 /// the same underlying log.
-pub struct Replica<'a, D>
+///
+/// # Allocation
+///
+/// All memory the replica needs to execute operations (the per-thread contexts
+/// and the buffers of the combiner) is allocated by `new`, sized for
+/// `MAX_THREADS_PER_REPLICA` threads with full batches each. Afterwards,
+/// `register`, `deregister`, `execute_mut`, `execute` and `sync` don't
+/// allocate, as long as cloning operations and `dispatch`/`dispatch_mut` of
+/// the data structure don't either. This isn't the case for methods that
+/// return collections (e.g., `execute_mut_batch`), for coalesced and published
+/// reads, for growing the log, and with the `deadlock-detection` feature. The
+/// `no-alloc-runtime` feature turns a combiner buffer that would grow into a
+/// panic.
+pub struct Replica<'a, D, C = IdentityCodec>
 where
     D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
 {
     /// A replica-identifier received when the replica is registered against
     /// the shared-log. Required when consuming operations from the log.
-    idx: usize,
+    idx: ReplicaId,
 
     /// Thread idx of the thread currently responsible for flat combining. Zero
     /// if there isn't any thread actively performing flat combining on the log.
@@ -84,6 +541,19 @@ where
     /// Idx that will be handed out to the next thread that registers with the replica.
     next: CachePadded<AtomicUsize>,
 
+    /// Bitmap of identifiers below `next` that were handed back by threads that
+    /// deregistered. Bit `i` of word `w` stands for the identifier `w * 64 + i + 1`.
+    free: CachePadded<[AtomicU64; FREE_WORDS]>,
+
+    /// Number of `register_at` calls that moved `next` past identifiers they
+    /// have yet to mark in `free`.
+    skipping: AtomicUsize,
+
+    /// Bitmap of threads waiting for an operation issued with
+    /// `execute_mut_urgent`, laid out like `free`. The combiner collects the
+    /// operations of these threads first.
+    urgent: CachePadded<[AtomicU64; FREE_WORDS]>,
+
     /// List of per-thread contexts. Threads buffer write operations in here when they
     /// cannot perform flat combining (because another thread might be doing so).
     ///
@@ -106,29 +576,139 @@ where
 
     /// Reference to the shared log that operations will be appended to and the
     /// data structure will be updated from.
-    slog: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+    slog: Arc<Log<'a, <D as Dispatch>::WriteOperation, C>>,
 
     /// The underlying replicated data structure. Shared between threads registered
     /// with this replica. Each replica maintains its own.
-    data: CachePadded<RwLock<D>>,
+    data: CachePadded<RwLock<D, MAX_THREADS_PER_REPLICA>>,
+
+    /// The pages of `contexts`, `buffer` and `result` that are locked into
+    /// memory, if they are. Only accessed while holding the combiner lock.
+    #[cfg(feature = "std")]
+    locked: RefCell<Option<[LockedRange; 3]>>,
+
+    /// Whether a dedicated thread combines on behalf of the threads registered
+    /// with this replica (see `NodeReplicated::spawn_combiners`). Threads then
+    /// wait for their responses instead of combining themselves.
+    #[cfg(feature = "std")]
+    background: AtomicBool,
+
+    /// Whether a task of `async_execute_mut` or `async_execute` ever waited for
+    /// a combiner. Only then do combiners check for tasks to wake up.
+    wakers: AtomicBool,
+
+    /// Counters updated by the combiner, along with an optional observer.
+    metrics: Metrics,
+
+    /// Decides what the combiner does while the shared log is full. Only
+    /// accessed by the combiner.
+    gc_policy: RefCell<Arc<dyn GcHelpPolicy + Send + Sync>>,
+
+    /// Records the operations the combiner appends, if installed with
+    /// `set_recorder`. Only accessed by the combiner.
+    #[cfg(feature = "std")]
+    recorder: RefCell<Option<Arc<Recorder<<D as Dispatch>::WriteOperation>>>>,
+
+    /// Maximum number of operations the combiner collects in one round, set
+    /// with `set_max_ops_per_round`.
+    max_ops_per_round: AtomicUsize,
+
+    /// How threads contend for the combiner lock and wait for responses.
+    config: CombinerConfig,
+
+    /// Position among the registered threads from which the combiner starts
+    /// collecting operations (after its own) in the next round. Only accessed
+    /// by the combiner.
+    cursor: Cell<usize>,
+
+    /// Copy of the data structure last published by the combiner for
+    /// `execute_published`, if enabled with `publish_every`.
+    published: Published<D>,
+
+    /// Reads that threads dispatch on behalf of other threads issuing the same
+    /// read, see `execute_coalesced`.
+    #[cfg(feature = "std")]
+    coalescer: Coalescer<D>,
+
+    /// NUMA node this replica is meant for, set with `set_node`. `usize::MAX`
+    /// if it wasn't set.
+    node: AtomicUsize,
+
+    /// The `ReplicaError` the replica failed with, as a `usize`. Zero as long
+    /// as the replica works.
+    failure: AtomicUsize,
+
+    /// Identifies the combiner lock of this replica for deadlock detection.
+    #[cfg(feature = "deadlock-detection")]
+    lock_id: usize,
 }
 
 /// The Replica is Sync. Member variables are protected by a CAS on `combiner`.
 /// Contexts are thread-safe.
-unsafe impl<'a, D> Sync for Replica<'a, D> where D: Sized + Sync + Dispatch {}
+unsafe impl<'a, D, C> Sync for Replica<'a, D, C>
+where
+    D: Sized + Sync + Dispatch,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+}
+
+impl<'a, D, C> Drop for Replica<'a, D, C>
+where
+    D: Sized + Sync + Dispatch,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Gives up the replica's slot on the shared log, so that the remaining
+    /// replicas don't wait for a replica that no longer executes the log. Also
+    /// unlocks the memory of the replica if it was locked with `lock_memory`.
+    fn drop(&mut self) {
+        self.slog.retire(self.idx);
+
+        #[cfg(feature = "std")]
+        if let Some(ranges) = self.locked.get_mut().take() {
+            for &range in ranges.iter() {
+                unlock_memory(range);
+            }
+        }
+    }
+}
 
-impl<'a, D> core::fmt::Debug for Replica<'a, D>
+impl<'a, D, C> core::fmt::Debug for Replica<'a, D, C>
 where
     D: Sized + Sync + Dispatch,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
 {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "Replica")
     }
 }
 
-impl<'a, D> Replica<'a, D>
+/// Abandons the operation a future of `async_execute_mut` enqueued on the
+/// context of its thread if the future is dropped before it took the response,
+/// so that the response doesn't go to the next operation of the thread.
+/// Forgotten once the future took the response.
+struct AbandonOnDrop<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    replica: &'r Replica<'a, D, C>,
+    tid: ThreadId,
+}
+
+impl<D, C> Drop for AbandonOnDrop<'_, '_, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    fn drop(&mut self) {
+        self.replica.contexts[self.tid.index()].abandon();
+    }
+}
+
+impl<'a, D, C> Replica<'a, D, C>
 where
     D: Sized + Default + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
 {
     /// Constructs an instance of a replicated data structure.
     ///
@@ -180,14 +760,90 @@ where
     /// // Create a replica that uses the above log.
     /// let replica = Replica::<Data>::new(&log);
     /// ```
-    pub fn new<'b>(log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>) -> Arc<Replica<'b, D>> {
+    pub fn new<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+    ) -> Arc<Replica<'b, D, C>> {
         Replica::with_data(log, Default::default())
     }
+
+    /// Similar to [`Replica<D>::new`], but returns an error instead of
+    /// panicking if the replica can't register with the log or if the memory
+    /// for the per-thread state of the replica can't be allocated.
+    pub fn try_new<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        Replica::try_with_data(log, Default::default())
+    }
+
+    /// Similar to [`Replica<D>::new`], but threads of the replica contend for
+    /// the combiner lock and wait for responses as set in `config`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{CombinerConfig, Dispatch, Log, ParkStrategy, Replica};
+    ///
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// #[derive(Default)]
+    /// struct Data(u64);
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.0
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.0 = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    ///
+    /// // Threads spin briefly, then sleep until their response is ready.
+    /// let config = CombinerConfig::default().spin_before_yield(1 << 12);
+    /// # #[cfg(feature = "std")]
+    /// let config = config.park_strategy(ParkStrategy::Park(Duration::from_millis(1)));
+    /// let replica = Replica::<Data>::with_config(&log, config);
+    ///
+    /// let idx = replica.register().unwrap();
+    /// assert_eq!(replica.execute_mut(7, idx), Ok(7));
+    /// ```
+    ///
+    /// # Panics
+    /// Under the same conditions as [`Replica<D>::new`].
+    pub fn with_config<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+        config: CombinerConfig,
+    ) -> Arc<Replica<'b, D, C>> {
+        match Replica::try_with_config(log, config) {
+            Ok(replica) => replica,
+            Err(LogError::TooManyReplicas) => panic!("Failed to register replica with the log!"),
+            Err(_) => panic!("Failed to allocate memory for the replica!"),
+        }
+    }
+
+    /// Similar to [`Replica<D>::with_config`], but returns an error instead of
+    /// panicking if the replica can't register with the log or if the memory
+    /// for the per-thread state of the replica can't be allocated.
+    pub fn try_with_config<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+        config: CombinerConfig,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        Replica::try_create(log, Default::default(), None, config)
+    }
 }
 
-impl<'a, D> Replica<'a, D>
+impl<'a, D, C> Replica<'a, D, C>
 where
     D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
 {
     /// Similar to [`Replica<D>::new`], but we pass a pre-initialized
     /// data-structure as an argument (`d`) rather than relying on the
@@ -198,91 +854,175 @@ where
     /// If `with_data` is used, care must be taken that the same state is passed
     /// to every Replica object. If not the resulting operations executed
     /// against replicas may not give deterministic results.
-    #[cfg(not(feature = "unstable"))]
     pub fn with_data<'b>(
-        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>,
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+        d: D,
+    ) -> Arc<Replica<'b, D, C>> {
+        match Replica::try_with_data(log, d) {
+            Ok(replica) => replica,
+            Err(LogError::TooManyReplicas) => panic!("Failed to register replica with the log!"),
+            Err(_) => panic!("Failed to allocate memory for the replica!"),
+        }
+    }
+
+    /// Fails to compile if the response type is larger than the data structure
+    /// allows with `Dispatch::MAX_RESPONSE_SIZE`.
+    #[cfg(feature = "size-checks")]
+    const RESPONSE_FITS: () = assert!(
+        core::mem::size_of::<<D as Dispatch>::Response>() <= <D as Dispatch>::MAX_RESPONSE_SIZE,
+        "Response is larger than Dispatch::MAX_RESPONSE_SIZE."
+    );
+
+    /// Similar to [`Replica<D>::with_data`], but returns an error instead of
+    /// panicking if the replica can't register with the log or if the memory
+    /// for the per-thread state of the replica can't be allocated.
+    pub fn try_with_data<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+        d: D,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        Replica::try_create(log, d, None, CombinerConfig::default())
+    }
+
+    /// Allocates a replica around `d` whose threads behave according to
+    /// `config`. The replica starts executing the log at `offset` if one is
+    /// given, otherwise from the beginning.
+    #[cfg(not(feature = "unstable"))]
+    fn try_create<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
         d: D,
-    ) -> Arc<Replica<'b, D>> {
-        let mut contexts = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
+        offset: Option<LogOffset>,
+        config: CombinerConfig,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        let mut contexts = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
         // Add `MAX_THREADS_PER_REPLICA` contexts
         for _idx in 0..MAX_THREADS_PER_REPLICA {
-            contexts.push(Default::default());
+            contexts.push(Context::with_capacity(config.batch_size));
         }
 
-        Arc::new(
-            Replica {
-                idx: log.register().unwrap(),
-                combiner: CachePadded::new(AtomicUsize::new(0)),
-                next: CachePadded::new(AtomicUsize::new(1)),
-                contexts,
-                buffer:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
-                result:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
-                slog: log.clone(),
-                data: CachePadded::new(RwLock::<D>::new(d)),
-            },
-        )
+        let buffer = try_vec_with_capacity(
+            MAX_THREADS_PER_REPLICA
+                * Context::<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>::batch_size(
+                ),
+        )?;
+        let result = try_vec_with_capacity(
+            MAX_THREADS_PER_REPLICA
+                * Context::<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>::batch_size(
+                ),
+        )?;
+
+        #[cfg(feature = "size-checks")]
+        #[allow(clippy::let_unit_value)]
+        let _fits = Replica::<D, C>::RESPONSE_FITS;
+
+        // Register last, so we don't use up a slot on the log if an allocation fails.
+        let idx = match offset {
+            Some(offset) => log.register_at(offset)?,
+            None => log.register().ok_or(LogError::TooManyReplicas)?,
+        };
+
+        Ok(Arc::new(Replica {
+            idx,
+            combiner: CachePadded::new(AtomicUsize::new(0)),
+            next: CachePadded::new(AtomicUsize::new(1)),
+            free: CachePadded::new(Default::default()),
+            skipping: AtomicUsize::new(0),
+            urgent: CachePadded::new(Default::default()),
+            contexts,
+            buffer: RefCell::new(buffer),
+            inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
+            result: RefCell::new(result),
+            slog: log.clone(),
+            data: CachePadded::new(RwLock::<D, MAX_THREADS_PER_REPLICA>::new(d)),
+            #[cfg(feature = "std")]
+            locked: RefCell::new(None),
+            #[cfg(feature = "std")]
+            background: AtomicBool::new(false),
+            wakers: AtomicBool::new(false),
+            metrics: Default::default(),
+            gc_policy: RefCell::new(Arc::new(ExecSelf)),
+            #[cfg(feature = "std")]
+            recorder: RefCell::new(None),
+            max_ops_per_round: AtomicUsize::new(usize::MAX),
+            config,
+            cursor: Cell::new(0),
+            published: Default::default(),
+            #[cfg(feature = "std")]
+            coalescer: Default::default(),
+            node: AtomicUsize::new(usize::MAX),
+            failure: AtomicUsize::new(0),
+            #[cfg(feature = "deadlock-detection")]
+            lock_id: lockdep::next_id(),
+        }))
     }
 
-    /// See `with_data` documentation without unstable feature.
+    /// See `try_create` documentation without unstable feature.
     #[cfg(feature = "unstable")]
-    pub fn with_data<'b>(
-        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation>>,
+    fn try_create<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
         d: D,
-    ) -> Arc<Replica<'b, D>> {
-        use core::mem::MaybeUninit;
-        let mut uninit_replica: Arc<MaybeUninit<Replica<D>>> = Arc::new_zeroed();
+        offset: Option<LogOffset>,
+        config: CombinerConfig,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        let contexts = try_vec_with_capacity(MAX_THREADS_PER_REPLICA)?;
+        let buffer = try_vec_with_capacity(
+            MAX_THREADS_PER_REPLICA
+                * Context::<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>::batch_size(
+                ),
+        )?;
+        let result = try_vec_with_capacity(
+            MAX_THREADS_PER_REPLICA
+                * Context::<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>::batch_size(
+                ),
+        )?;
+
+        #[cfg(feature = "size-checks")]
+        #[allow(clippy::let_unit_value)]
+        let _fits = Replica::<D, C>::RESPONSE_FITS;
+
+        // Register last, so we don't use up a slot on the log if an allocation fails.
+        let idx = match offset {
+            Some(offset) => log.register_at(offset)?,
+            None => log.register().ok_or(LogError::TooManyReplicas)?,
+        };
+
+        let mut uninit_replica: Arc<MaybeUninit<Replica<D, C>>> = Arc::new_zeroed();
 
         // This is the preferred (but unsafe) mode of initialization as it avoids
         // putting the big Replica object on the stack first.
         unsafe {
             let uninit_ptr = Arc::get_mut_unchecked(&mut uninit_replica).as_mut_ptr();
             uninit_ptr.write(Replica {
-                idx: log.register().unwrap(),
+                idx,
                 combiner: CachePadded::new(AtomicUsize::new(0)),
                 next: CachePadded::new(AtomicUsize::new(1)),
-                contexts: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
-                buffer:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
+                free: CachePadded::new(Default::default()),
+                skipping: AtomicUsize::new(0),
+                urgent: CachePadded::new(Default::default()),
+                contexts,
+                buffer: RefCell::new(buffer),
                 inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
-                result:
-                    RefCell::new(
-                        Vec::with_capacity(
-                            MAX_THREADS_PER_REPLICA
-                                * Context::<
-                                    <D as Dispatch>::WriteOperation,
-                                    <D as Dispatch>::Response,
-                                >::batch_size(),
-                        ),
-                    ),
+                result: RefCell::new(result),
                 slog: log.clone(),
-                data: CachePadded::new(RwLock::<D>::new(d)),
+                data: CachePadded::new(RwLock::<D, MAX_THREADS_PER_REPLICA>::new(d)),
+                #[cfg(feature = "std")]
+                locked: RefCell::new(None),
+                #[cfg(feature = "std")]
+                background: AtomicBool::new(false),
+                wakers: AtomicBool::new(false),
+                metrics: Default::default(),
+                gc_policy: RefCell::new(Arc::new(ExecSelf)),
+                #[cfg(feature = "std")]
+                recorder: RefCell::new(None),
+                max_ops_per_round: AtomicUsize::new(usize::MAX),
+                config,
+                cursor: Cell::new(0),
+                published: Default::default(),
+                #[cfg(feature = "std")]
+                coalescer: Default::default(),
+                node: AtomicUsize::new(usize::MAX),
+                failure: AtomicUsize::new(0),
+                #[cfg(feature = "deadlock-detection")]
+                lock_id: lockdep::next_id(),
             });
 
             let mut replica = uninit_replica.assume_init();
@@ -291,11 +1031,75 @@ where
                 Arc::get_mut(&mut replica)
                     .unwrap()
                     .contexts
-                    .push(Default::default());
+                    .push(Context::with_capacity(config.batch_size));
+            }
+
+            Ok(replica)
+        }
+    }
+
+    /// Locks the per-thread contexts and the flat combining buffers of the
+    /// replica (its largest allocations) into RAM. This avoids stalls from page
+    /// faults while a thread is combining. Returns true if the memory is locked.
+    ///
+    /// If the memory can't be locked (e.g., because `RLIMIT_MEMLOCK` is too low),
+    /// a warning is logged and the replica keeps working without locked memory.
+    /// The memory stays locked until the replica is dropped.
+    ///
+    /// # Note
+    /// Should be called before threads register with the replica, as it waits
+    /// for the combiner lock.
+    #[cfg(feature = "std")]
+    pub fn lock_memory(&self) -> bool {
+        // The buffers are only accessed by the combiner, so acquire the combiner
+        // lock. Use an idx greater than the maximum that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        let mut locked = self.locked.borrow_mut();
+        if locked.is_none() {
+            let mut ranges = [None; 3];
+            for (range, &(ptr, len)) in ranges.iter_mut().zip(self.allocations().iter()) {
+                *range = lock_memory(ptr, len);
             }
 
-            replica
+            match ranges {
+                [Some(c), Some(b), Some(r)] => *locked = Some([c, b, r]),
+                // Undo a partial success.
+                _ => ranges
+                    .iter()
+                    .flatten()
+                    .for_each(|&range| unlock_memory(range)),
+            }
         }
+
+        let is_locked = locked.is_some();
+        drop(locked);
+        drop(lock);
+        is_locked
+    }
+
+    /// Returns start and length (in bytes) of the memory backing `contexts`,
+    /// `buffer` and `result`.
+    #[cfg(feature = "std")]
+    fn allocations(&self) -> [(*const u8, usize); 3] {
+        let buffer = self.buffer.borrow();
+        let result = self.result.borrow();
+        [
+            (
+                self.contexts.as_ptr() as *const u8,
+                self.contexts.capacity()
+                    * size_of::<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>(
+                    ),
+            ),
+            (
+                buffer.as_ptr() as *const u8,
+                buffer.capacity() * size_of::<<D as Dispatch>::WriteOperation>(),
+            ),
+            (
+                result.as_ptr() as *const u8,
+                result.capacity() * size_of::<<D as Dispatch>::Response>(),
+            ),
+        ]
     }
 
     /// Registers a thread with this replica. Returns an idx inside an Option if the registration
@@ -344,6 +1148,29 @@ where
     /// let idx = replica.register().expect("Failed to register with replica.");
     /// ```
     pub fn register(&self) -> Option<ReplicaToken> {
+        // Prefer identifiers that were handed back by threads that deregistered.
+        for (w, word) in self.free.iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                match word.compare_exchange_weak(
+                    bits,
+                    bits & !(1 << bit),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let idx = w * 64 + bit + 1;
+                        // Don't inherit the rate limit of the previous owner.
+                        #[cfg(feature = "std")]
+                        self.contexts[idx - 1].limit.set(None);
+                        return Some(ReplicaToken::issue(idx));
+                    }
+                    Err(cur) => bits = cur,
+                }
+            }
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let idx = self.next.load(Ordering::SeqCst);
@@ -360,24 +1187,201 @@ where
                 continue;
             };
 
-            return Some(ReplicaToken(idx));
+            return Some(ReplicaToken::issue(idx));
         }
     }
 
-    /// Executes an mutable operation against this replica and returns a response.
-    /// `idx` is an identifier for the thread performing the execute operation.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use node_replication::Dispatch;
-    /// use node_replication::Log;
-    /// use node_replication::Replica;
+    /// Registers a thread with this replica under the identifier `desired`, e.g.,
+    /// to map threads to the same identifiers as before a restart when they
+    /// keep state keyed by their identifier. Identifiers skipped on the way
+    /// are handed out to threads that register later on.
     ///
-    /// use std::sync::Arc;
-    ///
-    /// #[derive(Default)]
-    /// struct Data {
+    /// Fails if another thread holds `desired`, or if `desired` is zero or
+    /// larger than `MAX_THREADS_PER_REPLICA`.
+    pub fn register_at(&self, desired: ThreadId) -> Result<ReplicaToken, SlotTaken> {
+        let idx = desired.get();
+        if idx == 0 || idx > MAX_THREADS_PER_REPLICA {
+            return Err(SlotTaken(desired));
+        }
+
+        let i = idx - 1;
+        loop {
+            let next = self.next.load(Ordering::SeqCst);
+
+            if idx < next {
+                // The identifier was handed out before; it's only available if
+                // its thread deregistered.
+                let prev = self.free[i / 64].fetch_and(!(1 << (i % 64)), Ordering::Acquire);
+                if prev & (1 << (i % 64)) == 0 {
+                    // Another `register_at` may have skipped it and not yet
+                    // marked it as free.
+                    if self.skipping.load(Ordering::SeqCst) != 0 {
+                        spin_loop();
+                        continue;
+                    }
+                    return Err(SlotTaken(desired));
+                }
+
+                // Don't inherit the rate limit of the previous owner.
+                #[cfg(feature = "std")]
+                self.contexts[i].limit.set(None);
+                return Ok(ReplicaToken::issue(idx));
+            }
+
+            self.skipping.fetch_add(1, Ordering::SeqCst);
+            let skipped =
+                self.next
+                    .compare_exchange_weak(next, idx + 1, Ordering::SeqCst, Ordering::SeqCst);
+            if skipped.is_ok() {
+                for j in next - 1..i {
+                    self.free[j / 64].fetch_or(1 << (j % 64), Ordering::Release);
+                }
+            }
+            self.skipping.fetch_sub(1, Ordering::SeqCst);
+
+            if skipped.is_ok() {
+                return Ok(ReplicaToken::issue(idx));
+            }
+        }
+    }
+
+    /// Deregisters a thread from this replica. Its identifier is handed out again
+    /// to a thread that registers later on, so threads coming and going don't
+    /// exhaust `MAX_THREADS_PER_REPLICA`.
+    ///
+    /// # Note
+    /// The thread must not have any operations in flight. `idx` (and copies of
+    /// it) must not be used afterwards; doing so panics, unless the identifier
+    /// was already handed out to another thread.
+    ///
+    /// # Panics
+    /// If `idx` isn't registered with this replica.
+    pub fn deregister(&self, idx: ReplicaToken) {
+        self.assert_registered(idx);
+
+        let i = idx.0.index();
+        let prev = self.free[i / 64].fetch_or(1 << (i % 64), Ordering::Release);
+        assert_eq!(prev & (1 << (i % 64)), 0, "Thread deregistered twice!");
+    }
+
+    /// Calls `f` with a branded version of the replica, which hands out tokens
+    /// that the compiler only accepts for this replica (see `BrandedReplica`).
+    /// The tokens can't leave `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    ///
+    /// let junk = replica.with_brand(|replica| {
+    ///     let idx = replica.register().unwrap();
+    ///     replica.execute_mut(1, idx).unwrap();
+    ///     replica.execute((), idx).unwrap()
+    /// });
+    /// assert_eq!(junk, Some(1));
+    /// ```
+    pub fn with_brand<R, F>(&self, f: F) -> R
+    where
+        F: for<'brand> FnOnce(BrandedReplica<'_, 'a, 'brand, D, C>) -> R,
+    {
+        f(BrandedReplica::new(self))
+    }
+
+    /// Panics if the thread holding `token` isn't registered with this replica (or,
+    /// with `strict-tokens`, if `token` belongs to another thread).
+    #[inline(always)]
+    fn assert_registered(&self, token: ReplicaToken) {
+        token.assert_owner();
+
+        let idx = token.0.get();
+        let i = idx.wrapping_sub(1);
+        assert!(
+            idx >= 1
+                && idx < self.next.load(Ordering::Relaxed)
+                && self.free[i / 64].load(Ordering::Relaxed) & (1 << (i % 64)) == 0,
+            "Thread {} isn't registered with this replica!",
+            idx
+        );
+    }
+
+    /// Registers a thread with this replica, like `register()`, and limits the
+    /// rate at which it can issue write operations to `limit`.
+    ///
+    /// `try_execute_mut()` returns an error once the thread exceeds its limit,
+    /// while `execute_mut()` waits until the operation is allowed. This keeps a
+    /// single thread (e.g., a background job) from flooding the shared log.
+    #[cfg(feature = "std")]
+    pub fn register_with_limit(&self, limit: RateLimit) -> Option<ReplicaToken> {
+        let idx = self.register()?;
+        self.contexts[idx.0.index()]
+            .limit
+            .set(Some(TokenBucket::new(limit)));
+        Some(idx)
+    }
+
+    /// Takes a token from the rate limit of thread `idx` (if it has one).
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn throttle(&self, idx: ThreadId) -> Result<(), Throttled> {
+        let limit = &self.contexts[idx.index()].limit;
+        match limit.get() {
+            Some(mut bucket) => {
+                let r = bucket.acquire(std::time::Instant::now());
+                limit.set(Some(bucket));
+                r
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Executes an mutable operation against this replica and returns a response.
+    /// `idx` is an identifier for the thread performing the execute operation.
+    ///
+    /// Fails if the replica can no longer execute operations, e.g., because a
+    /// thread panicked while combining on it; see `ReplicaError`. The operation
+    /// may or may not have been executed on the log in that case.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
     ///     junk: u64,
     /// }
     ///
@@ -408,22 +1412,23 @@ where
     ///
     /// // execute_mut() can be used to write to the replicated data structure.
     /// let res = replica.execute_mut(100, idx);
-    /// assert_eq!(None, res);
+    /// assert_eq!(Ok(None), res);
     pub fn execute_mut(
         &self,
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
-    ) -> <D as Dispatch>::Response {
-        // Enqueue the operation onto the thread local batch and then try to flat combine.
-        while !self.make_pending(op.clone(), idx.0) {}
-        self.try_combine(idx.0);
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        #[cfg(feature = "std")]
+        self.wait_for_limit(idx.0)?;
 
-        // Return the response to the caller function.
-        self.get_response(idx.0)
+        self.execute_mut_unthrottled(op, idx)
     }
 
-    /// Executes a read-only operation against this replica and returns a response.
-    /// `idx` is an identifier for the thread performing the execute operation.
+    /// Same as `execute_mut`, but also returns the logical offset at which the
+    /// operation was appended to the shared log. Offsets are unique and all
+    /// replicas apply operations in offset order, so tools can use them to
+    /// reconstruct the global order of operations (see
+    /// [`ReplicaObserver::on_apply`](trait.ReplicaObserver.html#method.on_apply)).
     ///
     /// # Example
     ///
@@ -463,393 +1468,4212 @@ where
     /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
     /// let replica = Replica::<Data>::new(&log);
     /// let idx = replica.register().expect("Failed to register with replica.");
-    /// let _wr = replica.execute_mut(100, idx);
     ///
-    /// // execute() can be used to read from the replicated data structure.
-    /// let res = replica.execute((), idx);
-    /// assert_eq!(Some(100), res);
-    pub fn execute(
+    /// let (_, first) = replica.execute_mut_traced(100, idx).unwrap();
+    /// let (_, second) = replica.execute_mut_traced(200, idx).unwrap();
+    /// assert!(first < second);
+    /// ```
+    pub fn execute_mut_traced(
         &self,
-        op: <D as Dispatch>::ReadOperation,
+        op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
-    ) -> <D as Dispatch>::Response {
-        self.read_only(op, idx.0)
+    ) -> Result<(<D as Dispatch>::Response, LogOffset), ReplicaError> {
+        let resp = self.execute_mut(op, idx)?;
+        Ok((resp, self.contexts[idx.0.index()].last_offset()))
     }
 
-    /// Busy waits until a response is available within the thread's context.
-    /// `idx` identifies this thread.
-    fn get_response(&self, idx: usize) -> <D as Dispatch>::Response {
-        let mut iter = 0;
-        let interval = 1 << 29;
+    /// Reads from the replica and, based on the response of the read, decides
+    /// whether to write to it, without letting other threads of this replica
+    /// run operations in between. `f` receives the response of `op` and returns
+    /// the write to execute, if any. Returns the response of the write, or
+    /// `None` if `f` didn't return a write.
+    ///
+    /// The thread syncs the replica, dispatches the read, calls `f` and appends
+    /// the write while holding the combiner lock once, which is cheaper than
+    /// issuing the read and the write separately and leaves no window for other
+    /// threads of this replica. Threads on other replicas can still append
+    /// operations to the log between the read and the write; those are applied
+    /// before the write. `f` runs while the replica is locked, so it should be
+    /// short and must not execute operations on this replica.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// // Only sets the value if it's still zero.
+    /// let res = replica.execute_rmw((), |junk| if junk == 0 { Some(5) } else { None }, idx);
+    /// assert_eq!(Ok(Some(5)), res);
+    /// let res = replica.execute_rmw((), |junk| if junk == 0 { Some(7) } else { None }, idx);
+    /// assert_eq!(Ok(None), res);
+    /// ```
+    pub fn execute_rmw<F>(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        f: F,
+        idx: ReplicaToken,
+    ) -> Result<Option<<D as Dispatch>::Response>, ReplicaError>
+    where
+        F: FnOnce(<D as Dispatch>::Response) -> Option<<D as Dispatch>::WriteOperation>,
+    {
+        self.assert_registered(idx);
+        #[cfg(feature = "std")]
+        self.wait_for_limit(idx.0)?;
 
-        // Keep trying to retrieve a response from the thread context. After trying `interval`
-        // times with no luck, try to perform flat combining to make some progress.
-        loop {
-            let r = self.contexts[idx - 1].res();
-            if let Some(resp) = r {
-                return resp;
+        let lock = self.acquire_combiner_lock(idx.0);
+
+        // Catch up with the log, so that the read observes all operations that
+        // completed before this call. If `f` or the data structure panics, the
+        // guard releases the combiner lock.
+        let r = self.exec_log();
+        let mut write = false;
+        if r.is_ok() {
+            let guard = self.poison_on_unwind();
+            let resp = self.data.read(idx.0.index()).dispatch(op);
+            if let Some(w) = f(resp) {
+                // The batch can be full of operations an earlier call gave up on.
+                while !self.make_pending(w.clone(), idx.0) {
+                    self.combine(idx.0);
+                }
+                self.combine(idx.0);
+                write = true;
             }
+            mem::forget(guard);
+        }
 
-            iter += 1;
+        drop(lock);
 
-            if iter == interval {
-                self.try_combine(idx);
-                iter = 0;
+        // Parked threads either got their responses, or have to combine
+        // themselves now that the lock is free.
+        #[cfg(feature = "std")]
+        if let ParkStrategy::Park(_) = self.config.park_strategy {
+            for i in 1..self.next.load(Ordering::Relaxed) {
+                self.contexts[i - 1].unpark();
             }
         }
-    }
 
-    /// Executes a passed in closure against the replica's underlying data
-    /// structure. Useful for unit testing; can be used to verify certain
-    /// properties of the data structure after issuing a bunch of operations
-    /// against it.
-    ///
-    /// # Note
-    /// There is probably no need for a regular client to ever call this function.
-    #[doc(hidden)]
-    pub fn verify<F: FnMut(&D)>(&self, mut v: F) {
-        // Acquire the combiner lock before attempting anything on the data structure.
-        // Use an idx greater than the maximum that can be allocated.
-        while self.combiner.compare_exchange_weak(
-            0,
-            MAX_THREADS_PER_REPLICA + 2,
-            Ordering::Acquire,
-            Ordering::Acquire,
-        ) != Ok(0)
-        {
-            spin_loop();
+        r?;
+        if write {
+            self.get_response(idx.0).map(Some)
+        } else {
+            Ok(None)
         }
+    }
 
-        let mut data = self.data.write(self.next.load(Ordering::Relaxed));
-
-        let mut f = |o: <D as Dispatch>::WriteOperation, _i: usize| {
-            data.dispatch_mut(o);
-        };
-
-        self.slog.exec(self.idx, &mut f);
-
-        v(&data);
+    /// Executes a batch of mutable operations against this replica and returns
+    /// their responses (in the same order). `idx` is an identifier for the thread
+    /// performing the execute operation.
+    ///
+    /// Cheaper than calling `execute_mut` for every operation: the operations are
+    /// enqueued together, so the thread hands them to the combiner (and gets the
+    /// responses back) once per `CombinerConfig::batch_size` operations rather than
+    /// once per operation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         let prev = self.junk;
+    ///         self.junk = op;
+    ///         Some(prev)
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let res = replica.execute_mut_batch(&[1, 2, 3], idx);
+    /// assert_eq!(Ok(vec![Some(0), Some(1), Some(2)]), res);
+    /// ```
+    pub fn execute_mut_batch(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        idx: ReplicaToken,
+    ) -> Result<Vec<<D as Dispatch>::Response>, ReplicaError> {
+        self.assert_registered(idx);
 
-        self.combiner.store(0, Ordering::Release);
-    }
+        let batch_size = self.contexts[idx.0.index()].capacity();
+        let mut responses = Vec::with_capacity(ops.len());
+        for batch in ops.chunks(batch_size) {
+            // Unless the thread gave up on operations earlier, it doesn't have any
+            // in flight, so the whole batch fits into its context.
+            for (i, op) in batch.iter().enumerate() {
+                #[cfg(feature = "std")]
+                self.wait_for_limit(idx.0)?;
 
-    /// This method is useful when a replica stops making progress and some threads
-    /// on another replica are still active. The active replica will use all the entries
-    /// in the log and won't be able perform garbage collection because of the inactive
-    /// replica. So, this method syncs up the replica against the underlying log.
-    pub fn sync(&self, idx: ReplicaToken) {
-        let ctail = self.slog.get_ctail();
-        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
-            self.try_combine(idx.0);
-            spin_loop();
-        }
-    }
+                while !self.make_pending(op.clone(), idx.0) {
+                    self.wait_for_room(idx.0, i)?;
+                }
+            }
+            self.try_combine(idx.0)?;
 
-    /// Issues a read-only operation against the replica and returns a response.
-    /// Makes sure the replica is synced up against the log before doing so.
-    fn read_only(
-        &self,
-        op: <D as Dispatch>::ReadOperation,
-        tid: usize,
-    ) -> <D as Dispatch>::Response {
-        // We can perform the read only if our replica is synced up against
-        // the shared log. If it isn't, then try to combine until it is synced up.
-        let ctail = self.slog.get_ctail();
-        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
-            self.try_combine(tid);
-            spin_loop();
+            for _i in 0..batch.len() {
+                responses.push(self.get_response(idx.0)?);
+            }
         }
 
-        self.data.read(tid - 1).dispatch(op)
-    }
-
-    /// Enqueues an operation inside a thread local context. Returns a boolean
-    /// indicating whether the operation was enqueued (true) or not (false).
-    #[inline(always)]
-    fn make_pending(&self, op: <D as Dispatch>::WriteOperation, idx: usize) -> bool {
-        self.contexts[idx - 1].enqueue(op)
+        Ok(responses)
     }
 
-    /// Appends an operation to the log and attempts to perform flat combining.
-    /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
-    fn try_combine(&self, tid: usize) {
-        // First, check if there already is a flat combiner. If there is no active flat combiner
-        // then try to acquire the combiner lock. If there is, then just return.
-        for _i in 0..4 {
-            if unsafe {
-                core::ptr::read_volatile(
-                    &self.combiner
+    /// Like `execute_mut`, but the combiner writes the response straight into
+    /// `out` instead of handing it back through the context of the thread,
+    /// which saves copying it once more. Returns a reference to the response
+    /// in `out`.
+    ///
+    /// As with `MaybeUninit::write`, the response isn't dropped along with
+    /// `out`; use `MaybeUninit::assume_init` to take it out if it needs to be.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::mem::MaybeUninit;
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         let prev = self.junk;
+    ///         self.junk = op;
+    ///         Some(prev)
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let mut out = MaybeUninit::uninit();
+    /// assert_eq!(Ok(&mut Some(0)), replica.execute_mut_into(100, &mut out, idx));
+    /// assert_eq!(Ok(&mut Some(100)), replica.execute_mut_into(200, &mut out, idx));
+    /// ```
+    pub fn execute_mut_into<'r>(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        out: &'r mut MaybeUninit<<D as Dispatch>::Response>,
+        idx: ReplicaToken,
+    ) -> Result<&'r mut <D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+
+        #[cfg(feature = "std")]
+        self.wait_for_limit(idx.0)?;
+
+        while !self.make_pending_into(op.clone(), out.as_mut_ptr(), idx.0) {
+            self.wait_for_room(idx.0, 0)?;
+        }
+        self.try_combine(idx.0)?;
+        self.wait_for_response_into(idx.0)?;
+
+        Ok(unsafe { &mut *out.as_mut_ptr() })
+    }
+
+    /// Like `execute_mut_batch`, but the combiner writes the responses straight
+    /// into `out` (see `execute_mut_into`). Returns the responses in `out`.
+    ///
+    /// # Panics
+    /// If `ops` and `out` differ in length.
+    pub fn execute_mut_batch_into<'r>(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        out: &'r mut [MaybeUninit<<D as Dispatch>::Response>],
+        idx: ReplicaToken,
+    ) -> Result<&'r mut [<D as Dispatch>::Response], ReplicaError> {
+        self.assert_registered(idx);
+        assert_eq!(
+            ops.len(),
+            out.len(),
+            "Need one response slot per operation."
+        );
+
+        let batch_size = self.contexts[idx.0.index()].capacity();
+        for (batch, slots) in ops.chunks(batch_size).zip(out.chunks_mut(batch_size)) {
+            for (i, (op, slot)) in batch.iter().zip(slots.iter_mut()).enumerate() {
+                #[cfg(feature = "std")]
+                self.wait_for_limit(idx.0)?;
+
+                while !self.make_pending_into(op.clone(), slot.as_mut_ptr(), idx.0) {
+                    self.wait_for_room(idx.0, i)?;
+                }
+            }
+            self.try_combine(idx.0)?;
+
+            for _i in 0..batch.len() {
+                self.wait_for_response_into(idx.0)?;
+            }
+        }
+
+        // Every slot was written by the combiner.
+        Ok(unsafe { &mut *(out as *mut [MaybeUninit<<D as Dispatch>::Response>] as *mut [_]) })
+    }
+
+    /// Waits until the rate limit of thread `idx` (if any) allows another operation.
+    /// Keeps the replica making progress in the meantime so that we don't hold up GC.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn wait_for_limit(&self, idx: ThreadId) -> Result<(), ReplicaError> {
+        while self.throttle(idx).is_err() {
+            self.try_combine(idx)?;
+            spin_loop();
+        }
+
+        Ok(())
+    }
+
+    /// Similar to `execute_mut`, but returns an error instead of waiting if the
+    /// shared log is nearly full, if the context of the thread is full (see
+    /// `CombinerConfig::batch_size`) or if the thread exceeded the
+    /// [RateLimit](struct.RateLimit.html) it was registered with. The operation
+    /// isn't executed in that case, so callers can apply their own backpressure.
+    ///
+    /// If the log is nearly full, executes outstanding entries on it against
+    /// this replica first (if no other thread is combining), so that the
+    /// replica doesn't hold up garbage collection.
+    ///
+    /// Fails with `WouldBlock::Failed` if the replica can no longer execute
+    /// operations.
+    pub fn try_execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, WouldBlock> {
+        if !self.slog.has_room() {
+            self.try_exec(idx.0)?;
+            return Err(WouldBlock::LogFull);
+        }
+        if !self.contexts[idx.0.index()].has_room() {
+            self.try_combine(idx.0)?;
+            return Err(WouldBlock::QueueFull);
+        }
+
+        #[cfg(feature = "std")]
+        self.throttle(idx.0)?;
+        Ok(self.execute_mut_unthrottled(op, idx)?)
+    }
+
+    /// Enqueues a write operation without waiting for its response, for event
+    /// loops that collect completions later with `poll`. Flat combines once if
+    /// no other thread is combining, so the operation may be executed by the
+    /// time this returns.
+    ///
+    /// Like `try_execute_mut`, fails with `WouldBlock` instead of waiting if
+    /// the operation can't be enqueued right now; `poll` makes room in the
+    /// context of the thread. The thread shouldn't issue operations through
+    /// other methods while it has submitted operations outstanding, as those
+    /// would take their responses. Fails with `WouldBlock::Failed` if the
+    /// replica can no longer execute operations.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk += op;
+    ///         self.junk
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let first = replica.submit(1, idx).unwrap();
+    /// let second = replica.submit(2, idx).unwrap();
+    ///
+    /// // ... do other work ...
+    ///
+    /// assert_eq!(replica.poll(idx), Ok(Some((first, 1))));
+    /// assert_eq!(replica.poll(idx), Ok(Some((second, 3))));
+    /// assert_eq!(replica.poll(idx), Ok(None));
+    /// ```
+    pub fn submit(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<OpId, WouldBlock> {
+        self.assert_registered(idx);
+        self.failure()?;
+
+        if !self.slog.has_room() {
+            self.try_exec(idx.0)?;
+            return Err(WouldBlock::LogFull);
+        }
+        let context = &self.contexts[idx.0.index()];
+        if !context.has_room() {
+            self.try_combine(idx.0)?;
+            return Err(WouldBlock::QueueFull);
+        }
+
+        #[cfg(feature = "std")]
+        self.throttle(idx.0)?;
+        let id = OpId::new(context.tail.load(Ordering::Relaxed));
+        let enqueued = self.make_pending(op, idx.0);
+        debug_assert!(enqueued, "Context filled up while submitting an operation.");
+        self.try_combine(idx.0)?;
+
+        Ok(id)
+    }
+
+    /// Returns the response of the oldest operation the thread submitted with
+    /// `submit`, along with the identifier `submit` returned for it, or `None`
+    /// if it wasn't executed yet. Flat combines once if no other thread is
+    /// combining, but never waits. Responses come back in the order the
+    /// operations were submitted.
+    ///
+    /// Fails if the replica can no longer execute operations; see `ReplicaError`.
+    pub fn poll(
+        &self,
+        idx: ReplicaToken,
+    ) -> Result<Option<(OpId, <D as Dispatch>::Response)>, ReplicaError> {
+        self.assert_registered(idx);
+
+        let context = &self.contexts[idx.0.index()];
+        if let Some((i, resp)) = context.res_indexed() {
+            return Ok(Some((OpId::new(i), resp)));
+        }
+
+        self.try_combine(idx.0)?;
+        if let Some((i, resp)) = context.res_indexed() {
+            return Ok(Some((OpId::new(i), resp)));
+        }
+        self.failure()?;
+        Ok(None)
+    }
+
+    /// Similar to `execute_mut`, but for operations that must not wait behind
+    /// the operations of other threads, e.g., those issued by interrupt
+    /// handlers. The operation is appended right away if no other thread is
+    /// combining. Otherwise the next round of flat combining collects it
+    /// before the operations of threads that didn't issue an urgent operation,
+    /// so it doesn't wait for more than the round in progress, no matter the
+    /// limit set with `set_max_ops_per_round`.
+    ///
+    /// The thread busy waits for the response instead of following the
+    /// `ParkStrategy` of the replica, and isn't held back by its
+    /// [RateLimit](struct.RateLimit.html). Operations the thread issued before
+    /// (e.g., with `async_execute_mut`) are still appended before this one.
+    pub fn execute_mut_urgent(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+
+        while !self.make_pending(op.clone(), idx.0) {
+            self.wait_for_room(idx.0, 0)?;
+        }
+
+        let i = idx.0.index();
+        self.urgent[i / 64].fetch_or(1 << (i % 64), Ordering::Release);
+        let r = loop {
+            if let Err(e) = self.try_combine(idx.0) {
+                break Err(e);
+            }
+            if let Some(resp) = self.contexts[i].res() {
+                break Ok(resp);
+            }
+            if let Err(e) = self.failure() {
+                break Err(e);
+            }
+            spin_loop();
+        };
+        self.urgent[i / 64].fetch_and(!(1 << (i % 64)), Ordering::Relaxed);
+
+        r
+    }
+
+    /// Executes a mutable operation, bypassing any rate limit of the thread.
+    #[inline(always)]
+    fn execute_mut_unthrottled(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+
+        // Enqueue the operation onto the thread local batch and then try to flat combine.
+        // The batch can be full of operations an earlier call gave up on.
+        while !self.make_pending(op.clone(), idx.0) {
+            self.wait_for_room(idx.0, 0)?;
+        }
+        self.try_combine(idx.0)?;
+
+        // Return the response to the caller function.
+        self.get_response(idx.0)
+    }
+
+    /// Similar to `execute_mut`, but gives up once `timeout` has passed, e.g.,
+    /// because another replica stopped executing operations and the shared log
+    /// filled up. See `execute_mut_until` for details.
+    #[cfg(feature = "std")]
+    pub fn execute_mut_timeout(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        timeout: core::time::Duration,
+    ) -> Result<<D as Dispatch>::Response, Timeout> {
+        let deadline = std::time::Instant::now() + timeout;
+        self.execute_mut_until(op, idx, || std::time::Instant::now() >= deadline)
+    }
+
+    /// Similar to `execute_mut`, but gives up as soon as `abort` returns true.
+    /// `abort` is called repeatedly while the thread waits; it can check a
+    /// deadline or count down a budget of iterations.
+    ///
+    /// The thread only appends operations to the shared log while there is room
+    /// on it, so that it doesn't end up waiting for a stalled replica to free
+    /// up entries. It keeps executing the log against this replica meanwhile.
+    ///
+    /// Returns `Timeout::Elapsed` if the call gave up. An operation that was
+    /// already enqueued can't be taken back; it is executed eventually and its
+    /// response is dropped. The thread can keep using `idx` for other
+    /// operations. Fails with `Timeout::Failed` if the replica can no longer
+    /// execute operations.
+    pub fn execute_mut_until<F: FnMut() -> bool>(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        mut abort: F,
+    ) -> Result<<D as Dispatch>::Response, Timeout> {
+        self.assert_registered(idx);
+
+        #[cfg(feature = "std")]
+        while self.throttle(idx.0).is_err() {
+            if abort() {
+                return Err(self.timeout(false));
+            }
+            self.try_progress(idx.0)?;
+            spin_loop();
+        }
+
+        while !self.make_pending(op.clone(), idx.0) {
+            if abort() {
+                return Err(self.timeout(false));
+            }
+            self.try_progress(idx.0)?;
+        }
+
+        loop {
+            self.try_progress(idx.0)?;
+            if let Some(resp) = self.contexts[idx.0.index()].res() {
+                return Ok(resp);
+            }
+
+            if abort() {
+                self.contexts[idx.0.index()].abandon();
+                return Err(self.timeout(true));
+            }
+            spin_loop();
+        }
+    }
+
+    /// Similar to `execute_mut`, but gives up once `token` is cancelled, e.g.,
+    /// to shut down a thread waiting on a replica that stopped making progress.
+    /// See `execute_mut_until` for details. Fails with `Cancelled::Failed` if
+    /// the replica can no longer execute operations.
+    pub fn execute_mut_cancellable(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        token: &CancellationToken,
+    ) -> Result<<D as Dispatch>::Response, Cancelled> {
+        self.execute_mut_until(op, idx, || token.is_cancelled())
+            .map_err(|t| match t {
+                Timeout::Elapsed { enqueued, .. } => Cancelled::Requested { enqueued },
+                Timeout::Failed(e) => Cancelled::Failed(e),
+            })
+    }
+
+    /// Returns the error for an operation that timed out.
+    fn timeout(&self, enqueued: bool) -> Timeout {
+        let stalled = if self.slog.has_room() {
+            None
+        } else {
+            Some(self.slog.slowest_replica())
+        };
+
+        Timeout::Elapsed { enqueued, stalled }
+    }
+
+    /// Executes a read-only operation against this replica and returns a response.
+    /// `idx` is an identifier for the thread performing the execute operation.
+    ///
+    /// Fails if the replica can no longer execute operations; see `ReplicaError`.
+    ///
+    /// The read observes every write operation that completed before the call,
+    /// on any replica, so it always checks the completed tail of the shared log;
+    /// a version kept by the replica can't tell whether other replicas appended
+    /// operations. While nobody writes, the check doesn't combine and only reads
+    /// cache lines that no thread modifies. To skip the read lock of the replica
+    /// as well in read-mostly phases, see `execute_published`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// let _wr = replica.execute_mut(100, idx);
+    ///
+    /// // execute() can be used to read from the replicated data structure.
+    /// let res = replica.execute((), idx);
+    /// assert_eq!(Ok(Some(100)), res);
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.read_only(op, idx)
+    }
+
+    /// Similar to `execute`, but gives up syncing the replica with the shared
+    /// log once `token` is cancelled. The read isn't executed in that case.
+    /// Fails with `Cancelled::Failed` if the replica can no longer execute
+    /// operations.
+    pub fn execute_cancellable(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        token: &CancellationToken,
+    ) -> Result<<D as Dispatch>::Response, Cancelled> {
+        self.assert_registered(idx);
+        self.sync_until(idx.0, token)?;
+
+        Ok(self.data.read(idx.0.index()).dispatch(op))
+    }
+
+    /// Same as `execute`, but shares the response with other threads of this
+    /// replica that issue an identical read (`==`) at the same time. One of the
+    /// threads syncs the replica and dispatches the read, while the others wait
+    /// for its response instead of dispatching the read themselves. This helps
+    /// when many threads read the same (hot) key while the replica catches up
+    /// with the log.
+    ///
+    /// Every thread still observes the effects of all operations that completed
+    /// before it issued the read: the dispatching thread stops sharing the read
+    /// and syncs the replica once more right before it dispatches it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    ///
+    /// let _wr = replica.execute_mut(100, idx);
+    /// let res = replica.execute_coalesced((), idx);
+    /// assert_eq!(Ok(Some(100)), res);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn execute_coalesced(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError>
+    where
+        <D as Dispatch>::ReadOperation: core::hash::Hash + Eq + Send + Sync,
+        <D as Dispatch>::Response: Send,
+    {
+        self.assert_registered(idx);
+
+        let mut leader = match self.coalescer.join(&op) {
+            Turn::Lead(leader) => leader,
+            Turn::Wait(pending) => match pending.wait() {
+                Some(outcome) => return outcome,
+                // The thread dispatching the read gave up.
+                None => return self.read_only(op, idx),
+            },
+        };
+
+        // Threads issuing the same read join it while we sync. They issued it
+        // before we close it, so syncing again afterwards makes the response
+        // reflect everything that completed before any of them issued it.
+        let r = self.sync_for_reads(idx.0);
+        leader.close();
+        let outcome = r
+            .and_then(|_| self.sync_for_reads(idx.0))
+            .map(|_| self.data.read(idx.0.index()).dispatch(op));
+
+        leader.finish(&outcome);
+        outcome
+    }
+
+    /// Executes a batch of read-only operations against this replica and returns
+    /// the responses in the same order as `ops`. Like `execute`, `idx` is an
+    /// identifier for the thread performing the execute operation.
+    ///
+    /// Cheaper than calling `execute` for every operation: the replica is synced
+    /// up against the log once, after which all operations are dispatched under
+    /// a single acquisition of the read lock. The operations therefore observe
+    /// the same state of the data structure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = u64;
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk * op
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().expect("Failed to register with replica.");
+    /// let _wr = replica.execute_mut(10, idx);
+    ///
+    /// let res = replica.execute_batch(&[1, 2, 3], idx);
+    /// assert_eq!(Ok(vec![10, 20, 30]), res);
+    /// ```
+    pub fn execute_batch(
+        &self,
+        ops: &[<D as Dispatch>::ReadOperation],
+        idx: ReplicaToken,
+    ) -> Result<Vec<<D as Dispatch>::Response>, ReplicaError> {
+        self.assert_registered(idx);
+        self.sync_for_reads(idx.0)?;
+
+        let data = self.data.read(idx.0.index());
+        Ok(ops.iter().map(|op| data.dispatch(op.clone())).collect())
+    }
+
+    /// Similar to `execute_mut`, but returns a future that resolves to the response
+    /// instead of busy waiting for it. This allows a replica to be used from within
+    /// an async executor without burning cores.
+    ///
+    /// While the response isn't available, every poll of the future tries to flat
+    /// combine once. If another thread is combining, the future stores the waker
+    /// of its task in the context of thread `idx` and returns `Pending`; the
+    /// combiner wakes the task once it released the combiner lock, which is when
+    /// the response is there (or the task has to combine itself). Only while the
+    /// thread is throttled by a rate limit does the future wake itself, as no
+    /// combiner would.
+    ///
+    /// The future can be dropped before it completes. An operation that was
+    /// already enqueued can't be taken back; it is executed eventually and its
+    /// response is dropped.
+    pub async fn async_execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+
+        #[cfg(feature = "std")]
+        while self.throttle(idx.0).is_err() {
+            self.try_combine(idx.0)?;
+            YieldNow(false).await;
+        }
+
+        // Enqueue the operation onto the thread local batch and then try to flat combine.
+        while !self.make_pending(op.clone(), idx.0) {
+            self.wait_for_room(idx.0, 0)?;
+            self.wait_for_task(idx.0).await;
+        }
+        let guard = AbandonOnDrop {
+            replica: self,
+            tid: idx.0,
+        };
+        self.try_combine(idx.0)?;
+
+        loop {
+            if let Some(resp) = self.contexts[idx.0.index()].res() {
+                mem::forget(guard);
+                return Ok(resp);
+            }
+            self.failure()?;
+
+            self.wait_for_task(idx.0).await;
+            self.try_combine(idx.0)?;
+        }
+    }
+
+    /// Similar to `execute`, but returns a future that resolves to the response.
+    /// If the replica isn't synced up against the log and another thread is
+    /// combining, the future waits for the combiner to wake its task (like
+    /// `async_execute_mut`) in between attempts to flat combine, instead of busy
+    /// waiting.
+    pub async fn async_execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+        self.failure()?;
+
+        let ctail = self.slog.get_ctail();
+        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            self.try_combine(idx.0)?;
+            self.failure()?;
+            self.wait_for_task(idx.0).await;
+        }
+
+        Ok(self.data.read(idx.0.index()).dispatch(op))
+    }
+
+    /// Returns a future that waits for a combiner on behalf of the task that
+    /// issued an operation on thread `idx`; see `WaitForCombiner`.
+    fn wait_for_task(&self, idx: ThreadId) -> WaitForCombiner<'_, 'a, D, C> {
+        WaitForCombiner {
+            replica: self,
+            tid: idx,
+            waiting: false,
+        }
+    }
+
+    /// Busy waits until a response is available within the thread's context.
+    /// `idx` identifies this thread.
+    fn get_response(&self, idx: ThreadId) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        let mut iter = 0;
+        let interval = self.config.spin_before_yield;
+
+        // Keep trying to retrieve a response from the thread context. After trying `interval`
+        // times with no luck, try to perform flat combining to make some progress. Give up
+        // if the replica failed; the combiner won't hand out the response then.
+        loop {
+            let r = self.contexts[idx.index()].res();
+            if let Some(resp) = r {
+                return Ok(resp);
+            }
+            self.failure()?;
+
+            iter += 1;
+
+            if iter == interval || self.has_background_combiner() {
+                self.try_combine(idx)?;
+                self.wait_for_combiner(idx);
+                iter = 0;
+            }
+        }
+    }
+
+    /// Busy waits until the combiner wrote the response of the oldest operation of
+    /// thread `idx`, which was enqueued with `make_pending_into`.
+    fn wait_for_response_into(&self, idx: ThreadId) -> Result<(), ReplicaError> {
+        let mut iter = 0;
+        let interval = self.config.spin_before_yield;
+
+        while !self.contexts[idx.index()].res_into() {
+            self.failure()?;
+
+            iter += 1;
+
+            if iter == interval || self.has_background_combiner() {
+                self.try_combine(idx)?;
+                self.wait_for_combiner(idx);
+                iter = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Called by thread `idx` if its context is full while it tries to enqueue
+    /// an operation, after it already enqueued `enqueued` operations of the
+    /// same call. Tries to combine to make room and follows the `OverflowPolicy`
+    /// of the replica. If that is `OverflowPolicy::Error`, fails and abandons the
+    /// operations the call enqueued; they still get executed, but their
+    /// responses are dropped.
+    fn wait_for_room(&self, idx: ThreadId, enqueued: usize) -> Result<(), ReplicaError> {
+        if self.config.overflow_policy == OverflowPolicy::Error {
+            for _i in 0..enqueued {
+                self.contexts[idx.index()].abandon();
+            }
+            return Err(ReplicaError::QueueFull);
+        }
+
+        self.try_combine(idx)?;
+        #[cfg(feature = "std")]
+        if self.config.overflow_policy == OverflowPolicy::Yield {
+            std::thread::yield_now();
+        }
+        Ok(())
+    }
+
+    /// Returns true while a dedicated thread combines on behalf of the threads
+    /// registered with this replica (see `start_background`).
+    #[inline(always)]
+    fn has_background_combiner(&self) -> bool {
+        #[cfg(feature = "std")]
+        return self.background.load(Ordering::Relaxed);
+        #[cfg(not(feature = "std"))]
+        return false;
+    }
+
+    /// Called by thread `idx` after it spun without getting a response and
+    /// another thread is combining. Follows the `ParkStrategy` of the replica.
+    /// Waits for the dedicated combiner thread instead, if there is one.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn wait_for_combiner(&self, idx: ThreadId) {
+        #[cfg(feature = "std")]
+        if self.has_background_combiner() {
+            self.contexts[idx.index()]
+                .park(BACKGROUND_PARK_TIMEOUT, || self.has_background_combiner());
+            return;
+        }
+
+        match self.config.park_strategy {
+            ParkStrategy::Spin => {}
+            #[cfg(feature = "std")]
+            ParkStrategy::Yield => std::thread::yield_now(),
+            #[cfg(feature = "std")]
+            ParkStrategy::Park(timeout) => self.contexts[idx.index()]
+                .park(timeout, || self.combiner.load(Ordering::Relaxed) != 0),
+        }
+    }
+
+    /// Executes a passed in closure against the replica's underlying data
+    /// structure. Useful for unit testing; can be used to verify certain
+    /// properties of the data structure after issuing a bunch of operations
+    /// against it.
+    ///
+    /// # Note
+    /// There is probably no need for a regular client to ever call this function.
+    #[doc(hidden)]
+    pub fn verify<F: FnMut(&D)>(&self, mut v: F) {
+        // Acquire the combiner lock before attempting anything on the data structure.
+        // Use an idx greater than the maximum that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        let mut data = self.data.write(self.next.load(Ordering::Relaxed));
+
+        let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+            data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+            self.metrics.on_apply(offset, self.idx);
+        };
+
+        self.slog.exec_traced(self.idx, &mut f);
+
+        // Readers don't have to wait for `v`. The combiner lock keeps writers
+        // out, so any reader slot will do.
+        let data = data.downgrade(0);
+        v(&data);
+        drop(data);
+
+        drop(lock);
+    }
+
+    /// Installs an observer that is notified whenever this replica combines
+    /// operations or stalls on the shared log. Replaces any previously
+    /// installed observer.
+    ///
+    /// Waits for an active combiner (if any) to finish before installing the
+    /// observer.
+    pub fn set_observer(&self, observer: Arc<dyn ReplicaObserver + Send + Sync>) {
+        // The observer is only accessed by the combiner, so acquire the combiner
+        // lock. Use an idx greater than the maximum that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        *self.metrics.observer.borrow_mut() = Some(observer);
+
+        drop(lock);
+    }
+
+    /// Installs a recorder that gets every write operation this replica appends
+    /// to the shared log, along with the thread that issued it. Replaces any
+    /// previously installed recorder. See the `replay` module.
+    ///
+    /// Waits for an active combiner (if any) to finish before installing the
+    /// recorder.
+    #[cfg(feature = "std")]
+    pub fn set_recorder(&self, recorder: Arc<Recorder<<D as Dispatch>::WriteOperation>>) {
+        // The recorder is only accessed by the combiner, so acquire the combiner
+        // lock. Use an idx greater than the maximum that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        *self.recorder.borrow_mut() = Some(recorder);
+
+        drop(lock);
+    }
+
+    /// Installs the policy that decides what the combiner of this replica does
+    /// while it waits for space on a full log. The default,
+    /// [ExecSelf](struct.ExecSelf.html), keeps executing the log until there is
+    /// space. Replaces any previously installed policy.
+    ///
+    /// If the policy gives up, the operations of that round of flat combining
+    /// stay pending with their threads and are appended in a later round.
+    ///
+    /// Waits for an active combiner (if any) to finish before installing the
+    /// policy.
+    pub fn set_gc_policy(&self, policy: Arc<dyn GcHelpPolicy + Send + Sync>) {
+        // The policy is only accessed by the combiner, so acquire the combiner
+        // lock. Use an idx greater than the maximum that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        *self.gc_policy.borrow_mut() = policy;
+
+        drop(lock);
+    }
+
+    /// Bounds the number of operations the combiner of this replica collects
+    /// from registered threads in one round of flat combining to `max`. By
+    /// default, a round collects every pending operation.
+    ///
+    /// A bounded round ends sooner, so the thread that became combiner returns
+    /// to its own operation sooner and other threads get a chance to take over.
+    /// The combiner always collects its own operations first. The remaining
+    /// threads take turns being collected from first, so that each of them gets
+    /// its operations appended within a bounded number of rounds. To bound how
+    /// long the combiner waits for space on a full log, see `set_gc_policy`.
+    ///
+    /// # Panics
+    /// If `max` is zero.
+    pub fn set_max_ops_per_round(&self, max: usize) {
+        assert!(max > 0, "A round must collect at least one operation.");
+        self.max_ops_per_round.store(max, Ordering::Relaxed);
+    }
+
+    /// Assigns this replica to NUMA node `node`. In debug builds, combining on a
+    /// core of another node then panics, provided the node of the current core
+    /// can be determined with the hook installed by `set_current_node`.
+    pub fn set_node(&self, node: usize) {
+        self.node.store(node, Ordering::Relaxed);
+    }
+
+    /// Returns a token for the current state of the data structure of this
+    /// replica: the offset on the shared log up to which the replica executed
+    /// operations. Together with `validate`, this allows applications to cache
+    /// the responses of expensive read operations.
+    ///
+    /// Take the token before executing the read whose response is cached. If
+    /// the replica moves on in between, validating the token fails, which is
+    /// safe; taking it afterwards could attach the token to a newer state than
+    /// the response reflects.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().unwrap();
+    ///
+    /// let version = replica.version_token();
+    /// let cached = replica.execute((), idx).unwrap();
+    /// assert_eq!(cached, Some(0));
+    ///
+    /// // Nothing changed, so `cached` is still up to date.
+    /// assert!(replica.validate(version));
+    ///
+    /// replica.execute_mut(1, idx).unwrap();
+    /// assert!(!replica.validate(version));
+    /// ```
+    pub fn version_token(&self) -> VersionToken {
+        VersionToken(self.slog.get_ltail(self.idx))
+    }
+
+    /// Returns true if a read executed now would observe the same state as when
+    /// `version` was taken with `version_token`, i.e., the replica didn't
+    /// execute any operations since and no other replica completed operations
+    /// it would have to execute first. Costs two loads.
+    pub fn validate(&self, version: VersionToken) -> bool {
+        self.slog.get_ltail(self.idx) == version.0 && self.slog.get_ctail() <= version.0.get()
+    }
+
+    /// Returns the shared log this replica executes.
+    #[cfg(feature = "std")]
+    pub(crate) fn log(&self) -> &Log<'a, <D as Dispatch>::WriteOperation, C> {
+        &self.slog
+    }
+
+    /// Returns a snapshot of the counters of this replica. Doesn't block or
+    /// otherwise interfere with threads executing operations; counters that are
+    /// updated concurrently may be slightly out of date with each other.
+    pub fn metrics(&self) -> ReplicaMetrics {
+        ReplicaMetrics {
+            log_entries: self.slog.len(),
+            ..self.metrics.snapshot()
+        }
+    }
+
+    /// This method is useful when a replica stops making progress and some threads
+    /// on another replica are still active. The active replica will use all the entries
+    /// in the log and won't be able perform garbage collection because of the inactive
+    /// replica. So, this method syncs up the replica against the underlying log.
+    ///
+    /// Fails if the replica can no longer execute operations; see `ReplicaError`.
+    pub fn sync(&self, idx: ReplicaToken) -> Result<(), ReplicaError> {
+        self.sync_for_reads(idx.0)
+    }
+
+    /// Executes outstanding operations on the log against this replica on
+    /// behalf of a thread that isn't registered with it, unless a combiner is
+    /// active already (which then executes them). Meant for helping a replica
+    /// that holds up garbage collection from the `StallHandler` of another
+    /// replica; see `AppendStall`. Doesn't append anything, so it never waits
+    /// for space on the log.
+    ///
+    /// Fails if the replica can no longer execute operations; see `ReplicaError`.
+    pub fn help_sync(&self) -> Result<(), ReplicaError> {
+        self.try_exec(ThreadId::new(MAX_THREADS_PER_REPLICA + 2))
+    }
+
+    /// Returns the identifier of this replica on the shared log, e.g., to find
+    /// the replica an `AppendStall` refers to.
+    pub fn id(&self) -> ReplicaId {
+        self.idx
+    }
+
+    /// Returns the logical offset up to which this replica has applied the
+    /// operations of the shared log.
+    ///
+    /// The offset only ever grows, so it identifies the version of the data
+    /// structure at this replica. Two replicas that report the same offset
+    /// have applied the same operations.
+    pub fn applied_offset(&self) -> u64 {
+        self.slog.get_ltail(self.idx).get() as u64
+    }
+
+    /// Mirrors operations exported from another log with `Log::export_since` to
+    /// the log of this replica (see `Log::import_entries`) and executes them
+    /// against this replica. Returns the offset of the entry to import next.
+    ///
+    /// Meant for a follower that keeps a copy of the data structure in sync with
+    /// another log (e.g., on another machine). Replicas using the log of the
+    /// follower must only serve reads.
+    ///
+    /// Fails if the replica can no longer execute operations; see `ReplicaError`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, LogOffset, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let leader = Arc::new(Log::<u64>::default());
+    /// let replica = Replica::<Data>::new(&leader);
+    /// let idx = replica.register().unwrap();
+    ///
+    /// // Operations stay on the log at least until this replica executes them.
+    /// let _lagging = Replica::<Data>::new(&leader);
+    ///
+    /// replica.execute_mut(10, idx).unwrap();
+    /// replica.execute_mut(20, idx).unwrap();
+    ///
+    /// let follower = Arc::new(Log::<u64>::default());
+    /// let mirror = Replica::<Data>::new(&follower);
+    /// let midx = mirror.register().unwrap();
+    ///
+    /// let next = mirror.import(leader.export_since(LogOffset::new(0)), midx);
+    /// assert_eq!(next, Ok(Ok(LogOffset::new(2))));
+    /// assert_eq!(mirror.execute((), midx), Ok(Some(20)));
+    /// ```
+    pub fn import<I>(
+        &self,
+        entries: I,
+        idx: ReplicaToken,
+    ) -> Result<Result<LogOffset, ImportGap>, ReplicaError>
+    where
+        I: IntoIterator<Item = WireEntry<<D as Dispatch>::WriteOperation>>,
+    {
+        self.assert_registered(idx);
+
+        let lock = self.acquire_combiner_lock(idx.0);
+
+        let r = self.check_log().map(|()| {
+            let guard = self.poison_on_unwind();
+            let mut data = self.data.write(self.next.load(Ordering::Relaxed));
+            let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+                data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+                self.metrics.on_apply(offset, self.idx);
+            };
+            let r = self.slog.import_traced(entries, self.idx, &mut f);
+            self.slog.exec_traced(self.idx, &mut f);
+            self.published
+                .maybe_publish(&data, self.slog.get_ltail(self.idx));
+            drop(data);
+            mem::forget(guard);
+            r
+        });
+
+        drop(lock);
+
+        r
+    }
+
+    /// Similar to `sync`, but gives up once `token` is cancelled. Fails with
+    /// `Cancelled::Failed` if the replica can no longer execute operations.
+    pub fn sync_cancellable(
+        &self,
+        idx: ReplicaToken,
+        token: &CancellationToken,
+    ) -> Result<(), Cancelled> {
+        self.sync_until(idx.0, token)
+    }
+
+    /// Performs up to `budget` rounds of combining and syncing with the shared
+    /// log on behalf of the threads of this replica, and returns without waiting
+    /// once the budget is used up or there is nothing left to do. `idx` is an
+    /// identifier for the thread performing the call.
+    ///
+    /// This lets a cooperative scheduler (e.g., an async executor or a kernel
+    /// with explicit preemption points) decide when the replica gets to make
+    /// progress, rather than the waiting loops of `execute_mut` and friends.
+    /// A round in which another thread was combining counts against the budget
+    /// as well.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::Dispatch;
+    /// use node_replication::Log;
+    /// use node_replication::Replica;
+    /// use node_replication::Work;
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.junk = op;
+    ///         op
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+    /// let first = Replica::<Data>::new(&log);
+    /// let second = Replica::<Data>::new(&log);
+    /// let (i1, i2) = (first.register().unwrap(), second.register().unwrap());
+    /// first.execute_mut(5, i1).unwrap();
+    ///
+    /// // One round brings the second replica up to date; nothing is left then.
+    /// let report = second.run(i2, Work(4)).unwrap();
+    /// assert!(!report.pending);
+    /// assert_eq!(report.remaining, Work(3));
+    /// ```
+    pub fn run(&self, idx: ReplicaToken, budget: Work) -> Result<RunReport, ReplicaError> {
+        self.assert_registered(idx);
+
+        let mut remaining = budget.0;
+        let mut pending = self.has_work();
+        while pending && remaining > 0 {
+            self.try_progress(idx.0)?;
+            remaining -= 1;
+            pending = self.has_work();
+        }
+
+        Ok(RunReport {
+            remaining: Work(remaining),
+            pending,
+        })
+    }
+
+    /// Returns true if threads of this replica have operations that no combiner
+    /// picked up yet, or if the replica is behind on the shared log.
+    fn has_work(&self) -> bool {
+        let next = self.next.load(Ordering::Relaxed);
+        self.contexts[..next - 1].iter().any(|c| !c.is_drained())
+            || self.slog.get_ltail(self.idx).get() < self.slog.get_tail()
+    }
+
+    /// Waits until no operation issued before the call remains anywhere: neither
+    /// in the per-thread contexts of this replica nor on the part of the shared
+    /// log that some replica hasn't executed yet. `idx` is an identifier for the
+    /// thread performing the call.
+    ///
+    /// This is a building block for changing the semantics of operations at
+    /// runtime: stop issuing operations in the old format, call `quiesce`, and
+    /// switch the data structure over. Operations issued concurrently by other
+    /// threads may or may not be covered by the call.
+    ///
+    /// # Note
+    /// Only the contexts of this replica are drained; call `quiesce` on every
+    /// replica that has threads issuing operations. Replicas without active
+    /// threads have to be kept in sync (e.g., with `sync`), otherwise this
+    /// method never returns.
+    pub fn quiesce(&self, idx: ReplicaToken) -> Result<QuiesceReport, ReplicaError> {
+        self.assert_registered(idx);
+
+        // Get operations that threads enqueued but didn't hand to a combiner yet
+        // onto the log.
+        let next = self.next.load(Ordering::Relaxed);
+        for context in self.contexts[..next - 1].iter() {
+            while !context.is_drained() {
+                self.try_combine(idx.0)?;
+                spin_loop();
+            }
+        }
+
+        // Wait for every replica to execute everything up to where the log is now.
+        // Keep this replica making progress, so we don't end up waiting on ourselves.
+        let offset = self.slog.get_tail();
+        while !self.slog.is_synced(offset) {
+            self.try_combine(idx.0)?;
+            spin_loop();
+        }
+
+        Ok(QuiesceReport {
+            offset: LogOffset::new(offset),
+        })
+    }
+
+    /// Issues a read-only operation against the replica and returns a response.
+    /// Makes sure the replica is synced up against the log before doing so.
+    fn read_only(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+        self.sync_for_reads(idx.0)?;
+
+        Ok(self.data.read(idx.0.index()).dispatch(op))
+    }
+
+    /// Executes the read `op` right away if the replica executed the shared log
+    /// up to `ctail` and no combiner is executing operations against it. Hands
+    /// `op` back if the read would have to wait, or if the replica failed.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_read_synced(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        ctail: usize,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::ReadOperation> {
+        self.assert_registered(idx);
+        if self.failure().is_err() || !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            return Err(op);
+        }
+
+        match self.data.try_read(idx.0.index()) {
+            Some(data) => Ok(data.dispatch(op)),
+            None => Err(op),
+        }
+    }
+
+    /// Waits until the replica has executed every operation that completed on the
+    /// shared log at the time of the call, so that a read observes their effects.
+    /// Fails if the replica failed, so that reads don't observe a data structure
+    /// left behind by a panicking combiner.
+    #[inline(always)]
+    fn sync_for_reads(&self, tid: ThreadId) -> Result<(), ReplicaError> {
+        self.failure()?;
+
+        // We can perform the read only if our replica is synced up against
+        // the shared log. If it isn't, then try to combine until it is synced up.
+        let ctail = self.slog.get_ctail();
+        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            self.try_combine(tid)?;
+            spin_loop();
+        }
+
+        Ok(())
+    }
+
+    /// Same as `sync_for_reads`, but gives up once `token` is cancelled.
+    fn sync_until(&self, tid: ThreadId, token: &CancellationToken) -> Result<(), Cancelled> {
+        self.failure()?;
+
+        let ctail = self.slog.get_ctail();
+        while !self.slog.is_replica_synced_for_reads(self.idx, ctail) {
+            if token.is_cancelled() {
+                return Err(Cancelled::Requested { enqueued: false });
+            }
+            self.try_combine(tid)?;
+            spin_loop();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the error the replica failed with, if any.
+    #[inline(always)]
+    pub(crate) fn failure(&self) -> Result<(), ReplicaError> {
+        match self.failure.load(Ordering::Acquire) {
+            0 => Ok(()),
+            f if f == ReplicaError::Desync as usize => Err(ReplicaError::Desync),
+            f if f == ReplicaError::ShuttingDown as usize => Err(ReplicaError::ShuttingDown),
+            _ => Err(ReplicaError::Poisoned),
+        }
+    }
+
+    /// Checks that the replica can execute the shared log; called by the
+    /// combiner before doing so. Marks the replica as desynced if it fell off
+    /// the log.
+    fn check_log(&self) -> Result<(), ReplicaError> {
+        self.failure()?;
+
+        if !self.slog.is_within(self.idx) {
+            self.failure
+                .store(ReplicaError::Desync as usize, Ordering::Release);
+            return Err(ReplicaError::Desync);
+        }
+
+        Ok(())
+    }
+
+    /// Returns a guard that poisons the replica if the combiner (the caller)
+    /// panics before forgetting it.
+    fn poison_on_unwind(&self) -> PoisonOnUnwind<'_> {
+        PoisonOnUnwind {
+            failure: &self.failure,
+        }
+    }
+
+    /// Spins until it acquired the combiner lock on behalf of thread `tid`.
+    fn acquire_combiner_lock(&self, tid: ThreadId) -> CombinerGuard<'_, 'a, D, C> {
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::before_acquire(self.lock_id);
+        while self.combiner.compare_exchange_weak(
+            0,
+            tid.get(),
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) != Ok(0)
+        {
+            spin_loop();
+        }
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::acquired(self.lock_id);
+
+        self.held_combiner_lock()
+    }
+
+    /// Acquires the combiner lock on behalf of thread `tid`, unless another
+    /// thread holds it already.
+    fn try_acquire_combiner_lock(&self, tid: ThreadId) -> Option<CombinerGuard<'_, 'a, D, C>> {
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::before_acquire(self.lock_id);
+        if self
+            .combiner
+            .compare_exchange(0, tid.get(), Ordering::Acquire, Ordering::Acquire)
+            != Ok(0)
+        {
+            return None;
+        }
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::acquired(self.lock_id);
+
+        Some(self.held_combiner_lock())
+    }
+
+    /// Returns a guard for the combiner lock, which the caller holds already
+    /// (e.g., since `try_halt`).
+    fn held_combiner_lock(&self) -> CombinerGuard<'_, 'a, D, C> {
+        CombinerGuard { replica: self }
+    }
+
+    /// Wakes the tasks of `async_execute_mut` and `async_execute` that wait
+    /// for a combiner (see `WaitForCombiner`). Invoked after releasing the
+    /// combiner lock.
+    fn wake_tasks(&self) {
+        // Pairs with the fence in `Context::register_waker()`: either we see
+        // the task waiting, or it sees the lock released.
+        fence(Ordering::SeqCst);
+        if self.wakers.load(Ordering::Relaxed) {
+            for i in 1..self.next.load(Ordering::Relaxed) {
+                self.contexts[i - 1].wake();
+            }
+        }
+    }
+
+    /// Panics if the current core isn't on the node this replica was assigned to.
+    #[cfg(debug_assertions)]
+    fn assert_node(&self) {
+        let node = self.node.load(Ordering::Relaxed);
+        if node == usize::MAX {
+            return;
+        }
+
+        if let Some(current) = current_node() {
+            assert_eq!(
+                current, node,
+                "Replica {} of node {} executes operations on a core of node {}.",
+                self.idx, node, current
+            );
+        }
+    }
+
+    /// Enqueues an operation inside a thread local context. Returns a boolean
+    /// indicating whether the operation was enqueued (true) or not (false).
+    #[inline(always)]
+    fn make_pending(&self, op: <D as Dispatch>::WriteOperation, idx: ThreadId) -> bool {
+        self.contexts[idx.index()].enqueue(op)
+    }
+
+    /// Like `make_pending`, but the combiner writes the response to `out`.
+    #[inline(always)]
+    fn make_pending_into(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        out: *mut <D as Dispatch>::Response,
+        idx: ThreadId,
+    ) -> bool {
+        self.contexts[idx.index()].enqueue_into(op, out)
+    }
+
+    /// Appends an operation to the log and attempts to perform flat combining.
+    /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
+    ///
+    /// Fails if the replica can no longer execute operations. Returns `Ok` without
+    /// doing anything if another thread is combining.
+    fn try_combine(&self, tid: ThreadId) -> Result<(), ReplicaError> {
+        // The dedicated combiner thread (if any) combines for everyone.
+        #[cfg(feature = "std")]
+        if tid != BACKGROUND_COMBINER && self.has_background_combiner() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "deadlock-detection")]
+        lockdep::before_acquire(self.lock_id);
+
+        // First, check if there already is a flat combiner. If there is no active flat combiner
+        // then try to acquire the combiner lock. If there is, then just return.
+        for _i in 0..self.config.backoff {
+            if unsafe {
+                core::ptr::read_volatile(
+                    &self.combiner
                         as *const crossbeam_utils::CachePadded<core::sync::atomic::AtomicUsize>
                         as *const usize,
                 )
             } != 0
             {
-                return;
+                return Ok(());
+            };
+        }
+
+        // Try to become the combiner here. If this fails, then simply return.
+        let lock = match self.try_acquire_combiner_lock(tid) {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+
+        // Successfully became the combiner; perform one round of flat combining.
+        // If the data structure panics, the guard releases the combiner lock.
+        let r = self.check_log();
+        if r.is_ok() {
+            let guard = self.poison_on_unwind();
+            self.combine(tid);
+            mem::forget(guard);
+        }
+
+        // Allow other threads to perform flat combining once we have finished all our work.
+        // At this point, we've dropped all mutable references to thread contexts and to
+        // the staging buffer as well.
+        drop(lock);
+
+        // Parked threads either got their responses, or have to combine
+        // themselves now that the lock is free.
+        #[cfg(feature = "std")]
+        if matches!(self.config.park_strategy, ParkStrategy::Park(_))
+            || self.has_background_combiner()
+        {
+            for i in 1..self.next.load(Ordering::Relaxed) {
+                self.contexts[i - 1].unpark();
+            }
+        }
+
+        r
+    }
+
+    /// Makes progress without waiting on other replicas: performs flat combining
+    /// if there is room on the log, otherwise only executes the log against this
+    /// replica. Accepts a thread `tid` as an argument.
+    fn try_progress(&self, tid: ThreadId) -> Result<(), ReplicaError> {
+        if self.slog.has_room() {
+            self.try_combine(tid)
+        } else {
+            self.try_exec(tid)
+        }
+    }
+
+    /// Executes outstanding operations on the log against this replica if no
+    /// other thread is combining. Doesn't append any operations, so it never
+    /// waits for other replicas to free up entries.
+    fn try_exec(&self, tid: ThreadId) -> Result<(), ReplicaError> {
+        match self.try_acquire_combiner_lock(tid) {
+            Some(_lock) => self.exec_log(),
+            None => Ok(()),
+        }
+    }
+
+    /// Executes outstanding operations on the log against this replica. Must be
+    /// called while holding the combiner lock.
+    fn exec_log(&self) -> Result<(), ReplicaError> {
+        invariant!(
+            combiner_exclusive,
+            self.combiner.load(Ordering::Relaxed) != 0,
+            "executing the log without holding the lock"
+        );
+        // This replica executed its own operations when it appended them, so
+        // there are no responses to hand out here.
+        let r = self.check_log();
+        if r.is_ok() {
+            let guard = self.poison_on_unwind();
+            let mut data = self.data.write(self.next.load(Ordering::Relaxed));
+            let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+                data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+                self.metrics.on_apply(offset, self.idx);
+            };
+            self.slog.exec_traced(self.idx, &mut f);
+            self.published
+                .maybe_publish(&data, self.slog.get_ltail(self.idx));
+            drop(data);
+            mem::forget(guard);
+        }
+
+        r
+    }
+
+    /// Stops threads from combining on this replica until `resume` is called,
+    /// by taking the combiner lock on behalf of a thread that isn't registered.
+    /// Returns false if another thread is combining. `exec_halted` executes the
+    /// log against the replica in the meantime.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_halt(&self) -> bool {
+        // The lock stays held until `resume`.
+        self.try_acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2))
+            .map(mem::forget)
+            .is_some()
+    }
+
+    /// Executes outstanding operations on the log against a replica that was
+    /// stopped with `try_halt`.
+    #[cfg(feature = "std")]
+    pub(crate) fn exec_halted(&self) -> Result<(), ReplicaError> {
+        // Releases the lock if the data structure panics, but keeps holding it
+        // otherwise.
+        let lock = self.held_combiner_lock();
+        let r = self.exec_log();
+        mem::forget(lock);
+        r
+    }
+
+    /// Makes a dedicated thread combine on behalf of the threads registered
+    /// with this replica, which then only enqueue their operations and wait
+    /// for the responses. Returns false if there already is such a thread.
+    #[cfg(feature = "std")]
+    pub(crate) fn start_background(&self) -> bool {
+        self.background
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Lets the threads registered with this replica combine themselves again
+    /// after `start_background`, and wakes up those waiting for a response.
+    #[cfg(feature = "std")]
+    pub(crate) fn stop_background(&self) {
+        self.background.store(false, Ordering::Release);
+        for i in 1..self.next.load(Ordering::Relaxed) {
+            self.contexts[i - 1].unpark();
+        }
+        self.wake_tasks();
+    }
+
+    /// Performs a round of flat combining on behalf of the dedicated combiner
+    /// thread if a thread has operations pending or the replica is behind on
+    /// the log. Returns false if there was nothing to do.
+    #[cfg(feature = "std")]
+    pub(crate) fn combine_background(&self) -> Result<bool, ReplicaError> {
+        self.failure()?;
+        if self.is_drained()
+            && self
+                .slog
+                .is_replica_synced_for_reads(self.idx, self.slog.get_ctail())
+        {
+            return Ok(false);
+        }
+
+        self.try_combine(BACKGROUND_COMBINER)?;
+        Ok(true)
+    }
+
+    /// Returns true if a combiner picked up the operations of every thread
+    /// registered with this replica.
+    #[cfg(feature = "std")]
+    pub(crate) fn is_drained(&self) -> bool {
+        let next = self.next.load(Ordering::Relaxed);
+        (1..next).all(|i| self.contexts[i - 1].is_drained())
+    }
+
+    /// Same as `try_combine`, but on behalf of a thread that isn't registered
+    /// with the replica, e.g., for a `NodeReplicated` that shuts down. Returns
+    /// true once a combiner picked up the operations of every thread.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_drain(&self) -> Result<bool, ReplicaError> {
+        self.failure()?;
+        if self.is_drained() {
+            return Ok(true);
+        }
+
+        self.try_combine(ThreadId::new(MAX_THREADS_PER_REPLICA + 2))?;
+        Ok(false)
+    }
+
+    /// Makes every further operation on this replica fail with
+    /// `ReplicaError::ShuttingDown`, unless it failed already, and wakes up
+    /// threads waiting for a response.
+    #[cfg(feature = "std")]
+    pub(crate) fn shut_down(&self) {
+        let _r = self.failure.compare_exchange(
+            0,
+            ReplicaError::ShuttingDown as usize,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        for i in 1..self.next.load(Ordering::Relaxed) {
+            self.contexts[i - 1].unpark();
+        }
+        self.wake_tasks();
+    }
+
+    /// Gives up the slot of a replica that was stopped with `try_halt` on the
+    /// shared log, as if it was dropped, so that garbage collection stops
+    /// waiting for it. The replica fails with `ReplicaError::Desync` from then on.
+    #[cfg(feature = "std")]
+    pub(crate) fn evict(&self) {
+        self.slog.retire(self.idx);
+        self.failure
+            .store(ReplicaError::Desync as usize, Ordering::Release);
+    }
+
+    /// Returns the number of operations on the shared log that this replica
+    /// didn't execute yet.
+    #[cfg(feature = "std")]
+    pub(crate) fn lag(&self) -> usize {
+        let ltail = self.slog.get_ltail(self.idx).get();
+        self.slog.get_tail().saturating_sub(ltail)
+    }
+
+    /// Lets threads combine on a replica that was stopped with `try_halt` again.
+    /// Combines the operations they enqueued in the meantime right away, rather
+    /// than leaving them until a waiting thread retries.
+    #[cfg(feature = "std")]
+    pub(crate) fn resume(&self) {
+        drop(self.held_combiner_lock());
+
+        if self.next.load(Ordering::Relaxed) > 1 {
+            let _r = self.try_combine(ThreadId::new(1));
+        }
+    }
+
+    /// Performs one round of flat combining on behalf of thread `tid`. Collects,
+    /// appends and executes operations.
+    #[inline(always)]
+    fn combine(&self, tid: ThreadId) {
+        #[cfg(debug_assertions)]
+        self.assert_node();
+        invariant!(
+            combiner_exclusive,
+            self.combiner.load(Ordering::Relaxed) == tid.get(),
+            "thread {} combines without holding the lock",
+            tid
+        );
+
+        #[cfg(feature = "std")]
+        let start = std::time::Instant::now();
+
+        let mut buffer = self.buffer.borrow_mut();
+        let mut operations = self.inflight.borrow_mut();
+        let mut results = self.result.borrow_mut();
+
+        buffer.clear();
+        results.clear();
+
+        let next = self.next.load(Ordering::Relaxed);
+
+        // Without registered threads (e.g., for a dedicated combiner on a replica
+        // no thread registered with yet) there are no operations to collect, so
+        // only execute the shared log against this replica.
+        if next == 1 {
+            let mut data = self.data.write(next);
+            let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+                data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+                self.metrics.on_apply(offset, self.idx);
+            };
+            self.slog.exec_traced(self.idx, &mut f);
+            self.published
+                .maybe_publish(&data, self.slog.get_ltail(self.idx));
+            return;
+        }
+
+        // The order in which threads are collected from (and hence handed their
+        // responses): threads waiting for an urgent operation, the combiner, then
+        // the others starting at `cursor`.
+        let mut urgent = [0; FREE_WORDS];
+        for (w, word) in self.urgent.iter().enumerate() {
+            urgent[w] = word.load(Ordering::Acquire);
+        }
+        let is_urgent = move |i: usize| urgent[(i - 1) / 64] & (1 << ((i - 1) % 64)) != 0;
+        let threads = next - 1;
+        let cursor = self.cursor.get() % threads;
+        let order = || {
+            (1..next)
+                .filter(move |&i| is_urgent(i))
+                .chain(core::iter::once(tid.get()).filter(move |&i| i < next && !is_urgent(i)))
+                .chain(
+                    (0..threads)
+                        .map(move |k| (cursor + k) % threads + 1)
+                        .filter(move |&i| i != tid.get() && !is_urgent(i)),
+                )
+        };
+        self.cursor.set((cursor + 1) % threads);
+
+        // Collect operations from each thread registered with this replica, up to
+        // the limit for this round. Skip threads that deregistered; they don't
+        // have any operations in flight.
+        let mut free = [0; FREE_WORDS];
+        for (w, word) in self.free.iter().enumerate() {
+            free[w] = word.load(Ordering::Relaxed);
+        }
+        let mut budget = self.max_ops_per_round.load(Ordering::Relaxed);
+        for i in order() {
+            if free[(i - 1) / 64] & (1 << ((i - 1) % 64)) != 0 || budget == 0 {
+                operations[i - 1] = 0;
+                continue;
+            }
+            operations[i - 1] = self.contexts[i - 1].ops(&mut buffer, budget);
+            budget -= operations[i - 1];
+        }
+
+        // Append all collected operations into the shared log. We pass a closure
+        // in here because operations on the log might need to be consumed for GC.
+        // If the GC policy gives up, the operations stay in the thread contexts
+        // (we only move past them once their responses are in) for a later round.
+        // Otherwise, the operations of this batch start at log offset `base`.
+        let base = {
+            let f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+                let origin = OpOrigin { replica: i, offset };
+                let resp = self.data.write(next).dispatch_mut_ctx(o, origin);
+                self.metrics.on_apply(offset, self.idx);
+                if i == self.idx {
+                    push_within(&mut results, resp);
+                }
+            };
+            let policy = self.gc_policy.borrow();
+            match self
+                .slog
+                .append_observed(&buffer, self.idx, f, &self.metrics, &**policy)
+            {
+                Ok(offset) => offset,
+                Err(_) => return,
+            }
+        };
+
+        // The operations of each thread follow each other in the order they
+        // were collected in, starting at `base`.
+        #[cfg(feature = "std")]
+        if let Some(recorder) = self.recorder.borrow().as_ref() {
+            let mut s = 0;
+            for i in order() {
+                for op in buffer[s..s + operations[i - 1]].iter() {
+                    let offset = LogOffset::new(base.get() + s);
+                    recorder.record(offset, op, self.idx, ThreadId::new(i));
+                    s += 1;
+                }
+            }
+        }
+
+        // Execute any operations on the shared log against this replica.
+        {
+            let mut data = self.data.write(next);
+            let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+                let resp = data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+                self.metrics.on_apply(offset, self.idx);
+                if i == self.idx {
+                    push_within(&mut results, resp);
+                };
+            };
+            self.slog.exec_traced(self.idx, &mut f);
+            self.published
+                .maybe_publish(&data, self.slog.get_ltail(self.idx));
+        }
+
+        // Return/Enqueue responses back into the appropriate thread context(s).
+        let (mut s, mut f) = (0, 0);
+        for i in order() {
+            if operations[i - 1] == 0 {
+                continue;
             };
+
+            f += operations[i - 1];
+            let first = LogOffset::new(base.get() + s);
+            self.contexts[i - 1].set_offsets(first, operations[i - 1]);
+            self.contexts[i - 1].enqueue_resps(&results[s..f]);
+            s += operations[i - 1];
+            operations[i - 1] = 0;
+        }
+
+        #[cfg(feature = "std")]
+        let duration = start.elapsed();
+        #[cfg(not(feature = "std"))]
+        let duration = core::time::Duration::default();
+        self.metrics.on_combine(buffer.len(), duration);
+    }
+}
+
+impl<'a, D, C> Replica<'a, D, C>
+where
+    D: Sized + Dispatch + Clone + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Makes the combiner of this replica publish a copy of the data structure
+    /// every `every` operations it executes, for `execute_published` to serve
+    /// reads from. Publishes a first copy right away.
+    ///
+    /// Every copy is a full clone of the data structure made by the combiner,
+    /// so this pays off for read-heavy workloads on data structures that are
+    /// cheap to clone (e.g., persistent data structures that share most of
+    /// their state between copies).
+    ///
+    /// Waits for an active combiner (if any) to finish.
+    ///
+    /// # Panics
+    /// If `every` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let replica = Replica::<Data>::new(&log);
+    /// let idx = replica.register().unwrap();
+    /// replica.publish_every(1);
+    ///
+    /// replica.execute_mut(5, idx).unwrap();
+    ///
+    /// // Served from the published copy, without taking the replica's locks.
+    /// assert_eq!(replica.execute_published((), 0, idx), Ok(Some(5)));
+    /// ```
+    pub fn publish_every(&self, every: usize) {
+        assert!(every > 0, "Must publish at least every operation.");
+
+        // The publishing state is only accessed by the combiner, so acquire the
+        // combiner lock. Use an idx greater than the maximum that can be
+        // allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        let data = self.data.write(self.next.load(Ordering::Relaxed));
+        self.published
+            .enable(D::clone, every, &data, self.slog.get_ltail(self.idx));
+        drop(data);
+
+        drop(lock);
+    }
+
+    /// Executes a read-only operation against the copy of the data structure
+    /// published last (see `publish_every`), without taking any of the locks
+    /// of the replica or combining. Falls back to `execute` if nothing was
+    /// published yet or if the copy misses more than `max_lag` operations that
+    /// completed on the shared log.
+    ///
+    /// With a `max_lag` of zero, the read observes every operation that
+    /// completed before the call, just like `execute`. Larger values trade
+    /// freshness for fewer fallbacks.
+    pub fn execute_published(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        max_lag: usize,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+        self.failure()?;
+
+        if let Some(version) = self.published.load() {
+            if self.slog.get_ctail() <= version.offset.get() + max_lag {
+                return Ok(version.data.dispatch(op));
+            }
+        }
+
+        self.read_only(op, idx)
+    }
+
+    /// Creates another replica of the log that `peer` uses, starting with a
+    /// copy of the data structure of `peer`. Unlike `new` (which replays the
+    /// whole log) and `from_checkpoint` (which needs a `Snapshot`), this only
+    /// takes as long as cloning the data structure. The new replica uses the
+    /// configuration of `peer`.
+    ///
+    /// Syncs `peer` with the log first and holds its combiner lock while the
+    /// data structure is cloned, so threads of `peer` wait for that.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let first = Replica::<Data>::new(&log);
+    /// let idx = first.register().unwrap();
+    /// first.execute_mut(5, idx).unwrap();
+    ///
+    /// let second = Replica::from_peer(&first).unwrap();
+    /// let idx = second.register().unwrap();
+    /// assert_eq!(second.execute((), idx), Ok(Some(5)));
+    /// ```
+    pub fn from_peer(peer: &Replica<'a, D, C>) -> Result<Arc<Replica<'a, D, C>>, LogError> {
+        // Acquire the combiner lock so that `peer` doesn't make progress on the
+        // log while we copy its state. Use an idx greater than the maximum that
+        // can be allocated.
+        let lock = peer.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        let mut data = peer.data.write(peer.next.load(Ordering::Relaxed));
+
+        let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+            data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+            peer.metrics.on_apply(offset, peer.idx);
+        };
+
+        peer.slog.exec_traced(peer.idx, &mut f);
+
+        // The local tail of `peer` keeps the head of the log from moving past
+        // the offset until the new replica registered at it.
+        let data = data.downgrade(0);
+        let offset = peer.slog.get_ltail(peer.idx);
+        let replica = Replica::try_create(&peer.slog, D::clone(&data), Some(offset), peer.config);
+
+        drop(data);
+        drop(lock);
+
+        replica
+    }
+}
+
+impl<'a, D, C> Replica<'a, D, C>
+where
+    D: Sized + DispatchRef + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Executes a read-only operation against this replica like `execute`, but
+    /// returns data borrowed from the data structure instead of a copy (see
+    /// `DispatchRef`), or `None` if the operation returned nothing. The replica
+    /// stays read-locked until the returned `ReadRef` is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, DispatchRef, Log, Replica};
+    ///
+    /// use std::collections::HashMap;
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Blobs(HashMap<u64, Vec<u8>>);
+    ///
+    /// impl Dispatch for Blobs {
+    ///     type ReadOperation = u64;
+    ///     type WriteOperation = (u64, Vec<u8>);
+    ///     type Response = Option<Vec<u8>>;
+    ///
+    ///     fn dispatch(&self, key: Self::ReadOperation) -> Self::Response {
+    ///         self.0.get(&key).cloned()
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, (key, blob): Self::WriteOperation) -> Self::Response {
+    ///         self.0.insert(key, blob)
+    ///     }
+    /// }
+    ///
+    /// impl DispatchRef for Blobs {
+    ///     type Borrowed = [u8];
+    ///
+    ///     fn dispatch_ref(&self, key: Self::ReadOperation) -> Option<&Self::Borrowed> {
+    ///         self.0.get(&key).map(|blob| blob.as_slice())
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<(u64, Vec<u8>)>::default());
+    /// let replica = Replica::<Blobs>::new(&log);
+    /// let idx = replica.register().unwrap();
+    /// replica.execute_mut((1, vec![0xab; 4096]), idx).unwrap();
+    ///
+    /// // Looks at the blob without copying it.
+    /// let blob = replica.execute_ref(1, idx).unwrap().unwrap();
+    /// assert_eq!(blob.len(), 4096);
+    /// ```
+    pub fn execute_ref(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<Option<ReadRef<'_, D>>, ReplicaError> {
+        self.assert_registered(idx);
+        self.sync_for_reads(idx.0)?;
+
+        Ok(ReadRef::new(self.data.read(idx.0.index()), op))
+    }
+}
+
+impl<'a, D, C> Replica<'a, D, C>
+where
+    D: Sized + DispatchReadMut + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Executes a read-only operation against this replica like `execute`, but
+    /// through `dispatch_read_mut`, which records into `ctx` (see
+    /// `DispatchReadMut`).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, DispatchReadMut, Log, ReadCtx, Replica};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Stack(Vec<u32>);
+    ///
+    /// impl Dispatch for Stack {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u32;
+    ///     type Response = Option<u32>;
+    ///
+    ///     fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+    ///         self.0.last().copied()
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.0.push(op);
+    ///         Some(op)
+    ///     }
+    /// }
+    ///
+    /// // Keeps track of what every peek returned.
+    /// impl DispatchReadMut for Stack {
+    ///     type ReadRecord = Option<u32>;
+    ///
+    ///     fn dispatch_read_mut(
+    ///         &self,
+    ///         op: Self::ReadOperation,
+    ///         ctx: &mut ReadCtx<Self::ReadRecord>,
+    ///     ) -> Self::Response {
+    ///         let top = self.dispatch(op);
+    ///         ctx.record(top);
+    ///         top
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u32>::default());
+    /// let replica = Replica::<Stack>::new(&log);
+    /// let idx = replica.register().unwrap();
+    ///
+    /// let mut peeked = ReadCtx::new();
+    /// replica.execute_read_mut((), idx, &mut peeked).unwrap();
+    /// replica.execute_mut(7, idx).unwrap();
+    /// replica.execute_read_mut((), idx, &mut peeked).unwrap();
+    /// assert_eq!(peeked.records(), &[None, Some(7)]);
+    /// ```
+    pub fn execute_read_mut(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+        ctx: &mut ReadCtx<<D as DispatchReadMut>::ReadRecord>,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.assert_registered(idx);
+        self.sync_for_reads(idx.0)?;
+
+        Ok(self.data.read(idx.0.index()).dispatch_read_mut(op, ctx))
+    }
+}
+
+impl<'a, D, C> Replica<'a, D, C>
+where
+    D: Sized + Dispatch + Snapshot + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Captures the state of the replicated data structure, along with the
+    /// offset on the shared log up to which it was executed. A replica created
+    /// from the checkpoint with `from_checkpoint` only has to execute the log
+    /// from that offset on.
+    ///
+    /// Syncs the replica with the log first; waits for an active combiner (if
+    /// any) to finish before doing so.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, Log, Replica, Snapshot};
+    ///
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// impl Snapshot for Data {
+    ///     type Snapshot = u64;
+    ///
+    ///     fn snapshot(&self) -> Self::Snapshot {
+    ///         self.junk
+    ///     }
+    ///
+    ///     fn restore(snapshot: Self::Snapshot) -> Self {
+    ///         Data { junk: snapshot }
+    ///     }
+    /// }
+    ///
+    /// let log = Arc::new(Log::<u64>::default());
+    /// let first = Replica::<Data>::new(&log);
+    /// let idx = first.register().unwrap();
+    /// first.execute_mut(5, idx).unwrap();
+    ///
+    /// // Bring up another replica without replaying the log.
+    /// let second = Replica::<Data>::from_checkpoint(&log, first.checkpoint()).unwrap();
+    /// let idx = second.register().unwrap();
+    /// assert_eq!(second.execute((), idx), Ok(Some(5)));
+    /// ```
+    pub fn checkpoint(&self) -> Checkpoint<<D as Snapshot>::Snapshot> {
+        // Acquire the combiner lock so that the replica doesn't make progress on
+        // the log while we capture its state. Use an idx greater than the maximum
+        // that can be allocated.
+        let lock = self.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+
+        let mut data = self.data.write(self.next.load(Ordering::Relaxed));
+
+        let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+            data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+            self.metrics.on_apply(offset, self.idx);
+        };
+
+        self.slog.exec_traced(self.idx, &mut f);
+
+        // Readers don't have to wait for the snapshot. The combiner lock keeps
+        // writers out, so any reader slot will do.
+        let data = data.downgrade(0);
+        let checkpoint = Checkpoint {
+            offset: self.slog.get_ltail(self.idx),
+            snapshot: data.snapshot(),
+        };
+
+        drop(data);
+        drop(lock);
+
+        checkpoint
+    }
+
+    /// Creates a replica from a checkpoint taken with `checkpoint` on another
+    /// replica of the same log. The replica executes the log from the offset of
+    /// the checkpoint on, rather than from the beginning.
+    ///
+    /// Fails with `LogError::OffsetReclaimed` if the log was garbage collected
+    /// past that offset in the meantime; a more recent checkpoint is needed then.
+    pub fn from_checkpoint<'b>(
+        log: &Arc<Log<'b, <D as Dispatch>::WriteOperation, C>>,
+        checkpoint: Checkpoint<<D as Snapshot>::Snapshot>,
+    ) -> Result<Arc<Replica<'b, D, C>>, LogError> {
+        let d = D::restore(checkpoint.snapshot);
+        Replica::try_create(log, d, Some(checkpoint.offset), CombinerConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+
+    // Really dumb data structure to test against the Replica and shared log.
+    #[derive(Clone, Default)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = u64;
+        type WriteOperation = u64;
+        type Response = Result<u64, ()>;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            Ok(self.junk)
+        }
+
+        fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+            self.junk += 1;
+            return Ok(107);
+        }
+    }
+
+    impl Snapshot for Data {
+        type Snapshot = u64;
+
+        fn snapshot(&self) -> Self::Snapshot {
+            self.junk
+        }
+
+        fn restore(snapshot: Self::Snapshot) -> Self {
+            Data { junk: snapshot }
+        }
+    }
+
+    // Tests whether we can construct a Replica given a log.
+    #[test]
+    fn test_replica_create() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        assert_eq!(repl.idx, ReplicaId::new(1));
+        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
+        assert_eq!(repl.next.load(Ordering::SeqCst), 1);
+        assert_eq!(repl.contexts.len(), MAX_THREADS_PER_REPLICA);
+        assert_eq!(
+            repl.buffer.borrow().capacity(),
+            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+        );
+        assert_eq!(repl.inflight.borrow().len(), MAX_THREADS_PER_REPLICA);
+        assert_eq!(
+            repl.result.borrow().capacity(),
+            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
+        );
+        assert_eq!(repl.data.read(0).junk, 0);
+    }
+
+    // Tests that try_new() fails once the log has no free replica slots left.
+    #[test]
+    fn test_replica_try_new_too_many() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        while slog.register().is_some() {}
+
+        assert_eq!(
+            Replica::<Data>::try_new(&slog).err(),
+            Some(LogError::TooManyReplicas)
+        );
+    }
+
+    // Tests that a replica keeps working after (trying to) lock its memory.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_lock_memory() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let locked = repl.lock_memory();
+        assert_eq!(repl.locked.borrow().is_some(), locked);
+        assert_eq!(repl.lock_memory(), locked);
+
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+    }
+
+    // Polls a future on the current thread until it completes.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        use std::task::{Context, Wake};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let waker = Arc::new(NoopWaker).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut f = std::boxed::Box::pin(f);
+        loop {
+            if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+                return r;
+            }
+        }
+    }
+
+    // Tests that the async variants of execute_mut() and execute() resolve to
+    // the same responses as their blocking counterparts.
+    #[test]
+    fn test_replica_async_execute() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        assert_eq!(block_on(repl.async_execute_mut(121, idx)), Ok(Ok(107)));
+        assert_eq!(block_on(repl.async_execute_mut(122, idx)), Ok(Ok(107)));
+        assert_eq!(block_on(repl.async_execute(11, idx)), Ok(Ok(2)));
+    }
+
+    // Tests that dropping the future of async_execute_mut() while its operation
+    // is pending doesn't hand its response to the next operation of the thread.
+    #[test]
+    fn test_replica_async_execute_mut_dropped() {
+        use std::task::{Context, Wake};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        // Nobody can combine, so the operation stays pending.
+        repl.combiner
+            .store(MAX_THREADS_PER_REPLICA + 2, Ordering::Relaxed);
+        let waker = Arc::new(NoopWaker).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut f = std::boxed::Box::pin(repl.async_execute_mut(121, idx));
+        assert!(f.as_mut().poll(&mut cx).is_pending());
+        drop(f);
+        repl.combiner.store(0, Ordering::Release);
+
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+        assert_eq!(repl.contexts[idx.0.index()].res(), None);
+        repl.verify(|d: &Data| assert_eq!(d.junk, 2));
+    }
+
+    // Tests that the future of async_execute_mut() waits for the combiner to
+    // wake up its task instead of waking it up itself.
+    #[test]
+    fn test_replica_async_execute_mut_woken() {
+        use std::task::{Context, Wake};
+
+        struct CountingWaker(AtomicUsize);
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        let woken = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = woken.clone().into();
+        let mut cx = Context::from_waker(&waker);
+
+        // Another thread is combining, so the operation stays pending.
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let r = repl.clone();
+        let combiner = std::thread::spawn(move || {
+            let lock = r.acquire_combiner_lock(ThreadId::new(MAX_THREADS_PER_REPLICA + 2));
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+            drop(lock);
+        });
+        locked_rx.recv().unwrap();
+
+        let mut f = std::boxed::Box::pin(repl.async_execute_mut(121, idx));
+        assert!(f.as_mut().poll(&mut cx).is_pending());
+        assert!(f.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(woken.0.load(Ordering::Relaxed), 0);
+
+        release_tx.send(()).unwrap();
+        combiner.join().unwrap();
+        assert_eq!(woken.0.load(Ordering::Relaxed), 1);
+        assert_eq!(f.as_mut().poll(&mut cx), Poll::Ready(Ok(Ok(107))));
+        assert_eq!(woken.0.load(Ordering::Relaxed), 1);
+    }
+
+    // Tests that tasks of async_execute_mut() on several threads that sleep
+    // until their waker is woken all complete, i.e., no wake-up gets lost.
+    #[test]
+    fn test_replica_async_execute_mut_threads() {
+        use std::task::{Context, Wake};
+
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+
+        let mut threads = Vec::new();
+        for _i in 0..4 {
+            let repl = repl.clone();
+            threads.push(std::thread::spawn(move || {
+                let idx = repl.register().unwrap();
+                let waker = Arc::new(Unpark(std::thread::current())).into();
+                let mut cx = Context::from_waker(&waker);
+                for _j in 0..1000 {
+                    let mut f = std::boxed::Box::pin(repl.async_execute_mut(121, idx));
+                    loop {
+                        match f.as_mut().poll(&mut cx) {
+                            Poll::Ready(r) => {
+                                assert_eq!(r, Ok(Ok(107)));
+                                break;
+                            }
+                            Poll::Pending => {
+                                let start = std::time::Instant::now();
+                                std::thread::park_timeout(core::time::Duration::from_secs(5));
+                                assert!(start.elapsed() < core::time::Duration::from_secs(5));
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        repl.verify(|d: &Data| assert_eq!(d.junk, 4000));
+    }
+
+    // Tests that a rate limited thread is throttled once it used up its burst,
+    // while other threads registered with the replica aren't.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_try_execute_mut_throttled() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let limited = repl.register_with_limit(RateLimit::new(1, 2)).unwrap();
+        let idx = repl.register().unwrap();
+
+        assert_eq!(repl.try_execute_mut(121, limited), Ok(Ok(107)));
+        assert_eq!(repl.try_execute_mut(122, limited), Ok(Ok(107)));
+        match repl.try_execute_mut(123, limited) {
+            Err(WouldBlock::Throttled(t)) => {
+                assert!(t.retry_after > core::time::Duration::from_millis(0))
+            }
+            r => panic!("Expected the thread to be throttled, got {:?}.", r),
+        }
+
+        assert_eq!(repl.try_execute_mut(124, idx), Ok(Ok(107)));
+        assert_eq!(3, repl.data.read(0).junk);
+    }
+
+    // Tests that try_execute_mut() gives up instead of waiting while another
+    // replica holds up garbage collection on a nearly full log.
+    #[test]
+    fn test_replica_try_execute_mut_log_full() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        let lagging = Replica::<Data>::new(&slog);
+        let lidx = lagging.register().unwrap();
+
+        let mut executed = 0;
+        while repl.try_execute_mut(121, idx).is_ok() {
+            executed += 1;
+        }
+        assert_eq!(repl.try_execute_mut(121, idx), Err(WouldBlock::LogFull));
+        assert_eq!(executed, slog.len());
+        assert!(executed < slog.capacity());
+
+        lagging.sync(lidx).unwrap();
+        assert_eq!(repl.try_execute_mut(121, idx), Ok(Ok(107)));
+        assert_eq!(executed + 1, repl.data.read(0).junk as usize);
+    }
+
+    // Tests that reads are served from the published copy as long as it doesn't
+    // lag too far behind, and from the replica otherwise.
+    #[test]
+    fn test_replica_execute_published() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        repl.publish_every(2);
+
+        for _i in 0..3 {
+            repl.execute_mut(121, idx).unwrap().unwrap();
+        }
+
+        assert_eq!(repl.execute_published(11, 1, idx), Ok(Ok(2)));
+        assert_eq!(repl.execute_published(11, 0, idx), Ok(Ok(3)));
+
+        repl.execute_mut(121, idx).unwrap().unwrap();
+        assert_eq!(repl.execute_published(11, 0, idx), Ok(Ok(4)));
+    }
+
+    // Tests that a read from a published copy doesn't miss operations that
+    // another replica completed after the copy was published.
+    #[test]
+    fn test_replica_execute_published_remote_write() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
+        one.publish_every(1);
+
+        one.execute_mut(121, i1).unwrap().unwrap();
+        assert_eq!(one.execute_published(11, 0, i1), Ok(Ok(1)));
+
+        two.execute_mut(121, i2).unwrap().unwrap();
+        assert_eq!(one.execute_published(11, 0, i1), Ok(Ok(2)));
+    }
+
+    // Tests whether we can register with this replica and receive an idx.
+    #[test]
+    fn test_replica_register() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        assert_eq!(repl.register(), Some(ReplicaToken::issue(1)));
+        assert_eq!(repl.next.load(Ordering::SeqCst), 2);
+        repl.next.store(17, Ordering::SeqCst);
+        assert_eq!(repl.register(), Some(ReplicaToken::issue(17)));
+        assert_eq!(repl.next.load(Ordering::SeqCst), 18);
+    }
+
+    // Tests that with strict tokens, a token can't be used on another thread.
+    #[cfg(feature = "strict-tokens")]
+    #[test]
+    fn test_replica_token_other_thread() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+
+        let other = {
+            let repl = repl.clone();
+            std::thread::spawn(move || repl.execute_mut(121, idx))
+        };
+        assert!(other.join().is_err());
+        repl.verify(|d: &Data| assert_eq!(d.junk, 1));
+    }
+
+    // Tests whether registering more than the maximum limit of threads per replica is disallowed.
+    // Tests that threads get the identifiers they ask for unless another thread
+    // holds them, and that skipped identifiers go to later registrations.
+    #[test]
+    fn test_replica_register_at() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+
+        let three = repl.register_at(ThreadId::new(3)).unwrap();
+        assert_eq!(three.id(), ThreadId::new(3));
+        assert_eq!(repl.register().map(|t| t.id().get()), Some(1));
+        assert_eq!(repl.register().map(|t| t.id().get()), Some(2));
+        assert_eq!(repl.register().map(|t| t.id().get()), Some(4));
+
+        assert_eq!(
+            repl.register_at(ThreadId::new(3)),
+            Err(SlotTaken(ThreadId::new(3)))
+        );
+        repl.deregister(three);
+        let again = repl.register_at(ThreadId::new(3)).unwrap();
+        assert_eq!(repl.execute_mut(121, again), Ok(Ok(107)));
+
+        assert!(repl.register_at(ThreadId::new(0)).is_err());
+        assert!(repl
+            .register_at(ThreadId::new(MAX_THREADS_PER_REPLICA + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_replica_register_none() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        repl.next
+            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
+        assert!(repl.register().is_none());
+    }
+
+    // Tests that identifiers of deregistered threads are handed out again, and that
+    // the remaining threads can still execute operations.
+    #[test]
+    fn test_replica_deregister() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let mut tokens = vec![];
+        while let Some(idx) = repl.register() {
+            tokens.push(idx);
+        }
+        assert_eq!(tokens.len(), MAX_THREADS_PER_REPLICA);
+
+        repl.deregister(tokens[7]);
+        repl.deregister(tokens[70]);
+        assert_eq!(repl.register(), Some(tokens[7]));
+        assert_eq!(repl.register(), Some(tokens[70]));
+        assert!(repl.register().is_none());
+
+        repl.deregister(tokens[0]);
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(121, tokens[1]));
+        assert_eq!(Ok(Ok(1)), repl.execute(11, tokens[1]));
+    }
+
+    // Tests that a token can't be used after it was deregistered.
+    #[test]
+    #[should_panic]
+    fn test_replica_deregister_use() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        let _other = repl.register().unwrap();
+
+        repl.deregister(idx);
+        let _r = repl.execute_mut(121, idx);
+    }
+
+    // Tests that we can successfully allow operations to go pending on this replica.
+    #[test]
+    fn test_replica_make_pending() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        let mut o = Vec::with_capacity(MAX_PENDING_OPS);
+
+        assert!(repl.make_pending(121, ThreadId::new(8)));
+        assert_eq!(repl.contexts[7].ops(&mut o, usize::MAX), 1);
+        assert_eq!(o.len(), 1);
+        assert_eq!(o[0], 121);
+    }
+
+    // Tests that we can't pend operations on a context that is already full of operations.
+    #[test]
+    fn test_replica_make_pending_false() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+        for _i in 0..Context::<u64, Result<u64, ()>>::batch_size() {
+            assert!(repl.make_pending(121, ThreadId::new(1)))
+        }
+
+        assert!(!repl.make_pending(11, ThreadId::new(1)));
+    }
+
+    // Tests that we can append and execute operations using try_combine().
+    #[test]
+    fn test_replica_try_combine() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let _idx = repl.register();
+
+        repl.make_pending(121, ThreadId::new(1));
+        repl.try_combine(ThreadId::new(1)).unwrap();
+
+        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
+        assert_eq!(repl.data.read(0).junk, 1);
+        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+    }
+
+    // Tests whether try_combine() also applies pending operations on other threads to the log.
+    #[test]
+    fn test_replica_try_combine_pending() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+
+        repl.next.store(9, Ordering::SeqCst);
+        repl.make_pending(121, ThreadId::new(8));
+        repl.try_combine(ThreadId::new(1)).unwrap();
+
+        assert_eq!(repl.data.read(0).junk, 1);
+        assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
+    }
+
+    // Tests that a bounded round of flat combining collects the operations of
+    // the combiner first and leaves the rest pending for later rounds.
+    #[test]
+    fn test_replica_max_ops_per_round() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        repl.set_max_ops_per_round(2);
+
+        repl.next.store(4, Ordering::SeqCst);
+        repl.make_pending(121, ThreadId::new(2));
+        repl.make_pending(121, ThreadId::new(2));
+        repl.make_pending(121, ThreadId::new(3));
+        repl.make_pending(121, ThreadId::new(1));
+
+        repl.try_combine(ThreadId::new(1)).unwrap();
+        assert_eq!(repl.data.read(0).junk, 2);
+        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[1].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[2].res(), None);
+
+        repl.try_combine(ThreadId::new(1)).unwrap();
+        assert_eq!(repl.data.read(0).junk, 4);
+        assert_eq!(repl.contexts[1].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[2].res(), Some(Ok(107)));
+    }
+
+    // Tests that a bounded round of flat combining collects the operations of
+    // threads waiting for an urgent operation before those of the combiner.
+    #[test]
+    fn test_replica_urgent_collected_first() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        repl.set_max_ops_per_round(1);
+
+        repl.next.store(4, Ordering::SeqCst);
+        repl.make_pending(121, ThreadId::new(1));
+        repl.make_pending(121, ThreadId::new(2));
+        repl.make_pending(121, ThreadId::new(3));
+        repl.urgent[0].fetch_or(1 << 2, Ordering::SeqCst);
+
+        repl.try_combine(ThreadId::new(1)).unwrap();
+        assert_eq!(repl.data.read(0).junk, 1);
+        assert_eq!(repl.contexts[0].res(), None);
+        assert_eq!(repl.contexts[1].res(), None);
+        assert_eq!(repl.contexts[2].res(), Some(Ok(107)));
+    }
+
+    // Tests that an urgent operation issued while another thread is combining
+    // is appended in the next round, ahead of the operations of other threads.
+    #[test]
+    fn test_replica_execute_mut_urgent() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        repl.set_max_ops_per_round(1);
+
+        repl.next.store(4, Ordering::SeqCst);
+        for _i in 0..8 {
+            repl.make_pending(121, ThreadId::new(2));
+            repl.make_pending(121, ThreadId::new(3));
         }
+        repl.combiner.store(2, Ordering::SeqCst);
 
-        // Try to become the combiner here. If this fails, then simply return.
-        if self
-            .combiner
-            .compare_exchange_weak(0, tid, Ordering::Acquire, Ordering::Acquire)
-            != Ok(0)
-        {
-            return;
+        let r = repl.clone();
+        let urgent = std::thread::spawn(move || {
+            let idx = ReplicaToken::issue(1);
+            r.execute_mut_urgent(121, idx)
+        });
+        while repl.urgent[0].load(Ordering::SeqCst) == 0 {
+            spin_loop();
+        }
+        assert_eq!(repl.data.read(0).junk, 0);
+        repl.combiner.store(0, Ordering::SeqCst);
+
+        assert_eq!(urgent.join().unwrap(), Ok(Ok(107)));
+        assert_eq!(repl.data.read(0).junk, 1);
+        assert_eq!(repl.contexts[1].res(), None);
+        assert_eq!(repl.contexts[2].res(), None);
+        assert_eq!(repl.urgent[0].load(Ordering::SeqCst), 0);
+    }
+
+    // Tests that submitted operations complete in order once a combiner
+    // executes them, and that submit() fails instead of waiting for room.
+    #[test]
+    fn test_replica_submit_poll() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let config = CombinerConfig::default().batch_size(2);
+        let repl = Replica::<Data>::with_config(&slog, config);
+        let idx = repl.register().unwrap();
+
+        repl.combiner.store(8, Ordering::SeqCst);
+        let first = repl.submit(121, idx).unwrap();
+        let second = repl.submit(121, idx).unwrap();
+        assert!(first < second);
+        assert_eq!(repl.submit(121, idx), Err(WouldBlock::QueueFull));
+        assert_eq!(repl.poll(idx), Ok(None));
+        assert_eq!(repl.data.read(0).junk, 0);
+
+        repl.combiner.store(0, Ordering::SeqCst);
+        assert_eq!(repl.poll(idx), Ok(Some((first, Ok(107)))));
+        assert_eq!(repl.poll(idx), Ok(Some((second, Ok(107)))));
+        assert_eq!(repl.poll(idx), Ok(None));
+        repl.verify(|d: &Data| assert_eq!(d.junk, 2));
+
+        let third = repl.submit(121, idx).unwrap();
+        assert!(second < third);
+        assert_eq!(repl.poll(idx), Ok(Some((third, Ok(107)))));
+    }
+
+    // Tests that threads wait for the dedicated combiner thread of a replica
+    // instead of combining themselves, until it stops.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_background_combiner() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        assert!(repl.start_background());
+        assert!(!repl.start_background());
+        assert_eq!(repl.combine_background(), Ok(false));
+
+        let r = repl.clone();
+        let waiter = std::thread::spawn(move || {
+            let idx = r.register().unwrap();
+            r.execute_mut(121, idx)
+        });
+        while repl.contexts[0].is_drained() {
+            spin_loop();
+        }
+        std::thread::sleep(core::time::Duration::from_millis(10));
+        assert_eq!(repl.data.read(0).junk, 0);
+
+        assert_eq!(repl.combine_background(), Ok(true));
+        assert_eq!(waiter.join().unwrap(), Ok(Ok(107)));
+        assert_eq!(repl.combine_background(), Ok(false));
+
+        repl.stop_background();
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+        assert_eq!(repl.data.read(0).junk, 2);
+    }
+
+    // Tests that a dedicated combiner brings a replica without registered
+    // threads up to date with the log.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_background_combiner_no_threads() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+
+        let idx = one.register().unwrap();
+        assert_eq!(one.execute_mut(121, idx), Ok(Ok(107)));
+
+        assert!(two.start_background());
+        assert_eq!(two.combine_background(), Ok(true));
+        assert_eq!(two.data.read(0).junk, 1);
+        assert_eq!(two.combine_background(), Ok(false));
+    }
+
+    // Tests that draining a replica executes the operations threads enqueued,
+    // and that operations fail once it was shut down.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_drain_shut_down() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        assert!(repl.make_pending(121, idx.0));
+        assert!(repl.make_pending(121, idx.0));
+        assert_eq!(repl.try_drain(), Ok(false));
+        assert_eq!(repl.try_drain(), Ok(true));
+        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        assert_eq!(repl.data.read(0).junk, 2);
+
+        repl.shut_down();
+        assert_eq!(repl.execute_mut(121, idx), Err(ReplicaError::ShuttingDown));
+        assert_eq!(repl.execute(11, idx), Err(ReplicaError::ShuttingDown));
+        assert_eq!(repl.try_drain(), Err(ReplicaError::ShuttingDown));
+        assert_eq!(repl.data.read(0).junk, 2);
+    }
+
+    // Tests whether try_combine() fails if someone else is currently flat combining.
+    #[test]
+    fn test_replica_try_combine_fail() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let repl = Replica::<Data>::new(&slog);
+
+        repl.next.store(9, Ordering::SeqCst);
+        repl.combiner.store(8, Ordering::SeqCst);
+        repl.make_pending(121, ThreadId::new(1));
+        repl.try_combine(ThreadId::new(1)).unwrap();
+
+        assert_eq!(repl.data.read(0).junk, 0);
+        assert_eq!(repl.contexts[0].res(), None);
+    }
+
+    // Tests whether we can execute an operation against the log using execute_mut().
+    #[test]
+    fn test_replica_execute_combine() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(121, idx));
+        assert_eq!(1, repl.data.read(0).junk);
+    }
+
+    // Tests that a replica that fell off the log fails operations instead of
+    // panicking, and keeps failing them.
+    #[test]
+    fn test_replica_desync() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(121, idx));
+
+        slog.set_ltail(repl.idx, 100);
+        assert_eq!(Err(ReplicaError::Desync), repl.execute_mut(121, idx));
+        assert_eq!(Err(ReplicaError::Desync), repl.execute(11, idx));
+        assert_eq!(Err(ReplicaError::Desync), repl.sync(idx));
+        assert_eq!(
+            Err(Timeout::Failed(ReplicaError::Desync)),
+            repl.execute_mut_until(121, idx, || false)
+        );
+        assert_eq!(
+            Err(WouldBlock::Failed(ReplicaError::Desync)),
+            repl.try_execute_mut(121, idx)
+        );
+        assert_eq!(
+            Err(WouldBlock::Failed(ReplicaError::Desync)),
+            repl.submit(121, idx)
+        );
+        assert_eq!(Err(ReplicaError::Desync), repl.poll(idx));
+        assert_eq!(Err(ReplicaError::Desync), repl.import(vec![], idx));
+        let token = CancellationToken::new();
+        assert_eq!(
+            Err(Cancelled::Failed(ReplicaError::Desync)),
+            repl.execute_mut_cancellable(121, idx, &token)
+        );
+        assert_eq!(
+            Err(Cancelled::Failed(ReplicaError::Desync)),
+            repl.sync_cancellable(idx, &token)
+        );
+    }
+
+    // Tests that a panic in the data structure poisons the replica and releases
+    // the combiner lock, so that other operations fail instead of spinning.
+    #[test]
+    fn test_replica_poisoned() {
+        #[derive(Default)]
+        struct Fragile;
+
+        impl Dispatch for Fragile {
+            type ReadOperation = ();
+            type WriteOperation = bool;
+            type Response = ();
+
+            fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {}
+
+            fn dispatch_mut(&mut self, fail: Self::WriteOperation) -> Self::Response {
+                assert!(!fail, "Operation failed.");
+            }
+        }
+
+        let slog = Arc::new(Log::<bool>::default());
+        let repl = Replica::<Fragile>::new(&slog);
+        let idx = repl.register().unwrap();
+        assert_eq!(Ok(()), repl.execute_mut(false, idx));
+
+        let r =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| repl.execute_mut(true, idx)));
+        assert!(r.is_err());
+        assert_eq!(repl.combiner.load(Ordering::Relaxed), 0);
+
+        assert_eq!(Err(ReplicaError::Poisoned), repl.execute_mut(false, idx));
+        assert_eq!(Err(ReplicaError::Poisoned), repl.execute((), idx));
+    }
+
+    // Tests that combining updates the counters of the replica and notifies its observer.
+    #[test]
+    fn test_replica_metrics() {
+        #[derive(Default)]
+        struct Combines(AtomicUsize);
+
+        impl ReplicaObserver for Combines {
+            fn on_combine(&self, batch_len: usize, _duration: core::time::Duration) {
+                self.0.fetch_add(batch_len, Ordering::Relaxed);
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.metrics(), ReplicaMetrics::default());
+
+        let observer = Arc::new(Combines::default());
+        repl.set_observer(observer.clone());
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(121, idx));
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(122, idx));
+
+        let metrics = repl.metrics();
+        assert!(metrics.combines >= 2);
+        assert_eq!(metrics.combined_ops, 2);
+        assert_eq!(metrics.append_retries, 0);
+        // The only replica is up to date, so exec() already advanced the head.
+        assert_eq!(metrics.log_entries, 0);
+        assert_eq!(observer.0.load(Ordering::Relaxed), 2);
+    }
+
+    // Tests that traced operations return their log offsets and that every replica
+    // reports applying all operations in offset order.
+    #[test]
+    fn test_replica_execute_mut_traced() {
+        #[derive(Default)]
+        struct Applied(std::sync::Mutex<vec::Vec<(LogOffset, ReplicaId)>>);
+
+        impl ReplicaObserver for Applied {
+            fn on_apply(&self, offset: LogOffset, replica: ReplicaId) {
+                self.0.lock().unwrap().push((offset, replica));
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let observer = Arc::new(Applied::default());
+        one.set_observer(observer.clone());
+        two.set_observer(observer.clone());
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
+
+        let (r, a) = one.execute_mut_traced(1, i1).unwrap();
+        assert_eq!((r, a), (Ok(107), LogOffset::new(0)));
+        let (_, b) = two.execute_mut_traced(2, i2).unwrap();
+        let (_, c) = one.execute_mut_traced(3, i1).unwrap();
+        assert_eq!((b, c), (LogOffset::new(1), LogOffset::new(2)));
+        two.sync(i2).unwrap();
+
+        let applied = observer.0.lock().unwrap();
+        for replica in [one.idx, two.idx].iter() {
+            let offsets: vec::Vec<LogOffset> = applied
+                .iter()
+                .filter(|(_, r)| r == replica)
+                .map(|(o, _)| *o)
+                .collect();
+            assert_eq!(offsets, vec![a, b, c]);
+        }
+    }
+
+    // Tests that every replica passes the same origin to `dispatch_mut_ctx`, so
+    // that a last-writer-wins register ends up the same on all of them.
+    #[test]
+    fn test_replica_dispatch_mut_ctx() {
+        // Keeps the value written by the replica with the highest identifier.
+        #[derive(Default)]
+        struct Lww(u64, Option<OpOrigin>, vec::Vec<OpOrigin>);
+
+        impl Dispatch for Lww {
+            type ReadOperation = ();
+            type WriteOperation = u64;
+            type Response = (u64, vec::Vec<OpOrigin>);
+
+            fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+                (self.0, self.2.clone())
+            }
+
+            fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+                unreachable!("Replicas call dispatch_mut_ctx.")
+            }
+
+            fn dispatch_mut_ctx(&mut self, op: u64, origin: OpOrigin) -> Self::Response {
+                if self.1.map_or(true, |last| last.replica <= origin.replica) {
+                    self.0 = op;
+                    self.1 = Some(origin);
+                }
+                self.2.push(origin);
+                (self.0, self.2.clone())
+            }
+        }
+
+        let slog = Arc::new(Log::<u64>::default());
+        let one = Replica::<Lww>::new(&slog);
+        let two = Replica::<Lww>::new(&slog);
+        let i1 = one.register().unwrap();
+        let i2 = two.register().unwrap();
+
+        two.execute_mut(2, i2).unwrap();
+        one.execute_mut(1, i1).unwrap();
+        one.execute_mut(3, i1).unwrap();
+
+        let (v1, o1) = one.execute((), i1).unwrap();
+        let (v2, o2) = two.execute((), i2).unwrap();
+        assert_eq!((v1, v2), (2, 2));
+        assert_eq!(o1, o2);
+        let origins: vec::Vec<(ReplicaId, LogOffset)> =
+            o1.iter().map(|o| (o.replica, o.offset)).collect();
+        assert_eq!(
+            origins,
+            vec![
+                (two.idx, LogOffset::new(0)),
+                (one.idx, LogOffset::new(1)),
+                (one.idx, LogOffset::new(2))
+            ]
+        );
+    }
+
+    // Tests that dropping a replica that never executes the log doesn't hold up
+    // garbage collection for the remaining replicas.
+    #[test]
+    fn test_replica_drop_releases_log() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1));
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        drop(r2);
+
+        for _ in 0..4 * slog.capacity() {
+            assert_eq!(r1.try_execute_mut(121, t1), Ok(Ok(107)));
+        }
+    }
+
+    // Tests that replicas and their log can be dropped in any order, and that
+    // every operation on the log is dropped exactly once.
+    #[test]
+    fn test_replica_drop_order() {
+        #[derive(Debug)]
+        struct Op(Arc<AtomicUsize>);
+
+        impl Clone for Op {
+            fn clone(&self) -> Op {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Op(self.0.clone())
+            }
+        }
+
+        impl Drop for Op {
+            fn drop(&mut self) {
+                assert!(self.0.fetch_sub(1, Ordering::Relaxed) > 0);
+            }
+        }
+
+        impl PartialEq for Op {
+            fn eq(&self, _other: &Op) -> bool {
+                true
+            }
+        }
+
+        #[derive(Default)]
+        struct Sink(usize);
+
+        impl Dispatch for Sink {
+            type ReadOperation = ();
+            type WriteOperation = Op;
+            type Response = usize;
+
+            fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+                self.0
+            }
+
+            fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+                self.0 += 1;
+                self.0
+            }
+        }
+
+        for log_first in [true, false].iter() {
+            let live = Arc::new(AtomicUsize::new(0));
+            let slog = Arc::new(Log::<Op>::new(1));
+            let r1 = Replica::<Sink>::new(&slog);
+            let r2 = Replica::<Sink>::new(&slog);
+            let (t1, t2) = (r1.register().unwrap(), r2.register().unwrap());
+
+            let n = 2 * slog.capacity();
+            for i in 0..n {
+                live.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(r1.execute_mut(Op(live.clone()), t1), Ok(i + 1));
+                r2.sync(t2).unwrap();
+            }
+
+            if *log_first {
+                // The replicas keep the log alive.
+                drop(slog);
+                live.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(r1.execute_mut(Op(live.clone()), t1), Ok(n + 1));
+                assert_eq!(r2.execute((), t2), Ok(n + 1));
+                drop((r1, r2));
+            } else {
+                drop((r1, r2));
+                assert!(live.load(Ordering::Relaxed) > 0);
+                drop(slog);
+            }
+            assert_eq!(live.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    // Tests that run() combines enqueued operations and syncs with the log within
+    // its budget, and reports whether work is left.
+    #[test]
+    fn test_replica_run() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let one = Replica::<Data>::new(&slog);
+        let two = Replica::<Data>::new(&slog);
+        let (i1, i2) = (one.register().unwrap(), two.register().unwrap());
+
+        for _ in 0..3 {
+            assert!(one.make_pending(1, i1.0));
+        }
+        let report = one.run(i1, Work(0)).unwrap();
+        assert_eq!(
+            report,
+            RunReport {
+                remaining: Work(0),
+                pending: true
+            }
+        );
+        let report = one.run(i1, Work(5)).unwrap();
+        assert_eq!(
+            report,
+            RunReport {
+                remaining: Work(4),
+                pending: false
+            }
+        );
+        for _ in 0..3 {
+            assert_eq!(one.get_response(i1.0), Ok(Ok(107)));
+        }
+
+        assert_eq!(two.data.read(0).junk, 0);
+        let report = two.run(i2, Work(1)).unwrap();
+        assert_eq!(
+            report,
+            RunReport {
+                remaining: Work(0),
+                pending: false
+            }
+        );
+        assert_eq!(two.data.read(0).junk, 3);
+    }
+
+    // Tests that threads of a replica don't run operations between the read and the
+    // write of a read-modify-write, so conditional writes never overshoot a bound.
+    #[test]
+    fn test_replica_execute_rmw() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+
+        let threads: vec::Vec<_> = (0..4)
+            .map(|_| {
+                let repl = repl.clone();
+                std::thread::spawn(move || {
+                    let idx = repl.register().unwrap();
+                    for _ in 0..50 {
+                        let r = repl.execute_rmw(
+                            0,
+                            |junk| if junk < Ok(100) { Some(1) } else { None },
+                            idx,
+                        );
+                        assert!(r == Ok(Some(Ok(107))) || r == Ok(None));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute_rmw(0, |_| None, idx), Ok(None));
+        repl.verify(|d| assert_eq!(d.junk, 100));
+    }
+
+    // Tests that a batch larger than a thread's context executes all operations and
+    // returns their responses in order.
+    #[test]
+    fn test_replica_execute_mut_batch() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        let ops: Vec<u64> = (0..100).collect();
+        let resps = repl.execute_mut_batch(&ops, idx);
+        assert_eq!(resps, Ok(vec![Ok(107); 100]));
+        assert_eq!(100, repl.data.read(0).junk);
+
+        assert!(repl.execute_mut_batch(&[], idx).unwrap().is_empty());
+    }
+
+    // Tests that responses written straight into caller-provided slots arrive
+    // in order, also when mixed with operations that return their response.
+    #[test]
+    fn test_replica_execute_mut_into() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().unwrap();
+
+        let mut out = MaybeUninit::uninit();
+        assert_eq!(repl.execute_mut_into(121, &mut out, idx), Ok(&mut Ok(107)));
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+
+        let ops: Vec<u64> = (0..100).collect();
+        let mut out = vec![MaybeUninit::uninit(); 100];
+        assert_eq!(
+            repl.execute_mut_batch_into(&ops, &mut out, idx),
+            Ok(&mut vec![Ok(107); 100][..])
+        );
+        assert_eq!(102, repl.data.read(0).junk);
+    }
+
+    // Tests that operations and responses owning heap memory (string keys and
+    // values of arbitrary length) go through replicas while the log wraps around.
+    #[test]
+    fn test_replica_heap_operations() {
+        use std::collections::HashMap;
+        use std::string::{String, ToString};
+
+        #[derive(Default)]
+        struct Kv(HashMap<String, String>);
+
+        impl Dispatch for Kv {
+            type ReadOperation = String;
+            type WriteOperation = (String, String);
+            type Response = Option<String>;
+
+            fn dispatch(&self, key: Self::ReadOperation) -> Self::Response {
+                self.0.get(&key).cloned()
+            }
+
+            fn dispatch_mut(&mut self, (key, value): Self::WriteOperation) -> Self::Response {
+                self.0.insert(key, value)
+            }
+        }
+
+        let slog = Arc::new(Log::<(String, String)>::new(64 * 1024));
+        let r1 = Replica::<Kv>::new(&slog);
+        let r2 = Replica::<Kv>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+
+        for i in 0..4096 {
+            let key = "k".repeat(i % 100 + 1);
+            let prev = (i >= 100).then(|| (i - 100).to_string());
+            let r = if i % 2 == 0 { &r1 } else { &r2 };
+            let t = if i % 2 == 0 { t1 } else { t2 };
+            assert_eq!(r.execute_mut((key, i.to_string()), t), Ok(prev));
         }
 
-        // Successfully became the combiner; perform one round of flat combining.
-        self.combine();
+        assert_eq!(r2.execute("k".repeat(7), t2), Ok(Some("4006".to_string())));
+        r1.sync(t1).unwrap();
+        r1.verify(|kv: &Kv| assert_eq!(kv.0.len(), 100));
+    }
+
+    // Tests whether get_response() retrieves a response to an operation that was executed
+    // against a replica.
+    #[test]
+    fn test_replica_get_response() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let _idx = repl.register();
+
+        repl.make_pending(121, ThreadId::new(1));
 
-        // Allow other threads to perform flat combining once we have finished all our work.
-        // At this point, we've dropped all mutable references to thread contexts and to
-        // the staging buffer as well.
-        self.combiner.store(0, Ordering::Release);
+        assert_eq!(repl.get_response(ThreadId::new(1)), Ok(Ok(107)));
     }
 
-    /// Performs one round of flat combining. Collects, appends and executes operations.
-    #[inline(always)]
-    fn combine(&self) {
-        let mut buffer = self.buffer.borrow_mut();
-        let mut operations = self.inflight.borrow_mut();
-        let mut results = self.result.borrow_mut();
+    // Tests whether we can issue a read-only operation against the replica.
+    #[test]
+    fn test_replica_execute() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        let idx = repl.register().expect("Failed to register with replica.");
 
-        buffer.clear();
-        results.clear();
+        assert_eq!(Ok(Ok(107)), repl.execute_mut(121, idx));
+        assert_eq!(Ok(Ok(1)), repl.execute(11, idx));
+    }
 
-        let next = self.next.load(Ordering::Relaxed);
+    // Tests that execute() syncs up the replica with the log before
+    // executing the read against the data structure.
+    #[test]
+    fn test_replica_execute_not_synced() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
 
-        // Collect operations from each thread registered with this replica.
-        for i in 1..next {
-            operations[i - 1] = self.contexts[i - 1].ops(&mut buffer);
-        }
+        // Add in operations to the log off the side, not through the replica.
+        let o = [121, 212];
+        slog.append(&o, ReplicaId::new(2), |_o: u64, _i: ReplicaId| {});
+        slog.exec(ReplicaId::new(2), &mut |_o: u64, _i: ReplicaId| {});
 
-        // Append all collected operations into the shared log. We pass a closure
-        // in here because operations on the log might need to be consumed for GC.
-        {
-            let f = |o: <D as Dispatch>::WriteOperation, i: usize| {
-                let resp = self.data.write(next).dispatch_mut(o);
-                if i == self.idx {
-                    results.push(resp);
-                }
-            };
-            self.slog.append(&buffer, self.idx, f);
-        }
+        let t1 = repl.register().expect("Failed to register with replica.");
+        assert_eq!(Ok(Ok(2)), repl.execute(11, t1));
+    }
 
-        // Execute any operations on the shared log against this replica.
-        {
-            let mut data = self.data.write(next);
-            let mut f = |o: <D as Dispatch>::WriteOperation, i: usize| {
-                let resp = data.dispatch_mut(o);
-                if i == self.idx {
-                    results.push(resp)
-                };
-            };
-            self.slog.exec(self.idx, &mut f);
-        }
+    // Tests that execute_batch() syncs up the replica once and returns the
+    // responses of all reads in order.
+    #[test]
+    fn test_replica_execute_batch() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
 
-        // Return/Enqueue responses back into the appropriate thread context(s).
-        let (mut s, mut f) = (0, 0);
-        for i in 1..next {
-            if operations[i - 1] == 0 {
-                continue;
-            };
+        // Add in operations to the log off the side, not through the replica.
+        let o = [121, 212];
+        slog.append(&o, ReplicaId::new(2), |_o: u64, _i: ReplicaId| {});
+        slog.exec(ReplicaId::new(2), &mut |_o: u64, _i: ReplicaId| {});
 
-            f += operations[i - 1];
-            self.contexts[i - 1].enqueue_resps(&results[s..f]);
-            s += operations[i - 1];
-            operations[i - 1] = 0;
-        }
+        let idx = repl.register().expect("Failed to register with replica.");
+        assert_eq!(repl.execute_batch(&[11, 12, 13], idx), Ok(vec![Ok(2); 3]));
+        assert!(repl.execute_batch(&[], idx).unwrap().is_empty());
     }
-}
 
-#[cfg(test)]
-mod test {
-    extern crate std;
+    // Tests that a replica created from a checkpoint picks up where the checkpoint
+    // was taken, even after the log wrapped around a couple of times.
+    #[test]
+    fn test_replica_from_checkpoint() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
+        let first = Replica::<Data>::new(&slog);
+        let idx = first.register().unwrap();
+        for _i in 0..5000 {
+            assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
+        }
 
-    use super::*;
-    use std::vec;
+        let checkpoint = first.checkpoint();
+        assert_eq!(checkpoint.offset, LogOffset::new(5000));
+        assert_eq!(checkpoint.snapshot, 5000);
 
-    // Really dumb data structure to test against the Replica and shared log.
-    #[derive(Default)]
-    struct Data {
-        junk: u64,
+        let second = Replica::<Data>::from_checkpoint(&slog, checkpoint).unwrap();
+        let t2 = second.register().unwrap();
+        for _i in 0..5000 {
+            assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
+            assert_eq!(second.execute_mut(121, t2), Ok(Ok(107)));
+        }
+
+        assert_eq!(first.execute(11, idx), Ok(Ok(15000)));
+        assert_eq!(second.execute(11, t2), Ok(Ok(15000)));
     }
 
-    impl Dispatch for Data {
-        type ReadOperation = u64;
-        type WriteOperation = u64;
-        type Response = Result<u64, ()>;
+    // Tests that a replica can't be created from a checkpoint if the log was
+    // garbage collected past its offset.
+    #[test]
+    fn test_replica_from_checkpoint_reclaimed() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let first = Replica::<Data>::new(&slog);
+        let idx = first.register().unwrap();
+        let stale = first.checkpoint();
+        assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
 
-        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
-            Ok(self.junk)
-        }
+        let err = Replica::<Data>::from_checkpoint(&slog, stale).unwrap_err();
+        assert_eq!(err, LogError::OffsetReclaimed);
 
-        fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
-            self.junk += 1;
-            return Ok(107);
+        // The failed attempt doesn't hold back garbage collection.
+        for _i in 0..5000 {
+            assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
         }
+        assert_eq!(first.execute(11, idx), Ok(Ok(5001)));
     }
 
-    // Tests whether we can construct a Replica given a log.
+    // Tests that a replica cloned from a peer after the log wrapped around
+    // starts at the state and offset of the peer and keeps up with it.
     #[test]
-    fn test_replica_create() {
+    fn test_replica_from_peer() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        assert_eq!(repl.idx, 1);
-        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
-        assert_eq!(repl.next.load(Ordering::SeqCst), 1);
-        assert_eq!(repl.contexts.len(), MAX_THREADS_PER_REPLICA);
-        assert_eq!(
-            repl.buffer.borrow().capacity(),
-            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
-        );
-        assert_eq!(repl.inflight.borrow().len(), MAX_THREADS_PER_REPLICA);
-        assert_eq!(
-            repl.result.borrow().capacity(),
-            MAX_THREADS_PER_REPLICA * Context::<u64, Result<u64, ()>>::batch_size()
-        );
-        assert_eq!(repl.data.read(0).junk, 0);
+        let first = Replica::<Data>::with_config(&slog, CombinerConfig::default().backoff(4));
+        let idx = first.register().unwrap();
+        for _i in 0..5000 {
+            assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
+        }
+
+        let second = Replica::from_peer(&first).unwrap();
+        assert_eq!(second.config, first.config);
+        assert_eq!(slog.get_ltail(second.idx), LogOffset::new(5000));
+        assert_eq!(second.data.read(0).junk, 5000);
+
+        let t2 = second.register().unwrap();
+        for _i in 0..5000 {
+            assert_eq!(first.execute_mut(121, idx), Ok(Ok(107)));
+            assert_eq!(second.execute_mut(121, t2), Ok(Ok(107)));
+        }
+
+        assert_eq!(first.execute(11, idx), Ok(Ok(15000)));
+        assert_eq!(second.execute(11, t2), Ok(Ok(15000)));
     }
 
-    // Tests whether we can register with this replica and receive an idx.
+    // Tests that a version token stays valid until the replica executes an
+    // operation, or until another replica completes one.
     #[test]
-    fn test_replica_register() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        assert_eq!(repl.register(), Some(ReplicaToken(1)));
-        assert_eq!(repl.next.load(Ordering::SeqCst), 2);
-        repl.next.store(17, Ordering::SeqCst);
-        assert_eq!(repl.register(), Some(ReplicaToken(17)));
-        assert_eq!(repl.next.load(Ordering::SeqCst), 18);
+    fn test_replica_version_token() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+
+        let version = r1.version_token();
+        assert_eq!(r1.execute(11, t1), Ok(Ok(0)));
+        assert!(r1.validate(version));
+
+        assert_eq!(r2.execute_mut(121, t2), Ok(Ok(107)));
+        assert!(!r1.validate(version));
+
+        assert_eq!(r1.execute(11, t1), Ok(Ok(1)));
+        let version = r1.version_token();
+        assert!(r1.validate(version));
+        assert!(!r1.validate(VersionToken(LogOffset::new(0))));
     }
 
-    // Tests whether registering more than the maximum limit of threads per replica is disallowed.
+    // Tests that the applied offset of a replica only moves once it executed
+    // the operations on the log.
     #[test]
-    fn test_replica_register_none() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        repl.next
-            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
-        assert!(repl.register().is_none());
+    fn test_replica_applied_offset() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+        assert_eq!(r1.applied_offset(), 0);
+
+        assert_eq!(r1.execute_mut(121, t1), Ok(Ok(107)));
+        assert_eq!(r1.execute_mut(121, t1), Ok(Ok(107)));
+        assert_eq!(r1.applied_offset(), 2);
+        assert_eq!(r2.applied_offset(), 0);
+
+        assert_eq!(r2.execute(11, t2), Ok(Ok(2)));
+        assert_eq!(r2.applied_offset(), 2);
     }
 
-    // Tests that we can successfully allow operations to go pending on this replica.
+    // Tests that quiesce() returns once all replicas executed everything that was
+    // issued before the call.
     #[test]
-    fn test_replica_make_pending() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        let mut o = vec![];
+    fn test_replica_quiesce() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
 
-        assert!(repl.make_pending(121, 8));
-        assert_eq!(repl.contexts[7].ops(&mut o), 1);
-        assert_eq!(o.len(), 1);
-        assert_eq!(o[0], 121);
+        for _i in 0..100 {
+            assert_eq!(r1.execute_mut(121, t1), Ok(Ok(107)));
+        }
+
+        // The second replica only makes progress if someone syncs it.
+        let done = Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let syncer = {
+            let (r2, done) = (r2.clone(), done.clone());
+            std::thread::spawn(move || {
+                let t2 = r2.register().unwrap();
+                while !done.load(Ordering::Relaxed) {
+                    r2.sync(t2).unwrap();
+                }
+            })
+        };
+
+        let report = r1.quiesce(t1).unwrap();
+        assert_eq!(report.offset, LogOffset::new(100));
+        assert!(slog.is_synced(100));
+
+        done.store(true, Ordering::Relaxed);
+        syncer.join().unwrap();
+        r2.verify(|d: &Data| assert_eq!(d.junk, 100));
     }
 
-    // Tests that we can't pend operations on a context that is already full of operations.
+    // Tests that threads waiting for responses on a replica that parks them get
+    // woken up by the combiner, and that every operation gets executed.
+    #[cfg(feature = "std")]
     #[test]
-    fn test_replica_make_pending_false() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
-        for _i in 0..Context::<u64, Result<u64, ()>>::batch_size() {
-            assert!(repl.make_pending(121, 1))
+    fn test_replica_with_config_park() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let config = CombinerConfig::default()
+            .spin_before_yield(16)
+            .backoff(0)
+            .park_strategy(ParkStrategy::Park(core::time::Duration::from_secs(1)));
+        let repl = Replica::<Data>::with_config(&slog, config);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let repl = repl.clone();
+                std::thread::spawn(move || {
+                    let idx = repl.register().unwrap();
+                    for _i in 0..1000 {
+                        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
         }
 
-        assert!(!repl.make_pending(11, 1));
+        repl.verify(|d: &Data| assert_eq!(d.junk, 4000));
     }
 
-    // Tests that we can append and execute operations using try_combine().
+    // Tests that the contexts of a replica created with a smaller batch size hold
+    // at most that many operations, and that batches larger than it still work.
     #[test]
-    fn test_replica_try_combine() {
+    fn test_replica_with_config_batch_size() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
-        let repl = Replica::<Data>::new(&slog);
-        let _idx = repl.register();
+        let config = CombinerConfig::default().batch_size(4);
+        let repl = Replica::<Data>::with_config(&slog, config);
+        let idx = repl.register().unwrap();
 
-        repl.make_pending(121, 1);
-        repl.try_combine(1);
+        let resps = repl.execute_mut_batch(&[121; 10], idx).unwrap();
+        assert_eq!(resps, vec![Ok(107); 10]);
+        repl.verify(|d: &Data| assert_eq!(d.junk, 10));
 
-        assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
-        assert_eq!(repl.data.read(0).junk, 1);
-        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        for _i in 0..4 {
+            assert!(repl.make_pending(121, idx.0));
+        }
+        assert!(!repl.make_pending(121, idx.0));
     }
 
-    // Tests whether try_combine() also applies pending operations on other threads to the log.
+    // Tests that with `OverflowPolicy::Error`, operations issued while the context
+    // of the thread is full fail without being executed, and succeed again once
+    // there is room.
     #[test]
-    fn test_replica_try_combine_pending() {
+    fn test_replica_overflow_error() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
-        let repl = Replica::<Data>::new(&slog);
+        let config = CombinerConfig::default()
+            .batch_size(2)
+            .overflow_policy(OverflowPolicy::Error);
+        let repl = Replica::<Data>::with_config(&slog, config);
+        let idx = repl.register().unwrap();
 
-        repl.next.store(9, Ordering::SeqCst);
-        repl.make_pending(121, 8);
-        repl.try_combine(1);
+        assert!(repl.make_pending(121, idx.0));
+        assert!(repl.make_pending(121, idx.0));
+        assert_eq!(repl.execute_mut(121, idx), Err(ReplicaError::QueueFull));
+        assert_eq!(repl.try_execute_mut(121, idx), Err(WouldBlock::QueueFull));
 
-        assert_eq!(repl.data.read(0).junk, 1);
-        assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
+        // `try_execute_mut` combined, so the pending operations have responses.
+        assert_eq!(repl.get_response(idx.0), Ok(Ok(107)));
+        assert_eq!(repl.get_response(idx.0), Ok(Ok(107)));
+        repl.verify(|d: &Data| assert_eq!(d.junk, 2));
+
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+        repl.verify(|d: &Data| assert_eq!(d.junk, 3));
     }
 
-    // Tests whether try_combine() fails if someone else is currently flat combining.
+    // Tests that configuring a batch size larger than a context can hold panics.
     #[test]
-    fn test_replica_try_combine_fail() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1024));
-        let repl = Replica::<Data>::new(&slog);
+    #[should_panic]
+    fn test_replica_with_config_batch_size_too_large() {
+        let _config = CombinerConfig::default().batch_size(MAX_PENDING_OPS + 1);
+    }
 
-        repl.next.store(9, Ordering::SeqCst);
-        repl.combiner.store(8, Ordering::SeqCst);
-        repl.make_pending(121, 1);
-        repl.try_combine(1);
+    // Tests that coalesced reads never return stale responses: a read issued
+    // after a write completed on another replica always observes it.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_execute_coalesced() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let done = Arc::new(core::sync::atomic::AtomicBool::new(false));
 
-        assert_eq!(repl.data.read(0).junk, 0);
-        assert_eq!(repl.contexts[0].res(), None);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (r1, done) = (r1.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let idx = r1.register().unwrap();
+                    let mut last = 0;
+                    loop {
+                        let finished = done.load(Ordering::Acquire);
+                        let junk = r1.execute_coalesced(0, idx).unwrap().unwrap();
+                        assert!(junk >= last);
+                        last = junk;
+                        if finished {
+                            assert_eq!(junk, 1000);
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let t2 = r2.register().unwrap();
+        for _i in 0..1000 {
+            assert_eq!(r2.execute_mut(121, t2), Ok(Ok(107)));
+        }
+        done.store(true, Ordering::Release);
+
+        for t in readers {
+            t.join().unwrap();
+        }
     }
 
-    // Tests whether we can execute an operation against the log using execute_mut().
+    // Tests that a combiner config can't make waiting threads skip spinning.
     #[test]
-    fn test_replica_execute_combine() {
+    #[should_panic(expected = "Must spin at least once")]
+    fn test_replica_with_config_no_spin() {
+        let _config = CombinerConfig::default().spin_before_yield(0);
+    }
+
+    // Tests that execute_mut_timeout() completes operations if the log has room.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replica_execute_mut_timeout() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
         let repl = Replica::<Data>::new(&slog);
         let idx = repl.register().unwrap();
 
-        assert_eq!(Ok(107), repl.execute_mut(121, idx));
-        assert_eq!(1, repl.data.read(0).junk);
+        let timeout = core::time::Duration::from_secs(60);
+        for _i in 0..100 {
+            assert_eq!(repl.execute_mut_timeout(121, idx, timeout), Ok(Ok(107)));
+        }
+        repl.verify(|d: &Data| assert_eq!(d.junk, 100));
     }
 
-    // Tests whether get_response() retrieves a response to an operation that was executed
-    // against a replica.
+    // Tests that execute_mut_until() gives up if a stalled replica keeps the log
+    // full, and that the thread can keep issuing operations afterwards.
     #[test]
-    fn test_replica_get_response() {
-        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
-        let repl = Replica::<Data>::new(&slog);
-        let _idx = repl.register();
+    fn test_replica_execute_mut_until_stalled() {
+        // The smallest possible log is full after a single operation.
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1));
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
 
-        repl.make_pending(121, 1);
+        assert_eq!(r1.execute_mut(121, t1), Ok(Ok(107)));
 
-        assert_eq!(repl.get_response(1), Ok(107));
+        let mut budget = 1000;
+        let err = r1.execute_mut_until(121, t1, || {
+            budget -= 1;
+            budget == 0
+        });
+        assert_eq!(
+            err,
+            Err(Timeout::Elapsed {
+                enqueued: true,
+                stalled: Some(ReplicaId::new(2)),
+            })
+        );
+
+        // Once the second replica catches up, the abandoned operation is executed
+        // along with the next one.
+        r2.sync(t2).unwrap();
+        assert_eq!(r1.execute_mut_until(121, t1, || false), Ok(Ok(107)));
+        r1.verify(|d: &Data| assert_eq!(d.junk, 3));
     }
 
-    // Tests whether we can issue a read-only operation against the replica.
+    // Tests that execute_mut_cancellable() stops waiting on a stalled replica
+    // once another thread cancels its token.
     #[test]
-    fn test_replica_execute() {
+    fn test_replica_execute_mut_cancellable() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1));
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+
+        let token = CancellationToken::new();
+        assert_eq!(r1.execute_mut_cancellable(121, t1, &token), Ok(Ok(107)));
+
+        let canceller = token.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(core::time::Duration::from_millis(10));
+            canceller.cancel();
+        });
+        assert_eq!(
+            r1.execute_mut_cancellable(121, t1, &token),
+            Err(Cancelled::Requested { enqueued: true })
+        );
+        t.join().unwrap();
+
+        // Once the second replica catches up, the abandoned operation is executed
+        // along with the next one.
+        r2.sync(t2).unwrap();
+        let token = CancellationToken::new();
+        assert_eq!(r1.execute_mut_cancellable(121, t1, &token), Ok(Ok(107)));
+        r1.verify(|d: &Data| assert_eq!(d.junk, 3));
+    }
+
+    // Tests that sync_cancellable() and execute_cancellable() give up while the
+    // replica can't catch up with the log, and succeed once it can.
+    #[test]
+    fn test_replica_sync_cancellable() {
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
-        let repl = Replica::<Data>::new(&slog);
-        let idx = repl.register().expect("Failed to register with replica.");
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+        assert_eq!(r1.execute_mut(121, t1), Ok(Ok(107)));
+
+        // Nobody can combine on the second replica, so it can't execute the
+        // operation of the first one.
+        r2.combiner
+            .store(MAX_THREADS_PER_REPLICA + 2, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        token.cancel();
+        assert_eq!(
+            r2.sync_cancellable(t2, &token),
+            Err(Cancelled::Requested { enqueued: false })
+        );
+        assert_eq!(
+            r2.execute_cancellable(11, t2, &token),
+            Err(Cancelled::Requested { enqueued: false })
+        );
 
-        assert_eq!(Ok(107), repl.execute_mut(121, idx));
-        assert_eq!(Ok(1), repl.execute(11, idx));
+        r2.combiner.store(0, Ordering::Release);
+        let token = CancellationToken::new();
+        assert_eq!(r2.sync_cancellable(t2, &token), Ok(()));
+        assert_eq!(r2.execute_cancellable(11, t2, &token), Ok(Ok(1)));
     }
 
-    // Tests that execute() syncs up the replica with the log before
-    // executing the read against the data structure.
+    // Tests that the combiner consults the GC policy of its replica while the log
+    // is full, and that operations stay pending if the policy gives up.
     #[test]
-    fn test_replica_execute_not_synced() {
+    fn test_replica_gc_policy_error() {
+        struct Recorder(AtomicUsize);
+
+        impl GcHelpPolicy for Recorder {
+            fn on_log_full(&self, ctx: crate::GcContext) -> crate::HelpAction {
+                self.0.store(ctx.slowest.get(), Ordering::Relaxed);
+                crate::HelpAction::Error
+            }
+        }
+
+        // The smallest possible log; the second replica never executes it.
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1));
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+        let t2 = r2.register().unwrap();
+
+        let policy = Arc::new(Recorder(AtomicUsize::new(0)));
+        r1.set_gc_policy(policy.clone());
+
+        // Fill the log up to where appends have to wait for GC.
+        let gc_from_head = MAX_THREADS_PER_REPLICA
+            * Context::<<Data as Dispatch>::WriteOperation, <Data as Dispatch>::Response>::batch_size();
+        let ops = vec![121; gc_from_head + 1];
+        assert_eq!(
+            r1.execute_mut_batch(&ops, t1).unwrap().len(),
+            gc_from_head + 1
+        );
+        assert_eq!(policy.0.load(Ordering::Relaxed), 2);
+
+        // The next round of flat combining gives up and leaves the operation
+        // with the thread.
+        let tail = slog.get_tail();
+        assert!(r1.make_pending(121, t1.0));
+        r1.try_combine(t1.0).unwrap();
+        assert_eq!(slog.get_tail(), tail);
+        assert!(!r1.contexts[t1.0.index()].is_drained());
+
+        // Once the second replica catches up, the operation goes through.
+        r2.sync(t2).unwrap();
+        r1.try_combine(t1.0).unwrap();
+        assert_eq!(slog.get_tail(), tail + 1);
+        assert_eq!(r1.get_response(t1.0), Ok(Ok(107)));
+    }
+
+    // Tests that a `StallHandler` can help the replica holding up garbage
+    // collection instead of waiting for its (idle) threads.
+    #[test]
+    fn test_replica_help_sync() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new(1));
+        let r1 = Replica::<Data>::new(&slog);
+        let r2 = Replica::<Data>::new(&slog);
+        let t1 = r1.register().unwrap();
+
+        let helped = Arc::new(AtomicUsize::new(0));
+        let (other, h) = (r2.clone(), helped.clone());
+        r1.set_gc_policy(Arc::new(crate::StallHandler::new(2, 16, move |stall| {
+            assert_eq!(stall.replica, ReplicaId::new(1));
+            assert_eq!(stall.slowest, other.id());
+            other.help_sync().unwrap();
+            h.fetch_add(1, Ordering::Relaxed);
+            crate::HelpAction::Exec
+        })));
+
+        for _i in 0..4 * slog.capacity() {
+            r1.execute_mut(121, t1).unwrap().unwrap();
+        }
+        assert!(helped.load(Ordering::Relaxed) > 0);
+        assert!(slog.get_ltail(r2.id()).get() > slog.capacity());
+    }
+
+    std::thread_local! {
+        static NODE: core::cell::Cell<usize> = core::cell::Cell::new(0);
+    }
+
+    fn node() -> usize {
+        NODE.with(|n| n.get())
+    }
+
+    // Tests that a replica combines on cores of the node it was assigned to.
+    #[test]
+    fn test_replica_set_node() {
+        crate::set_current_node(node);
+        NODE.with(|n| n.set(1));
+
         let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
         let repl = Replica::<Data>::new(&slog);
+        repl.set_node(1);
+        let idx = repl.register().unwrap();
+        assert_eq!(repl.execute_mut(121, idx), Ok(Ok(107)));
+    }
 
-        // Add in operations to the log off the side, not through the replica.
-        let o = [121, 212];
-        slog.append(&o, 2, |_o: u64, _i: usize| {});
-        slog.exec(2, &mut |_o: u64, _i: usize| {});
+    // Tests that combining on a core of another node panics in debug builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "executes operations on a core of node 0")]
+    fn test_replica_set_node_mismatch() {
+        crate::set_current_node(node);
+        NODE.with(|n| n.set(0));
 
-        let t1 = repl.register().expect("Failed to register with replica.");
-        assert_eq!(Ok(2), repl.execute(11, t1));
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(&slog);
+        repl.set_node(1);
+        let idx = repl.register().unwrap();
+        let _r = repl.execute_mut(121, idx);
     }
 }
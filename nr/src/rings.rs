@@ -0,0 +1,346 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! An experimental log that keeps small and bulk appends on separate rings.
+//! Requires the `multi-ring` feature.
+//!
+//! In a single ring, a large batch of operations delays every small append
+//! that reserves entries after it: replicas execute the ring in order, and
+//! appends wait for garbage collection behind the batch. With two rings,
+//! small appends only ever wait for other small appends.
+//!
+//! Both rings still describe one total order. Every operation gets a sequence
+//! number when it is appended, and a replica applies operations in the order
+//! of these numbers. It takes operations off both rings as they show up,
+//! stages those that overtook an operation still being appended to the other
+//! ring, and applies them once the gap closes.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::gc::{GcContext, GcHelpPolicy, HelpAction};
+use crate::ids::{LogOffset, ReplicaId};
+use crate::log::{Log, MAX_REPLICAS_PER_LOG};
+
+/// Which ring of a [MultiRingLog](struct.MultiRingLog.html) a batch of
+/// operations is appended to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpSize {
+    /// Few operations that are sensitive to latency.
+    Small,
+
+    /// A large batch, e.g., from a burst of ingestion.
+    Bulk,
+}
+
+/// Operations a replica took off the rings but didn't apply yet.
+struct Staged<T> {
+    /// Sequence number of the next operation the replica applies.
+    next: u64,
+
+    /// Operations with a sequence number past `next`, waiting for the ones
+    /// before them.
+    ops: BTreeMap<u64, (T, ReplicaId)>,
+}
+
+/// A log made of two rings, one for small and one for bulk appends, that
+/// replicas execute in a single total order. Offers the `register`, `append`
+/// and `exec` interface of the [Log](struct.Log.html), with a hint which ring
+/// to append to. Experimental; replicas don't use it yet.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{MultiRingLog, OpSize};
+///
+/// let log = MultiRingLog::<u64>::new(1024 * 1024, 2 * 1024 * 1024);
+/// let idx = log.register().unwrap();
+///
+/// log.append(&[1, 2, 3], idx, OpSize::Bulk, |_op, _id| {});
+/// log.append(&[4], idx, OpSize::Small, |_op, _id| {});
+///
+/// let mut applied = Vec::new();
+/// log.exec(idx, &mut |op, _id| applied.push(op));
+/// assert_eq!(applied, vec![1, 2, 3, 4]);
+/// ```
+pub struct MultiRingLog<'a, T>
+where
+    T: Sized + Clone + 'a,
+{
+    /// The ring for `OpSize::Small` appends.
+    small: Log<'a, (u64, T)>,
+
+    /// The ring for `OpSize::Bulk` appends.
+    bulk: Log<'a, (u64, T)>,
+
+    /// Sequence number of the next appended operation.
+    seq: CachePadded<AtomicU64>,
+
+    /// Taken while a replica registers, so that it gets the same identifier
+    /// on both rings.
+    registering: AtomicBool,
+
+    /// Staging area of each replica, indexed like the local tails of the rings.
+    staged: Vec<CachePadded<RefCell<Staged<T>>>>,
+}
+
+/// The MultiRingLog is Sync. The rings are, and a replica's staging area is
+/// only touched on behalf of that replica, by one thread at a time (the same
+/// rule the per-replica state of a `Log` relies on).
+unsafe impl<'a, T> Sync for MultiRingLog<'a, T> where T: Sized + Clone + 'a {}
+
+impl<'a, T> MultiRingLog<'a, T>
+where
+    T: Sized + Clone + 'a,
+{
+    /// Constructs a log whose small ring has `small_bytes` bytes and whose
+    /// bulk ring has `bulk_bytes` bytes. See `Log::new` for how the sizes are
+    /// rounded.
+    pub fn new<'b>(small_bytes: usize, bulk_bytes: usize) -> MultiRingLog<'b, T> {
+        let mut staged = Vec::with_capacity(MAX_REPLICAS_PER_LOG);
+        for _i in 0..MAX_REPLICAS_PER_LOG {
+            staged.push(CachePadded::new(RefCell::new(Staged {
+                next: 0,
+                ops: BTreeMap::new(),
+            })));
+        }
+
+        MultiRingLog {
+            small: Log::new(small_bytes),
+            bulk: Log::new(bulk_bytes),
+            seq: CachePadded::new(AtomicU64::new(0)),
+            registering: AtomicBool::new(false),
+            staged,
+        }
+    }
+
+    /// Registers a replica with both rings. Returns `None` if the rings don't
+    /// accept another replica.
+    pub fn register(&self) -> Option<ReplicaId> {
+        while self
+            .registering
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+
+        let idx = self.small.register().and_then(|idx| {
+            let bulk = self.bulk.register();
+            debug_assert!(bulk.is_none() || bulk == Some(idx));
+            bulk
+        });
+
+        self.registering.store(false, Ordering::Release);
+        idx
+    }
+
+    /// Appends a batch of operations to the ring `size` picks. The operations
+    /// follow every operation appended before the call in the total order.
+    ///
+    /// While waiting for space on the ring, executes both rings against
+    /// replica `idx` (so that it doesn't hold up garbage collection on either
+    /// of them) and passes the operations that are next in the total order to
+    /// `s`, like `Log::append` does.
+    pub fn append<F: FnMut(T, ReplicaId)>(
+        &self,
+        ops: &[T],
+        idx: ReplicaId,
+        size: OpSize,
+        mut s: F,
+    ) {
+        let first = self.seq.fetch_add(ops.len() as u64, Ordering::Relaxed);
+        let ops: Vec<(u64, T)> = ops
+            .iter()
+            .enumerate()
+            .map(|(i, op)| (first + i as u64, op.clone()))
+            .collect();
+
+        let (ring, other) = match size {
+            OpSize::Small => (&self.small, &self.bulk),
+            OpSize::Bulk => (&self.bulk, &self.small),
+        };
+        let policy = ExecOther {
+            log: self,
+            other,
+            idx,
+        };
+        let r = ring.append_observed(
+            &ops,
+            idx,
+            |op: (u64, T), id: ReplicaId, _offset: LogOffset| self.stage(idx, op, id),
+            &(),
+            &policy,
+        );
+        debug_assert!(r.is_ok(), "ExecOther never gives up waiting for GC.");
+
+        self.apply(idx, &mut s);
+    }
+
+    /// Executes the operations on both rings against replica `idx`, passing
+    /// them to `d` in the total order. Stops at the first operation that is
+    /// still being appended; the ones after it are passed on by a later call.
+    pub fn exec<F: FnMut(T, ReplicaId)>(&self, idx: ReplicaId, d: &mut F) {
+        self.small
+            .exec_traced(idx, &mut |op, id, _o| self.stage(idx, op, id));
+        self.bulk
+            .exec_traced(idx, &mut |op, id, _o| self.stage(idx, op, id));
+        self.apply(idx, d);
+    }
+
+    /// Returns the number of operations replica `idx` took off the rings but
+    /// can't apply yet, because an operation before them is still being
+    /// appended.
+    pub fn staged(&self, idx: ReplicaId) -> usize {
+        self.staged[idx.index()].borrow().ops.len()
+    }
+
+    /// Adds an operation replica `idx` took off one of the rings to its
+    /// staging area.
+    fn stage(&self, idx: ReplicaId, (seq, op): (u64, T), id: ReplicaId) {
+        let mut staged = self.staged[idx.index()].borrow_mut();
+        debug_assert!(seq >= staged.next, "operation {} taken twice", seq);
+        staged.ops.insert(seq, (op, id));
+    }
+
+    /// Passes the staged operations of replica `idx` that are next in the
+    /// total order to `d`.
+    fn apply<F: FnMut(T, ReplicaId)>(&self, idx: ReplicaId, d: &mut F) {
+        loop {
+            // `d` may append (e.g., from a `Log::append` closure), so don't hold
+            // the staging area while calling it.
+            let next = {
+                let mut staged = self.staged[idx.index()].borrow_mut();
+                let seq = staged.next;
+                match staged.ops.remove(&seq) {
+                    Some(next) => {
+                        staged.next += 1;
+                        next
+                    }
+                    None => return,
+                }
+            };
+            d(next.0, next.1);
+        }
+    }
+}
+
+/// Keeps the other ring moving while an append waits for space, so that two
+/// appends to different rings can't end up waiting for each other's replica.
+struct ExecOther<'r, 'a, T>
+where
+    T: Sized + Clone + 'a,
+{
+    log: &'r MultiRingLog<'a, T>,
+    other: &'r Log<'a, (u64, T)>,
+    idx: ReplicaId,
+}
+
+impl<'r, 'a, T> GcHelpPolicy for ExecOther<'r, 'a, T>
+where
+    T: Sized + Clone + 'a,
+{
+    fn on_log_full(&self, _ctx: GcContext) -> HelpAction {
+        let (log, idx) = (self.log, self.idx);
+        self.other
+            .exec_traced(idx, &mut |op, id, _o| log.stage(idx, op, id));
+        HelpAction::Exec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use alloc::sync::Arc;
+    use std::vec;
+
+    // Tests that replicas apply operations in the order they were appended,
+    // regardless of the ring they went to.
+    #[test]
+    fn test_rings_total_order() {
+        let log = MultiRingLog::<u64>::new(1024 * 1024, 1024 * 1024);
+        let (r1, r2) = (log.register().unwrap(), log.register().unwrap());
+        assert_ne!(r1, r2);
+
+        log.append(&[0, 1, 2, 3], r1, OpSize::Bulk, |_o, _i| {});
+        log.append(&[4], r2, OpSize::Small, |_o, _i| {});
+        log.append(&[5, 6], r1, OpSize::Bulk, |_o, _i| {});
+        log.append(&[7], r1, OpSize::Small, |_o, _i| {});
+
+        for idx in [r1, r2].iter() {
+            let mut applied = vec![];
+            log.exec(*idx, &mut |op, _id| applied.push(op));
+            assert_eq!(applied, (0..8).collect::<Vec<u64>>());
+            assert_eq!(log.staged(*idx), 0);
+        }
+    }
+
+    // Tests that an operation that overtook one still being appended is staged
+    // until the gap closes.
+    #[test]
+    fn test_rings_gap() {
+        let log = MultiRingLog::<u64>::new(1024 * 1024, 1024 * 1024);
+        let idx = log.register().unwrap();
+
+        // Reserve a sequence number as if another thread started appending.
+        let gap = log.seq.fetch_add(1, Ordering::Relaxed);
+        log.append(&[1], idx, OpSize::Small, |_o, _i| {});
+
+        let mut applied = vec![];
+        log.exec(idx, &mut |op, _id| applied.push(op));
+        assert!(applied.is_empty());
+        assert_eq!(log.staged(idx), 1);
+
+        log.bulk
+            .append(&[(gap, 0)], idx, |_o: (u64, u64), _i: ReplicaId| {});
+        log.exec(idx, &mut |op, _id| applied.push(op));
+        assert_eq!(applied, vec![0, 1]);
+        assert_eq!(log.staged(idx), 0);
+    }
+
+    // Tests that threads appending to different rings concurrently don't wait
+    // for each other forever, and agree on the order.
+    #[test]
+    fn test_rings_concurrent() {
+        // Enough operations to wrap around both rings.
+        const OPS: u64 = 64 * 600;
+
+        // Both replicas register before any operation is appended, so that
+        // neither misses operations that were reclaimed already.
+        let log = Arc::new(MultiRingLog::<u64>::new(1024 * 1024, 1024 * 1024));
+        assert!(log.small.capacity() < OPS as usize && log.bulk.capacity() < OPS as usize);
+        let replicas = [log.register().unwrap(), log.register().unwrap()];
+        let threads: Vec<_> = [OpSize::Small, OpSize::Bulk]
+            .iter()
+            .zip(replicas.iter())
+            .map(|(&size, &idx)| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    let mut applied = vec![];
+                    let batch = if size == OpSize::Small { 1 } else { 64 };
+                    for i in 0..OPS / batch {
+                        let ops: Vec<u64> = (0..batch).map(|j| i * batch + j).collect();
+                        log.append(&ops, idx, size, |op, _id| applied.push(op));
+                    }
+                    while applied.len() < 2 * OPS as usize {
+                        log.exec(idx, &mut |op, _id| applied.push(op));
+                    }
+                    applied
+                })
+            })
+            .collect();
+
+        let mut orders = vec![];
+        for t in threads {
+            orders.push(t.join().unwrap());
+        }
+        assert_eq!(orders[0], orders[1]);
+    }
+}
@@ -10,15 +10,12 @@
 use core::cell::UnsafeCell;
 use core::default::Default;
 use core::hint::spin_loop;
+use core::mem;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crossbeam_utils::CachePadded;
 
-/// Maximum number of reader threads that this lock supports.
-const MAX_READER_THREADS: usize = 192;
-const_assert!(MAX_READER_THREADS > 0);
-
 #[allow(clippy::declare_interior_mutable_const)]
 const RLOCK_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::new(0));
 
@@ -27,10 +24,12 @@ const RLOCK_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::ne
 /// This lock favours reader performance over writers. Each reader thread gets
 /// its own "lock" while writers share a single lock.
 ///
-/// `T` represents the underlying type protected by the lock.
+/// `T` represents the underlying type protected by the lock. `READERS` is the
+/// number of reader threads the lock supports; every reader passes an id below
+/// it to `read()`.
 /// Calling `read()` returns a read-guard that can be used to safely read `T`.
 /// Calling `write()` returns a write-guard that can be used to safely mutate `T`.
-pub struct RwLock<T>
+pub struct RwLock<T, const READERS: usize>
 where
     T: Sized + Sync,
 {
@@ -38,7 +37,7 @@ where
     wlock: CachePadded<AtomicBool>,
 
     /// Each reader use an individual lock to access the underlying data-structure.
-    rlock: [CachePadded<AtomicUsize>; MAX_READER_THREADS],
+    rlock: [CachePadded<AtomicUsize>; READERS],
 
     /// The underlying data-structure.
     data: UnsafeCell<T>,
@@ -46,38 +45,38 @@ where
 
 /// A read-guard that can be used to read the underlying data structure. Writes on
 /// the data structure will be blocked as long as one of these is lying around.
-pub struct ReadGuard<'a, T: Sized + Sync + 'a> {
+pub struct ReadGuard<'a, T: Sized + Sync + 'a, const READERS: usize> {
     /// Id of the thread that acquired this guard. Required at drop time so that
     /// we can release the appropriate read lock.
     tid: usize,
 
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, READERS>,
 }
 
 /// A write-guard that can be used to write to the underlying data structure. All
 /// reads will be blocked until this is dropped.
-pub struct WriteGuard<'a, T: Sized + Sync + 'a> {
+pub struct WriteGuard<'a, T: Sized + Sync + 'a, const READERS: usize> {
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, READERS>,
 }
 
-impl<T> Default for RwLock<T>
+impl<T, const READERS: usize> Default for RwLock<T, READERS>
 where
     T: Sized + Default + Sync,
 {
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
-    fn default() -> RwLock<T> {
+    fn default() -> RwLock<T, READERS> {
         RwLock {
             wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
+            rlock: [RLOCK_DEFAULT; READERS],
             data: UnsafeCell::new(T::default()),
         }
     }
 }
 
-impl<T> RwLock<T>
+impl<T, const READERS: usize> RwLock<T, READERS>
 where
     T: Sized + Sync,
 {
@@ -86,7 +85,7 @@ where
     pub fn new(t: T) -> Self {
         Self {
             wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
+            rlock: [RLOCK_DEFAULT; READERS],
             data: UnsafeCell::new(t),
         }
     }
@@ -102,7 +101,7 @@ where
     ///     use node_replication::rwlock::RwLock;
     ///
     ///     // Create the lock.
-    ///     let lock = RwLock::<usize>::default();
+    ///     let lock = RwLock::<usize, 64>::default();
     ///
     ///     // Acquire the write lock. This returns a guard that can be used
     ///     // to perform writes against the protected data. We need to know
@@ -111,32 +110,105 @@ where
     ///     let mut w_guard = lock.write(N_CONCURRENT_READERS);
     ///     *w_guard = 777;
     /// ```
-    pub fn write(&self, n: usize) -> WriteGuard<T> {
-        // First, wait until we can acquire the writer lock.
+    pub fn write(&self, n: usize) -> WriteGuard<T, READERS> {
+        // First, wait until we can acquire the writer lock. This and the reader lock
+        // checks below are sequentially consistent, pairing with `read()`: either
+        // the reader sees the writer lock or we see its reader lock.
         loop {
-            match self.wlock.compare_exchange_weak(
-                false,
-                true,
-                Ordering::Acquire,
-                Ordering::Acquire,
-            ) {
+            match self
+                .wlock
+                .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            {
                 Ok(_) => break,
                 Err(_) => continue,
             }
         }
 
-        // Next, wait until all readers have released their locks. This condition
-        // evaluates to true if each reader lock is free (i.e equal to zero).
-        while !self
-            .rlock
-            .iter()
-            .take(n)
-            .all(|item| item.load(Ordering::Relaxed) == 0)
+        // Next, wait until all readers have released their locks.
+        self.wait_for_readers(n);
+
+        unsafe { WriteGuard::new(self) }
+    }
+
+    /// Like `write()`, but returns `None` instead of waiting if another writer
+    /// holds the lock or if any of the first `n` readers does.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///     use node_replication::rwlock::RwLock;
+    ///
+    ///     let lock = RwLock::<usize, 64>::default();
+    ///     let r_guard = lock.read(0);
+    ///     assert!(lock.try_write(1).is_none());
+    ///
+    ///     drop(r_guard);
+    ///     assert!(lock.try_write(1).is_some());
+    /// ```
+    pub fn try_write(&self, n: usize) -> Option<WriteGuard<T, READERS>> {
+        if self
+            .wlock
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+
+        if !self.readers_gone(n) {
+            unsafe { self.write_unlock() };
+            return None;
+        }
+
+        Some(unsafe { WriteGuard::new(self) })
+    }
+
+    /// Like `write()`, but gives up and returns `None` once `timeout` has passed.
+    /// Readers that hold the lock for a long time (e.g., during a long dispatch)
+    /// would block a writer indefinitely otherwise.
+    #[cfg(feature = "std")]
+    pub fn write_timeout(
+        &self,
+        n: usize,
+        timeout: core::time::Duration,
+    ) -> Option<WriteGuard<T, READERS>> {
+        let deadline = std::time::Instant::now() + timeout;
+        while self
+            .wlock
+            .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
         {
+            if std::time::Instant::now() >= deadline {
+                return None;
+            }
             spin_loop();
         }
 
-        unsafe { WriteGuard::new(self) }
+        // Readers that come in while we wait back off, so they don't starve us.
+        while !self.readers_gone(n) {
+            if std::time::Instant::now() >= deadline {
+                unsafe { self.write_unlock() };
+                return None;
+            }
+            spin_loop();
+        }
+
+        Some(unsafe { WriteGuard::new(self) })
+    }
+
+    /// Returns true if the first `n` reader locks are free (i.e equal to zero).
+    fn readers_gone(&self, n: usize) -> bool {
+        self.rlock
+            .iter()
+            .take(n)
+            .all(|item| item.load(Ordering::SeqCst) == 0)
+    }
+
+    /// Waits until the first `n` reader locks are free (i.e equal to zero). Must
+    /// be called while holding the writer lock, which keeps new readers out.
+    fn wait_for_readers(&self, n: usize) {
+        while !self.readers_gone(n) {
+            spin_loop();
+        }
     }
 
     /// Locks the underlying data-structure for reads. Allows multiple readers to acquire the lock.
@@ -148,7 +220,7 @@ where
     ///     use node_replication::rwlock::RwLock;
     ///
     ///     // Create the lock.
-    ///     let lock = RwLock::<usize>::default();
+    ///     let lock = RwLock::<usize, 64>::default();
     ///
     ///     // Acquire the read lock. This returns a guard that can be used
     ///     // to perform reads against the protected data. We need
@@ -156,7 +228,9 @@ where
     ///     const MY_THREAD_ID: usize = 16;
     ///     let r_guard = lock.read(MY_THREAD_ID);
     ///     assert_eq!(0, *r_guard);
-    pub fn read(&self, tid: usize) -> ReadGuard<T> {
+    pub fn read(&self, tid: usize) -> ReadGuard<T, READERS> {
+        self.check_reader(tid);
+
         // We perform a small optimization. Before attempting to acquire a read lock, we issue
         // naked reads to the write lock and wait until it is free. For that, we retrieve a
         // raw pointer to the write lock over here.
@@ -178,8 +252,8 @@ where
             // is free. If it is, then we're good to go because any new writers will now
             // see this acquired read lock and block. If it isn't free, then we got unlucky;
             // release the read lock and retry.
-            self.rlock[tid].fetch_add(1, Ordering::Acquire);
-            if !self.wlock.load(Ordering::Relaxed) {
+            self.rlock[tid].fetch_add(1, Ordering::SeqCst);
+            if !self.wlock.load(Ordering::SeqCst) {
                 break;
             }
 
@@ -189,11 +263,73 @@ where
         unsafe { ReadGuard::new(self, tid) }
     }
 
+    /// Like `read()`, but returns `None` instead of waiting if a writer holds
+    /// the lock.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///     use node_replication::rwlock::RwLock;
+    ///
+    ///     let lock = RwLock::<usize, 64>::default();
+    ///     let w_guard = lock.write(1);
+    ///     assert!(lock.try_read(0).is_none());
+    ///
+    ///     drop(w_guard);
+    ///     assert_eq!(0, *lock.try_read(0).unwrap());
+    /// ```
+    pub fn try_read(&self, tid: usize) -> Option<ReadGuard<T, READERS>> {
+        self.check_reader(tid);
+        if self.wlock.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // Same as in `read()`: the write lock has to be free once our read lock
+        // is visible.
+        self.rlock[tid].fetch_add(1, Ordering::SeqCst);
+        if self.wlock.load(Ordering::SeqCst) {
+            self.rlock[tid].fetch_sub(1, Ordering::Release);
+            return None;
+        }
+
+        Some(unsafe { ReadGuard::new(self, tid) })
+    }
+
+    /// Like `read()`, but gives up and returns `None` once `timeout` has passed.
+    #[cfg(feature = "std")]
+    pub fn read_timeout(
+        &self,
+        tid: usize,
+        timeout: core::time::Duration,
+    ) -> Option<ReadGuard<T, READERS>> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if let Some(guard) = self.try_read(tid) {
+                return Some(guard);
+            }
+            if std::time::Instant::now() >= deadline {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Panics if there is no reader lock for thread `tid`.
+    #[inline(always)]
+    fn check_reader(&self, tid: usize) {
+        assert!(
+            tid < READERS,
+            "Reader id {} is out of range, the lock supports {} readers.",
+            tid,
+            READERS
+        );
+    }
+
     /// Unlocks the write lock; invoked by the drop() method.
     pub(in crate::rwlock) unsafe fn write_unlock(&self) {
         match self
             .wlock
-            .compare_exchange_weak(true, false, Ordering::Acquire, Ordering::Acquire)
+            .compare_exchange(true, false, Ordering::Release, Ordering::Relaxed)
         {
             Ok(_) => (),
             Err(_) => panic!("write_unlock() called without acquiring the write lock"),
@@ -208,28 +344,103 @@ where
     }
 }
 
-impl<'rwlock, T: Sized + Sync> ReadGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const READERS: usize> ReadGuard<'rwlock, T, READERS> {
     /// Returns a read guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>, tid: usize) -> ReadGuard<'rwlock, T> {
+    unsafe fn new(lock: &'rwlock RwLock<T, READERS>, tid: usize) -> ReadGuard<'rwlock, T, READERS> {
         ReadGuard { tid, lock }
     }
 }
 
-impl<'rwlock, T: Sized + Sync> WriteGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const READERS: usize> ReadGuard<'rwlock, T, READERS> {
+    /// Turns the read lock into a write lock, unless another thread holds or
+    /// waits for the write lock; the read guard is handed back in that case,
+    /// since waiting for that writer while holding a read lock would deadlock.
+    /// Otherwise, waits until the other readers have released their locks.
+    ///
+    /// `n` is the number of active readers, as for `write()`. The thread must not
+    /// hold any other read guard of the lock, or this waits forever.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///     use node_replication::rwlock::RwLock;
+    ///
+    ///     let lock = RwLock::<usize, 64>::default();
+    ///     let r_guard = lock.read(0);
+    ///
+    ///     // No other thread holds the lock, so the upgrade succeeds.
+    ///     let mut w_guard = r_guard.try_upgrade(1).ok().unwrap();
+    ///     *w_guard = 777;
+    /// ```
+    pub fn try_upgrade(
+        self,
+        n: usize,
+    ) -> Result<WriteGuard<'rwlock, T, READERS>, ReadGuard<'rwlock, T, READERS>> {
+        if self
+            .lock
+            .wlock
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(self);
+        }
+
+        // New readers now back off, so we only wait for the ones that are
+        // already in (other than us).
+        let (lock, tid) = (self.lock, self.tid);
+        mem::forget(self);
+        unsafe { lock.read_unlock(tid) };
+        lock.wait_for_readers(n);
+
+        Ok(unsafe { WriteGuard::new(lock) })
+    }
+}
+
+impl<'rwlock, T: Sized + Sync, const READERS: usize> WriteGuard<'rwlock, T, READERS> {
     /// Returns a write guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>) -> WriteGuard<'rwlock, T> {
+    unsafe fn new(lock: &'rwlock RwLock<T, READERS>) -> WriteGuard<'rwlock, T, READERS> {
         WriteGuard { lock }
     }
+
+    /// Turns the write lock into a read lock of thread `tid`, without letting
+    /// another writer in between. Other readers can proceed right away.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///     use node_replication::rwlock::RwLock;
+    ///
+    ///     let lock = RwLock::<usize, 64>::default();
+    ///     let mut w_guard = lock.write(2);
+    ///     *w_guard = 777;
+    ///
+    ///     // Keep reading what we wrote while others can read it too.
+    ///     let r_guard = w_guard.downgrade(0);
+    ///     assert_eq!(777, *lock.read(1));
+    ///     assert_eq!(777, *r_guard);
+    /// ```
+    pub fn downgrade(self, tid: usize) -> ReadGuard<'rwlock, T, READERS> {
+        // Take the read lock first, so that a writer waiting for the write lock
+        // sees it once we release the write lock.
+        let lock = self.lock;
+        lock.check_reader(tid);
+        lock.rlock[tid].fetch_add(1, Ordering::SeqCst);
+        mem::forget(self);
+        unsafe {
+            lock.write_unlock();
+            ReadGuard::new(lock, tid)
+        }
+    }
 }
 
 /// `Sync` trait allows `RwLock` to be shared between threads. The `read()` and
 /// `write()` logic ensures that we will never have threads writing to and
 /// reading from the underlying data structure simultaneously.
-unsafe impl<T: Sized + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Sized + Sync, const READERS: usize> Sync for RwLock<T, READERS> {}
 
 /// This `Deref` trait allows a thread to use T from a ReadGuard.
 /// ReadGuard can only be dereferenced into an immutable reference.
-impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const READERS: usize> Deref for ReadGuard<'_, T, READERS> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -239,7 +450,7 @@ impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
 
 /// This `Deref` trait allows a thread to use T from a WriteGuard.
 /// This allows us to dereference an immutable reference.
-impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const READERS: usize> Deref for WriteGuard<'_, T, READERS> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -249,7 +460,7 @@ impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
 
 /// This `DerefMut` trait allow a thread to use T from a WriteGuard.
 /// This allows us to dereference a mutable reference.
-impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const READERS: usize> DerefMut for WriteGuard<'_, T, READERS> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
@@ -257,7 +468,7 @@ impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
 
 /// This `Drop` trait implements the unlock logic for a reader lock. Once the `ReadGuard`
 /// goes out of scope, the corresponding read lock is marked as released.
-impl<T: Sized + Sync> Drop for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const READERS: usize> Drop for ReadGuard<'_, T, READERS> {
     fn drop(&mut self) {
         unsafe {
             let tid = self.tid;
@@ -268,7 +479,7 @@ impl<T: Sized + Sync> Drop for ReadGuard<'_, T> {
 
 /// This `Drop` trait implements the unlock logic for a writer lock. Once the `WriteGuard`
 /// goes out of scope, the corresponding write lock is marked as released.
-impl<T: Sized + Sync> Drop for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const READERS: usize> Drop for WriteGuard<'_, T, READERS> {
     fn drop(&mut self) {
         unsafe {
             self.lock.write_unlock();
@@ -278,19 +489,22 @@ impl<T: Sized + Sync> Drop for WriteGuard<'_, T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{RwLock, MAX_READER_THREADS};
+    use super::RwLock;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
     use std::thread;
     use std::vec::Vec;
 
+    /// Number of reader threads supported by the locks in these tests.
+    const READERS: usize = 128;
+
     // Tests if we can successfully default-construct a reader-writer lock.
     #[test]
     fn test_rwlock_default() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
 
         assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
-        for idx in 0..MAX_READER_THREADS {
+        for idx in 0..READERS {
             assert_eq!(lock.rlock[idx].load(Ordering::Relaxed), 0);
         }
         assert_eq!(unsafe { *lock.data.get() }, usize::default());
@@ -300,7 +514,7 @@ mod tests {
     // can be used to write to the underlying data structure.
     #[test]
     fn test_writer_lock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let val = 10;
 
         let mut guard = lock.write(1);
@@ -314,7 +528,7 @@ mod tests {
     // Tests if the write lock is released once a WriteGuard goes out of scope.
     #[test]
     fn test_writer_unlock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
 
         {
             let mut _guard = lock.write(1);
@@ -328,7 +542,7 @@ mod tests {
     // can be used to read from the underlying data structure.
     #[test]
     fn test_reader_lock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let val = 10;
 
         unsafe {
@@ -344,7 +558,7 @@ mod tests {
     // Tests if a reader lock is released once a ReadGuard goes out of scope.
     #[test]
     fn test_reader_unlock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
 
         {
             let mut _guard = lock.read(0);
@@ -357,7 +571,7 @@ mod tests {
     // Tests that multiple readers can simultaneously acquire a readers lock
     #[test]
     fn test_multiple_readers() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let val = 10;
 
         unsafe {
@@ -380,7 +594,7 @@ mod tests {
     // acquire the lock.
     #[test]
     fn test_lock_combinations() {
-        let l = RwLock::<usize>::default();
+        let l = RwLock::<usize, READERS>::default();
 
         {
             let _g = l.write(2);
@@ -403,7 +617,7 @@ mod tests {
     // Tests that writes to the underlying data structure are atomic.
     #[test]
     fn test_atomic_writes() {
-        let lock = Arc::new(RwLock::<usize>::default());
+        let lock = Arc::new(RwLock::<usize, READERS>::default());
         let t = 100;
 
         let mut threads = Vec::new();
@@ -430,7 +644,7 @@ mod tests {
     // Tests that the multiple readers can read from the lock in parallel.
     #[test]
     fn test_parallel_readers() {
-        let lock = Arc::new(RwLock::<usize>::default());
+        let lock = Arc::new(RwLock::<usize, READERS>::default());
         let t = 100;
 
         unsafe {
@@ -456,11 +670,122 @@ mod tests {
         }
     }
 
+    // Tests that a downgraded write lock lets readers in but keeps writers out
+    // until the read guard is dropped.
+    #[test]
+    fn test_writer_downgrade() {
+        let lock = RwLock::<usize, READERS>::default();
+
+        let mut w = lock.write(2);
+        *w = 10;
+        let r = w.downgrade(0);
+        assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 1);
+        assert_eq!(*lock.read(1), 10);
+        assert_eq!(*r, 10);
+
+        drop(r);
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        *lock.write(2) = 11;
+        assert_eq!(*lock.read(0), 11);
+    }
+
+    // Tests that a read lock is upgraded to a write lock only if no other thread
+    // holds the write lock.
+    #[test]
+    fn test_reader_try_upgrade() {
+        let lock = Arc::new(RwLock::<usize, READERS>::default());
+
+        let r = lock.read(0);
+        lock.wlock.store(true, Ordering::SeqCst);
+        let r = r.try_upgrade(2).err().unwrap();
+        lock.wlock.store(false, Ordering::SeqCst);
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 1);
+
+        // The upgrade waits for the other reader to go away.
+        let entered = Arc::new(AtomicUsize::new(0));
+        let (l, e) = (lock.clone(), entered.clone());
+        let reader = thread::spawn(move || {
+            let _r = l.read(1);
+            e.store(1, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(100));
+        });
+        while entered.load(Ordering::SeqCst) == 0 {}
+        let mut w = r.try_upgrade(2).ok().unwrap();
+        *w = 10;
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        assert_eq!(lock.rlock[1].load(Ordering::Relaxed), 0);
+        drop(w);
+        reader.join().unwrap();
+        assert_eq!(*lock.read(0), 10);
+    }
+
+    // Tests that try_read() and try_write() fail instead of waiting while the lock
+    // is held in a conflicting mode, and leave the lock as it was.
+    #[test]
+    fn test_try_lock() {
+        let lock = RwLock::<usize, READERS>::default();
+
+        {
+            let _w = lock.try_write(2).unwrap();
+            assert!(lock.try_read(0).is_none());
+            assert!(lock.try_write(2).is_none());
+            assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+        }
+
+        {
+            let _r = lock.try_read(0).unwrap();
+            let _s = lock.try_read(1).unwrap();
+            assert!(lock.try_write(2).is_none());
+            assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+        }
+
+        // Readers beyond the first `n` don't keep writers out.
+        let _r = lock.read(3);
+        assert!(lock.try_write(2).is_some());
+    }
+
+    // Tests that read_timeout() and write_timeout() give up once the timeout
+    // passed, and succeed once the lock is released in time.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_lock_timeout() {
+        use std::time::{Duration, Instant};
+
+        let lock = Arc::new(RwLock::<usize, READERS>::default());
+
+        let r = lock.read(0);
+        let start = Instant::now();
+        assert!(lock.write_timeout(1, Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(lock.wlock.load(Ordering::Relaxed), false);
+        drop(r);
+
+        let w = lock.write(1);
+        assert!(lock.read_timeout(0, Duration::from_millis(50)).is_none());
+        assert_eq!(lock.rlock[0].load(Ordering::Relaxed), 0);
+
+        let l = lock.clone();
+        let reader = thread::spawn(move || l.read_timeout(0, Duration::from_secs(10)).is_some());
+        thread::sleep(Duration::from_millis(50));
+        drop(w);
+        assert!(reader.join().unwrap());
+    }
+
+    // Tests that reading with an id beyond the reader locks panics with a clear
+    // message rather than an index out of bounds.
+    #[test]
+    #[should_panic(expected = "Reader id 4 is out of range, the lock supports 4 readers.")]
+    fn test_reader_out_of_range() {
+        let lock = RwLock::<usize, 4>::default();
+        let _r = lock.read(4);
+    }
+
     // Tests that write_unlock() panics if called without acquiring a write lock.
     #[test]
     #[should_panic]
     fn test_writer_unlock_without_lock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         unsafe { lock.write_unlock() };
     }
 
@@ -468,7 +793,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_reader_unlock_without_lock() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         unsafe { lock.read_unlock(1) };
     }
 
@@ -484,7 +809,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "This test should always panic")]
     fn test_reader_after_writer() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let shared = Arc::new(AtomicUsize::new(0));
 
         let s = shared.clone();
@@ -513,7 +838,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "This test should always panic")]
     fn test_writer_after_reader() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let shared = Arc::new(AtomicUsize::new(0));
 
         let s = shared.clone();
@@ -542,7 +867,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "This test should always panic")]
     fn test_writer_after_writer() {
-        let lock = RwLock::<usize>::default();
+        let lock = RwLock::<usize, READERS>::default();
         let shared = Arc::new(AtomicUsize::new(0));
 
         let s = shared.clone();
@@ -0,0 +1,37 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Checkpoints of a replicated data structure, used to bring up new replicas
+//! without replaying the shared log from the beginning.
+
+use crate::ids::LogOffset;
+use crate::Dispatch;
+
+/// Trait that a data structure must implement so that replicas of it can be
+/// checkpointed with `Replica::checkpoint` and created from a checkpoint with
+/// `Replica::from_checkpoint`.
+pub trait Snapshot: Dispatch + Sized {
+    /// The captured state of the data structure. This can be the data structure
+    /// itself (if it's cheap to clone) or a serialized form of it (if the
+    /// checkpoint is written to disk or sent over the network).
+    type Snapshot;
+
+    /// Captures the state of the data structure.
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Creates a data structure from a state captured with `snapshot()`.
+    fn restore(snapshot: Self::Snapshot) -> Self;
+}
+
+/// State of a replica at a specific position on the shared log, returned by
+/// [`Replica::checkpoint`](struct.Replica.html#method.checkpoint).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint<S> {
+    /// Logical offset of the first operation on the log that isn't reflected
+    /// in `snapshot`.
+    pub offset: LogOffset,
+
+    /// State of the data structure after executing all operations before
+    /// `offset`.
+    pub snapshot: S,
+}
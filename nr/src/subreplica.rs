@@ -0,0 +1,395 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Groups of threads that combine among themselves before handing their write
+//! operations to a replica shared by several such groups.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::cell::RefCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::context::{Context, MAX_PENDING_OPS};
+use crate::replica::{Replica, ReplicaError, ReplicaToken, MAX_THREADS_PER_REPLICA};
+use crate::Dispatch;
+
+/// A group of threads in front of a [Replica](struct.Replica.html) that is
+/// shared with other groups, e.g., the cores of one socket split into groups of
+/// a few cores each.
+///
+/// Threads of a group flat combine on a combiner lock of their own: whichever
+/// thread holds it collects the write operations of the whole group and hands
+/// them to the replica as a single batch, from a single thread of the replica.
+/// The combiner lock of the replica is then only contended by one thread per
+/// group, rather than by every thread on the socket. Reads bypass the group and
+/// go to the replica directly.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::{Dispatch, Log, Replica, SubReplica};
+///
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::default());
+/// let replica = Replica::<Data>::new(&log);
+///
+/// // Two groups of up to four threads share the replica.
+/// let first = SubReplica::new(&replica, 4).unwrap();
+/// let second = SubReplica::new(&replica, 4).unwrap();
+///
+/// let idx = first.register().unwrap();
+/// assert_eq!(first.execute_mut(2, idx), Ok(None));
+/// let idx = second.register().unwrap();
+/// assert_eq!(second.execute((), idx), Ok(Some(2)));
+/// ```
+pub struct SubReplica<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// The replica shared with the other groups.
+    replica: Arc<Replica<'a, D>>,
+
+    /// Identifier of the thread of `replica` that the combiner of this group
+    /// hands operations to.
+    slot: usize,
+
+    /// Identifier of the thread currently combining for the group. Zero if no
+    /// thread does; this doubles up as the combiner lock of the group.
+    combiner: CachePadded<AtomicUsize>,
+
+    /// Identifier that will be handed out to the next thread that joins.
+    next: CachePadded<AtomicUsize>,
+
+    /// Per-thread contexts, one for each thread that can join the group.
+    contexts: Vec<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>,
+
+    /// Identifiers the threads of the group are registered with on `replica`
+    /// for reads. Index `i` belongs to the thread with identifier `i + 1`.
+    readers: Vec<AtomicUsize>,
+
+    /// Operations collected by the combiner of the group. Only accessed by the
+    /// combiner.
+    buffer: RefCell<Vec<<D as Dispatch>::WriteOperation>>,
+
+    /// Number of operations collected from each thread, indexed like
+    /// `readers`. Only accessed by the combiner.
+    inflight: RefCell<Vec<usize>>,
+}
+
+/// The SubReplica is Sync. Member variables are protected by a CAS on
+/// `combiner`. Contexts are thread-safe.
+unsafe impl<'a, D> Sync for SubReplica<'a, D> where D: Sized + Dispatch + Sync {}
+
+impl<'a, D> core::fmt::Debug for SubReplica<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "SubReplica {{ slot: {}, members: {} }}",
+            self.slot,
+            self.next.load(Ordering::Relaxed) - 1
+        )
+    }
+}
+
+impl<'a, D> SubReplica<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Creates a group of at most `members` threads in front of `replica`.
+    /// Returns None if the group can't be registered with `replica`, which
+    /// happens once `MAX_THREADS_PER_REPLICA` threads are.
+    ///
+    /// # Panics
+    /// If `members` is zero or larger than `MAX_THREADS_PER_REPLICA`.
+    pub fn new(replica: &Arc<Replica<'a, D>>, members: usize) -> Option<Arc<SubReplica<'a, D>>> {
+        assert!(
+            members > 0 && members <= MAX_THREADS_PER_REPLICA,
+            "A group must have within 1..={} threads.",
+            MAX_THREADS_PER_REPLICA
+        );
+        let slot = replica.register()?;
+
+        let mut contexts = Vec::with_capacity(members);
+        let mut readers = Vec::with_capacity(members);
+        for _i in 0..members {
+            contexts.push(Default::default());
+            readers.push(AtomicUsize::new(0));
+        }
+
+        Some(Arc::new(SubReplica {
+            replica: replica.clone(),
+            slot: slot.id().get(),
+            combiner: CachePadded::new(AtomicUsize::new(0)),
+            next: CachePadded::new(AtomicUsize::new(1)),
+            contexts,
+            readers,
+            buffer: RefCell::new(Vec::with_capacity(members * MAX_PENDING_OPS)),
+            inflight: RefCell::new(alloc::vec![0; members]),
+        }))
+    }
+
+    /// Returns the replica shared with the other groups.
+    pub fn replica(&self) -> &Arc<Replica<'a, D>> {
+        &self.replica
+    }
+
+    /// Registers the calling thread with the group (and with the replica, for
+    /// reads). Returns None if the group is full or no thread can be
+    /// registered with the replica anymore.
+    pub fn register(&self) -> Option<ReplicaToken> {
+        let reader = self.replica.register()?;
+
+        let members = self.contexts.len();
+        match self
+            .next
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n <= members {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            }) {
+            Ok(idx) => {
+                self.readers[idx - 1].store(reader.id().get(), Ordering::Release);
+                Some(ReplicaToken::issue(idx))
+            }
+            Err(_) => {
+                self.replica.deregister(reader);
+                None
+            }
+        }
+    }
+
+    /// Executes a mutable operation against the data structure. The combiner of
+    /// the group appends it to the shared log, along with the operations of the
+    /// other threads of the group.
+    ///
+    /// # Errors
+    /// If the replica can no longer execute operations (see `ReplicaError`).
+    pub fn execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        let context = self.context(idx);
+
+        // The thread waits for the response of each operation before issuing
+        // the next one, so its context always has room.
+        let enqueued = context.enqueue(op);
+        debug_assert!(enqueued, "Context of a group member is full!");
+
+        loop {
+            self.try_combine(idx.id().get())?;
+            if let Some(resp) = context.res() {
+                return Ok(resp);
+            }
+            self.replica.failure()?;
+            spin_loop();
+        }
+    }
+
+    /// Executes a read-only operation against the replica, syncing it with the
+    /// shared log first.
+    ///
+    /// # Errors
+    /// If the replica can no longer execute operations (see `ReplicaError`).
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.replica.execute(op, self.reader(idx))
+    }
+
+    /// Brings the replica up to date with the shared log.
+    ///
+    /// # Errors
+    /// If the replica can no longer execute operations (see `ReplicaError`).
+    pub fn sync(&self, idx: ReplicaToken) -> Result<(), ReplicaError> {
+        self.replica.sync(self.reader(idx))
+    }
+
+    /// Returns the context of the thread `idx` was handed out to.
+    ///
+    /// # Panics
+    /// If `idx` wasn't handed out by this group.
+    fn context(
+        &self,
+        idx: ReplicaToken,
+    ) -> &Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response> {
+        idx.assert_owner();
+        let i = idx.id().get();
+        assert!(
+            i >= 1 && i < self.next.load(Ordering::Acquire),
+            "Thread {} isn't registered with this group!",
+            i
+        );
+        &self.contexts[i - 1]
+    }
+
+    /// Returns the token the thread `idx` was handed out to uses for reads on
+    /// the replica.
+    fn reader(&self, idx: ReplicaToken) -> ReplicaToken {
+        self.context(idx);
+        ReplicaToken::issue(self.readers[idx.id().get() - 1].load(Ordering::Acquire))
+    }
+
+    /// Combines for the group, unless another thread already does.
+    #[inline(always)]
+    fn try_combine(&self, tid: usize) -> Result<(), ReplicaError> {
+        if self.combiner.load(Ordering::Relaxed) != 0
+            || self
+                .combiner
+                .compare_exchange_weak(0, tid, Ordering::Acquire, Ordering::Acquire)
+                .is_err()
+        {
+            return Ok(());
+        }
+
+        let res = self.combine();
+        self.combiner.store(0, Ordering::Release);
+        res
+    }
+
+    /// Collects the pending operations of every thread of the group, executes
+    /// them on the replica and hands the responses back.
+    fn combine(&self) -> Result<(), ReplicaError> {
+        let mut buffer = self.buffer.borrow_mut();
+        let mut inflight = self.inflight.borrow_mut();
+        buffer.clear();
+
+        let next = self.next.load(Ordering::Acquire);
+        for i in 1..next {
+            inflight[i - 1] = self.contexts[i - 1].ops(&mut buffer, usize::MAX);
+        }
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        // Whichever thread holds the combiner lock of the group uses the slot,
+        // so the token is handed over to it.
+        let responses = self
+            .replica
+            .execute_mut_batch(&buffer, ReplicaToken::issue(self.slot))?;
+
+        let mut s = 0;
+        for i in 1..next {
+            let n = inflight[i - 1];
+            self.contexts[i - 1].enqueue_resps(&responses[s..s + n]);
+            s += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::Log;
+
+    use std::thread;
+    use std::vec;
+
+    #[derive(Default)]
+    struct Data {
+        junk: u64,
+    }
+
+    impl Dispatch for Data {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = u64;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.junk
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.junk += op;
+            self.junk
+        }
+    }
+
+    // Tests that a group only lets as many threads join as it was created for.
+    #[test]
+    fn test_subreplica_register() {
+        let log = Arc::new(Log::<u64>::default());
+        let replica = Replica::<Data>::new(&log);
+        let group = SubReplica::new(&replica, 2).unwrap();
+
+        assert_eq!(group.register().unwrap().id().get(), 1);
+        assert_eq!(group.register().unwrap().id().get(), 2);
+        assert!(group.register().is_none());
+
+        // The group and its two threads are registered with the replica; the
+        // thread that failed to join isn't.
+        assert_eq!(replica.register().unwrap().id().get(), 4);
+    }
+
+    // Tests that threads of several groups sharing a replica see each other's
+    // write operations.
+    #[test]
+    fn test_subreplica_groups() {
+        let log = Arc::new(Log::<u64>::default());
+        let replica = Replica::<Data>::new(&log);
+        let groups = [
+            SubReplica::new(&replica, 2).unwrap(),
+            SubReplica::new(&replica, 2).unwrap(),
+        ];
+
+        let mut threads = vec![];
+        for group in groups.iter() {
+            for _t in 0..2 {
+                let group = group.clone();
+                threads.push(thread::spawn(move || {
+                    let idx = group.register().unwrap();
+                    for _i in 0..1000 {
+                        assert!(group.execute_mut(1, idx).unwrap() <= 4000);
+                        assert!(group.execute((), idx).unwrap() <= 4000);
+                    }
+                }));
+            }
+        }
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+
+        replica.verify(|d: &Data| assert_eq!(d.junk, 4000));
+    }
+}
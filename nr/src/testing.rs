@@ -0,0 +1,966 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Helpers to test [Dispatch](../trait.Dispatch.html) implementations against
+//! node-replication (against a sequential execution, or for linearizability of
+//! concurrent histories, or deterministically on a single thread), and to test
+//! how an application copes with replicas that stall. Requires the `std`
+//! feature.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use std::sync::Arc;
+use std::thread;
+
+use crate::gc::{GcContext, GcHelpPolicy, HelpAction};
+use crate::log::{DeltaCodec, IdentityCodec, LogError};
+use crate::{Dispatch, Log, LogOffset, OpOrigin, Replica, ReplicaId, MAX_REPLICAS_PER_LOG};
+
+/// A xorshift pseudo-random number generator. Good enough to derive a
+/// reproducible schedule from a seed without pulling in a dependency.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state of a xorshift generator must never be zero.
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+/// Counts a thread of `differential` as finished when dropped, which also
+/// happens if the thread panics.
+struct Finished(Arc<AtomicUsize>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Executes `ops` on `replicas` replicas of `D` and compares the outcome against
+/// a sequential execution of the same operations on a fresh `D`.
+///
+/// Each replica gets one thread. Every operation is issued by a randomly picked
+/// thread, and threads randomly yield before issuing an operation; `seed`
+/// determines both (the actual interleaving remains up to the OS scheduler).
+/// While the threads run, the operations are applied to a fresh `D` in the
+/// order in which they appear on the shared log.
+///
+/// # Panics
+/// If an operation returns a different response on the replicas than in the
+/// sequential execution, or if the final state of a replica differs from the
+/// state after the sequential execution.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::testing::differential;
+/// use node_replication::Dispatch;
+///
+/// #[derive(Default, Debug, PartialEq)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let ops: Vec<u64> = (0..1000).collect();
+/// differential::<Data>(&ops, 4, 0xdead_beef);
+/// ```
+pub fn differential<D>(ops: &[<D as Dispatch>::WriteOperation], replicas: usize, seed: u64)
+where
+    D: Dispatch + Default + Debug + PartialEq + Send + Sync + 'static,
+    <D as Dispatch>::Response: Debug + PartialEq + Send,
+{
+    assert!(
+        replicas > 0 && replicas < MAX_REPLICAS_PER_LOG,
+        "Need between 1 and {} replicas.",
+        MAX_REPLICAS_PER_LOG - 1
+    );
+
+    let log = Arc::new(Log::<<D as Dispatch>::WriteOperation>::default());
+    let mut nr = Vec::with_capacity(replicas);
+    for _i in 0..replicas {
+        nr.push(Replica::<D>::new(&log));
+    }
+
+    // The sequential execution consumes the log through a slot of its own. As
+    // the log is fresh, the replicas above received identifiers 1..=replicas.
+    let seq_idx = log.register().expect("Failed to register with the log.");
+
+    // Decide which thread issues which operation and when it yields.
+    let mut rng = XorShift::new(seed);
+    let mut schedule: Vec<Vec<(<D as Dispatch>::WriteOperation, bool)>> =
+        (0..replicas).map(|_| Vec::new()).collect();
+    for op in ops.iter() {
+        let tid = rng.next() as usize % replicas;
+        schedule[tid].push((op.clone(), rng.next() % 4 == 0));
+    }
+
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut threads = Vec::with_capacity(replicas);
+    for (replica, ops) in nr.iter().zip(schedule) {
+        let replica = replica.clone();
+        let finished = Finished(finished.clone());
+        threads.push(thread::spawn(move || {
+            let _finished = finished;
+            let idx = replica
+                .register()
+                .expect("Failed to register with replica.");
+            let mut responses = Vec::with_capacity(ops.len());
+            for (op, yield_now) in ops.into_iter() {
+                if yield_now {
+                    thread::yield_now();
+                }
+                responses.push(
+                    replica
+                        .execute_mut(op, idx)
+                        .expect("Replica can no longer execute operations."),
+                );
+            }
+            responses
+        }));
+    }
+
+    // Replay the log on a fresh `D` while the threads are running, so that the
+    // slot of the sequential execution doesn't hold up garbage collection.
+    let mut seq = D::default();
+    let mut expected: Vec<Vec<<D as Dispatch>::Response>> =
+        (0..replicas).map(|_| Vec::new()).collect();
+    let mut replayed = 0;
+    loop {
+        // Once every thread is done (or panicked), everything they appended is
+        // on the log, so one more replay catches up with it.
+        let done = finished.load(Ordering::Acquire) == replicas;
+        log.exec_traced(
+            seq_idx,
+            &mut |op: <D as Dispatch>::WriteOperation, rid: ReplicaId, offset: LogOffset| {
+                let origin = OpOrigin {
+                    replica: rid,
+                    offset,
+                };
+                expected[rid.index()].push(seq.dispatch_mut_ctx(op, origin));
+                replayed += 1;
+            },
+        );
+        if replayed == ops.len() || done {
+            break;
+        }
+        thread::yield_now();
+    }
+
+    for (i, thread) in threads.into_iter().enumerate() {
+        let responses = thread.join().expect("Thread didn't finish successfully.");
+        assert_eq!(
+            responses,
+            expected[i],
+            "Responses of replica {} don't match the sequential execution.",
+            i + 1
+        );
+    }
+
+    for (i, replica) in nr.iter().enumerate() {
+        replica.verify(|data: &D| {
+            assert_eq!(
+                data,
+                &seq,
+                "State of replica {} doesn't match the sequential execution.",
+                i + 1
+            );
+        });
+    }
+}
+
+/// An operation that a thread issues against a replica in `linearizability`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op<R, W> {
+    /// A read-only operation, executed with `Replica::execute`.
+    Read(R),
+
+    /// A write operation, executed with `Replica::execute_mut`.
+    Write(W),
+}
+
+/// An operation that completed during a concurrent execution, as observed by
+/// the thread that issued it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Event<R, W, T> {
+    /// The thread that issued the operation.
+    pub thread: usize,
+
+    /// The operation.
+    pub op: Op<R, W>,
+
+    /// The response the replica returned.
+    pub response: T,
+
+    /// Logical time right before the operation was issued.
+    pub invoked: u64,
+
+    /// Logical time right after the operation returned.
+    pub returned: u64,
+}
+
+/// A concurrent execution of operations against replicas of `D`.
+pub type History<D> = Vec<
+    Event<
+        <D as Dispatch>::ReadOperation,
+        <D as Dispatch>::WriteOperation,
+        <D as Dispatch>::Response,
+    >,
+>;
+
+/// Checks whether `history` is linearizable with respect to `D`, i.e., whether
+/// there is a sequential order of its operations that respects their real-time
+/// order (an operation that returned before another one was invoked comes
+/// first) and in which executing them on a fresh `D` yields the observed
+/// responses.
+///
+/// Implements the search of Wing & Gong: it repeatedly picks an operation
+/// that no pending operation returned before, executes it on a copy of the
+/// model and backtracks if the response differs. The search is exponential in
+/// the number of overlapping operations in the worst case, so keep histories
+/// to a few hundred operations from a handful of threads. Write operations are
+/// executed with `Dispatch::dispatch_mut`, so `D` must not depend on the origin
+/// of operations.
+pub fn is_linearizable<D>(
+    history: &[Event<D::ReadOperation, D::WriteOperation, D::Response>],
+) -> bool
+where
+    D: Dispatch + Default + Clone,
+    <D as Dispatch>::Response: PartialEq,
+{
+    let mut linearized = vec![false; history.len()];
+    linearize(&D::default(), history, &mut linearized, history.len())
+}
+
+/// Tries to linearize the `left` events of `history` not yet marked in
+/// `linearized`, starting from `model`.
+fn linearize<D>(
+    model: &D,
+    history: &[Event<D::ReadOperation, D::WriteOperation, D::Response>],
+    linearized: &mut [bool],
+    left: usize,
+) -> bool
+where
+    D: Dispatch + Clone,
+    <D as Dispatch>::Response: PartialEq,
+{
+    if left == 0 {
+        return true;
+    }
+
+    // Only operations invoked before every pending operation returned can
+    // take effect next.
+    let horizon = history
+        .iter()
+        .zip(linearized.iter())
+        .filter(|(_e, done)| !**done)
+        .map(|(e, _done)| e.returned)
+        .min()
+        .unwrap_or(u64::MAX);
+
+    for i in 0..history.len() {
+        if linearized[i] || history[i].invoked > horizon {
+            continue;
+        }
+
+        let mut next = model.clone();
+        let response = match &history[i].op {
+            Op::Read(op) => next.dispatch(op.clone()),
+            Op::Write(op) => next.dispatch_mut(op.clone()),
+        };
+        if response != history[i].response {
+            continue;
+        }
+
+        linearized[i] = true;
+        if linearize(&next, history, linearized, left - 1) {
+            return true;
+        }
+        linearized[i] = false;
+    }
+
+    false
+}
+
+/// Executes `ops` from `threads` threads spread over `replicas` replicas of `D`,
+/// records when every operation was invoked and when it returned, and checks
+/// that the resulting history is linearizable with `is_linearizable`.
+///
+/// Every operation is issued by a randomly picked thread, and threads randomly
+/// yield before issuing an operation; `seed` determines both. Returns the
+/// history, e.g., to inspect it further.
+///
+/// # Panics
+/// If the history isn't linearizable; the message contains the history.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::testing::{linearizability, Op};
+/// use node_replication::Dispatch;
+///
+/// #[derive(Default, Clone)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let ops: Vec<Op<(), u64>> = (0..200)
+///     .map(|i| if i % 2 == 0 { Op::Write(i) } else { Op::Read(()) })
+///     .collect();
+/// linearizability::<Data>(&ops, 4, 2, 0xdead_beef);
+/// ```
+pub fn linearizability<D>(
+    ops: &[Op<D::ReadOperation, D::WriteOperation>],
+    threads: usize,
+    replicas: usize,
+    seed: u64,
+) -> History<D>
+where
+    D: Dispatch + Default + Clone + Send + Sync + 'static,
+    <D as Dispatch>::ReadOperation: Send,
+    <D as Dispatch>::Response: Debug + PartialEq + Send,
+{
+    assert!(threads > 0, "Need at least one thread.");
+    assert!(
+        replicas > 0 && replicas <= MAX_REPLICAS_PER_LOG,
+        "Need between 1 and {} replicas.",
+        MAX_REPLICAS_PER_LOG
+    );
+
+    let log = Arc::new(Log::<<D as Dispatch>::WriteOperation>::default());
+    let mut nr = Vec::with_capacity(replicas);
+    for _i in 0..replicas {
+        nr.push(Replica::<D>::new(&log));
+    }
+
+    let mut rng = XorShift::new(seed);
+    let mut schedule: Vec<Vec<_>> = (0..threads).map(|_| Vec::new()).collect();
+    for op in ops.iter() {
+        let tid = rng.next() as usize % threads;
+        schedule[tid].push((op.clone(), rng.next() % 4 == 0));
+    }
+
+    let clock = Arc::new(AtomicU64::new(0));
+    let mut handles = Vec::with_capacity(threads);
+    for (tid, ops) in schedule.into_iter().enumerate() {
+        let replica = nr[tid % replicas].clone();
+        let clock = clock.clone();
+        handles.push(thread::spawn(move || {
+            let idx = replica
+                .register()
+                .expect("Failed to register with replica.");
+            let mut events = Vec::with_capacity(ops.len());
+            for (op, yield_now) in ops.into_iter() {
+                if yield_now {
+                    thread::yield_now();
+                }
+                let invoked = clock.fetch_add(1, Ordering::SeqCst);
+                let response = match &op {
+                    Op::Read(op) => replica.execute(op.clone(), idx),
+                    Op::Write(op) => replica.execute_mut(op.clone(), idx),
+                }
+                .expect("Replica can no longer execute operations.");
+                let returned = clock.fetch_add(1, Ordering::SeqCst);
+                events.push(Event {
+                    thread: tid,
+                    op,
+                    response,
+                    invoked,
+                    returned,
+                });
+            }
+            events
+        }));
+    }
+
+    let mut history: History<D> = Vec::with_capacity(ops.len());
+    for handle in handles.into_iter() {
+        history.extend(handle.join().expect("Thread didn't finish successfully."));
+    }
+    history.sort_by_key(|e| e.invoked);
+
+    assert!(
+        is_linearizable::<D>(&history),
+        "History isn't linearizable: {:#?}",
+        history
+    );
+    history
+}
+
+/// A replica that stopped executing the shared log, as if the node it runs
+/// on stalled, returned by `pause`. Its local tail stays where it is, so the
+/// other replicas of the log eventually run out of space: `execute_mut` waits
+/// (as configured with `Replica::set_gc_policy`) and `try_execute_mut` fails
+/// with `WouldBlock::LogFull`. The threads of the replica itself wait until it
+/// is resumed or evicted.
+///
+/// Dropping it resumes the replica.
+pub struct Paused<'r, 'a, D, C = IdentityCodec>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    replica: &'r Replica<'a, D, C>,
+}
+
+/// Stops `replica` from executing the shared log until the returned
+/// [Paused](struct.Paused.html) is resumed, evicted or dropped. Waits for
+/// an active combiner (if any) to finish first.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::testing::pause;
+/// use node_replication::{Dispatch, Log, Replica, ReplicaError, WouldBlock};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::new(1));
+/// let (one, two) = (Replica::<Data>::new(&log), Replica::<Data>::new(&log));
+/// let (i1, i2) = (one.register().unwrap(), two.register().unwrap());
+///
+/// // `two` stalls; the log fills up behind it.
+/// let paused = pause(&two);
+/// while one.try_execute_mut(1, i1).is_ok() {}
+/// assert_eq!(one.try_execute_mut(1, i1), Err(WouldBlock::LogFull));
+///
+/// // Evicting `two` lets `one` go on, and `two` fails from then on.
+/// paused.evict();
+/// assert!(one.try_execute_mut(1, i1).is_ok());
+/// assert_eq!(two.execute((), i2), Err(ReplicaError::Desync));
+/// ```
+pub fn pause<'r, 'a, D, C>(replica: &'r Replica<'a, D, C>) -> Paused<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    while !replica.try_halt() {
+        spin_loop();
+    }
+    Paused { replica }
+}
+
+impl<'r, 'a, D, C> Paused<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    /// Returns the number of operations on the log the replica didn't execute
+    /// yet, i.e., how much it holds up garbage collection.
+    pub fn lag(&self) -> usize {
+        self.replica.lag()
+    }
+
+    /// Lets the replica execute the log again. It catches up the next time one
+    /// of its threads executes an operation or calls `Replica::sync`.
+    pub fn resume(self) {
+        // Dropping `self` resumes the replica.
+    }
+
+    /// Removes the replica from the log, like the application would with a
+    /// replica that doesn't recover, so that the other replicas don't wait for
+    /// it anymore. Operations on the replica fail with `ReplicaError::Desync`
+    /// from then on.
+    pub fn evict(self) {
+        self.replica.evict();
+    }
+}
+
+impl<'r, 'a, D, C> Drop for Paused<'r, 'a, D, C>
+where
+    D: Sized + Dispatch + Sync,
+    C: DeltaCodec<<D as Dispatch>::WriteOperation>,
+{
+    fn drop(&mut self) {
+        self.replica.resume();
+    }
+}
+
+/// A replica without threads, for deterministic tests of `Dispatch`
+/// implementations. Operations are appended to the shared log and executed
+/// right away on the calling thread, bypassing the contexts and the combiner
+/// of a [Replica](../struct.Replica.html), through the same `dispatch_mut_ctx`
+/// calls.
+///
+/// Several of them can share a log to test how replicas interleave: each one
+/// only executes the operations of the others once it executes an operation
+/// of its own or is synced, so the interleaving is up to the test.
+///
+/// # Panics
+/// Nothing runs in the background, so appending to a log that is full
+/// because another replica didn't execute it panics instead of waiting for
+/// that replica to catch up. Sync the replicas of a log every now and then.
+///
+/// # Example
+///
+/// ```
+/// use node_replication::testing::SyncReplica;
+/// use node_replication::{Dispatch, Log};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct Data {
+///     junk: u64,
+/// }
+///
+/// impl Dispatch for Data {
+///     type ReadOperation = ();
+///     type WriteOperation = u64;
+///     type Response = Option<u64>;
+///
+///     fn dispatch(
+///         &self,
+///         _op: Self::ReadOperation,
+///     ) -> Self::Response {
+///         Some(self.junk)
+///     }
+///
+///     fn dispatch_mut(
+///         &mut self,
+///         op: Self::WriteOperation,
+///     ) -> Self::Response {
+///         self.junk = op;
+///         None
+///     }
+/// }
+///
+/// let log = Arc::new(Log::<u64>::default());
+/// let mut one = SyncReplica::<Data>::new(&log);
+/// let mut two = SyncReplica::<Data>::new(&log);
+///
+/// assert_eq!(one.execute_mut(2), None);
+/// // `two` hasn't executed the log yet.
+/// assert_eq!(two.data().junk, 0);
+/// assert_eq!(two.execute_mut(3), None);
+/// assert_eq!(one.execute(()), Some(3));
+/// ```
+pub struct SyncReplica<'a, D>
+where
+    D: Sized + Dispatch,
+{
+    /// The shared log operations are appended to and executed from.
+    log: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+
+    /// Identifier of the replica on `log`.
+    idx: ReplicaId,
+
+    /// The data structure of the replica.
+    data: D,
+}
+
+/// Executes the log against the waiting replica while it is the one holding up
+/// garbage collection, and gives up otherwise: the replica that does runs on
+/// the same thread, so waiting for it would never end.
+struct ExecIfSlowest;
+
+impl GcHelpPolicy for ExecIfSlowest {
+    fn on_log_full(&self, ctx: GcContext) -> HelpAction {
+        if ctx.slowest == ctx.replica {
+            HelpAction::Exec
+        } else {
+            HelpAction::Error
+        }
+    }
+}
+
+impl<'a, D> SyncReplica<'a, D>
+where
+    D: Sized + Dispatch + Default,
+{
+    /// Creates a replica of a default-constructed `D` on `log`.
+    ///
+    /// # Panics
+    /// If `MAX_REPLICAS_PER_LOG` replicas are registered with `log` already.
+    pub fn new(log: &Arc<Log<'a, <D as Dispatch>::WriteOperation>>) -> SyncReplica<'a, D> {
+        SyncReplica::with_data(log, D::default())
+    }
+}
+
+impl<'a, D> SyncReplica<'a, D>
+where
+    D: Sized + Dispatch,
+{
+    /// Creates a replica of `data` on `log`.
+    ///
+    /// # Panics
+    /// If `MAX_REPLICAS_PER_LOG` replicas are registered with `log` already.
+    pub fn with_data(
+        log: &Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+        data: D,
+    ) -> SyncReplica<'a, D> {
+        let idx = log
+            .register()
+            .expect("Too many replicas registered with the log.");
+        SyncReplica {
+            log: log.clone(),
+            idx,
+            data,
+        }
+    }
+
+    /// Appends `op` to the shared log and executes the log up to and including
+    /// it. Returns the response of `op`.
+    pub fn execute_mut(
+        &mut self,
+        op: <D as Dispatch>::WriteOperation,
+    ) -> <D as Dispatch>::Response {
+        let (idx, data) = (self.idx, &mut self.data);
+        let mut f = |o: <D as Dispatch>::WriteOperation, i: ReplicaId, offset: LogOffset| {
+            data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+        };
+        match self
+            .log
+            .append_observed(&[op], idx, &mut f, &(), &ExecIfSlowest)
+        {
+            Ok(_offset) => {}
+            Err(LogError::LogFull) => panic!(
+                "The log is full and another replica holds up garbage collection; \
+                 sync it before appending more operations."
+            ),
+            Err(e) => panic!("Failed to append to the log: {:?}", e),
+        }
+
+        // Operations of this replica were all executed before the append, so
+        // the last one executed now is `op`.
+        let mut resp = None;
+        self.log.exec_traced(idx, &mut |o, i, offset| {
+            let r = data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+            if i == idx {
+                resp = Some(r);
+            }
+        });
+        resp.expect("Operation wasn't executed.")
+    }
+
+    /// Executes the shared log, then `op` against the data structure.
+    pub fn execute(&mut self, op: <D as Dispatch>::ReadOperation) -> <D as Dispatch>::Response {
+        self.sync();
+        self.data.dispatch(op)
+    }
+
+    /// Executes the operations other replicas appended to the shared log.
+    pub fn sync(&mut self) {
+        let data = &mut self.data;
+        self.log.exec_traced(self.idx, &mut |o, i, offset| {
+            data.dispatch_mut_ctx(o, OpOrigin { replica: i, offset });
+        });
+    }
+
+    /// Returns the data structure, as of the last operation the replica
+    /// executed (without syncing it first).
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+
+    /// Returns the identifier of the replica on the shared log.
+    pub fn id(&self) -> ReplicaId {
+        self.idx
+    }
+}
+
+impl<'a, D> Drop for SyncReplica<'a, D>
+where
+    D: Sized + Dispatch,
+{
+    /// Gives up the replica's slot on the shared log, so that the remaining
+    /// replicas don't wait for it.
+    fn drop(&mut self) {
+        self.log.retire(self.idx);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::vec;
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Stack {
+        storage: Vec<u32>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Op {
+        Push(u32),
+        Pop,
+    }
+
+    impl Dispatch for Stack {
+        type ReadOperation = ();
+        type WriteOperation = Op;
+        type Response = Option<u32>;
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            self.storage.last().cloned()
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            match op {
+                Op::Push(v) => {
+                    self.storage.push(v);
+                    None
+                }
+                Op::Pop => self.storage.pop(),
+            }
+        }
+    }
+
+    // Tests that the same seed always results in the same schedule.
+    #[test]
+    fn test_xorshift_deterministic() {
+        let mut a = XorShift::new(7);
+        let mut b = XorShift::new(7);
+        for _i in 0..100 {
+            assert_eq!(a.next(), b.next());
+        }
+        assert_ne!(XorShift::new(0).next(), 0);
+    }
+
+    // Tests that a stack replicated with node-replication behaves like a
+    // sequential one for a mix of pushes and pops.
+    #[test]
+    fn test_differential_stack() {
+        let mut ops = vec![];
+        for i in 0..5000 {
+            ops.push(if i % 3 == 2 { Op::Pop } else { Op::Push(i) });
+        }
+
+        differential::<Stack>(&ops, 1, 1);
+        differential::<Stack>(&ops, 4, 0xcafe);
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Brittle;
+
+    impl Dispatch for Brittle {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = ();
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {}
+
+        // Panics on the (unnamed) threads of `differential`, but not on the
+        // test thread that runs the sequential execution.
+        fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+            assert!(thread::current().name().is_some());
+        }
+    }
+
+    // Tests that `differential` reports a panicking thread instead of waiting
+    // for its operations to show up on the log.
+    #[test]
+    #[should_panic(expected = "Thread didn't finish successfully.")]
+    fn test_differential_thread_panics() {
+        let ops: Vec<u64> = (0..100).collect();
+        differential::<Brittle>(&ops, 2, 1);
+    }
+
+    // Tests that a paused replica holds up the log and its own threads, and
+    // that both go on once it is resumed.
+    #[test]
+    fn test_testing_pause_resume() {
+        let log = Arc::new(Log::<u64>::new(1));
+        let one = Replica::<Counter>::new(&log);
+        let two = Replica::<Counter>::new(&log);
+        let i1 = one.register().unwrap();
+
+        let paused = pause(&two);
+        let mut appended = 0;
+        while one.try_execute_mut(1, i1).is_ok() {
+            appended += 1;
+        }
+        assert_eq!(paused.lag(), appended);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let waiting = {
+            let (two, done) = (two.clone(), done.clone());
+            thread::spawn(move || {
+                let i2 = two.register().unwrap();
+                let r = two.execute_mut(1, i2);
+                done.store(true, Ordering::Relaxed);
+                r
+            })
+        };
+        thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!done.load(Ordering::Relaxed));
+
+        paused.resume();
+        assert_eq!(waiting.join().unwrap(), Ok(appended as u64 + 1));
+        one.sync(i1).unwrap();
+        assert!(one.try_execute_mut(1, i1).is_ok());
+    }
+
+    // Tests that the checker accepts responses that a sequential order explains
+    // and rejects those that contradict the real-time order.
+    #[test]
+    fn test_testing_is_linearizable() {
+        let event = |op: super::Op<(), u64>, response: u64, invoked: u64, returned: u64| Event {
+            thread: 0,
+            op,
+            response,
+            invoked,
+            returned,
+        };
+
+        // The two writes overlap, so either may go first.
+        let overlapping = vec![
+            event(super::Op::Write(1), 3, 0, 3),
+            event(super::Op::Write(2), 2, 1, 2),
+            event(super::Op::Read(()), 3, 4, 5),
+        ];
+        assert!(is_linearizable::<Counter>(&overlapping));
+
+        // The second write starts after the first returned.
+        let sequential = vec![
+            event(super::Op::Write(1), 3, 0, 1),
+            event(super::Op::Write(2), 2, 2, 3),
+        ];
+        assert!(!is_linearizable::<Counter>(&sequential));
+
+        // A read can't miss a write that returned before it started.
+        let stale = vec![
+            event(super::Op::Write(1), 1, 0, 1),
+            event(super::Op::Read(()), 0, 2, 3),
+        ];
+        assert!(!is_linearizable::<Counter>(&stale));
+    }
+
+    // Tests that concurrent reads and writes on several replicas form a
+    // linearizable history.
+    #[test]
+    fn test_testing_linearizability() {
+        let ops: Vec<super::Op<(), u64>> = (0..400)
+            .map(|i| {
+                if i % 3 == 0 {
+                    super::Op::Read(())
+                } else {
+                    super::Op::Write(i)
+                }
+            })
+            .collect();
+        let history = linearizability::<Counter>(&ops, 4, 2, 0xcafe);
+        assert_eq!(history.len(), ops.len());
+    }
+
+    // Tests that replicas sharing a log on one thread only see each other's
+    // operations once they execute the log, and end up in the same state.
+    #[test]
+    fn test_testing_sync_replica() {
+        let log = Arc::new(Log::<Op>::default());
+        let mut one = SyncReplica::<Stack>::new(&log);
+        let mut two = SyncReplica::<Stack>::new(&log);
+
+        assert_eq!(one.execute_mut(Op::Push(1)), None);
+        assert_eq!(one.execute_mut(Op::Push(2)), None);
+        assert_eq!(two.data().storage, vec![]);
+        assert_eq!(two.execute_mut(Op::Pop), Some(2));
+        assert_eq!(one.data().storage, vec![1, 2]);
+        assert_eq!(one.execute(()), Some(1));
+
+        two.sync();
+        assert_eq!(one.data(), two.data());
+    }
+
+    // Tests that appending to a log that another replica holds up panics
+    // instead of waiting forever, and that syncing that replica avoids it.
+    #[test]
+    fn test_testing_sync_replica_log_full() {
+        let log = Arc::new(Log::<Op>::new(1));
+        let mut one = SyncReplica::<Stack>::new(&log);
+        let mut two = SyncReplica::<Stack>::new(&log);
+
+        for i in 0..4 * log.capacity() {
+            one.execute_mut(Op::Push(i as u32));
+            two.sync();
+        }
+
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for i in 0..log.capacity() {
+                one.execute_mut(Op::Push(i as u32));
+            }
+        }));
+        assert!(r.is_err());
+    }
+}
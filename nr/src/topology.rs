@@ -0,0 +1,1326 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Placement of one replica per NUMA node of the machine, based on the
+//! topology Linux reports in sysfs. Requires the `std` feature.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use alloc::boxed::Box;
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use std::fs;
+use std::io;
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "stats")]
+use crate::advisor::{Bottleneck, SizingAdvice};
+use crate::api::ReplicaApi;
+use crate::gc::{AppendStall, GcHelpPolicy, HelpAction, StallHandler};
+use crate::ids::{LogOffset, ReplicaId, ThreadId};
+use crate::log::{Log, LogError};
+use crate::replica::{QuiesceReport, Replica, ReplicaError, ReplicaToken, SlotTaken};
+use crate::Dispatch;
+
+/// Parses a list of ranges as used by sysfs, e.g., `0-3,8,10-11`.
+fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut ids = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start: usize = bounds.next()?.parse().ok()?;
+        let end: usize = match bounds.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        ids.extend(start..=end);
+    }
+    Some(ids)
+}
+
+/// Reads a list of ranges from the sysfs file at `path`.
+fn read_list(path: &str) -> Option<Vec<usize>> {
+    parse_list(&fs::read_to_string(path).ok()?)
+}
+
+/// Returns the NUMA nodes of the machine that are online, in ascending order.
+/// Machines (or operating systems) that don't report their topology are
+/// treated as a single node, node 0.
+pub fn numa_nodes() -> Vec<usize> {
+    match read_list("/sys/devices/system/node/online") {
+        Some(nodes) if !nodes.is_empty() => nodes,
+        _ => alloc::vec![0],
+    }
+}
+
+/// Returns the NUMA node of the core the calling thread currently runs on, or
+/// node 0 if it can't be determined.
+///
+/// Can be installed with `set_current_node` so that replicas created by
+/// `NodeReplicated::with_topology` check in debug builds that they only
+/// combine on their node. Reads sysfs on every call.
+pub fn current_numa_node() -> usize {
+    #[cfg(target_os = "linux")]
+    {
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu >= 0 {
+            let dir = alloc::format!("/sys/devices/system/cpu/cpu{}", cpu);
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let node = name
+                        .to_str()
+                        .and_then(|n| n.strip_prefix("node"))
+                        .and_then(|n| n.parse().ok());
+                    if let Some(node) = node {
+                        return node;
+                    }
+                }
+            }
+        }
+    }
+    0
+}
+
+/// Restores the affinity of the calling thread when dropped. Returned by
+/// `AffinityManager::switch`.
+pub struct AffinityGuard {
+    /// Restores the previous affinity, unless the guard didn't change it.
+    restore: Option<Box<dyn FnOnce()>>,
+}
+
+impl AffinityGuard {
+    /// Creates a guard that calls `restore` when it is dropped.
+    pub fn new<F: FnOnce() + 'static>(restore: F) -> AffinityGuard {
+        AffinityGuard {
+            restore: Some(Box::new(restore)),
+        }
+    }
+
+    /// Creates a guard for a switch that left the affinity as it was.
+    pub fn unchanged() -> AffinityGuard {
+        AffinityGuard { restore: None }
+    }
+}
+
+impl Drop for AffinityGuard {
+    fn drop(&mut self) {
+        if let Some(restore) = self.restore.take() {
+            restore();
+        }
+    }
+}
+
+/// Moves the calling thread to a NUMA node, so that the memory it allocates
+/// and touches first is placed on that node. `NodeReplicated` switches to the
+/// node of every replica to create it, and to execute the log against it after
+/// `NodeReplicated::grow_log`.
+pub trait AffinityManager {
+    /// Moves the calling thread to NUMA node `node` until the returned guard
+    /// is dropped. Fails if the thread can't be moved; its affinity must be
+    /// unchanged then.
+    fn switch(&self, node: usize) -> io::Result<AffinityGuard>;
+}
+
+/// The `AffinityManager` used by `NodeReplicated::with_topology`: pins the
+/// calling thread to the cores Linux reports for a node in sysfs. Fails on
+/// other operating systems.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SysfsAffinity;
+
+impl AffinityManager for SysfsAffinity {
+    #[cfg(target_os = "linux")]
+    fn switch(&self, node: usize) -> io::Result<AffinityGuard> {
+        use core::mem::{size_of, zeroed};
+
+        let path = alloc::format!("/sys/devices/system/node/node{}/cpulist", node);
+        let cpus = read_list(&path).unwrap_or_default();
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                alloc::format!("no cores found for NUMA node {}", node),
+            ));
+        }
+
+        // `cpu_set_t` is plain old data; all-zero is the empty set.
+        let mut previous: libc::cpu_set_t = unsafe { zeroed() };
+        let mut set: libc::cpu_set_t = unsafe { zeroed() };
+        for cpu in cpus.iter().filter(|&&c| c < libc::CPU_SETSIZE as usize) {
+            unsafe { libc::CPU_SET(*cpu, &mut set) };
+        }
+
+        if unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut previous) } != 0
+            || unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(AffinityGuard::new(move || unsafe {
+            libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &previous);
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn switch(&self, _node: usize) -> io::Result<AffinityGuard> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pinning threads to NUMA nodes is only supported on Linux",
+        ))
+    }
+}
+
+/// Errors returned by `NodeReplicated::with_affinity`.
+#[derive(Debug)]
+pub enum TopologyError {
+    /// The log couldn't take another replica, or a replica couldn't be allocated.
+    Log(LogError),
+
+    /// The calling thread couldn't be moved to the node of a replica.
+    Affinity {
+        /// The node of the replica.
+        node: usize,
+        /// Why the `AffinityManager` failed.
+        error: io::Error,
+    },
+}
+
+impl From<LogError> for TopologyError {
+    fn from(e: LogError) -> TopologyError {
+        TopologyError::Log(e)
+    }
+}
+
+/// A data structure replicated once per NUMA node of the machine, with all
+/// replicas sharing one log. Created with `with_topology`; threads pick the
+/// replica of their node with `register_on_current_node`, or register with
+/// all replicas with `register_everywhere` to spread their reads.
+pub struct NodeReplicated<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// The log shared by all replicas.
+    log: Arc<Log<'a, <D as Dispatch>::WriteOperation>>,
+
+    /// The replicas along with the NUMA node each one was created on, in
+    /// ascending order of nodes.
+    replicas: Vec<(usize, Arc<Replica<'a, D>>)>,
+
+    /// Moves threads to the node of a replica, e.g., to execute the log
+    /// against it.
+    affinity: Arc<dyn AffinityManager + Send + Sync>,
+
+    /// Set by `shutdown`. Operations issued through this `NodeReplicated`
+    /// fail with `ReplicaError::ShuttingDown` from then on.
+    shutting_down: AtomicBool,
+}
+
+/// The tokens of a thread that is registered with every replica of a
+/// `NodeReplicated`, so that its reads can go to whichever replica answers
+/// them right away. Returned by `NodeReplicated::register_everywhere`.
+#[derive(Clone, Debug)]
+pub struct NodeToken {
+    /// The replica that executes the thread's write operations, as an index
+    /// into `NodeReplicated::replicas`.
+    home: usize,
+
+    /// The thread's token for every replica, in the order of `replicas`.
+    tokens: Vec<ReplicaToken>,
+}
+
+/// The dedicated combiner threads of a `NodeReplicated`, one per replica.
+/// Returned by `NodeReplicated::spawn_combiners`; dropping it stops the
+/// threads, after which threads registered with the replicas combine
+/// themselves again.
+pub struct Combiners {
+    /// Tells the combiner threads to stop.
+    stop: Arc<AtomicBool>,
+
+    /// The combiner threads.
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Combiners {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("A combiner thread panicked.");
+            }
+        }
+    }
+}
+
+impl<'a, D> NodeReplicated<'a, D>
+where
+    D: Sized + Default + Dispatch + Sync,
+{
+    /// Creates a log of the default size and one replica for every NUMA node
+    /// that is online (see `numa_nodes`).
+    ///
+    /// Each replica is created by the calling thread while it is temporarily
+    /// pinned to the cores of the replica's node (see `SysfsAffinity`). The
+    /// per-thread state of the replica and its copy of the data structure are
+    /// allocated and first touched there, so that the kernel places them on
+    /// that node. Every replica is assigned its node with `Replica::set_node`.
+    /// If the thread can't be pinned, a warning is logged and the replica is
+    /// created wherever the thread runs.
+    ///
+    /// Returns an error if the log doesn't accept a replica for every node or
+    /// if the memory for a replica can't be allocated.
+    pub fn with_topology() -> Result<NodeReplicated<'a, D>, LogError> {
+        NodeReplicated::create(Arc::new(SysfsAffinity), false).map_err(|e| match e {
+            TopologyError::Log(e) => e,
+            TopologyError::Affinity { .. } => unreachable!("Affinity errors are only logged."),
+        })
+    }
+
+    /// Like `with_topology`, but moves the calling thread to the node of every
+    /// replica with `affinity`, and fails if that doesn't work.
+    pub fn with_affinity<A>(affinity: A) -> Result<NodeReplicated<'a, D>, TopologyError>
+    where
+        A: AffinityManager + Send + Sync + 'static,
+    {
+        NodeReplicated::create(Arc::new(affinity), true)
+    }
+
+    /// Creates a replica for every NUMA node, on that node. Fails if the
+    /// thread can't be moved to a node and `strict` is set, otherwise logs a
+    /// warning.
+    fn create(
+        affinity: Arc<dyn AffinityManager + Send + Sync>,
+        strict: bool,
+    ) -> Result<NodeReplicated<'a, D>, TopologyError> {
+        let log = Arc::new(Log::<<D as Dispatch>::WriteOperation>::default());
+
+        let nodes = numa_nodes();
+        let mut replicas = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let replica = {
+                let _guard = match affinity.switch(node) {
+                    Ok(guard) => guard,
+                    Err(error) if strict => return Err(TopologyError::Affinity { node, error }),
+                    Err(error) => {
+                        warn!(
+                            "Failed to move thread to NUMA node {} ({}), memory may be placed on another node.",
+                            node, error
+                        );
+                        AffinityGuard::unchanged()
+                    }
+                };
+                Replica::<D>::try_new(&log)?
+            };
+            replica.set_node(node);
+            replicas.push((node, replica));
+        }
+
+        Ok(NodeReplicated {
+            log,
+            replicas,
+            affinity,
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+}
+
+impl<'a, D> NodeReplicated<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Returns the log shared by all replicas.
+    pub fn log(&self) -> &Arc<Log<'a, <D as Dispatch>::WriteOperation>> {
+        &self.log
+    }
+
+    /// Returns the NUMA nodes that have a replica, in ascending order.
+    pub fn nodes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.replicas.iter().map(|(node, _r)| *node)
+    }
+
+    /// Returns the replica of NUMA node `node`, if it has one.
+    pub fn replica(&self, node: usize) -> Option<&Arc<Replica<'a, D>>> {
+        self.replicas
+            .iter()
+            .find(|(n, _r)| *n == node)
+            .map(|(_n, r)| r)
+    }
+
+    /// Returns the offset up to which each replica executed the shared log (see
+    /// `Replica::applied_offset`), in the order of `nodes`. A replica with a higher offset holds a newer
+    /// version of the data structure; replicas with the same offset hold the
+    /// same one.
+    pub fn version(&self) -> Vec<u64> {
+        self.replicas
+            .iter()
+            .map(|(_node, r)| r.applied_offset())
+            .collect()
+    }
+
+    /// Registers the calling thread with the replica of the NUMA node it
+    /// currently runs on (see `current_numa_node`), or with the first replica if
+    /// that node has none. Returns the replica along with the thread's token,
+    /// or `None` if the replica has no room for another thread.
+    ///
+    /// The thread should stay on the node afterwards, e.g., by pinning it to
+    /// the node's cores before registering.
+    pub fn register_on_current_node(&self) -> Option<(Arc<Replica<'a, D>>, ReplicaToken)> {
+        let replica = self
+            .replica(current_numa_node())
+            .unwrap_or(&self.replicas[0].1);
+        let idx = replica.register()?;
+        Some((replica.clone(), idx))
+    }
+
+    /// Registers the calling thread with every replica, so that it can read
+    /// from any of them with `execute` and `execute_on`. Its write operations
+    /// go to the replica of the node it currently runs on, like with
+    /// `register_on_current_node`.
+    ///
+    /// Returns `None`, and keeps none of the registrations, if one of the
+    /// replicas has no room for another thread.
+    pub fn register_everywhere(&self) -> Option<NodeToken> {
+        let node = current_numa_node();
+        let home = self
+            .replicas
+            .iter()
+            .position(|(n, _r)| *n == node)
+            .unwrap_or(0);
+
+        let mut tokens = Vec::with_capacity(self.replicas.len());
+        for (_node, replica) in self.replicas.iter() {
+            match replica.register() {
+                Some(idx) => tokens.push(idx),
+                None => {
+                    for ((_node, replica), idx) in self.replicas.iter().zip(tokens) {
+                        replica.deregister(idx);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        Some(NodeToken { home, tokens })
+    }
+
+    /// Registers the calling thread with every replica, like
+    /// `register_everywhere`, under the identifier `desired` on all of them
+    /// (see `Replica::register_at`).
+    ///
+    /// Fails, and keeps none of the registrations, if `desired` is taken on
+    /// one of the replicas.
+    pub fn register_everywhere_at(&self, desired: ThreadId) -> Result<NodeToken, SlotTaken> {
+        let node = current_numa_node();
+        let home = self
+            .replicas
+            .iter()
+            .position(|(n, _r)| *n == node)
+            .unwrap_or(0);
+
+        let mut tokens = Vec::with_capacity(self.replicas.len());
+        for (_node, replica) in self.replicas.iter() {
+            match replica.register_at(desired) {
+                Ok(idx) => tokens.push(idx),
+                Err(e) => {
+                    for ((_node, replica), idx) in self.replicas.iter().zip(tokens) {
+                        replica.deregister(idx);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(NodeToken { home, tokens })
+    }
+
+    /// Executes the write operation `op` against the thread's own replica
+    /// (see `register_everywhere`) and returns its response.
+    pub fn execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        token: &NodeToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.accepting()?;
+        self.replicas[token.home]
+            .1
+            .execute_mut(op, token.tokens[token.home])
+    }
+
+    /// Executes the read-only operation `op` and returns its response. The read
+    /// goes to the thread's own replica if that replica executed every operation
+    /// completed on the log, and no combiner is busy writing to it. Otherwise it
+    /// goes to the first other replica that is in that state, so that a busy or
+    /// lagging replica doesn't hold up reads that any replica can answer. If no
+    /// replica can answer it right away, the thread's own replica catches up with
+    /// the log and executes it, like `Replica::execute` does.
+    ///
+    /// The read observes the effects of every operation that completed before
+    /// the call, whichever replica it goes to.
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        token: &NodeToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.execute_on(op, self.replicas[token.home].0, token)
+    }
+
+    /// Same as `execute`, but tries the replica of NUMA node `node` before the
+    /// thread's own replica, e.g., because the caller knows that the data it
+    /// reads is hot in that node's caches. `node` is only a hint: the read goes
+    /// to another replica if this one can't answer it right away, or if the node
+    /// has no replica.
+    pub fn execute_on(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        node: usize,
+        token: &NodeToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.accepting()?;
+
+        // Operations that completed before the call are at most up to here.
+        let ctail = self.log.get_ctail();
+
+        let first = self
+            .replicas
+            .iter()
+            .position(|(n, _r)| *n == node)
+            .unwrap_or(token.home);
+        let mut op = op;
+        for i in (0..self.replicas.len()).map(|i| (first + i) % self.replicas.len()) {
+            match self.replicas[i]
+                .1
+                .try_read_synced(op, token.tokens[i], ctail)
+            {
+                Ok(response) => return Ok(response),
+                Err(back) => op = back,
+            }
+        }
+
+        self.replicas[token.home]
+            .1
+            .execute(op, token.tokens[token.home])
+    }
+
+    /// Grows the shared log to `bytes` bytes without recreating the replicas,
+    /// e.g., because bursts of operations keep stalling on garbage collection.
+    /// See `Log::grow` for how the size is rounded and when it fails.
+    ///
+    /// Stops the world while the log grows: waits for active combiners to
+    /// finish and keeps threads from combining on any replica (their operations
+    /// wait) until every replica executed the log and the log grew. The
+    /// replicas then execute the new log on their nodes, which the calling
+    /// thread switches to with the `AffinityManager` of the replicas.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let nr = NodeReplicated::<Data>::with_topology().unwrap();
+    /// let capacity = nr.log().capacity();
+    ///
+    /// nr.grow_log(64 * 1024 * 1024).unwrap();
+    /// assert_eq!(nr.log().capacity(), 2 * capacity);
+    /// ```
+    pub fn grow_log(&self, bytes: usize) -> Result<(), LogError> {
+        // Replicas we don't know about could append while the log grows.
+        if self.log.registered() != self.replicas.len() {
+            return Err(LogError::ForeignReplicas);
+        }
+
+        // A combiner we wait for might itself wait for a stopped replica to
+        // execute the log and free up space on it, so keep them going.
+        let mut halted = 0;
+        while halted < self.replicas.len() {
+            if self.replicas[halted].1.try_halt() {
+                halted += 1;
+                continue;
+            }
+            for (_node, replica) in self.replicas[..halted].iter() {
+                let _r = replica.exec_halted();
+            }
+            spin_loop();
+        }
+
+        // Replicas that failed can't execute the log anymore; they fall off it.
+        for (_node, replica) in self.replicas.iter() {
+            let _r = replica.exec_halted();
+        }
+        let r = unsafe { self.log.grow(bytes) };
+
+        // The replicas have to resume even if the thread can't move to them.
+        for (node, replica) in self.replicas.iter() {
+            let _guard = self.affinity.switch(*node).unwrap_or_else(|error| {
+                warn!(
+                    "Failed to move thread to NUMA node {} ({}), executing the log from here.",
+                    node, error
+                );
+                AffinityGuard::unchanged()
+            });
+            replica.resume();
+        }
+        r
+    }
+
+    /// Grows the shared log if `Log::advise` finds that its size is what holds
+    /// up appends (`Bottleneck::LogSize`), and returns the advice either way.
+    /// Call it once the advice is `complete`, e.g., from a thread that checks
+    /// every few minutes; growing restarts the observations of the log.
+    #[cfg(feature = "stats")]
+    pub fn grow_log_as_advised(&self) -> Result<SizingAdvice, LogError> {
+        let advice = self.log.advise();
+        if advice.bottleneck == Bottleneck::LogSize {
+            self.grow_log(advice.suggested_bytes)?;
+        }
+        Ok(advice)
+    }
+
+    /// Waits until every replica executed every operation appended to the log
+    /// before the call, e.g., to take a consistent backup from any of them.
+    /// The returned offset is the barrier: the state of every replica includes
+    /// all operations before it.
+    ///
+    /// Executes the log against replicas that are behind whenever nobody else
+    /// is combining on them, so replicas without active threads don't hold up
+    /// the call. Fails if one of the replicas can no longer execute operations.
+    ///
+    /// # Note
+    /// Operations that threads enqueued but that no combiner appended yet
+    /// aren't covered; `Replica::quiesce` waits for those as well. Replicas of
+    /// the log that weren't created along with the others have to be kept in
+    /// sync by their threads, otherwise this method never returns.
+    pub fn quiesce(&self) -> Result<QuiesceReport, ReplicaError> {
+        let offset = self.log.get_tail();
+        while !self.log.is_synced(offset) {
+            for (_node, replica) in self.replicas.iter() {
+                replica.help_sync()?;
+            }
+            spin_loop();
+        }
+
+        Ok(QuiesceReport {
+            offset: LogOffset::new(offset),
+        })
+    }
+
+    /// Executes outstanding operations on the log against the replica with the
+    /// identifier `stalled` on behalf of the calling thread, e.g., the
+    /// `AppendStall::slowest` replica that holds up garbage collection because
+    /// none of its threads is active. Takes the combiner lock of that replica
+    /// from wherever the thread runs, unless a combiner is active on it already
+    /// (which then executes the operations). See `enable_helping` for helping
+    /// automatically.
+    ///
+    /// Returns false if none of the replicas has the identifier `stalled`.
+    /// Fails if the replica can no longer execute operations.
+    pub fn try_help(&self, stalled: ReplicaId) -> Result<bool, ReplicaError> {
+        match self.replicas.iter().find(|(_node, r)| r.id() == stalled) {
+            Some((_node, replica)) => replica.help_sync().map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Shuts the replicas down, e.g., before dropping them while threads may
+    /// still hold tokens for them. From the call on, operations issued through
+    /// this `NodeReplicated` fail with `ReplicaError::ShuttingDown`. The
+    /// operations threads already enqueued on a replica are executed, and
+    /// every replica executes the log (like `quiesce`). Then the replicas fail
+    /// with `ReplicaError::ShuttingDown`, so that threads still using them
+    /// directly get an error instead of waiting, and combiner threads (see
+    /// `spawn_combiners`) stop.
+    ///
+    /// Returns the offset up to which every replica executed the log. Fails if
+    /// one of the replicas can no longer execute operations, or with
+    /// `ReplicaError::ShuttingDown` if it was called before.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, NodeReplicated, ReplicaError};
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let nr = NodeReplicated::<Data>::with_topology().unwrap();
+    /// let token = nr.register_everywhere().unwrap();
+    /// assert_eq!(nr.execute_mut(3, &token), Ok(None));
+    ///
+    /// nr.shutdown().unwrap();
+    /// assert_eq!(nr.execute_mut(4, &token), Err(ReplicaError::ShuttingDown));
+    /// ```
+    pub fn shutdown(&self) -> Result<QuiesceReport, ReplicaError> {
+        if self.shutting_down.swap(true, Ordering::AcqRel) {
+            return Err(ReplicaError::ShuttingDown);
+        }
+
+        // Combining on a replica may wait for the others to execute the log,
+        // so keep them going.
+        for (node, replica) in self.replicas.iter() {
+            let _guard = self.affinity.switch(*node).unwrap_or_else(|error| {
+                warn!(
+                    "Failed to move thread to NUMA node {} ({}), draining the replica from here.",
+                    node, error
+                );
+                AffinityGuard::unchanged()
+            });
+            while !replica.try_drain()? {
+                for (_node, other) in self.replicas.iter() {
+                    other.help_sync()?;
+                }
+                spin_loop();
+            }
+        }
+
+        let report = self.quiesce()?;
+        for (_node, replica) in self.replicas.iter() {
+            replica.shut_down();
+        }
+        Ok(report)
+    }
+
+    /// Fails with `ReplicaError::ShuttingDown` after `shutdown`.
+    fn accepting(&self) -> Result<(), ReplicaError> {
+        if self.shutting_down.load(Ordering::Acquire) {
+            return Err(ReplicaError::ShuttingDown);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, D> Drop for NodeReplicated<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// In debug builds, checks that no thread has operations in flight on the
+    /// replicas, unless they were shut down with `shutdown`.
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        if !*self.shutting_down.get_mut() && !thread::panicking() {
+            for (node, replica) in self.replicas.iter() {
+                assert!(
+                    replica.is_drained(),
+                    "The replica of NUMA node {} has operations in flight; shut it down before dropping it.",
+                    node
+                );
+            }
+        }
+    }
+}
+
+/// Number of rounds a combiner thread finds nothing to do before it yields
+/// its core to other threads.
+const IDLE_SPINS: usize = 1 << 10;
+
+/// Upper bound for the number of iterations a replica spins for in one round
+/// of waiting for space on the log before helping another one (see
+/// `enable_helping`).
+const HELP_SPINS: usize = 1 << 10;
+
+/// Returns a policy for a replica that, once it waited `threshold` rounds for
+/// space on the log, executes the log against whichever of `replicas` holds up
+/// garbage collection. Holds on to the replicas weakly, as they hold on to
+/// their policies.
+fn helper<D>(
+    replicas: Vec<Weak<Replica<'static, D>>>,
+    threshold: usize,
+) -> impl GcHelpPolicy + Send + Sync
+where
+    D: Sized + Dispatch + Sync + Send + 'static,
+    <D as Dispatch>::WriteOperation: Send + Sync,
+    <D as Dispatch>::Response: Send,
+{
+    StallHandler::new(threshold, HELP_SPINS, move |stall: AppendStall| {
+        let stalled = replicas
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|replica| replica.id() == stall.slowest);
+        if let Some(replica) = stalled {
+            // A replica that failed can't be helped; keep waiting like
+            // without a helper.
+            if let Err(e) = replica.help_sync() {
+                debug!("Failed to help replica {:?}: {:?}", stall.slowest, e);
+            }
+        }
+        HelpAction::Exec
+    })
+}
+
+impl<D> NodeReplicated<'static, D>
+where
+    D: Sized + Dispatch + Sync + Send + 'static,
+    <D as Dispatch>::WriteOperation: Send + Sync,
+    <D as Dispatch>::Response: Send,
+{
+    /// Launches a dedicated combiner thread for every replica, on the replica's
+    /// NUMA node, for server workloads that would rather not spend the cores
+    /// of the threads issuing operations on flat combining. The combiner
+    /// threads keep collecting operations from the threads registered with
+    /// their replica, appending them to the log and executing the log. These
+    /// threads only enqueue their operations and park until the combiner
+    /// hands out their responses.
+    ///
+    /// The combiner threads run until the returned `Combiners` is dropped.
+    /// Each one busy waits for operations, yielding its core once in a while
+    /// if there are none, so it should get a core of its own. A combiner
+    /// thread also stops if its replica fails; the threads registered with
+    /// the replica see the failure as usual.
+    ///
+    /// # Panics
+    /// If combiner threads were already launched for one of the replicas.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use node_replication::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Data {
+    ///     junk: u64,
+    /// }
+    ///
+    /// impl Dispatch for Data {
+    ///     type ReadOperation = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = Option<u64>;
+    ///
+    ///     fn dispatch(
+    ///         &self,
+    ///         _op: Self::ReadOperation,
+    ///     ) -> Self::Response {
+    ///         Some(self.junk)
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.junk = op;
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// let nr = NodeReplicated::<Data>::with_topology().unwrap();
+    /// let combiners = nr.spawn_combiners();
+    ///
+    /// let token = nr.register_everywhere().unwrap();
+    /// assert_eq!(nr.execute_mut(3, &token), Ok(None));
+    /// assert_eq!(nr.execute((), &token), Ok(Some(3)));
+    ///
+    /// drop(combiners);
+    /// assert_eq!(nr.execute_mut(4, &token), Ok(None));
+    /// ```
+    pub fn spawn_combiners(&self) -> Combiners {
+        for (node, replica) in self.replicas.iter() {
+            assert!(
+                replica.start_background(),
+                "The replica of NUMA node {} already has a combiner thread.",
+                node
+            );
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = self
+            .replicas
+            .iter()
+            .map(|(node, replica)| {
+                let (node, replica) = (*node, replica.clone());
+                let (stop, affinity) = (stop.clone(), self.affinity.clone());
+                thread::spawn(move || {
+                    let _guard = affinity.switch(node).unwrap_or_else(|error| {
+                        warn!(
+                            "Failed to move combiner thread to NUMA node {} ({}), combining from here.",
+                            node, error
+                        );
+                        AffinityGuard::unchanged()
+                    });
+
+                    let mut idle = 0;
+                    while !stop.load(Ordering::Acquire) {
+                        match replica.combine_background() {
+                            Ok(true) => idle = 0,
+                            Ok(false) if idle < IDLE_SPINS => {
+                                idle += 1;
+                                spin_loop();
+                            }
+                            Ok(false) => {
+                                idle = 0;
+                                thread::yield_now();
+                            }
+                            Err(_e) => break,
+                        }
+                    }
+                    replica.stop_background();
+                })
+            })
+            .collect();
+
+        Combiners { stop, threads }
+    }
+
+    /// Makes the replicas help each other with garbage collection: once a
+    /// combiner waited `threshold` rounds for space on the log (backing off
+    /// in the meantime), it executes the log against the replica holding up
+    /// garbage collection (like `try_help`), so that replicas without active
+    /// threads don't make the others spin until one of their threads shows
+    /// up. Replaces the `GcHelpPolicy` of every replica.
+    ///
+    /// # Panics
+    /// If `threshold` is zero.
+    pub fn enable_helping(&self, threshold: usize) {
+        let replicas: Vec<_> = self
+            .replicas
+            .iter()
+            .map(|(_node, replica)| Arc::downgrade(replica))
+            .collect();
+        for (_node, replica) in self.replicas.iter() {
+            replica.set_gc_policy(Arc::new(helper(replicas.clone(), threshold)));
+        }
+    }
+}
+
+impl<'a, D> ReplicaApi<D> for NodeReplicated<'a, D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    type Token = NodeToken;
+
+    fn register(&self) -> Option<NodeToken> {
+        self.register_everywhere()
+    }
+
+    fn exec(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        token: &NodeToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.execute_mut(op, token)
+    }
+
+    fn exec_ro(
+        &self,
+        op: <D as Dispatch>::ReadOperation,
+        token: &NodeToken,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError> {
+        self.execute(op, token)
+    }
+
+    /// Syncs every replica the thread is registered with.
+    fn sync(&self, token: &NodeToken) -> Result<(), ReplicaError> {
+        for ((_node, replica), idx) in self.replicas.iter().zip(token.tokens.iter()) {
+            replica.sync(*idx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::Counter;
+    use std::vec;
+
+    /// Records the nodes it switches to, and how many guards were dropped.
+    #[derive(Clone, Default)]
+    struct Recording {
+        switches: Arc<std::sync::Mutex<Vec<usize>>>,
+        restores: Arc<core::sync::atomic::AtomicUsize>,
+        fail: bool,
+    }
+
+    impl AffinityManager for Recording {
+        fn switch(&self, node: usize) -> io::Result<AffinityGuard> {
+            if self.fail {
+                return Err(io::Error::new(io::ErrorKind::Other, "failed"));
+            }
+            self.switches.lock().unwrap().push(node);
+            let restores = self.restores.clone();
+            Ok(AffinityGuard::new(move || {
+                restores.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            }))
+        }
+    }
+
+    // Tests that replicas are created and resumed after growing the log on
+    // their nodes, and that the affinity is restored every time.
+    #[test]
+    fn test_topology_with_affinity() {
+        let affinity = Recording::default();
+        let nr = NodeReplicated::<Counter>::with_affinity(affinity.clone()).unwrap();
+        assert_eq!(*affinity.switches.lock().unwrap(), numa_nodes());
+        assert_eq!(
+            affinity
+                .restores
+                .load(core::sync::atomic::Ordering::Relaxed),
+            numa_nodes().len()
+        );
+
+        nr.grow_log(64 * 1024 * 1024).unwrap();
+        assert_eq!(
+            affinity.switches.lock().unwrap().len(),
+            2 * numa_nodes().len()
+        );
+        assert_eq!(
+            affinity
+                .restores
+                .load(core::sync::atomic::Ordering::Relaxed),
+            2 * numa_nodes().len()
+        );
+    }
+
+    // Tests that with_affinity() fails if the thread can't switch nodes.
+    #[test]
+    fn test_topology_with_affinity_error() {
+        let affinity = Recording {
+            fail: true,
+            ..Default::default()
+        };
+        match NodeReplicated::<Counter>::with_affinity(affinity) {
+            Err(TopologyError::Affinity { node, .. }) => assert_eq!(node, numa_nodes()[0]),
+            _ => panic!("Expected an affinity error."),
+        }
+    }
+
+    // Tests that operations of several threads complete while combiner
+    // threads run, and that threads combine themselves once they stopped.
+    #[test]
+    fn test_topology_spawn_combiners() {
+        let nr = Arc::new(NodeReplicated::<Counter>::with_topology().unwrap());
+        let combiners = nr.spawn_combiners();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_i| {
+                let nr = nr.clone();
+                thread::spawn(move || {
+                    let token = nr.register_everywhere().unwrap();
+                    for _j in 0..100 {
+                        nr.execute_mut(1, &token).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let token = nr.register_everywhere().unwrap();
+        assert_eq!(nr.execute((), &token), Ok(400));
+        drop(combiners);
+        assert_eq!(nr.execute_mut(1, &token), Ok(401));
+    }
+
+    // Tests that operations fail after a shutdown, both through the
+    // `NodeReplicated` and on its replicas, and that combiner threads stop.
+    #[test]
+    fn test_topology_shutdown() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let combiners = nr.spawn_combiners();
+        let token = nr.register_everywhere().unwrap();
+        let (replica, idx) = nr.register_on_current_node().unwrap();
+        assert_eq!(nr.execute_mut(3, &token), Ok(3));
+        assert_eq!(replica.execute_mut(4, idx), Ok(7));
+
+        let report = nr.shutdown().unwrap();
+        assert_eq!(report.offset, LogOffset::new(2));
+        assert_eq!(nr.shutdown(), Err(ReplicaError::ShuttingDown));
+        assert_eq!(nr.execute_mut(1, &token), Err(ReplicaError::ShuttingDown));
+        assert_eq!(nr.execute((), &token), Err(ReplicaError::ShuttingDown));
+        assert_eq!(replica.execute_mut(1, idx), Err(ReplicaError::ShuttingDown));
+        drop(combiners);
+    }
+
+    // Tests that try_help() executes the log against the replica it is asked to
+    // help, and ignores replicas it doesn't know about.
+    #[test]
+    fn test_topology_try_help() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let (replica, idx) = nr.register_on_current_node().unwrap();
+        let other = Replica::<Counter>::new(nr.log());
+        let oidx = other.register().unwrap();
+        assert_eq!(other.execute_mut(3, oidx), Ok(3));
+
+        let offset = nr.log().get_tail();
+        assert!(!nr.log().is_synced(offset));
+        for (_node, replica) in nr.replicas.iter() {
+            assert_eq!(nr.try_help(replica.id()), Ok(true));
+        }
+        assert_eq!(nr.try_help(other.id()), Ok(false));
+        assert!(nr.log().is_synced(offset));
+        assert_eq!(replica.execute((), idx), Ok(3));
+    }
+
+    // Tests that a replica waiting for space on the log executes it against the
+    // replica without active threads, and that replicas that are gone are
+    // skipped.
+    #[test]
+    fn test_topology_helper() {
+        let log = Arc::new(Log::<u64>::new(1));
+        let one = Replica::<Counter>::new(&log);
+        let two = Replica::<Counter>::new(&log);
+        let gone = Replica::<Counter>::new(&log);
+        let replicas = vec![Arc::downgrade(&gone), Arc::downgrade(&two)];
+        log.retire(gone.id());
+        drop(gone);
+        one.set_gc_policy(Arc::new(helper(replicas, 2)));
+
+        let idx = one.register().unwrap();
+        for _i in 0..4 * log.capacity() {
+            one.execute_mut(1, idx).unwrap();
+        }
+        let tidx = two.register().unwrap();
+        assert_eq!(two.execute((), tidx), Ok(4 * log.capacity() as u64));
+    }
+
+    // Tests that lists of ranges in the format of sysfs are parsed.
+    #[test]
+    fn test_topology_parse_list() {
+        assert_eq!(parse_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_list("0-2,4,6-7"), Some(vec![0, 1, 2, 4, 6, 7]));
+        assert_eq!(parse_list(""), Some(vec![]));
+        assert_eq!(parse_list("0-x"), None);
+    }
+
+    // Tests that there is a replica for every node, and that threads registered
+    // on the current node execute against replicas sharing the same log.
+    #[test]
+    fn test_topology_with_topology() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        assert_eq!(nr.nodes().collect::<Vec<usize>>(), numa_nodes());
+
+        let (replica, idx) = nr.register_on_current_node().unwrap();
+        assert_eq!(replica.execute_mut(5, idx), Ok(5));
+
+        for node in numa_nodes() {
+            let other = nr.replica(node).unwrap();
+            let idx = other.register().unwrap();
+            assert_eq!(other.execute((), idx), Ok(5));
+        }
+    }
+
+    // Tests that a thread gets the same identifier on every replica, and that
+    // no replica keeps a registration if the identifier is taken on one.
+    #[test]
+    fn test_topology_register_everywhere_at() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let desired = ThreadId::new(7);
+        let token = nr.register_everywhere_at(desired).unwrap();
+        assert!(token.tokens.iter().all(|t| t.id() == desired));
+        assert_eq!(nr.execute_mut(1, &token), Ok(1));
+
+        assert_eq!(
+            nr.register_everywhere_at(desired).map(|t| t.home),
+            Err(SlotTaken(desired))
+        );
+        let last = nr.replicas.last().unwrap().1.clone();
+        let theirs = last.register_at(ThreadId::new(8)).unwrap();
+        assert!(nr.register_everywhere_at(ThreadId::new(8)).is_err());
+        last.deregister(theirs);
+        assert!(nr.register_everywhere_at(ThreadId::new(8)).is_ok());
+    }
+
+    // Tests that the register/exec/exec_ro/sync of `ReplicaApi` reach the
+    // replicas of a `NodeReplicated`.
+    #[test]
+    fn test_topology_replica_api() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let token = ReplicaApi::register(&nr).unwrap();
+        assert_eq!(nr.exec(2, &token), Ok(2));
+        assert_eq!(nr.exec(3, &token), Ok(5));
+        assert_eq!(ReplicaApi::sync(&nr, &token), Ok(()));
+        assert_eq!(nr.exec_ro((), &token), Ok(5));
+    }
+
+    // Tests that the log grows while threads issue operations against the
+    // replicas, and that no operation gets lost in the process.
+    #[test]
+    fn test_topology_grow_log() {
+        let nr = Arc::new(NodeReplicated::<Counter>::with_topology().unwrap());
+        let capacity = nr.log().capacity();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let nr = nr.clone();
+                std::thread::spawn(move || {
+                    let (replica, idx) = nr.register_on_current_node().unwrap();
+                    for _i in 0..10_000 {
+                        replica.execute_mut(1, idx).unwrap();
+                    }
+                })
+            })
+            .collect();
+        assert_eq!(nr.grow_log(64 * 1024 * 1024), Ok(()));
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(nr.log().capacity(), 2 * capacity);
+        for node in nr.nodes() {
+            nr.replica(node)
+                .unwrap()
+                .verify(|c: &Counter| assert_eq!(c.0, 40_000));
+        }
+    }
+
+    // Tests that quiesce() returns once every replica executed the operations
+    // issued before, even the replicas without threads.
+    #[test]
+    fn test_topology_quiesce() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let (replica, idx) = nr.register_on_current_node().unwrap();
+        for _i in 0..100 {
+            replica.execute_mut(1, idx).unwrap();
+        }
+
+        let report = nr.quiesce().unwrap();
+        assert_eq!(report.offset, LogOffset::new(nr.log().get_tail()));
+        assert!(nr.log().is_synced(report.offset.get()));
+        for node in nr.nodes() {
+            let other = nr.replica(node).unwrap();
+            let idx = other.register().unwrap();
+            assert_eq!(other.execute((), idx), Ok(100));
+        }
+    }
+
+    // Tests that version() follows the operations each replica executed.
+    #[test]
+    fn test_topology_version() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        assert_eq!(nr.version(), vec![0; numa_nodes().len()]);
+
+        let (replica, idx) = nr.register_on_current_node().unwrap();
+        replica.execute_mut(1, idx).unwrap();
+        replica.execute_mut(1, idx).unwrap();
+        let version = nr.version();
+        assert!(version.iter().all(|&v| v <= 2));
+        assert!(version.contains(&2));
+
+        nr.quiesce().unwrap();
+        assert_eq!(nr.version(), vec![2; numa_nodes().len()]);
+    }
+
+    /// A counter that also tells which replica answered a read.
+    #[derive(Default)]
+    struct Tagged {
+        tag: usize,
+        count: u64,
+    }
+
+    impl Dispatch for Tagged {
+        type ReadOperation = ();
+        type WriteOperation = u64;
+        type Response = (usize, u64);
+
+        fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+            (self.tag, self.count)
+        }
+
+        fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+            self.count += op;
+            (self.tag, self.count)
+        }
+    }
+
+    // Tests that reads go to the hinted replica once it is synced, that they
+    // go to a replica that is synced while the hinted one lags behind, and that
+    // they always observe the writes that completed before.
+    #[test]
+    fn test_topology_execute_on() {
+        let log = Arc::new(Log::<u64>::default());
+        let replicas = vec![
+            (0, Replica::with_data(&log, Tagged { tag: 0, count: 0 })),
+            (1, Replica::with_data(&log, Tagged { tag: 1, count: 0 })),
+        ];
+        let nr = NodeReplicated {
+            log,
+            replicas,
+            affinity: Arc::new(SysfsAffinity),
+            shutting_down: AtomicBool::new(false),
+        };
+        let token = nr.register_everywhere().unwrap();
+        let (home, other) = (nr.replicas[token.home].0, nr.replicas[1 - token.home].0);
+
+        assert_eq!(nr.execute_mut(5, &token), Ok((home, 5)));
+        assert_eq!(nr.execute_on((), other, &token), Ok((home, 5)));
+        assert!(!nr.log().is_synced(nr.log().get_tail()));
+
+        nr.quiesce().unwrap();
+        assert_eq!(nr.execute_on((), other, &token), Ok((other, 5)));
+        assert_eq!(nr.execute((), &token), Ok((home, 5)));
+        assert_eq!(nr.execute_on((), 7, &token), Ok((home, 5)));
+    }
+
+    // Tests that a thread registers with every replica or with none of them.
+    #[test]
+    fn test_topology_register_everywhere() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let full = nr.replicas.last().unwrap().1.clone();
+        let held: Vec<ReplicaToken> = core::iter::from_fn(|| full.register()).collect();
+        assert!(nr.register_everywhere().is_none());
+
+        full.deregister(held[0]);
+        let token = nr.register_everywhere().unwrap();
+        assert_eq!(token.tokens.len(), nr.replicas.len());
+        assert_eq!(nr.execute_mut(3, &token), Ok(3));
+        assert_eq!(nr.execute((), &token), Ok(3));
+    }
+
+    // Tests that the log doesn't grow if it has replicas that weren't created
+    // along with the others.
+    #[test]
+    fn test_topology_grow_log_foreign() {
+        let nr = NodeReplicated::<Counter>::with_topology().unwrap();
+        let _foreign = Replica::<Counter>::new(nr.log());
+        assert_eq!(
+            nr.grow_log(64 * 1024 * 1024),
+            Err(LogError::ForeignReplicas)
+        );
+    }
+}
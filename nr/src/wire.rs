@@ -0,0 +1,254 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Shipping the operations on a shared log to another log (e.g., in a follower
+//! process on another machine, for disaster recovery). With the `serde`
+//! feature, entries can be sent over the wire in any format serde supports.
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::context::MAX_PENDING_OPS;
+use crate::gc::ExecSelf;
+use crate::ids::{LogOffset, ReplicaId};
+use crate::log::{DeltaCodec, Log};
+
+/// An operation on a log along with the metadata of its entry, as returned by
+/// `Log::export_since` and consumed by `Log::import_entries`.
+///
+/// With the `serde` feature, it is serialized as a tuple of the offset, the
+/// replica (both as `u64`) and the operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WireEntry<T> {
+    /// Logical offset of the entry on the log.
+    pub offset: LogOffset,
+
+    /// Identifier of the replica that appended the operation.
+    pub replica: ReplicaId,
+
+    /// The operation.
+    pub op: T,
+}
+
+#[cfg(feature = "serde")]
+impl<T: Serialize> Serialize for WireEntry<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (
+            self.offset.get() as u64,
+            self.replica.get() as u64,
+            &self.op,
+        )
+            .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for WireEntry<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (offset, replica, op) = <(u64, u64, T)>::deserialize(deserializer)?;
+        Ok(WireEntry {
+            offset: LogOffset::new(offset as usize),
+            replica: ReplicaId::new(replica as usize),
+            op,
+        })
+    }
+}
+
+/// Returned by `Log::import_entries` if an entry doesn't follow the entries
+/// that were imported before it, e.g., because the log it was exported from
+/// was garbage collected past the entries in between.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ImportGap {
+    /// The offset of the entry that `import_entries` expected next.
+    pub expected: LogOffset,
+
+    /// The offset of the entry that came instead.
+    pub found: LogOffset,
+}
+
+impl<'a, T, C> Log<'a, T, C>
+where
+    T: Sized + Clone,
+    C: DeltaCodec<T>,
+{
+    /// Returns the committed operations on the log (those that at least one
+    /// replica executed) from logical offset `offset` on, for a follower to
+    /// mirror with `import_entries`.
+    ///
+    /// Entries are garbage collected once every replica executed them, so the
+    /// follower has to keep up with the slowest replica. Like `iter_from`, the
+    /// iterator ends early if the head of the log moves past an entry before
+    /// the iterator gets to it. The follower notices that when it imports the
+    /// entries that come after.
+    pub fn export_since<'l>(&'l self, offset: LogOffset) -> impl Iterator<Item = WireEntry<T>> + 'l
+    where
+        'a: 'l,
+    {
+        let ctail = self.get_ctail();
+        self.iter_from(offset)
+            .take_while(move |(_op, _replica, offset)| offset.get() < ctail)
+            .map(|(op, replica, offset)| WireEntry {
+                offset,
+                replica,
+                op,
+            })
+    }
+
+    /// Appends the operations exported from another log with `export_since` to
+    /// this log, at the same offsets and marked with the same replicas. Returns
+    /// the offset of the entry to import next. Replicas on a follower should
+    /// use `Replica::import` instead.
+    ///
+    /// Entries before the tail of this log are skipped, so that a follower can
+    /// ask for entries it may already have after reconnecting. Fails with
+    /// `ImportGap` (after importing the entries before it) if an entry is past
+    /// the tail. Nothing else may append to this log; replicas of a follower
+    /// only serve reads.
+    ///
+    /// `idx` and `s` are used like in `append`: if the log is full, `s` executes
+    /// outstanding entries against replica `idx`.
+    pub fn import_entries<I, F>(
+        &self,
+        entries: I,
+        idx: ReplicaId,
+        mut s: F,
+    ) -> Result<LogOffset, ImportGap>
+    where
+        I: IntoIterator<Item = WireEntry<T>>,
+        F: FnMut(T, ReplicaId),
+    {
+        self.import_traced(entries, idx, |o: T, i: ReplicaId, _offset: LogOffset| {
+            s(o, i)
+        })
+    }
+
+    /// Same as `import_entries()`, but also passes the logical offset of every
+    /// executed entry to `s`.
+    pub(crate) fn import_traced<I, F>(
+        &self,
+        entries: I,
+        idx: ReplicaId,
+        mut s: F,
+    ) -> Result<LogOffset, ImportGap>
+    where
+        I: IntoIterator<Item = WireEntry<T>>,
+        F: FnMut(T, ReplicaId, LogOffset),
+    {
+        let mut next = self.get_tail();
+
+        // Entries of the same replica are appended in batches, like a combiner
+        // would.
+        let mut batch = Vec::with_capacity(MAX_PENDING_OPS);
+        let mut origin = idx;
+        let mut flush = |batch: &mut Vec<T>, origin: ReplicaId, next: &mut usize| {
+            if batch.is_empty() {
+                return;
+            }
+            let r = self.append_as(batch, idx, origin, &mut s, &(), &ExecSelf);
+            debug_assert_eq!(
+                r,
+                Ok(LogOffset::new(*next)),
+                "Another thread appended to a log while importing into it."
+            );
+            *next += batch.len();
+            batch.clear();
+        };
+
+        for entry in entries {
+            let expected = next + batch.len();
+            if entry.offset.get() < expected {
+                continue;
+            }
+            if entry.offset.get() > expected {
+                flush(&mut batch, origin, &mut next);
+                return Err(ImportGap {
+                    expected: LogOffset::new(expected),
+                    found: entry.offset,
+                });
+            }
+
+            if entry.replica != origin || batch.len() == MAX_PENDING_OPS {
+                flush(&mut batch, origin, &mut next);
+            }
+            origin = entry.replica;
+            batch.push(entry.op);
+        }
+        flush(&mut batch, origin, &mut next);
+
+        Ok(LogOffset::new(next))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests that a log mirrors the committed entries of another log, skips the
+    // entries it already has and rejects entries past its tail.
+    #[test]
+    fn test_wire_export_import() {
+        let leader = Log::<u64>::default();
+        let one = leader.register().unwrap();
+        let two = leader.register().unwrap();
+        leader.append(&[10, 20], one, |_o: u64, _i: ReplicaId| {});
+        leader.append(&[30], two, |_o: u64, _i: ReplicaId| {});
+        leader.exec(one, &mut |_o: u64, _i: ReplicaId| {});
+        leader.append(&[40], one, |_o: u64, _i: ReplicaId| {});
+
+        let follower = Log::<u64>::default();
+        let idx = follower.register().unwrap();
+        let noop = |_o: u64, _i: ReplicaId| {};
+
+        let exported: Vec<WireEntry<u64>> = leader.export_since(LogOffset::new(0)).collect();
+        assert_eq!(exported.len(), 3);
+        assert_eq!(
+            follower.import_entries(exported.clone(), idx, noop),
+            Ok(LogOffset::new(3))
+        );
+        assert_eq!(
+            follower.import_entries(exported.clone(), idx, noop),
+            Ok(LogOffset::new(3))
+        );
+        assert_eq!(
+            follower.export_since(LogOffset::new(0)).count(),
+            0,
+            "No replica executed the mirrored entries yet."
+        );
+
+        let mirrored: Vec<(u64, ReplicaId, LogOffset)> =
+            follower.iter_from(LogOffset::new(0)).collect();
+        let expected: Vec<(u64, ReplicaId, LogOffset)> = exported
+            .into_iter()
+            .map(|e| (e.op, e.replica, e.offset))
+            .collect();
+        assert_eq!(mirrored, expected);
+
+        leader.append(&[50], one, |_o: u64, _i: ReplicaId| {});
+        leader.exec(one, &mut |_o: u64, _i: ReplicaId| {});
+        assert_eq!(
+            follower.import_entries(leader.export_since(LogOffset::new(4)), idx, noop),
+            Err(ImportGap {
+                expected: LogOffset::new(3),
+                found: LogOffset::new(4)
+            })
+        );
+    }
+
+    // Tests that entries survive a round trip through a serde format.
+    #[cfg(feature = "export")]
+    #[test]
+    fn test_wire_serde() {
+        let entry = WireEntry {
+            offset: LogOffset::new(7),
+            replica: ReplicaId::new(2),
+            op: (1u64, 2u32),
+        };
+        let bytes = bincode::serialize(&entry).unwrap();
+        assert_eq!(
+            bincode::deserialize::<WireEntry<(u64, u32)>>(&bytes).unwrap(),
+            entry
+        );
+    }
+}
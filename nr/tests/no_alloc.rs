@@ -0,0 +1,94 @@
+// Copyright © 2019-2020 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Checks that replicas don't allocate memory once they are created. Run with
+//! the `no-alloc-runtime` feature.
+#![cfg(feature = "no-alloc-runtime")]
+
+extern crate std;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+use node_replication::Dispatch;
+use node_replication::Log;
+use node_replication::Replica;
+
+thread_local! {
+    /// Set while the thread must not allocate.
+    static FORBIDDEN: Cell<bool> = Cell::new(false);
+}
+
+/// The system allocator, except that it panics if the thread allocating is
+/// in a `forbid_alloc` section.
+struct PanicOnAlloc;
+
+unsafe impl GlobalAlloc for PanicOnAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Panicking allocates, so allow it again first.
+        if FORBIDDEN.with(|f| f.replace(false)) {
+            panic!("Allocated {} bytes after construction.", layout.size());
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PanicOnAlloc = PanicOnAlloc;
+
+/// Runs `f` and panics if it allocates on the calling thread.
+fn forbid_alloc<R, F: FnOnce() -> R>(f: F) -> R {
+    FORBIDDEN.with(|f| f.set(true));
+    let r = f();
+    FORBIDDEN.with(|f| f.set(false));
+    r
+}
+
+#[derive(Default)]
+struct Counter(u64);
+
+impl Dispatch for Counter {
+    type ReadOperation = ();
+    type WriteOperation = u64;
+    type Response = u64;
+
+    fn dispatch(&self, _op: Self::ReadOperation) -> Self::Response {
+        self.0
+    }
+
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        self.0 += op;
+        self.0
+    }
+}
+
+/// Tests that executing operations (including wrapping around the log a few
+/// times) doesn't allocate.
+#[test]
+fn no_alloc_after_new() {
+    let log = Arc::new(Log::<u64>::new(1024 * 1024));
+    let one = Replica::<Counter>::new(&log);
+    let two = Replica::<Counter>::new(&log);
+    let ridx = one.register().unwrap();
+    let idx = two.register().unwrap();
+
+    let entries = log.capacity() as u64;
+    forbid_alloc(|| {
+        for i in 0..3 * entries {
+            assert_eq!(one.execute_mut(1, ridx), Ok(i + 1));
+            if i % 64 == 0 {
+                two.sync(idx).unwrap();
+            }
+        }
+        assert_eq!(two.execute((), idx), Ok(3 * entries));
+        assert_eq!(one.execute((), ridx), Ok(3 * entries));
+    });
+
+    one.deregister(ridx);
+    two.deregister(idx);
+}
@@ -7,12 +7,14 @@ extern crate rand;
 extern crate std;
 
 use std::collections::HashMap;
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::{Arc, Barrier};
 use std::thread;
 use std::usize;
 
 use node_replication::Dispatch;
+use node_replication::DispatchReadMut;
 use node_replication::Log;
+use node_replication::ReadCtx;
 use node_replication::Replica;
 
 use rand::{thread_rng, Rng};
@@ -31,7 +33,6 @@ enum OpRd {
 struct Stack {
     storage: Vec<u32>,
     popped: Vec<Option<u32>>,
-    peeked: RwLock<Vec<Option<u32>>>,
 }
 
 fn compare_vectors<T: PartialEq>(a: &Vec<T>, b: &Vec<T>) -> bool {
@@ -56,7 +57,6 @@ impl Stack {
         if len > 0 {
             r = Some(self.storage[len - 1]);
         }
-        self.peeked.write().unwrap().push(r);
         return r;
     }
 }
@@ -66,7 +66,6 @@ impl Default for Stack {
         let s = Stack {
             storage: Default::default(),
             popped: Default::default(),
-            peeked: Default::default(),
         };
 
         s
@@ -95,6 +94,20 @@ impl Dispatch for Stack {
     }
 }
 
+impl DispatchReadMut for Stack {
+    type ReadRecord = Option<u32>;
+
+    fn dispatch_read_mut(
+        &self,
+        op: Self::ReadOperation,
+        peeked: &mut ReadCtx<Self::ReadRecord>,
+    ) -> Self::Response {
+        let r = self.dispatch(op);
+        peeked.record(r);
+        r
+    }
+}
+
 /// Sequential data structure test (one thread).
 ///
 /// Execute operations at random, comparing the result
@@ -113,11 +126,12 @@ fn sequential_test() {
     let mut correct_stack: Vec<u32> = Vec::new();
     let mut correct_popped: Vec<Option<u32>> = Vec::new();
     let mut correct_peeked: Vec<Option<u32>> = Vec::new();
+    let mut peeked = ReadCtx::new();
 
     // Populate with some initial data
     for _i in 0..50 {
         let element = orng.gen();
-        r.execute_mut(OpWr::Push(element), idx).unwrap();
+        r.execute_mut(OpWr::Push(element), idx).unwrap().unwrap();
         correct_stack.push(element);
     }
 
@@ -125,19 +139,19 @@ fn sequential_test() {
         let op: usize = orng.gen();
         match op % 3usize {
             0usize => {
-                let o = r.execute_mut(OpWr::Pop, idx);
+                let o = r.execute_mut(OpWr::Pop, idx).unwrap();
                 let popped = correct_stack.pop();
                 assert_eq!(popped, o);
                 correct_popped.push(popped);
             }
             1usize => {
                 let element = orng.gen();
-                let pushed = r.execute_mut(OpWr::Push(element), idx);
+                let pushed = r.execute_mut(OpWr::Push(element), idx).unwrap();
                 assert_eq!(pushed, Some(element));
                 correct_stack.push(element);
             }
             2usize => {
-                let o = r.execute(OpRd::Peek, idx);
+                let o = r.execute_read_mut(OpRd::Peek, idx, &mut peeked).unwrap();
                 let mut ele = None;
                 let len = correct_stack.len();
                 if len > 0 {
@@ -159,12 +173,12 @@ fn sequential_test() {
             compare_vectors(&correct_stack, &data.storage),
             "Push operation error detected"
         );
-        assert!(
-            compare_vectors(&correct_peeked, &data.peeked.read().unwrap()),
-            "Peek operation error detected"
-        );
     };
     r.verify(v);
+    assert!(
+        compare_vectors(&correct_peeked, &peeked.take()),
+        "Peek operation error detected"
+    );
 }
 
 /// A stack to verify that the log works correctly with multiple threads.
@@ -314,6 +328,7 @@ fn parallel_push_sequential_pop_test() {
                 for i in 0..nop {
                     replica
                         .execute_mut(OpWr::Push((i as u32) << 16 | tid), idx)
+                        .unwrap()
                         .unwrap();
                 }
             });
@@ -335,8 +350,8 @@ fn parallel_push_sequential_pop_test() {
         let token = replica.register().unwrap();
         for _j in 0..t {
             for _z in 0..nop {
-                replica.execute(OpRd::Peek, token).unwrap();
-                replica.execute_mut(OpWr::Pop, token).unwrap();
+                replica.execute(OpRd::Peek, token).unwrap().unwrap();
+                replica.execute_mut(OpWr::Pop, token).unwrap().unwrap();
             }
         }
     }
@@ -380,14 +395,15 @@ fn parallel_push_and_pop_test() {
                 for i in 0..nop {
                     replica
                         .execute_mut(OpWr::Push((i as u32) << 16 | tid), idx)
+                        .unwrap()
                         .unwrap();
                 }
 
                 // 2. Dequeue phase, verification
                 b.wait();
                 for _i in 0..nop {
-                    replica.execute(OpRd::Peek, idx).unwrap();
-                    replica.execute_mut(OpWr::Pop, idx).unwrap();
+                    replica.execute(OpRd::Peek, idx).unwrap().unwrap();
+                    replica.execute_mut(OpWr::Pop, idx).unwrap().unwrap();
                 }
             });
             threads.push(child);
@@ -421,7 +437,7 @@ fn bench(r: Arc<Replica<Stack>>, nop: usize, barrier: Arc<Barrier>) -> (u64, u64
     barrier.wait();
 
     for i in 0..nop {
-        r.execute_mut(ops[i], idx);
+        r.execute_mut(ops[i], idx).unwrap();
     }
 
     barrier.wait();